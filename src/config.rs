@@ -1,27 +1,407 @@
+use std::path::{Path, PathBuf};
+
 use serde::{Deserialize, Serialize};
 
-use crate::source::Playlist;
+use crate::source::{Playlist, Source};
+use crate::smart_playlist::Rule;
+use crate::layout::Layout;
+use crate::utils::matches_pattern;
+use crate::transcode::{Codec, Profile};
+use crate::sync::InterruptedSyncAction;
 
 pub fn val_true() -> bool{ true }
 pub fn val_false() -> bool{ false }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Config {
-    source: String,
+    #[serde(flatten)]
+    sources: Sources,
     #[serde(default = "crate::config::val_true")]
     include_ratings: bool,
     #[serde(default = "crate::config::val_false")]
     use_computed_ratings: bool,
-    playlists: Vec<String>,
+    /// What to do with songs whose ratings have changed on both the source and the device since the previous sync
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    rating_conflicts: Option<RatingConflictPolicy>,
+    /// Maximum total size of the music files pushed to the device
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    size_budget: Option<ByteSize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    transcoding: Option<TranscodingConfig>,
+    /// Where files are pushed into the music folder, from the tags of their tracks (see [`crate::layout`]).
+    /// By default, they have the same paths as in the source.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    layout: Option<String>,
+    /// Whether this device is synced as soon as it is plugged in, while `starsync watch` is running
+    #[serde(default = "crate::config::val_false")]
+    auto_sync: bool,
+    /// How the sanity checks are answered when nobody is there to answer them (i.e. for automatic syncs)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    unattended: Option<UnattendedPolicy>,
+}
+
+
+
+/// A source the device is synced with, and which of its playlists are synced
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SourceConfig {
+    pub name: String,
+    pub playlists: Vec<PlaylistConfig>,
+}
+
+/// The sources of a device.
+///
+/// In the config file, a device that is synced with a single source has a `source` and a `playlists` field (this is how older versions of StarSync wrote it).
+/// Otherwise, it has a `sources` field, that lists objects with a `name` and `playlists`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(from = "SourcesEntry", into = "SourcesEntry")]
+struct Sources(Vec<SourceConfig>);
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum SourcesEntry {
+    Single {
+        source: String,
+        playlists: Vec<PlaylistConfig>,
+    },
+    Multiple {
+        sources: Vec<SourceConfig>,
+    },
+}
+
+impl From<SourcesEntry> for Sources {
+    fn from(entry: SourcesEntry) -> Self {
+        match entry {
+            SourcesEntry::Single{ source, playlists } => Sources(vec![SourceConfig{ name: source, playlists }]),
+            SourcesEntry::Multiple{ sources } => Sources(sources),
+        }
+    }
+}
+
+impl From<Sources> for SourcesEntry {
+    fn from(mut sources: Sources) -> Self {
+        // Keep the config file as simple as possible (and readable by older versions)
+        if sources.0.len() == 1 {
+            let SourceConfig{ name, playlists } = sources.0.remove(0);
+            SourcesEntry::Single{ source: name, playlists }
+        } else {
+            SourcesEntry::Multiple{ sources: sources.0 }
+        }
+    }
+}
+
+
+
+/// What to do with a song whose rating has changed on both the source and the device since the previous sync.
+///
+/// In the config file, this is one of `"source_wins"` (the default), `"device_wins"`, `"highest"`, `"lowest"` or `"ask"`.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RatingConflictPolicy {
+    /// Keep the rating of the source (and push it to the device)
+    #[default]
+    SourceWins,
+    /// Update the source with the rating of the device
+    DeviceWins,
+    /// Keep the highest of both ratings (a song without rating is rated lower than any rated song)
+    Highest,
+    /// Keep the lowest of both ratings
+    Lowest,
+    /// Let the user choose for every song (see [`crate::sync::SyncValidator::rating_conflicts`]).
+    /// In case there is nobody to answer, the rating of the source is kept.
+    Ask,
+}
+
+impl std::str::FromStr for RatingConflictPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.replace('-', "_").as_str() {
+            "source_wins" => Ok(Self::SourceWins),
+            "device_wins" => Ok(Self::DeviceWins),
+            "highest" => Ok(Self::Highest),
+            "lowest" => Ok(Self::Lowest),
+            "ask" => Ok(Self::Ask),
+            _ => Err(format!("Invalid rating conflict policy '{s}' (expected source-wins, device-wins, highest, lowest or ask)")),
+        }
+    }
+}
+
+
+
+/// How the sanity checks of a sync (see [`crate::sync::SyncValidator`]) are answered when nobody is there to answer them.
+///
+/// By default, any failed check aborts the sync.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct UnattendedPolicy {
+    /// Sync even though the previous sync has been performed from another computer
+    #[serde(default)]
+    pub allow_other_computer: bool,
+    /// Sync even though the files to sync do not fit into the device
+    #[serde(default)]
+    pub allow_not_enough_space: bool,
+    /// What to do in case the previous sync has been interrupted (`"resume"` or `"roll_back"`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub interrupted_sync: Option<InterruptedSyncAction>,
+    /// Keep syncing playlists that seem to have been renamed in the source, under their new names
+    #[serde(default)]
+    pub follow_renamed_playlists: bool,
+}
+
+
+
+/// A playlist to sync, and how much of it should be pushed
+///
+/// In the config file, this is either the plain name of the playlist, or an object with a `name` and any of the options.
+#[derive(Clone, Debug, Eq, Hash, PartialEq, Serialize, Deserialize)]
+#[serde(from = "PlaylistEntry", into = "PlaylistEntry")]
+pub struct PlaylistConfig {
+    pub name: String,
+    /// In case this is set, this is a smart playlist, made of the tracks of the source that match this rule, rather than a playlist of the source.
+    /// See [`crate::smart_playlist`] for the syntax.
+    pub rule: Option<String>,
+    pub options: PlaylistOptions,
+}
+
+#[derive(Clone, Debug, Default, Eq, Hash, PartialEq, Serialize, Deserialize)]
+pub struct PlaylistOptions {
+    /// Playlists with a higher priority are filled first when the size budget is limited.
+    /// Playlists with the same priority (the default is 0) are filled in the order of the config file.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub priority: Option<i32>,
+    /// Maximum total size of the tracks of this playlist
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_size: Option<ByteSize>,
+    /// Maximum number of tracks of this playlist
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_tracks: Option<usize>,
+    /// Tracks rated lower than this (in stars) are not pushed. Unrated tracks count as 0 stars.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_rating: Option<u8>,
+}
+
+impl PlaylistConfig {
+    pub fn new(name: String) -> Self {
+        Self{ name, rule: None, options: PlaylistOptions::default() }
+    }
+
+    pub fn priority(&self) -> i32 {
+        self.options.priority.unwrap_or(0)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum PlaylistEntry {
+    Name(String),
+    WithOptions {
+        name: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        rule: Option<String>,
+        #[serde(flatten)]
+        options: PlaylistOptions,
+    },
+}
+
+impl From<PlaylistEntry> for PlaylistConfig {
+    fn from(entry: PlaylistEntry) -> Self {
+        match entry {
+            PlaylistEntry::Name(name) => PlaylistConfig::new(name),
+            PlaylistEntry::WithOptions{ name, rule, options } => PlaylistConfig{ name, rule, options },
+        }
+    }
+}
+
+impl From<PlaylistConfig> for PlaylistEntry {
+    fn from(config: PlaylistConfig) -> Self {
+        // Keep the config file as simple as possible
+        if config.rule.is_none() && config.options == PlaylistOptions::default() {
+            PlaylistEntry::Name(config.name)
+        } else {
+            PlaylistEntry::WithOptions{ name: config.name, rule: config.rule, options: config.options }
+        }
+    }
+}
+
+
+
+/// A size, in bytes.
+///
+/// In the config file, this is either a number of bytes, or a string with a unit, such as `"28 GB"` or `"500MiB"`
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "RawByteSize", into = "u64")]
+pub struct ByteSize(pub u64);
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawByteSize {
+    Bytes(u64),
+    Text(String),
+}
+
+impl TryFrom<RawByteSize> for ByteSize {
+    type Error = String;
+
+    fn try_from(raw: RawByteSize) -> Result<Self, Self::Error> {
+        match raw {
+            RawByteSize::Bytes(bytes) => Ok(ByteSize(bytes)),
+            RawByteSize::Text(text) => text.parse(),
+        }
+    }
+}
+
+impl From<ByteSize> for u64 {
+    fn from(size: ByteSize) -> u64 {
+        size.0
+    }
+}
+
+impl std::str::FromStr for ByteSize {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let unit_start = s.find(|c: char| c.is_ascii_digit() == false && c != '.').unwrap_or(s.len());
+        let (number, unit) = s.split_at(unit_start);
+        let number: f64 = number.parse().map_err(|_| format!("Invalid size '{s}'"))?;
+        let multiplier: u64 = match unit.trim().to_lowercase().as_str() {
+            "" | "b" => 1,
+            "k" | "kb" => 1_000,
+            "m" | "mb" => 1_000_000,
+            "g" | "gb" => 1_000_000_000,
+            "t" | "tb" => 1_000_000_000_000,
+            "kib" => 1 << 10,
+            "mib" => 1 << 20,
+            "gib" => 1 << 30,
+            "tib" => 1 << 40,
+            _ => return Err(format!("Invalid unit in size '{s}'")),
+        };
+        Ok(ByteSize((number * multiplier as f64) as u64))
+    }
+}
+
+
+
+
+/// Convert some files (usually lossless ones) before pushing them to the device, e.g. to save space, or because the device is not able to play them
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TranscodingConfig {
+    /// Extensions of the files to convert. Other files (e.g. lossy ones) are pushed as-is
+    #[serde(default = "crate::config::default_transcoded_extensions")]
+    pub extensions: Vec<String>,
+    pub codec: Codec,
+    pub bitrate_kbps: u32,
+    #[serde(default)]
+    pub encoder: EncoderConfig,
+}
+
+pub fn default_transcoded_extensions() -> Vec<String> {
+    ["flac", "wav", "aiff", "aif", "ape", "wv"].iter().map(|ext| ext.to_string()).collect()
+}
+
+impl TranscodingConfig {
+    /// How a file should be converted, or `None` if it should be pushed as-is
+    pub fn profile_for(&self, path: &Path) -> Option<Profile> {
+        let extension = path.extension()?.to_string_lossy();
+        if self.extensions.iter().any(|ext| ext.eq_ignore_ascii_case(&extension)) {
+            Some(Profile{ codec: self.codec, bitrate_kbps: self.bitrate_kbps })
+        } else {
+            None
+        }
+    }
+}
+
+/// The program used to convert files
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum EncoderConfig {
+    /// `ffmpeg`, either the one in the `PATH` or at a given location
+    Ffmpeg {
+        #[serde(default)]
+        path: Option<PathBuf>,
+    },
+    /// Any command. See [`crate::transcode::CustomCommand`] for the placeholders that can be used in its arguments
+    Command {
+        program: String,
+        args: Vec<String>,
+    },
+}
+
+impl Default for EncoderConfig {
+    fn default() -> Self {
+        EncoderConfig::Ffmpeg{ path: None }
+    }
+}
+
+impl SourceConfig {
+    /// A default config for a source, that selects the playlists that match any of the `include` patterns (or every
+    /// playlist if there is no such pattern), except those that match any of the `exclude` patterns.
+    ///
+    /// See [`matches_pattern`] for the syntax of patterns.
+    pub fn new_template(source_name: &str, playlists: &[Box<dyn Playlist>], include: &[String], exclude: &[String]) -> Self {
+        let is_selected = |name: &str| {
+            (include.is_empty() || include.iter().any(|pattern| matches_pattern(pattern, name)))
+            && exclude.iter().any(|pattern| matches_pattern(pattern, name)) == false
+        };
+
+        Self {
+            name: source_name.to_string(),
+            playlists: playlists.iter()
+                .map(|p| p.name())
+                .filter(|name| is_selected(name))
+                .map(PlaylistConfig::new)
+                .collect(),
+        }
+    }
+
+    /// Names of the playlists to sync
+    pub fn playlists(&self) -> impl Iterator<Item = &str> {
+        self.playlists.iter().map(|p| p.name.as_str())
+    }
+
+    /// Add a playlist to sync. This returns `false` in case it was already selected.
+    pub fn add_playlist(&mut self, name: &str) -> bool {
+        if self.playlists().any(|selected| selected == name) {
+            return false;
+        }
+        self.playlists.push(PlaylistConfig::new(name.to_string()));
+        true
+    }
+
+    /// Stop syncing the playlists that match a pattern (see [`matches_pattern`]), and return their names
+    pub fn remove_playlists(&mut self, pattern: &str) -> Vec<String> {
+        let (removed, kept) = std::mem::take(&mut self.playlists)
+            .into_iter()
+            .partition(|p| matches_pattern(pattern, &p.name));
+        self.playlists = kept;
+        removed.into_iter().map(|p: PlaylistConfig| p.name).collect()
+    }
+
+    /// Sync a playlist under its new name, keeping its options. This returns `false` in case no playlist had the former name.
+    pub fn rename_playlist(&mut self, old_name: &str, new_name: &str) -> bool {
+        match self.playlists.iter_mut().find(|p| p.name == old_name) {
+            None => false,
+            Some(playlist) => {
+                playlist.name = new_name.to_string();
+                true
+            },
+        }
+    }
 }
 
 impl Config {
-    pub fn new_template(source_name: &str, playlists: &[Box<dyn Playlist>]) -> Config {
+    /// A default config, for a device synced with a single source. See [`SourceConfig::new_template`].
+    pub fn new_template(source_name: &str, playlists: &[Box<dyn Playlist>], include: &[String], exclude: &[String]) -> Config {
         Config {
-            source: source_name.to_string(),
+            sources: Sources(vec![SourceConfig::new_template(source_name, playlists, include, exclude)]),
             include_ratings: true,
             use_computed_ratings: false,
-            playlists: playlists.iter().map(|p| p.name()).collect()
+            rating_conflicts: None,
+            size_budget: None,
+            transcoding: None,
+            layout: None,
+            auto_sync: false,
+            unattended: None,
         }
     }
 
@@ -29,12 +409,59 @@ impl Config {
         serde_json::from_str(config_str)
     }
 
-    pub fn source(&self) -> &str {
-        &self.source
+    /// The sources this device is synced with.
+    ///
+    /// The first one is the main source. Songs and playlists that have been synced by older versions of StarSync, that did not record their sources, are assumed to come from it.
+    pub fn sources(&self) -> &[SourceConfig] {
+        &self.sources.0
     }
 
-    pub fn playlists(&self) -> &[String] {
-        &self.playlists
+    /// Names of the sources this device is synced with
+    pub fn source_names(&self) -> impl Iterator<Item = &str> {
+        self.sources.0.iter().map(|s| s.name.as_str())
+    }
+
+    pub fn source(&self, name: &str) -> Option<&SourceConfig> {
+        self.sources.0.iter().find(|s| s.name == name)
+    }
+
+    pub fn source_mut(&mut self, name: &str) -> Option<&mut SourceConfig> {
+        self.sources.0.iter_mut().find(|s| s.name == name)
+    }
+
+    /// Sync this device with another source. This returns `false` in case it already was.
+    pub fn add_source(&mut self, source: SourceConfig) -> bool {
+        if self.source(&source.name).is_some() {
+            return false;
+        }
+        self.sources.0.push(source);
+        true
+    }
+
+    /// Stop syncing this device with a source. This returns `false` in case it was not.
+    pub fn remove_source(&mut self, name: &str) -> bool {
+        let n_sources = self.sources.0.len();
+        self.sources.0.retain(|s| s.name != name);
+        self.sources.0.len() != n_sources
+    }
+
+    /// Names of the playlists to sync, from every source
+    pub fn playlists(&self) -> impl Iterator<Item = &str> {
+        self.sources.0.iter().flat_map(|s| s.playlists())
+    }
+
+    /// The playlists to sync (along with the source they come from), in the order they should be filled
+    pub fn playlists_by_priority(&self) -> Vec<(&SourceConfig, &PlaylistConfig)> {
+        let mut playlists: Vec<_> = self.sources.0.iter()
+            .flat_map(|source| source.playlists.iter().map(move |playlist| (source, playlist)))
+            .collect();
+        // This sort is stable, so that the order of the config file is kept for playlists with the same priority
+        playlists.sort_by_key(|(_, p)| std::cmp::Reverse(p.priority()));
+        playlists
+    }
+
+    pub fn size_budget(&self) -> Option<u64> {
+        self.size_budget.map(|size| size.0)
     }
 
     pub fn include_ratings(&self) -> bool {
@@ -44,4 +471,200 @@ impl Config {
     pub fn use_computed_ratings(&self) -> bool {
         self.use_computed_ratings
     }
+
+    pub fn rating_conflict_policy(&self) -> RatingConflictPolicy {
+        self.rating_conflicts.unwrap_or_default()
+    }
+
+    pub fn transcoding(&self) -> Option<&TranscodingConfig> {
+        self.transcoding.as_ref()
+    }
+
+    pub fn layout(&self) -> Option<&str> {
+        self.layout.as_deref()
+    }
+
+    pub fn auto_sync(&self) -> bool {
+        self.auto_sync
+    }
+
+    pub fn unattended_policy(&self) -> UnattendedPolicy {
+        self.unattended.clone().unwrap_or_default()
+    }
+
+    pub fn set_include_ratings(&mut self, include_ratings: bool) {
+        self.include_ratings = include_ratings;
+    }
+
+    pub fn set_use_computed_ratings(&mut self, use_computed_ratings: bool) {
+        self.use_computed_ratings = use_computed_ratings;
+    }
+
+    pub fn set_rating_conflict_policy(&mut self, policy: RatingConflictPolicy) {
+        self.rating_conflicts = Some(policy);
+    }
+
+    pub fn set_layout(&mut self, layout: Option<String>) {
+        self.layout = layout;
+    }
+
+    pub fn set_auto_sync(&mut self, auto_sync: bool) {
+        self.auto_sync = auto_sync;
+    }
+
+    /// Check this config is consistent with its sources, and return the list of issues.
+    ///
+    /// `sources` are the sources that are currently available. The ones this config refers to but that are missing are reported as well.
+    pub fn validate(&self, sources: &[Box<dyn Source>]) -> Vec<String> {
+        let mut issues = Vec::new();
+
+        if self.sources.0.is_empty() {
+            issues.push("The device is synced with no source".to_string());
+        }
+
+        if let Some(Err(err)) = self.layout.as_ref().map(|layout| layout.parse::<Layout>()) {
+            issues.push(err.to_string());
+        }
+
+        let mut names = std::collections::HashSet::new();
+        let mut source_names = std::collections::HashSet::new();
+        for source_config in &self.sources.0 {
+            if source_names.insert(source_config.name.as_str()) == false {
+                issues.push(format!("Source '{}' is listed several times", source_config.name));
+            }
+
+            let source_playlists = match sources.iter().find(|source| source.name() == source_config.name) {
+                None => {
+                    issues.push(format!("Source '{}' is not available", source_config.name));
+                    None
+                },
+                Some(source) => match source.playlists() {
+                    Err(err) => {
+                        issues.push(format!("Unable to list the playlists of source '{}': {}", source_config.name, err));
+                        None
+                    },
+                    Ok(playlists) => Some(playlists.iter().map(|p| p.name()).collect::<Vec<_>>()),
+                },
+            };
+
+            if source_config.playlists.is_empty() {
+                issues.push(format!("No playlist is selected from source '{}'", source_config.name));
+            }
+
+            for playlist in &source_config.playlists {
+                // Playlists are stored on the device by name, whatever their sources
+                if names.insert(playlist.name.as_str()) == false {
+                    issues.push(format!("Playlist '{}' is selected several times", playlist.name));
+                }
+
+                match (&playlist.rule, &source_playlists) {
+                    (None, Some(source_playlists)) => if source_playlists.contains(&playlist.name) == false {
+                        issues.push(format!("Playlist '{}' does not exist in source '{}'", playlist.name, source_config.name));
+                    },
+                    (None, None) => (),
+                    (Some(rule), _) => if let Err(err) = rule.parse::<Rule>() {
+                        issues.push(format!("Invalid rule for smart playlist '{}': {}", playlist.name, err));
+                    },
+                }
+
+                if let Some(min_rating) = playlist.options.min_rating.filter(|stars| *stars > 5) {
+                    issues.push(format!("Playlist '{}' has a minimum rating of {} stars, which is more than 5", playlist.name, min_rating));
+                }
+            }
+        }
+
+        issues
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_sizes() {
+        assert_eq!("28 GB".parse(), Ok(ByteSize(28_000_000_000)));
+        assert_eq!("1.5GiB".parse(), Ok(ByteSize(1_610_612_736)));
+        assert_eq!("500 mb".parse(), Ok(ByteSize(500_000_000)));
+        assert_eq!("1234".parse(), Ok(ByteSize(1234)));
+        assert!("12 parsecs".parse::<ByteSize>().is_err());
+        assert!("GB".parse::<ByteSize>().is_err());
+    }
+
+    #[test]
+    fn parse_playlists() {
+        let config = Config::new(r#"{
+            "source": "rhythmbox",
+            "size_budget": "28 GB",
+            "playlists": [
+                "Road trip",
+                { "name": "Everything", "max_size": 1000, "min_rating": 3 },
+                { "name": "Favourites", "priority": 10, "max_tracks": 50 },
+                { "name": "Recent jazz", "rule": "genre = \"Jazz\" and added within 30 days" }
+            ]
+        }"#).unwrap();
+        assert_eq!(config.size_budget(), Some(28_000_000_000));
+        assert_eq!(config.playlists().collect::<Vec<_>>(), vec!["Road trip", "Everything", "Favourites", "Recent jazz"]);
+        assert_eq!(config.playlists_by_priority().iter().map(|(_, p)| p.name.as_str()).collect::<Vec<_>>(), vec!["Favourites", "Road trip", "Everything", "Recent jazz"]);
+        let playlists = &config.sources()[0].playlists;
+        assert_eq!(playlists[1].options.max_size, Some(ByteSize(1000)));
+        assert_eq!(playlists[3].rule.as_deref(), Some("genre = \"Jazz\" and added within 30 days"));
+
+        // Playlists without options are written back as plain names
+        let serialized = serde_json::to_value(&config).unwrap();
+        assert_eq!(serialized["playlists"][0], "Road trip");
+        assert_eq!(serialized["playlists"][2]["priority"], 10);
+        assert_eq!(serialized["playlists"][3]["rule"], "genre = \"Jazz\" and added within 30 days");
+    }
+
+    #[test]
+    fn edit_playlists() {
+        let mut config = Config::new(r#"{ "source": "rhythmbox", "playlists": ["Road trip", "Jazz 2022", "Jazz 2023"] }"#).unwrap();
+        let source = config.source_mut("rhythmbox").unwrap();
+        assert!(source.add_playlist("Road trip") == false);
+        assert!(source.add_playlist("Christmas"));
+        assert_eq!(source.remove_playlists("jazz*"), vec!["Jazz 2022", "Jazz 2023"]);
+        assert!(source.rename_playlist("Christmas", "Xmas"));
+        assert!(source.rename_playlist("Easter", "Spring") == false);
+        assert_eq!(config.playlists().collect::<Vec<_>>(), vec!["Road trip", "Xmas"]);
+    }
+
+    #[test]
+    fn parse_rating_conflicts() {
+        let config = Config::new(r#"{ "source": "rhythmbox", "playlists": [], "rating_conflicts": "device_wins" }"#).unwrap();
+        assert_eq!(config.rating_conflict_policy(), RatingConflictPolicy::DeviceWins);
+        assert_eq!("source-wins".parse(), Ok(RatingConflictPolicy::SourceWins));
+        assert_eq!("highest".parse(), Ok(RatingConflictPolicy::Highest));
+        assert!("loudest".parse::<RatingConflictPolicy>().is_err());
+    }
+
+    #[test]
+    fn parse_sources() {
+        let config = Config::new(r#"{
+            "sources": [
+                { "name": "rhythmbox", "playlists": ["Road trip", "Favourites"] },
+                { "name": "folder:///srv/recordings", "playlists": ["Rehearsals"] }
+            ],
+            "include_ratings": false
+        }"#).unwrap();
+        assert_eq!(config.source_names().collect::<Vec<_>>(), vec!["rhythmbox", "folder:///srv/recordings"]);
+        assert_eq!(config.playlists().collect::<Vec<_>>(), vec!["Road trip", "Favourites", "Rehearsals"]);
+        assert_eq!(config.source("folder:///srv/recordings").map(|s| s.playlists.len()), Some(1));
+        assert!(config.include_ratings() == false);
+        assert_eq!(config.rating_conflict_policy(), RatingConflictPolicy::SourceWins);
+
+        let serialized = serde_json::to_value(&config).unwrap();
+        assert_eq!(serialized["sources"][1]["name"], "folder:///srv/recordings");
+        assert!(serialized.get("source").is_none());
+
+        // Configs with a single source are written the way older versions of StarSync expect them
+        let mut config = config;
+        assert!(config.remove_source("folder:///srv/recordings"));
+        let serialized = serde_json::to_value(&config).unwrap();
+        assert_eq!(serialized["source"], "rhythmbox");
+        assert_eq!(serialized["playlists"][1], "Favourites");
+        assert!(serialized.get("sources").is_none());
+    }
 }
@@ -3,13 +3,12 @@
 use std::ffi::OsStr;
 use std::path::{Path, PathBuf};
 use std::error::Error;
-use std::io::Read;
+use std::io::{Read, Write};
 
 use sysinfo::{System, SystemExt, RefreshKind, DiskExt};
 
 use super::{File, Folder};
-use crate::config::Config;
-use crate::sync::SyncInfo;
+use super::filesystem::FileSystem;
 
 #[cfg(feature = "debug_folder")]
 use once_cell::sync::Lazy;
@@ -27,7 +26,7 @@ pub fn devices() -> Vec<LocalDevice> {
     let system = System::new_with_specifics(RefreshKind::new().with_disks_list());
     let disks = system.disks();
     for disk in disks {
-        devs.push(LocalDevice{ mount_point: disk.mount_point().to_owned() });
+        devs.push(LocalDevice{ mount_point: disk.mount_point().to_owned(), is_gvfs_mount: false });
     }
 
     #[cfg(unix)]
@@ -35,21 +34,46 @@ pub fn devices() -> Vec<LocalDevice> {
         match mtp_gvfs::devices() {
             Err(err) => log::info!("Unable to list MTP devices. Are you using GNOME? ({err})"),
             Ok(mtp_devices) => for mount_point in mtp_devices {
-                devs.push(LocalDevice{ mount_point });
+                devs.push(LocalDevice{ mount_point, is_gvfs_mount: true });
             }
         }
     }
 
 
     #[cfg(feature = "debug_folder")]
-    devs.push(LocalDevice{ mount_point: DEBUG_FOLDER.to_owned() });
+    devs.push(LocalDevice{ mount_point: DEBUG_FOLDER.to_owned(), is_gvfs_mount: false });
 
     devs
 }
 
+/// A snapshot of the mounted filesystems (including MTP devices mounted by gvfs), that changes whenever something is mounted or unmounted.
+///
+/// This is much cheaper than listing devices, so that it can be polled to notice when a device is plugged in.
+/// This is `None` on platforms where there is no such thing.
+#[cfg(unix)]
+pub fn mount_state() -> Option<String> {
+    let mut state = std::fs::read_to_string("/proc/self/mountinfo").ok()?;
+    if let Ok(gvfs_folder) = mtp_gvfs::gvfs_folder() {
+        if let Ok(entries) = std::fs::read_dir(gvfs_folder) {
+            for entry in entries.filter_map(|entry| entry.ok()) {
+                state.push_str(&entry.file_name().to_string_lossy());
+                state.push('\n');
+            }
+        }
+    }
+    Some(state)
+}
+
+#[cfg(not(unix))]
+pub fn mount_state() -> Option<String> {
+    None
+}
+
 
 pub struct LocalDevice {
     mount_point: PathBuf,
+    /// GNOME (gvfs) FUSE mounts (e.g. MTP devices) usually do not report their disk usage to `statvfs`
+    is_gvfs_mount: bool,
 }
 
 impl LocalDevice {
@@ -91,6 +115,66 @@ impl LocalDevice {
             None
         }
     }
+
+    fn rockbox_folder_impl(&self) -> Option<PathBuf> {
+        let candidate = self.mount_point.join(crate::device::rockbox::ROCKBOX_FOLDER_NAME);
+        if candidate.is_dir() {
+            Some(candidate)
+        } else {
+            None
+        }
+    }
+
+    /// The free space and capacity of this device
+    fn disk_usage(&self) -> Option<DiskUsage> {
+        #[cfg(unix)]
+        if self.is_gvfs_mount {
+            return match mtp_gvfs::disk_usage(&self.mount_point) {
+                Err(err) => {
+                    log::info!("Unable to get disk usage of {}: {err}", self.mount_point.display());
+                    None
+                },
+                Ok(usage) => Some(usage),
+            };
+        }
+
+        match disk_usage(&self.mount_point) {
+            Err(err) => {
+                log::info!("Unable to get disk usage of {}: {err}", self.mount_point.display());
+                None
+            },
+            Ok(usage) => usage,
+        }
+    }
+}
+
+/// Free space and capacity, in bytes
+pub(crate) struct DiskUsage {
+    pub free: u64,
+    pub capacity: u64,
+}
+
+#[cfg(unix)]
+fn disk_usage(path: &Path) -> Result<Option<DiskUsage>, Box<dyn Error>> {
+    let stats = nix::sys::statvfs::statvfs(path)?;
+    if stats.blocks() == 0 {
+        // Some (e.g. FUSE) filesystems do not tell
+        return Ok(None);
+    }
+    let fragment_size = stats.fragment_size() as u64;
+    Ok(Some(DiskUsage{
+        free: stats.blocks_available() as u64 * fragment_size,
+        capacity: stats.blocks() as u64 * fragment_size,
+    }))
+}
+
+#[cfg(not(unix))]
+fn disk_usage(path: &Path) -> Result<Option<DiskUsage>, Box<dyn Error>> {
+    let system = System::new_with_specifics(RefreshKind::new().with_disks_list());
+    Ok(system.disks()
+        .iter()
+        .find(|disk| disk.mount_point() == path)
+        .map(|disk| DiskUsage{ free: disk.available_space(), capacity: disk.total_space() }))
 }
 
 impl super::Device for LocalDevice {
@@ -137,36 +221,47 @@ impl super::Device for LocalDevice {
         self.config_folder_path().join(crate::device::CONFIG_FILE).display().to_string()
     }
 
-    fn config(&self) -> Option<Config> {
-        let config_folder = self.config_folder_impl()?;
-        let config_file = config_folder.join(crate::device::CONFIG_FILE);
-        let config_str = std::fs::File::open(config_file).ok()?;
-        serde_json::from_reader(&config_str).ok()
-    }
-
-    fn push_config(&self, config: &Config) -> Result<(), Box<dyn Error>> {
+    fn push_config_file(&self, file_name: &OsStr, content: &[u8]) -> Result<(), Box<dyn Error>> {
         let config_folder = self.config_folder_impl().ok_or("Missing StarSync folder")?;
-        let config_path = config_folder.join(crate::device::CONFIG_FILE);
-        let config_file = std::fs::File::create(config_path).map_err(|err| format!("Unable to write to device: {}", err))?;
-        serde_json::to_writer_pretty(&config_file, config).map_err(|err| format!("Unable to write the configuration file: {}", err))?;
+        write_atomically(&config_folder.join(file_name), content).map_err(|err| format!("Unable to write to device: {}", err))?;
         Ok(())
     }
 
-    fn previous_sync_infos(&self) -> Option<SyncInfo> {
-        let config_folder = self.config_folder_impl()?;
-        let info_path = config_folder.join(crate::device::SYNC_INFO_FILE);
-        let sync_info_str = std::fs::File::open(info_path).ok()?;
-        serde_json::from_reader(&sync_info_str).ok()
+    fn file_system(&self) -> Option<FileSystem> {
+        if self.is_gvfs_mount {
+            // MTP devices do not tell
+            return None;
+        }
+        let system = System::new_with_specifics(RefreshKind::new().with_disks_list());
+        system.disks()
+            .iter()
+            .find(|disk| disk.mount_point() == self.mount_point)
+            .and_then(|disk| FileSystem::from_name(&String::from_utf8_lossy(disk.file_system())))
     }
 
-    fn push_sync_infos(&self, sync_infos: &SyncInfo) -> Result<(), Box<dyn Error>> {
-        let config_folder = self.config_folder_impl().ok_or("Missing StarSync folder")?;
-        let info_path = config_folder.join(crate::device::SYNC_INFO_FILE);
-        let info_file = std::fs::File::create(info_path).map_err(|err| format!("Unable to write to device: {}", err))?;
-        serde_json::to_writer(&info_file, sync_infos).map_err(|err| format!("Unable to write the sync info file: {}", err))?;
+    fn root_folder(&self) -> Option<Box<dyn Folder>> {
+        Some(Box::new(LocalFolder(self.mount_point.clone())))
+    }
+
+    fn rockbox_folder(&self) -> Option<Box<dyn Folder>> {
+        self.rockbox_folder_impl()
+            .map(|folder| Box::new(LocalFolder(folder)) as Box<dyn Folder>)
+    }
+
+    fn push_rockbox_file(&self, file_name: &OsStr, content: &[u8]) -> Result<(), Box<dyn Error>> {
+        let rockbox_folder = self.rockbox_folder_impl().ok_or("Missing Rockbox folder")?;
+        write_atomically(&rockbox_folder.join(file_name), content).map_err(|err| format!("Unable to write to device: {}", err))?;
         Ok(())
     }
 
+    fn free_space(&self) -> Option<u64> {
+        self.disk_usage().map(|usage| usage.free)
+    }
+
+    fn capacity(&self) -> Option<u64> {
+        self.disk_usage().map(|usage| usage.capacity)
+    }
+
     fn push_music_file(&self, local_absolute_path: &Path, device_relative_path: &Path) -> Result<(), Box<dyn Error>> {
         let dest_path = self.music_folder_path().join(device_relative_path);
         if let Some(dest_folder) = dest_path.parent() {
@@ -186,10 +281,35 @@ impl super::Device for LocalDevice {
                 std::fs::create_dir_all(dest_folder)?;
             }
         }
-        Ok(std::fs::write(dest_path, content)?)
+        Ok(write_atomically(&dest_path, content.as_bytes())?)
     }
 }
 
+/// Write a file, so that it has either its previous content or its new content, even in case this is interrupted (e.g. the device is unplugged).
+///
+/// The content is written into a temporary file, that is flushed to the disk, then renamed.
+fn write_atomically(path: &Path, content: &[u8]) -> std::io::Result<()> {
+    let mut temp_file_name = path.file_name().unwrap_or_default().to_os_string();
+    temp_file_name.push(".tmp");
+    let temp_path = path.with_file_name(temp_file_name);
+
+    let mut temp_file = std::fs::File::create(&temp_path)?;
+    temp_file.write_all(content)?;
+    temp_file.sync_all()?;
+    drop(temp_file);
+
+    std::fs::rename(&temp_path, path)?;
+
+    // Make sure the rename itself has reached the disk
+    // (not every filesystem supports this, e.g. some FUSE mounts, hence the ignored error)
+    #[cfg(unix)]
+    if let Some(parent) = path.parent() {
+        let _ = std::fs::File::open(parent).and_then(|folder| folder.sync_all());
+    }
+
+    Ok(())
+}
+
 pub struct LocalFolder(PathBuf);
 pub struct LocalFile(PathBuf);
 
@@ -253,5 +373,10 @@ impl File for LocalFile {
     fn delete(&mut self) -> Result<(), Box<dyn Error>> {
         Ok(std::fs::remove_file(&self.0)?)
     }
+
+    fn size(&self) -> Option<usize> {
+        std::fs::metadata(&self.0).ok()
+            .and_then(|md| usize::try_from(md.len()).ok())
+    }
 }
 
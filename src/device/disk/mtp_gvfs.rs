@@ -3,11 +3,14 @@
 //! This may not be the most efficient, but this definitely is convenient.
 
 use std::error::Error;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process::Command;
 
+use super::DiskUsage;
 
-pub fn devices() -> Result<Vec<PathBuf>, Box<dyn Error>> {
+
+/// The folder gvfs mounts things into, for the current user
+pub fn gvfs_folder() -> Result<PathBuf, Box<dyn Error>> {
     // What is my user ID?
     let id_output = Command::new("id")
         .arg("-u")
@@ -22,8 +25,12 @@ pub fn devices() -> Result<Vec<PathBuf>, Box<dyn Error>> {
         stripped.parse()?
     };
 
+    Ok(PathBuf::from(format!("/run/user/{my_id}/gvfs/")))
+}
+
+pub fn devices() -> Result<Vec<PathBuf>, Box<dyn Error>> {
     // Are there currently munted MTP devices?
-    let mnt = PathBuf::from(format!("/run/user/{my_id}/gvfs/"));
+    let mnt = gvfs_folder()?;
     let mtp_roots = std::fs::read_dir(&mnt)?
         .filter_map(|entry| entry.ok())
         .filter(|entry| entry.file_type().ok().map(|ty| ty.is_dir()).unwrap_or(false))
@@ -42,3 +49,48 @@ pub fn devices() -> Result<Vec<PathBuf>, Box<dyn Error>> {
 
     Ok(mtp_paths)
 }
+
+/// Query the disk usage of a gvfs mount.
+///
+/// gvfs FUSE mounts do not forward this to `statvfs`, so we have to ask gvfs itself.
+pub fn disk_usage(path: &Path) -> Result<DiskUsage, Box<dyn Error>> {
+    let output = Command::new("gio")
+        .args(["info", "--filesystem"])
+        .arg(path)
+        .output()
+        .map_err(|err| format!("Unable to run gio: {err:?}"))?;
+    if output.status.success() == false {
+        return Err(format!("gio failed: {}", String::from_utf8_lossy(&output.stderr)).into());
+    }
+
+    parse_gio_filesystem_info(&String::from_utf8(output.stdout)?)
+        .ok_or_else(|| "gio did not report the disk usage".into())
+}
+
+fn parse_gio_filesystem_info(output: &str) -> Option<DiskUsage> {
+    let attribute = |name: &str| output
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix(name))
+        .filter_map(|value| value.trim().parse::<u64>().ok())
+        .next();
+
+    Some(DiskUsage{
+        free: attribute("filesystem::free:")?,
+        capacity: attribute("filesystem::size:")?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_disk_usage() {
+        let output = "attributes:\n  filesystem::size: 62008590336\n  filesystem::free: 18144325632\n  filesystem::type: mtpfs\n  filesystem::remote: FALSE\n";
+        let usage = parse_gio_filesystem_info(output).unwrap();
+        assert_eq!(usage.free, 18144325632);
+        assert_eq!(usage.capacity, 62008590336);
+
+        assert!(parse_gio_filesystem_info("attributes:\n  filesystem::type: mtpfs\n").is_none());
+    }
+}
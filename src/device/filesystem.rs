@@ -0,0 +1,184 @@
+//! Most music players are formatted with FAT32 or exFAT, which restrict what paths can be.
+//!
+//! Files whose paths are not valid on such filesystems are pushed under sanitized paths instead:
+//! invalid characters are replaced, trailing dots and spaces are removed, reserved names (e.g. `CON`) are renamed, and long paths are truncated.
+
+use std::collections::{HashMap, HashSet};
+use std::path::{Component, Path, PathBuf};
+
+use serde::Serialize;
+
+/// Characters FAT filesystems do not allow in file names (along with control characters)
+const INVALID_CHARS: &[char] = &['"', '*', '/', ':', '<', '>', '?', '\\', '|'];
+const REPLACEMENT: char = '_';
+/// The maximum length of a path, from the root of the device. Like file names on FAT filesystems, this is counted in UTF-16 code units
+const MAX_PATH_LENGTH: usize = 255;
+/// The maximum length of a file or folder name
+const MAX_NAME_LENGTH: usize = 255;
+
+/// A filesystem that has constraints on paths
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FileSystem {
+    Fat32,
+    ExFat,
+}
+
+impl FileSystem {
+    /// Recognize a filesystem from the name the OS gives it (e.g. `vfat`), or return `None` in case it has no known constraints
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "vfat" | "fat" | "fat32" | "msdos" => Some(Self::Fat32),
+            "exfat" => Some(Self::ExFat),
+            _ => None,
+        }
+    }
+
+    /// The paths (relative to the music folder) files should be pushed to, indexed by the paths they would have if they were mirrored from the source.
+    ///
+    /// Paths are sanitized (see [`Self::sanitize`]).
+    /// These filesystems are case-insensitive, so that paths that differ only by their case collide as well. In case of collisions, a number is appended to the names of the files, e.g. `Song (2).mp3`.<br/>
+    /// This is deterministic: paths that are already valid are given precedence, then paths come in alphabetical order.
+    pub fn device_paths<'p>(&self, relative_paths: impl IntoIterator<Item = &'p Path>) -> HashMap<PathBuf, PathBuf> {
+        let mut relative_paths: Vec<&Path> = relative_paths.into_iter().collect();
+        relative_paths.sort();
+        let (valid_paths, invalid_paths): (Vec<&Path>, Vec<&Path>) = relative_paths
+            .into_iter()
+            .partition(|path| self.sanitize(path) == *path);
+
+        let mut taken_paths = HashSet::new();
+        let mut device_paths = HashMap::new();
+        for path in valid_paths.into_iter().chain(invalid_paths) {
+            let mut device_path = self.sanitize(path);
+            let mut number = 1;
+            while taken_paths.insert(device_path.to_string_lossy().to_lowercase()) == false {
+                number += 1;
+                device_path = sanitize_path(path, &format!(" ({})", number));
+            }
+            device_paths.insert(path.to_path_buf(), device_path);
+        }
+        device_paths
+    }
+
+    /// Make a path (relative to the music folder) valid for this filesystem
+    pub fn sanitize(&self, relative_path: &Path) -> PathBuf {
+        // FAT32 and exFAT have the same constraints
+        sanitize_path(relative_path, "")
+    }
+}
+
+/// Sanitize a path, and append a suffix to the name of its file (before its extension)
+fn sanitize_path(relative_path: &Path, suffix: &str) -> PathBuf {
+    let mut names: Vec<String> = relative_path
+        .components()
+        .filter_map(|component| match component {
+            Component::Normal(name) => Some(sanitize_name(&name.to_string_lossy())),
+            _ => None,
+        })
+        .collect();
+    let file_name = match names.pop() {
+        None => return PathBuf::new(),
+        Some(file_name) => file_name,
+    };
+    let (mut stem, extension) = match file_name.rfind('.').filter(|index| *index > 0) {
+        None => (file_name.clone(), String::new()),
+        Some(index) => (file_name[..index].to_string(), file_name[index..].to_string()),
+    };
+
+    // Shorten the longest names until the path is short enough. Extensions and suffixes are kept
+    let music_folder_length = length(crate::device::FOLDER_NAME) + length(crate::device::MUSIC_FOLDER_NAME) + 2;
+    let fixed_length = music_folder_length + length(suffix) + length(&extension);
+    loop {
+        let path_length = fixed_length + length(&stem) + names.iter().map(|name| length(name) + 1).sum::<usize>();
+        let longest_folder = names.iter_mut().max_by_key(|name| length(name));
+        let (longest, longest_length) = match longest_folder {
+            Some(folder) if length(folder) > length(&stem) => {
+                let folder_length = length(folder);
+                (folder, folder_length)
+            },
+            _ => {
+                let stem_length = length(&stem) + length(suffix) + length(&extension);
+                (&mut stem, stem_length)
+            },
+        };
+        if (path_length <= MAX_PATH_LENGTH && longest_length <= MAX_NAME_LENGTH) || length(longest) <= 1 {
+            break;
+        }
+        longest.pop();
+    }
+
+    let mut path: PathBuf = names.iter().map(|name| trim_name(name)).collect();
+    path.push(trim_name(&format!("{}{}{}", stem, suffix, extension)));
+    path
+}
+
+fn sanitize_name(name: &str) -> String {
+    let sanitized: String = name.chars()
+        .map(|c| if c.is_control() || INVALID_CHARS.contains(&c) { REPLACEMENT } else { c })
+        .collect();
+    let mut sanitized = trim_name(&sanitized);
+
+    // Windows reserves some names for devices, even with an extension (e.g. `CON.mp3`)
+    let base_name_length = sanitized.find('.').unwrap_or(sanitized.len());
+    if is_reserved(&sanitized[..base_name_length]) {
+        sanitized.insert(base_name_length, REPLACEMENT);
+    }
+    sanitized
+}
+
+/// FAT filesystems silently drop the trailing dots and spaces of names
+fn trim_name(name: &str) -> String {
+    match name.trim_end_matches(['.', ' ']) {
+        "" if name.is_empty() == false => REPLACEMENT.to_string(),
+        trimmed => trimmed.to_string(),
+    }
+}
+
+fn is_reserved(base_name: &str) -> bool {
+    let base_name = base_name.to_uppercase();
+    match base_name.as_str() {
+        "CON" | "PRN" | "AUX" | "NUL" => true,
+        _ => (base_name.starts_with("COM") || base_name.starts_with("LPT"))
+            && base_name.len() == 4
+            && matches!(base_name.as_bytes()[3], b'1'..=b'9'),
+    }
+}
+
+/// The length of a name, in UTF-16 code units
+fn length(name: &str) -> usize {
+    name.encode_utf16().count()
+}
+
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn sanitize() {
+        let fs = FileSystem::Fat32;
+        assert_eq!(fs.sanitize(Path::new("Artist/Album/Song.mp3")), Path::new("Artist/Album/Song.mp3"));
+        assert_eq!(fs.sanitize(Path::new("AC:DC/Who? \"Me\".mp3")), Path::new("AC_DC/Who_ _Me_.mp3"));
+        assert_eq!(fs.sanitize(Path::new("Artist/Vol. 2.../Song. .flac")), Path::new("Artist/Vol. 2/Song. .flac"));
+        assert_eq!(fs.sanitize(Path::new("CON/aux.live.mp3")), Path::new("CON_/aux_.live.mp3"));
+        assert_eq!(fs.sanitize(Path::new("COM10/LPT1")), Path::new("COM10/LPT1_"));
+
+        let long_path = fs.sanitize(Path::new(&format!("{}/{}/{}.mp3", "a".repeat(200), "b".repeat(100), "c".repeat(300))));
+        let long_path = long_path.to_string_lossy();
+        assert_eq!(length(&long_path) + "StarSync/music/".len(), MAX_PATH_LENGTH);
+        assert!(long_path.ends_with("c.mp3"));
+    }
+
+    #[test]
+    fn device_paths() {
+        let paths = ["Who?.mp3", "Who_.mp3", "who_.MP3", "What:.mp3", "What_.mp3"];
+        let device_paths = FileSystem::ExFat.device_paths(paths.iter().map(Path::new));
+        let device_path = |path: &str| device_paths[Path::new(path)].to_string_lossy().to_string();
+        assert_eq!(device_path("Who_.mp3"), "Who_.mp3");
+        assert_eq!(device_path("who_.MP3"), "who_ (2).MP3");
+        assert_eq!(device_path("Who?.mp3"), "Who_ (3).mp3");
+        assert_eq!(device_path("What_.mp3"), "What_.mp3");
+        assert_eq!(device_path("What:.mp3"), "What_ (2).mp3");
+    }
+}
@@ -9,7 +9,11 @@ use crate::config::Config;
 use crate::sync::SyncInfo;
 
 pub mod disk;
+pub mod filesystem;
 pub mod m3u;
+pub mod rockbox;
+pub mod scrobbler;
+pub mod watch;
 #[cfg(windows)]
 pub mod mtp_win;
 
@@ -18,6 +22,16 @@ pub const MUSIC_FOLDER_NAME: &str = "music";
 pub const CONFIG_FOLDER_NAME: &str = "config";
 pub const CONFIG_FILE: &str = "starsync.json";
 pub const SYNC_INFO_FILE: &str = "sync-info.json";
+pub const SYNC_INFO_BACKUP_FILE: &str = "sync-info.backup.json";
+pub const SYNC_HISTORY_FILE: &str = "sync-history.json";
+pub const SYNC_HISTORY_CORRUPTED_FILE: &str = "sync-history.corrupted.json";
+pub const SYNC_JOURNAL_FILE: &str = "sync-journal.json";
+pub const SYNC_JOURNAL_PROGRESS_FILE: &str = "sync-journal.progress";
+pub const SYNC_JOURNAL_CORRUPTED_FILE: &str = "sync-journal.corrupted.json";
+pub const SYNC_JOURNAL_PROGRESS_CORRUPTED_FILE: &str = "sync-journal.corrupted.progress";
+pub const IMPORTED_PLAYS_FILE: &str = "imported-plays.json";
+pub const LISTENBRAINZ_EXPORT_FILE: &str = "listenbrainz-listens.json";
+pub const LISTENBRAINZ_EXPORT_CORRUPTED_FILE: &str = "listenbrainz-listens.corrupted.json";
 
 pub trait Device {
     // Required methods
@@ -36,15 +50,18 @@ pub trait Device {
 
     /// Write a file into the device, creating parent folders if needed
     fn push_music_file(&self, local_absolute_path: &Path, device_relative_path: &Path) -> Result<(), Box<dyn Error>>;
-    /// Write a playlist into the device, creating parent folders if needed
+    /// Write a playlist into the device, creating parent folders if needed.
+    ///
+    /// In case the playlist already exists, it must be replaced atomically, see [`Self::push_config_file`].
     fn push_playlist(&self, content: &str, playlist_name: &OsStr) -> Result<(), Box<dyn Error>>;
 
     /// A hint to explain the user where to look for the config file
     fn config_display_path(&self) -> String;
-    fn config(&self) -> Option<Config>;
-    fn push_config(&self, config: &Config) -> Result<(), Box<dyn Error>>;
-    fn previous_sync_infos(&self) -> Option<SyncInfo>;
-    fn push_sync_infos(&self, sync_infos: &SyncInfo) -> Result<(), Box<dyn Error>>;
+    /// Write a file into the config folder, replacing it if it exists.
+    ///
+    /// This must be done so that an interrupted write (e.g. because the device is unplugged) never leaves a half-written file:
+    /// the file has either its previous content or its new content.
+    fn push_config_file(&self, file_name: &OsStr, content: &[u8]) -> Result<(), Box<dyn Error>>;
 
 
     // Provided methods
@@ -56,6 +73,96 @@ pub trait Device {
         self.starsync_folder().is_some()
     }
 
+    /// The free space (in bytes) on the device, in case the device is able to tell it
+    fn free_space(&self) -> Option<u64> {
+        None
+    }
+
+    /// The total capacity (in bytes) of the device, in case the device is able to tell it
+    fn capacity(&self) -> Option<u64> {
+        None
+    }
+
+    /// The filesystem of this device, in case it is known to have constraints on paths (see [`filesystem::FileSystem`])
+    fn file_system(&self) -> Option<filesystem::FileSystem> {
+        None
+    }
+
+    /// The root folder of this device, in case it is accessible (players may write files there, e.g. scrobbler logs)
+    fn root_folder(&self) -> Option<Box<dyn Folder>> {
+        None
+    }
+
+    /// The `.rockbox` folder of this device, in case it runs Rockbox (see [`rockbox::Rockbox`])
+    fn rockbox_folder(&self) -> Option<Box<dyn Folder>> {
+        None
+    }
+
+    /// Write a file into the `.rockbox` folder, replacing it if it exists (see [`Self::push_config_file`])
+    fn push_rockbox_file(&self, _file_name: &OsStr, _content: &[u8]) -> Result<(), Box<dyn Error>> {
+        Err("This device does not run Rockbox".into())
+    }
+
+    /// Read a file from the config folder, if it exists
+    fn config_file(&self, file_name: &str) -> Option<Box<dyn Read>> {
+        self.config_folder()?
+            .file_at(Path::new(file_name)).ok()?
+            .get_reader().ok()
+    }
+
+    fn config(&self) -> Option<Config> {
+        let reader = self.config_file(CONFIG_FILE)?;
+        serde_json::from_reader(reader).ok()
+    }
+
+    fn push_config(&self, config: &Config) -> Result<(), Box<dyn Error>> {
+        let config_json = serde_json::to_vec_pretty(config).map_err(|err| format!("Unable to serialize the configuration: {}", err))?;
+        self.push_config_file(OsStr::new(CONFIG_FILE), &config_json)
+    }
+
+    /// The info written by the previous sync, or `None` in case this device has never been synced.
+    ///
+    /// An error is returned in case they exist, but they cannot be parsed (see [`Self::sync_infos_backup`] in this case).
+    fn previous_sync_infos(&self) -> Result<Option<SyncInfo>, Box<dyn Error>> {
+        match self.config_file(SYNC_INFO_FILE) {
+            None => Ok(None),
+            Some(reader) => serde_json::from_reader(reader)
+                .map(Some)
+                .map_err(|err| format!("Unable to parse {}: {}", SYNC_INFO_FILE, err).into()),
+        }
+    }
+
+    /// The info written by the sync before the previous one, if they are available
+    fn sync_infos_backup(&self) -> Option<SyncInfo> {
+        let reader = self.config_file(SYNC_INFO_BACKUP_FILE)?;
+        serde_json::from_reader(reader).ok()
+    }
+
+    /// Write the info about the current sync, and keep the previous ones as a backup
+    fn push_sync_infos(&self, sync_infos: &SyncInfo) -> Result<(), Box<dyn Error>> {
+        let info_json = serde_json::to_vec(sync_infos).map_err(|err| format!("Unable to serialize the sync info: {}", err))?;
+
+        if let Some(mut reader) = self.config_file(SYNC_INFO_FILE) {
+            let mut previous_info = Vec::new();
+            reader.read_to_end(&mut previous_info)?;
+            // A corrupted file would not be a useful backup
+            if serde_json::from_slice::<SyncInfo>(&previous_info).is_ok() {
+                self.push_config_file(OsStr::new(SYNC_INFO_BACKUP_FILE), &previous_info)?;
+            }
+        }
+
+        self.push_config_file(OsStr::new(SYNC_INFO_FILE), &info_json)
+    }
+
+    /// Remove a file from the config folder. This does nothing if it does not exist.
+    fn remove_config_file(&self, file_name: &str) -> Result<(), Box<dyn Error>> {
+        let config_folder = self.config_folder().ok_or("Missing StarSync folder")?;
+        match config_folder.file_at(Path::new(file_name)) {
+            Err(_) => Ok(()),
+            Ok(mut file) => file.delete(),
+        }
+    }
+
 }
 
 pub trait Folder {
@@ -70,6 +177,25 @@ pub trait File {
     fn path(&self) -> &Path;
     fn get_reader(&self) -> Result<Box<dyn Read>, Box<dyn Error>>;
     fn delete(&mut self) -> Result<(), Box<dyn Error>>;
+
+    /// The size of this file (in bytes), in case the device is able to tell it
+    fn size(&self) -> Option<usize> {
+        None
+    }
+}
+
+
+/// The total size (in bytes) of the files of a folder and its sub-folders (files whose size is unknown are not counted)
+pub fn folder_size(folder: &dyn Folder) -> Result<u64, Box<dyn Error>> {
+    let mut size = folder.files()?
+        .iter()
+        .filter_map(|file| file.size())
+        .map(|size| size as u64)
+        .sum();
+    for sub_folder in folder.sub_folders()? {
+        size += folder_size(sub_folder.as_ref())?;
+    }
+    Ok(size)
 }
 
 
@@ -1,6 +1,6 @@
 //! MTP devices on Windows
 
-use std::ffi::OsStr;
+use std::ffi::{OsStr, OsString};
 use std::path::{Path, PathBuf};
 use std::error::Error;
 use std::io::Read;
@@ -10,8 +10,6 @@ use winmtp::device::BasicDevice;
 use winmtp::device::device_values::AppIdentifiers;
 use winmtp::object::Object;
 
-use crate::config::Config;
-use crate::sync::SyncInfo;
 use super::{File, Folder};
 
 
@@ -141,44 +139,19 @@ impl super::Device for RootObject {
         )
     }
 
-    fn config(&self) -> Option<Config> {
-        let config_file = self
-            .config_folder_impl()
-            .ok()?
-            .0
-            .object_by_path(&Path::new(crate::device::CONFIG_FILE))
-            .ok()?
-            .open_read_stream()
-            .ok()?;
-        serde_json::from_reader(config_file).ok()
-    }
-
-    fn push_config(&self, config: &Config) -> Result<(), Box<dyn Error>> {
-        let config_json = serde_json::to_string_pretty(&config).map_err(|err| format!("Unable to serialize the configuration: {}", err))?;
-
-        Ok(self.config_folder_impl()?
-            .0
-            .push_data(&OsStr::new(crate::device::CONFIG_FILE), config_json.as_bytes(), true)?)
+    fn push_config_file(&self, file_name: &OsStr, content: &[u8]) -> Result<(), Box<dyn Error>> {
+        replace_file(&self.config_folder_impl()?.0, file_name, content)
     }
 
-    fn previous_sync_infos(&self) -> Option<SyncInfo> {
-        let reader = self.config_folder_impl()
-            .ok()?
-            .0
-            .object_by_path(&Path::new(crate::device::SYNC_INFO_FILE))
-            .ok()?
-            .open_read_stream()
+    fn config_file(&self, file_name: &str) -> Option<Box<dyn Read>> {
+        let config_folder = self.config_folder_impl().ok()?.0;
+        // In case a write has been interrupted right before the new file was renamed, the temporary file is the latest version
+        let file = config_folder.object_by_path(Path::new(file_name))
+            .or_else(|_| config_folder.object_by_path(Path::new(&temp_file_name(OsStr::new(file_name)))))
             .ok()?;
-
-        serde_json::from_reader(reader).ok()
-    }
-
-    fn push_sync_infos(&self, sync_infos: &SyncInfo) -> Result<(), Box<dyn Error>> {
-        let info_json = serde_json::to_string_pretty(&sync_infos).map_err(|err| format!("Unable to serialize the sync info: {}", err))?;
-
-        Ok(self.config_folder_impl()?
-            .0
-            .push_data(&OsStr::new(crate::device::SYNC_INFO_FILE), info_json.as_bytes(), true)?)
+        file.open_read_stream()
+            .ok()
+            .map(|stream| Box::new(stream) as Box<dyn Read>)
     }
 
     fn push_music_file(&self, local_absolute_path: &Path, device_relative_path: &Path) -> Result<(), Box<dyn Error>> {
@@ -197,10 +170,29 @@ impl super::Device for RootObject {
     }
 
     fn push_playlist(&self, content: &str, playlist_name: &OsStr) -> Result<(), Box<dyn Error>> {
-        Ok(self.starsync_folder_impl()?
-            .0
-            .push_data(playlist_name, content.as_bytes(), true)?)
+        replace_file(&self.starsync_folder_impl()?.0, playlist_name, content.as_bytes())
+    }
+}
+
+fn temp_file_name(file_name: &OsStr) -> OsString {
+    let mut temp_file_name = file_name.to_os_string();
+    temp_file_name.push(".tmp");
+    temp_file_name
+}
+
+/// Write a file into a folder, replacing it if it exists.
+///
+/// MTP is not able to atomically replace a file. So, the new content is pushed under a temporary name, and it only replaces
+/// the current file once it is complete.
+fn replace_file(folder: &Object, file_name: &OsStr, content: &[u8]) -> Result<(), Box<dyn Error>> {
+    let temp_file_name = temp_file_name(file_name);
+    folder.push_data(&temp_file_name, content, true)?;
+
+    if let Ok(current_file) = folder.object_by_path(Path::new(file_name)) {
+        current_file.delete(false)?;
     }
+    folder.object_by_path(Path::new(&temp_file_name))?.rename(file_name)?;
+    Ok(())
 }
 
 impl Folder for FolderObject {
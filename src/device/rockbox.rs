@@ -0,0 +1,237 @@
+//! Rockbox players keep the ratings and the play counts of songs in their database (the "tagcache").
+//!
+//! This database can export these runtime info into `.rockbox/database_changelog.txt`, and import them back from this file.
+//! Each line of this file describes a song, as `tag="value"` pairs (e.g. `filename="/StarSync/music/song.mp3" rating="8" playcount="3"`).
+//! Ratings range from 0 to 10.
+
+use std::error::Error;
+use std::ffi::OsStr;
+use std::io::Read;
+use std::num::NonZeroU8;
+use std::path::{Path, PathBuf};
+
+use super::Device;
+use crate::source::Rating;
+
+pub const ROCKBOX_FOLDER_NAME: &str = ".rockbox";
+pub const CHANGELOG_FILE: &str = "database_changelog.txt";
+
+/// A device that runs Rockbox
+pub struct Rockbox<'d> {
+    device: &'d dyn Device,
+    /// The path of the music folder of the device, as Rockbox sees it (e.g. `/StarSync/music/`)
+    music_folder: String,
+}
+
+impl<'d> Rockbox<'d> {
+    /// Returns `None` in case this device does not run Rockbox (or is not inited)
+    pub fn detect(device: &'d dyn Device) -> Option<Self> {
+        let rockbox_folder = device.rockbox_folder()?;
+        let music_folder = device.music_folder()?;
+        // Rockbox paths are absolute paths from the root of the device, which is where the .rockbox folder is
+        let root = rockbox_folder.path().parent()?;
+        let relative_music_folder = music_folder.path().strip_prefix(root).ok()?;
+        let mut music_folder = String::from("/");
+        for component in relative_music_folder.components() {
+            music_folder.push_str(&component.as_os_str().to_string_lossy());
+            music_folder.push('/');
+        }
+        Some(Self{ device, music_folder })
+    }
+
+    /// The changelog of the database, or `None` in case it has never been exported
+    pub fn changelog(&self) -> Result<Option<Changelog>, Box<dyn Error>> {
+        let rockbox_folder = self.device.rockbox_folder().ok_or("Missing Rockbox folder")?;
+        match rockbox_folder.file_at(Path::new(CHANGELOG_FILE)) {
+            Err(_) => Ok(None),
+            Ok(file) => Changelog::parse(file.get_reader()?).map(Some),
+        }
+    }
+
+    /// Replace the changelog, so that Rockbox imports it next time it is asked to
+    pub fn push_changelog(&self, changelog: &Changelog) -> Result<(), Box<dyn Error>> {
+        self.device.push_rockbox_file(OsStr::new(CHANGELOG_FILE), changelog.to_string().as_bytes())
+    }
+
+    /// How Rockbox refers to a file of the music folder
+    pub fn rockbox_path(&self, relative_path: &Path) -> String {
+        let relative_path = relative_path.to_string_lossy().replace('\\', "/");
+        format!("{}{}", self.music_folder, relative_path)
+    }
+
+    /// The path (relative to the music folder) of a file Rockbox refers to, or `None` in case it is not in the music folder
+    pub fn relative_path(&self, rockbox_path: &str) -> Option<PathBuf> {
+        // FAT filesystems are case-insensitive, and so are the paths Rockbox records
+        let prefix = rockbox_path.get(..self.music_folder.len())?;
+        if prefix.eq_ignore_ascii_case(&self.music_folder) == false {
+            return None;
+        }
+        Some(PathBuf::from(&rockbox_path[self.music_folder.len()..]))
+    }
+}
+
+
+
+/// The content of `database_changelog.txt`
+#[derive(Debug, Default, PartialEq)]
+pub struct Changelog {
+    entries: Vec<ChangelogEntry>,
+}
+
+/// The runtime info of a song, as `(tag, value)` pairs
+#[derive(Debug, Default, PartialEq)]
+pub struct ChangelogEntry {
+    tags: Vec<(String, String)>,
+}
+
+impl Changelog {
+    pub fn parse(mut reader: Box<dyn Read>) -> Result<Self, Box<dyn Error>> {
+        let mut content = Vec::new();
+        reader.read_to_end(&mut content)?;
+        let content = String::from_utf8_lossy(&content);
+
+        let entries = content.lines()
+            .filter(|line| line.starts_with('#') == false && line.trim().is_empty() == false)
+            .map(ChangelogEntry::parse)
+            .collect::<Result<_, _>>()?;
+        Ok(Self{ entries })
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = &ChangelogEntry> {
+        self.entries.iter()
+    }
+
+    pub fn push(&mut self, entry: ChangelogEntry) {
+        self.entries.push(entry)
+    }
+}
+
+impl std::fmt::Display for Changelog {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for entry in &self.entries {
+            for (tag, value) in &entry.tags {
+                write!(f, "{}=\"", tag)?;
+                for c in value.chars() {
+                    match c {
+                        '"' => write!(f, "\\\"")?,
+                        '\\' => write!(f, "\\\\")?,
+                        '\n' => write!(f, "\\n")?,
+                        c => write!(f, "{}", c)?,
+                    }
+                }
+                write!(f, "\" ")?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+impl ChangelogEntry {
+    pub fn new(filename: String) -> Self {
+        Self{ tags: vec![("filename".to_string(), filename)] }
+    }
+
+    fn parse(line: &str) -> Result<Self, Box<dyn Error>> {
+        let mut tags = Vec::new();
+        let mut chars = line.chars().peekable();
+        loop {
+            while chars.next_if(|c| c.is_whitespace()).is_some() {}
+            if chars.peek().is_none() {
+                break;
+            }
+
+            let tag: String = std::iter::from_fn(|| chars.next_if(|c| *c != '=')).collect();
+            if chars.next() != Some('=') || chars.next() != Some('"') {
+                return Err(format!("Invalid line in {}: {}", CHANGELOG_FILE, line).into());
+            }
+            let mut value = String::new();
+            loop {
+                match chars.next() {
+                    None => return Err(format!("Unterminated value in {}: {}", CHANGELOG_FILE, line).into()),
+                    Some('"') => break,
+                    Some('\\') => match chars.next() {
+                        Some('n') => value.push('\n'),
+                        Some(c) => value.push(c),
+                        None => return Err(format!("Unterminated value in {}: {}", CHANGELOG_FILE, line).into()),
+                    },
+                    Some(c) => value.push(c),
+                }
+            }
+            tags.push((tag, value));
+        }
+        Ok(Self{ tags })
+    }
+
+    pub fn tag(&self, name: &str) -> Option<&str> {
+        self.tags.iter()
+            .find(|(tag, _)| tag == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn set_tag(&mut self, name: &str, value: String) {
+        match self.tags.iter_mut().find(|(tag, _)| tag == name) {
+            Some((_, current_value)) => *current_value = value,
+            None => self.tags.push((name.to_string(), value)),
+        }
+    }
+
+    pub fn filename(&self) -> Option<&str> {
+        self.tag("filename")
+    }
+
+    /// The rating of this song (in stars), or `None` in case it is either unrated, or its rating is unknown (see [`Self::has_rating`])
+    pub fn rating(&self) -> Rating {
+        self.tag("rating")
+            .and_then(|rating| rating.parse::<u8>().ok())
+            .and_then(stars_from_rockbox_rating)
+    }
+
+    /// Whether this entry tells the rating of its song
+    pub fn has_rating(&self) -> bool {
+        self.tag("rating").map(|rating| rating.parse::<u8>().is_ok()).unwrap_or(false)
+    }
+
+    pub fn set_rating(&mut self, rating: Rating) {
+        let rockbox_rating = rating.map(|stars| stars.get().min(5) * 2).unwrap_or(0);
+        self.set_tag("rating", rockbox_rating.to_string());
+    }
+
+    pub fn play_count(&self) -> Option<u32> {
+        self.tag("playcount").and_then(|count| count.parse().ok())
+    }
+}
+
+/// Rockbox ratings range from 0 to 10. Odd ratings are rounded up
+fn stars_from_rockbox_rating(rating: u8) -> Rating {
+    NonZeroU8::new((rating.saturating_add(1) / 2).min(5))
+}
+
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn changelog() {
+        let content = "## Some comment\n\
+            filename=\"/StarSync/music/Some \\\"quoted\\\" song.mp3\" title=\"Song\" playcount=\"3\" rating=\"7\" \n\
+            filename=\"/Music/unrated.ogg\" rating=\"0\" \n";
+        let changelog = Changelog::parse(Box::new(content.as_bytes())).unwrap();
+        let entries: Vec<_> = changelog.entries().collect();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].filename(), Some("/StarSync/music/Some \"quoted\" song.mp3"));
+        assert_eq!(entries[0].rating(), NonZeroU8::new(4));
+        assert_eq!(entries[0].play_count(), Some(3));
+        assert_eq!(entries[1].rating(), None);
+        assert!(entries[1].has_rating());
+
+        let mut written = Changelog::default();
+        let mut entry = ChangelogEntry::new("/StarSync/music/Some \"quoted\" song.mp3".to_string());
+        entry.set_rating(NonZeroU8::new(3));
+        written.push(entry);
+        assert_eq!(written.to_string(), "filename=\"/StarSync/music/Some \\\"quoted\\\" song.mp3\" rating=\"6\" \n");
+        assert_eq!(Changelog::parse(Box::new(std::io::Cursor::new(written.to_string()))).unwrap(), written);
+    }
+}
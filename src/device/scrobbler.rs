@@ -0,0 +1,124 @@
+//! Players such as Rockbox record what has been played into an Audioscrobbler log (`.scrobbler.log`), meant to be submitted to Last.fm later on.
+//!
+//! This is a tab-separated file, with a few `#` header lines, then one line per play:
+//! `artist`, `album`, `title`, `track number`, `length (in seconds)`, `L` (listened) or `S` (skipped), `timestamp`, and an optional MusicBrainz track ID.
+
+use std::error::Error;
+use std::io::Read;
+
+pub const SCROBBLER_LOG_FILE: &str = ".scrobbler.log";
+
+/// A play recorded in a scrobbler log
+#[derive(Clone, Debug, PartialEq)]
+pub struct ScrobblerEntry {
+    pub artist: String,
+    pub album: Option<String>,
+    pub title: String,
+    pub track_number: Option<u32>,
+    /// The length of the track, in seconds
+    pub length: Option<u32>,
+    /// Whether the track has been listened to (rather than skipped)
+    pub listened: bool,
+    /// When the track has been played, as a Unix timestamp.<br/>
+    /// Unless the log is in UTC (see [`ScrobblerLog::is_utc`]), this is actually in the local time of the player.
+    pub timestamp: i64,
+    pub musicbrainz_id: Option<String>,
+}
+
+#[derive(Debug, Default)]
+pub struct ScrobblerLog {
+    is_utc: bool,
+    entries: Vec<ScrobblerEntry>,
+}
+
+impl ScrobblerLog {
+    /// Parse a scrobbler log. Invalid lines are skipped
+    pub fn parse(mut reader: Box<dyn Read>) -> Result<Self, Box<dyn Error>> {
+        let mut content = Vec::new();
+        reader.read_to_end(&mut content)?;
+        let content = String::from_utf8_lossy(&content);
+
+        let mut log = Self::default();
+        for line in content.lines() {
+            if let Some(header) = line.strip_prefix('#') {
+                if header.trim() == "TZ/UTC" {
+                    log.is_utc = true;
+                }
+                continue;
+            }
+            match ScrobblerEntry::parse(line) {
+                None => log::info!("Skipping invalid line in {}: {}", SCROBBLER_LOG_FILE, line),
+                Some(entry) => log.entries.push(entry),
+            }
+        }
+        Ok(log)
+    }
+
+    /// Whether the timestamps of this log are in UTC. Otherwise, they are in the (unknown) local time of the player
+    pub fn is_utc(&self) -> bool {
+        self.is_utc
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = &ScrobblerEntry> {
+        self.entries.iter()
+    }
+}
+
+impl ScrobblerEntry {
+    fn parse(line: &str) -> Option<Self> {
+        let fields: Vec<&str> = line.trim_end_matches(['\r', '\n']).split('\t').collect();
+        if fields.len() < 7 {
+            return None;
+        }
+        let optional = |field: &str| Some(field.to_string()).filter(|value| value.is_empty() == false);
+        Some(Self{
+            artist: fields[0].to_string(),
+            album: optional(fields[1]),
+            title: fields[2].to_string(),
+            track_number: fields[3].parse().ok(),
+            length: fields[4].parse().ok(),
+            listened: match fields[5] {
+                "L" => true,
+                "S" => false,
+                _ => return None,
+            },
+            timestamp: fields[6].parse().ok()?,
+            musicbrainz_id: fields.get(7).and_then(|id| optional(id)),
+        })
+    }
+}
+
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let content = "#AUDIOSCROBBLER/1.1\n\
+            #TZ/UNKNOWN\n\
+            #CLIENT/Rockbox ipod6g $Revision$\n\
+            Someone\tFirst album\tFirst song\t1\t215\tL\t1700000000\t\n\
+            Someone\t\tSecond song\t\t180\tS\t1700000300\n\
+            not a valid line\n\
+            Another one\tAlbum\tThird song\t3\t200\tL\t1700000600\tb1a9c0e9-d987-4042-ae91-78d6a3267d69\n";
+        let log = ScrobblerLog::parse(Box::new(content.as_bytes())).unwrap();
+        assert!(log.is_utc() == false);
+
+        let entries: Vec<_> = log.entries().collect();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[0], &ScrobblerEntry{
+            artist: "Someone".to_string(),
+            album: Some("First album".to_string()),
+            title: "First song".to_string(),
+            track_number: Some(1),
+            length: Some(215),
+            listened: true,
+            timestamp: 1700000000,
+            musicbrainz_id: None,
+        });
+        assert_eq!((entries[1].album.as_deref(), entries[1].track_number, entries[1].listened), (None, None, false));
+        assert_eq!(entries[2].musicbrainz_id.as_deref(), Some("b1a9c0e9-d987-4042-ae91-78d6a3267d69"));
+    }
+}
@@ -0,0 +1,88 @@
+//! Noticing when devices are plugged in
+
+use std::collections::HashSet;
+use std::time::Duration;
+
+/// How often mounts are checked, and how long they must stay unchanged before devices are listed
+#[derive(Clone, Copy, Debug)]
+pub struct WatchOptions {
+    pub poll_interval: Duration,
+    /// Plugging a device in often (un)mounts several things in a row (e.g. partitions, or the storages of an MTP device).
+    /// Devices are listed only once mounts have stopped changing for this long.
+    pub settle_delay: Duration,
+}
+
+impl Default for WatchOptions {
+    fn default() -> Self {
+        Self{
+            poll_interval: Duration::from_secs(2),
+            settle_delay: Duration::from_secs(3),
+        }
+    }
+}
+
+/// Watches for inited devices being plugged in
+pub struct DeviceWatcher {
+    options: WatchOptions,
+    /// See [`super::disk::mount_state`]
+    mount_state: Option<String>,
+    /// The inited devices that were connected the last time devices have been listed
+    connected: HashSet<String>,
+}
+
+impl DeviceWatcher {
+    /// Start watching. Devices that are currently connected are not reported, until they are unplugged and plugged in again.
+    pub fn new(options: WatchOptions) -> Self {
+        Self{
+            options,
+            mount_state: super::disk::mount_state(),
+            connected: inited_device_names(),
+        }
+    }
+
+    /// The inited devices that are currently connected
+    pub fn connected_devices(&self) -> impl Iterator<Item = &str> {
+        self.connected.iter().map(|name| name.as_str())
+    }
+
+    /// Block until inited devices are plugged in, and return their names
+    pub fn wait_for_new_devices(&mut self) -> Vec<String> {
+        loop {
+            std::thread::sleep(self.options.poll_interval);
+
+            let mut mount_state = super::disk::mount_state();
+            if mount_state.is_some() && mount_state == self.mount_state {
+                continue;
+            }
+
+            // Wait for things to settle down
+            if mount_state.is_some() {
+                loop {
+                    std::thread::sleep(self.options.settle_delay);
+                    let new_state = super::disk::mount_state();
+                    if new_state == mount_state {
+                        break;
+                    }
+                    mount_state = new_state;
+                }
+            }
+            self.mount_state = mount_state;
+
+            let connected = inited_device_names();
+            let mut new_devices: Vec<String> = connected.difference(&self.connected).cloned().collect();
+            // Unplugged devices are forgotten, so that they are reported again when they come back
+            self.connected = connected;
+            if new_devices.is_empty() == false {
+                new_devices.sort();
+                return new_devices;
+            }
+        }
+    }
+}
+
+fn inited_device_names() -> HashSet<String> {
+    super::list_devices(true)
+        .iter()
+        .map(|device| device.name())
+        .collect()
+}
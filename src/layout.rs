@@ -0,0 +1,203 @@
+//! Layouts tell where files are pushed into the music folder of a device, from the metadata of their tracks
+//!
+//! A layout is a template, such as `{album_artist}/{year} - {album}/{disc}-{track} {title}.{ext}`, whose placeholders are replaced by the tags of each track:
+//! * `{title}`, `{artist}`, `{album}` and `{genre}`
+//! * `{album_artist}`, which is the artist of the track in case it has no album artist
+//! * `{year}`, `{disc}` and `{track}` (on two digits, e.g. `03`)
+//! * `{ext}`, the extension of the file. In case the template does not use it, it is appended to the path.
+//!
+//! Folders are separated by `/`. Slashes in tags are replaced, so that they do not create folders.
+//!
+//! Tracks that lack a tag the template uses are pushed as if there were no layout, i.e. under the path they have in the source (relatively to the common folder of the files of their source).
+
+use std::path::{Path, PathBuf};
+
+use crate::source::Track;
+
+#[derive(thiserror::Error, Debug, PartialEq)]
+#[error("Invalid layout: {0}")]
+pub struct InvalidLayout(pub String);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Field {
+    Title,
+    Artist,
+    AlbumArtist,
+    Album,
+    Genre,
+    Year,
+    Disc,
+    Track,
+    Extension,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Part {
+    Text(String),
+    Field(Field),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Layout {
+    parts: Vec<Part>,
+}
+
+/// The tags of a track, that layouts can use
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TrackTags {
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album_artist: Option<String>,
+    pub album: Option<String>,
+    pub genre: Option<String>,
+    pub year: Option<i32>,
+    pub disc_number: Option<u32>,
+    pub track_number: Option<u32>,
+}
+
+impl TrackTags {
+    pub fn of(track: &dyn Track) -> Self {
+        Self{
+            title: Some(track.name()),
+            artist: track.artist(),
+            album_artist: track.album_artist(),
+            album: track.album(),
+            genre: track.genre(),
+            year: track.year(),
+            disc_number: track.disc_number(),
+            track_number: track.track_number(),
+        }
+    }
+}
+
+impl std::str::FromStr for Layout {
+    type Err = InvalidLayout;
+
+    fn from_str(template: &str) -> Result<Self, Self::Err> {
+        if template.trim().is_empty() {
+            return Err(InvalidLayout("the template is empty".to_string()));
+        }
+        if template.starts_with('/') {
+            return Err(InvalidLayout("paths are relative to the music folder, they cannot start with '/'".to_string()));
+        }
+
+        let mut parts = Vec::new();
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            if start > 0 {
+                parts.push(Part::Text(rest[..start].to_string()));
+            }
+            let end = rest[start..].find('}').ok_or_else(|| InvalidLayout(format!("unclosed '{{' in '{}'", template)))? + start;
+            let field = match &rest[start + 1..end] {
+                "title" => Field::Title,
+                "artist" => Field::Artist,
+                "album_artist" => Field::AlbumArtist,
+                "album" => Field::Album,
+                "genre" => Field::Genre,
+                "year" => Field::Year,
+                "disc" => Field::Disc,
+                "track" => Field::Track,
+                "ext" => Field::Extension,
+                other => return Err(InvalidLayout(format!("unknown placeholder '{{{}}}'", other))),
+            };
+            parts.push(Part::Field(field));
+            rest = &rest[end + 1..];
+        }
+        if rest.is_empty() == false {
+            parts.push(Part::Text(rest.to_string()));
+        }
+        Ok(Self{ parts })
+    }
+}
+
+impl Layout {
+    /// The path of a file (relative to the music folder), or `None` in case its track lacks some tags this layout uses
+    pub fn path_for(&self, tags: &TrackTags, source_path: &Path) -> Option<PathBuf> {
+        let extension = source_path.extension().map(|ext| ext.to_string_lossy().to_string());
+
+        let mut path = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Text(text) => path.push_str(text),
+                Part::Field(field) => path.push_str(&field_value(tags, *field, extension.as_deref())?),
+            }
+        }
+        if self.parts.contains(&Part::Field(Field::Extension)) == false {
+            if let Some(extension) = &extension {
+                path.push('.');
+                path.push_str(extension);
+            }
+        }
+
+        Some(path
+            .split('/')
+            .filter(|name| name.trim().is_empty() == false)
+            // Tags such as ".." must not escape the music folder
+            .map(|name| if name.chars().all(|c| c == '.') { "_" } else { name })
+            .collect())
+    }
+}
+
+fn field_value(tags: &TrackTags, field: Field, extension: Option<&str>) -> Option<String> {
+    let value = match field {
+        Field::Title => tags.title.clone(),
+        Field::Artist => tags.artist.clone(),
+        Field::AlbumArtist => tags.album_artist.clone().or_else(|| tags.artist.clone()),
+        Field::Album => tags.album.clone(),
+        Field::Genre => tags.genre.clone(),
+        Field::Year => tags.year.map(|year| year.to_string()),
+        Field::Disc => tags.disc_number.map(|disc| disc.to_string()),
+        Field::Track => tags.track_number.map(|track| format!("{:02}", track)),
+        Field::Extension => extension.map(|ext| ext.to_string()),
+    }?;
+    let value = value.trim().replace(['/', '\\'], "_");
+    if value.is_empty() {
+        None
+    } else {
+        Some(value)
+    }
+}
+
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        assert!("{album_artist}/{year} - {album}/{disc}-{track} {title}.{ext}".parse::<Layout>().is_ok());
+        assert_eq!("{artist}/{composer}".parse::<Layout>(), Err(InvalidLayout("unknown placeholder '{composer}'".to_string())));
+        assert!("{artist}/{title".parse::<Layout>().is_err());
+        assert!("/{artist}/{title}".parse::<Layout>().is_err());
+        assert!("  ".parse::<Layout>().is_err());
+    }
+
+    #[test]
+    fn path_for() {
+        let layout: Layout = "{album_artist}/{year} - {album}/{disc}-{track} {title}.{ext}".parse().unwrap();
+        let mut tags = TrackTags{
+            title: Some("Either/Or".to_string()),
+            artist: Some("Someone".to_string()),
+            album_artist: None,
+            album: Some("First album".to_string()),
+            genre: None,
+            year: Some(2008),
+            disc_number: Some(1),
+            track_number: Some(3),
+        };
+        let source_path = Path::new("/music/Someone/First album/03 Either-Or.flac");
+        assert_eq!(layout.path_for(&tags, source_path), Some(PathBuf::from("Someone/2008 - First album/1-03 Either_Or.flac")));
+
+        tags.album_artist = Some("Various artists".to_string());
+        assert_eq!(layout.path_for(&tags, source_path), Some(PathBuf::from("Various artists/2008 - First album/1-03 Either_Or.flac")));
+
+        // Missing tags
+        tags.disc_number = None;
+        assert_eq!(layout.path_for(&tags, source_path), None);
+
+        let layout: Layout = "{artist}/{album}/{title}".parse().unwrap();
+        tags.album = Some("..".to_string());
+        assert_eq!(layout.path_for(&tags, source_path), Some(PathBuf::from("Someone/_/Either_Or.flac")));
+    }
+}
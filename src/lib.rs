@@ -5,10 +5,17 @@ pub mod device;
 pub mod config;
 pub mod sync;
 pub mod utils;
+pub mod transcode;
+pub mod smart_playlist;
+pub mod layout;
 pub mod os;
 mod common_path;
 
+use serde::Serialize;
+use time::OffsetDateTime;
+
 use crate::config::Config;
+use crate::sync::PendingChanges;
 
 //
 //
@@ -45,13 +52,31 @@ pub enum DeinitError {
     WriteError,
 }
 
+#[derive(thiserror::Error, Debug)]
+pub enum StatusError {
+    #[error("Device {0} not found")]
+    DeviceNotFound(String),
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum HistoryError {
+    #[error("Device {0} not found")]
+    DeviceNotFound(String),
+    #[error("This device is not inited")]
+    NotInited,
+    #[error("Unable to read the sync history: {0}")]
+    Unreadable(String),
+}
+
 
 /// Init a device, and return its config file
-pub fn init_device(device_name: &str, source_name: &str) -> Result<String, InitError> {
+///
+/// The playlists of the source to sync can be chosen with `include` and `exclude` patterns (see [`Config::new_template`]).
+pub fn init_device(device_name: &str, source_name: &str, include: &[String], exclude: &[String]) -> Result<String, InitError> {
     // Get a template config file
     let source = source::get(source_name).ok_or_else(|| InitError::SourceNotFound(source_name.to_string()))?;
     let all_playlists = source.playlists().map_err(|_| InitError::SourceNotFound(source_name.to_string()))?;
-    let template_config = Config::new_template(source_name, &all_playlists);
+    let template_config = Config::new_template(source_name, &all_playlists, include, exclude);
 
     // Create the folder on the device
     let device = device::get(device_name).ok_or_else(|| InitError::DeviceNotFound(device_name.to_string()))?;
@@ -74,3 +99,95 @@ pub fn deinit_device(device_name: &str) -> Result<(), DeinitError> {
     device.remove_folders().map_err(|_err| DeinitError::WriteError)?;
     Ok(())
 }
+
+/// The previous sync sessions of a device, the oldest first
+pub fn sync_history(device_name: &str) -> Result<Vec<sync::SyncSession>, HistoryError> {
+    let device = device::get(device_name).ok_or_else(|| HistoryError::DeviceNotFound(device_name.to_string()))?;
+    if device.starsync_folder().is_none() {
+        return Err(HistoryError::NotInited);
+    }
+    sync::history(device.as_ref()).map_err(|err| HistoryError::Unreadable(err.to_string()))
+}
+
+/// The state of a device, see [`device_status`]
+#[derive(Debug, Serialize)]
+pub struct DeviceStatus {
+    pub device: String,
+    pub inited: bool,
+    /// The sources this device is synced with, as set in its config
+    pub sources: Vec<String>,
+    /// The playlists to sync, as set in its config
+    pub playlists: Vec<String>,
+    /// The hostname of the computer the latest sync has been performed on, and when
+    pub last_sync: Option<(String, OffsetDateTime)>,
+    /// Whether the latest sync has been interrupted, and will have to be resumed or rolled back
+    pub interrupted_sync: bool,
+    /// The space (in bytes) used by the StarSync folder of the device
+    pub used_space: Option<u64>,
+    /// The free space (in bytes) on the device, in case the device is able to tell it
+    pub free_space: Option<u64>,
+    /// What the next sync would do. This is `None` in case this could not be computed (e.g. because the source is not available)
+    pub pending_changes: Option<PendingChanges>,
+    /// Issues encountered while building this status
+    pub warnings: Vec<String>,
+}
+
+/// Get the state of a device, and a summary of what its next sync would do
+pub fn device_status(device_name: &str) -> Result<DeviceStatus, StatusError> {
+    let device = device::get(device_name).ok_or_else(|| StatusError::DeviceNotFound(device_name.to_string()))?;
+    let mut status = DeviceStatus{
+        device: device.name(),
+        inited: device.is_inited(),
+        sources: Vec::new(),
+        playlists: Vec::new(),
+        last_sync: None,
+        interrupted_sync: false,
+        used_space: None,
+        free_space: device.free_space(),
+        pending_changes: None,
+        warnings: Vec::new(),
+    };
+    if status.inited == false {
+        return Ok(status);
+    }
+
+    match device.config() {
+        None => status.warnings.push(format!("Unable to read the config at {}", device.config_display_path())),
+        Some(config) => {
+            status.sources = config.source_names().map(|name| name.to_string()).collect();
+            status.playlists = config.playlists().map(|name| name.to_string()).collect();
+        }
+    }
+
+    match device.previous_sync_infos() {
+        Err(err) => status.warnings.push(err.to_string()),
+        Ok(None) => (),
+        Ok(Some(info)) => status.last_sync = Some((info.hostname().to_string(), *info.timestamp())),
+    }
+
+    status.interrupted_sync = device.config_file(device::SYNC_JOURNAL_FILE).is_some();
+
+    if let Some(folder) = device.starsync_folder() {
+        match device::folder_size(folder.as_ref()) {
+            Err(err) => status.warnings.push(format!("Unable to compute the space used on the device: {}", err)),
+            Ok(size) => status.used_space = Some(size),
+        }
+    }
+
+    let (status_tx, status_rx) = sync::status::channel();
+    let pending_changes = sync::SyncManager::with_device(device_name)
+        .and_then(|manager| manager.pending_changes(status_tx));
+    status.warnings.extend(status_rx
+        .try_iter()
+        .filter_map(|message| match message {
+            sync::status::Message::Warning(warning) => Some(warning),
+            _ => None,
+        })
+    );
+    match pending_changes {
+        Err(err) => status.warnings.push(format!("Unable to list the changes to sync: {}", err)),
+        Ok(pending_changes) => status.pending_changes = Some(pending_changes),
+    }
+
+    Ok(status)
+}
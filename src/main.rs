@@ -1,12 +1,20 @@
+#![allow(clippy::bool_comparison)]  // because I like them
+
 use std::error::Error;
+use std::process::ExitCode;
 use std::sync::mpsc;
+use std::time::Duration;
 
-use clap::{Args, Parser, Subcommand};
+use clap::{ArgAction, Args, Parser, Subcommand};
+use clap::builder::BoolishValueParser;
 use humansize::format_size;
+use serde_json::json;
 
 use starsync::source::list_sources;
+use starsync::config::RatingConflictPolicy;
 use starsync::device::list_devices;
-use starsync::sync::SyncManager;
+use starsync::device::watch::{DeviceWatcher, WatchOptions};
+use starsync::sync::{HunkResolution, InterruptedSyncAction, SyncManager, SyncPlan, SyncValidator};
 use starsync::sync::status;
 
 
@@ -14,10 +22,18 @@ use starsync::sync::status;
 #[command(author, version, about, long_about = None)]
 #[command(propagate_version = true)]
 struct Cli {
+    /// Output JSON instead of text (see the README for the format)
+    #[arg(long, global = true)]
+    json: bool,
     #[command(subcommand)]
     command: Commands,
 }
 
+/// Exit codes
+const EXIT_SUCCESS: u8 = 0;
+const EXIT_FATAL: u8 = 1;
+const EXIT_WARNINGS: u8 = 2;
+
 #[derive(Subcommand)]
 enum Commands {
     /// List currently available source
@@ -28,8 +44,16 @@ enum Commands {
     Init(InitArgs),
     /// De-initializes a device (by removing its config files)
     Deinit(DeinitArgs),
+    /// Show or edit the config of an inited device
+    Config(ConfigArgs),
+    /// Show the state of a device, and what its next sync would do
+    Status(StatusArgs),
     /// Sync an already inited device
     Sync(SyncArgs),
+    /// List the previous syncs of a device, or show the details of one of them
+    History(HistoryArgs),
+    /// Keep running, and sync devices as soon as they are plugged in (only devices whose config enables auto-sync are synced)
+    Watch(WatchArgs),
 }
 
 #[derive(Args)]
@@ -43,6 +67,12 @@ struct ListDevicesArgs {
 struct InitArgs {
     device: String,
     source: String,
+    /// Only sync the playlists whose names match this pattern (`*` and `?` are wildcards). Can be repeated. By default, every playlist is synced.
+    #[arg(long, value_name = "PATTERN")]
+    playlists: Vec<String>,
+    /// Do not sync the playlists whose names match this pattern. Can be repeated.
+    #[arg(long, value_name = "PATTERN")]
+    exclude: Vec<String>,
 }
 
 #[derive(Args)]
@@ -50,95 +80,458 @@ struct DeinitArgs {
     device: String,
 }
 
+#[derive(Args)]
+struct ConfigArgs {
+    device: String,
+    /// The source whose playlists are listed or edited. By default, this is the first source of the device
+    #[arg(long, global = true)]
+    source: Option<String>,
+    #[command(subcommand)]
+    action: ConfigAction,
+}
+
+#[derive(Subcommand)]
+enum ConfigAction {
+    /// List the playlists of the source, and whether they are synced
+    Playlists,
+    /// Sync the playlists of the source whose names match these patterns (`*` and `?` are wildcards)
+    Add {
+        #[arg(required = true)]
+        patterns: Vec<String>,
+    },
+    /// Stop syncing the playlists whose names match these patterns (`*` and `?` are wildcards)
+    Remove {
+        #[arg(required = true)]
+        patterns: Vec<String>,
+    },
+    /// Sync the device with another source as well
+    AddSource {
+        source: String,
+        /// Only sync the playlists whose names match this pattern (`*` and `?` are wildcards). Can be repeated. By default, every playlist is synced.
+        #[arg(long, value_name = "PATTERN")]
+        playlists: Vec<String>,
+        /// Do not sync the playlists whose names match this pattern. Can be repeated.
+        #[arg(long, value_name = "PATTERN")]
+        exclude: Vec<String>,
+    },
+    /// Stop syncing the device with a source (its songs will be removed from the device during the next sync)
+    RemoveSource {
+        source: String,
+    },
+    /// Choose whether song ratings are synced (`on` or `off`)
+    IncludeRatings {
+        #[arg(action = ArgAction::Set, value_parser = BoolishValueParser::new())]
+        enabled: bool,
+    },
+    /// Choose whether ratings computed by the source (e.g. iTunes infers ratings from the album ratings) are used for tracks that have no rating (`on` or `off`)
+    ComputedRatings {
+        #[arg(action = ArgAction::Set, value_parser = BoolishValueParser::new())]
+        enabled: bool,
+    },
+    /// Choose what to do with songs whose ratings have changed on both the source and the device (`source-wins`, `device-wins`, `highest`, `lowest` or `ask`)
+    RatingConflicts {
+        policy: RatingConflictPolicy,
+    },
+    /// Choose where files are pushed on the device from the tags of their tracks, e.g. `{album_artist}/{album}/{track} {title}`. Without a template, files keep the paths they have in the source
+    Layout {
+        template: Option<String>,
+    },
+    /// Choose whether the device is synced as soon as it is plugged in, while `starsync watch` is running (`on` or `off`)
+    AutoSync {
+        #[arg(action = ArgAction::Set, value_parser = BoolishValueParser::new())]
+        enabled: bool,
+    },
+    /// Check the config is consistent with its sources
+    Validate,
+}
+
+#[derive(Args)]
+struct StatusArgs {
+    device: String,
+}
+
 #[derive(Args)]
 struct SyncArgs {
+    #[arg(required_unless_present = "all")]
+    device: Option<String>,
+    /// Sync every connected device that has been inited, one after the other
+    #[arg(long, conflicts_with_all = ["device", "dry_run"])]
+    all: bool,
+    /// Only show what the sync would do, without modifying the device nor the source
+    #[arg(long)]
+    dry_run: bool,
+}
+
+#[derive(Args)]
+struct HistoryArgs {
     device: String,
+    /// Show the details of a sync (1 is the latest one, 2 the one before, etc.)
+    session: Option<usize>,
+}
+
+#[derive(Args)]
+struct WatchArgs {
+    /// How often (in seconds) to check whether something has been plugged in
+    #[arg(long, default_value_t = 2)]
+    interval: u64,
+    /// How long (in seconds) mounts must stay unchanged before looking for new devices
+    #[arg(long, default_value_t = 3)]
+    settle: u64,
 }
 
 
-fn main() {
+fn main() -> ExitCode {
     env_logger::init_from_env(
         env_logger::Env::default().filter_or("RUST_LOG", "debug")
     );
 
     let cli = Cli::parse();
+    let json = cli.json;
 
     let res = match &cli.command {
-        Commands::ListSources => cli_list_sources(),
-        Commands::ListDevices(args) => cli_list_devices(args.already_inited),
-        Commands::Init(args) => cli_init_device(args),
-        Commands::Deinit(args) => cli_deinit_device(args),
-        Commands::Sync(args) => cli_sync_device(args),
+        Commands::ListSources => cli_list_sources(json),
+        Commands::ListDevices(args) => cli_list_devices(args.already_inited, json),
+        Commands::Init(args) => cli_init_device(args, json),
+        Commands::Deinit(args) => cli_deinit_device(args, json),
+        Commands::Config(args) => cli_config(args, json),
+        Commands::Status(args) => cli_device_status(args, json),
+        Commands::Sync(SyncArgs{ device: Some(device), dry_run: true, .. }) => cli_plan_sync(device, json),
+        Commands::Sync(SyncArgs{ device: Some(device), .. }) => cli_sync_device(device, json),
+        Commands::Sync(_) => cli_sync_all_devices(json),
+        Commands::History(args) => cli_history(args, json),
+        Commands::Watch(args) => cli_watch(args, json),
     };
 
-    if let Err(err) = res {
-        panic!("{}", err);
+    match res {
+        Ok(code) => ExitCode::from(code),
+        Err(err) => {
+            if json {
+                println!("{}", json!({ "error": err.to_string() }));
+            } else {
+                eprintln!("Error: {}", err);
+            }
+            ExitCode::from(EXIT_FATAL)
+        },
     }
 }
 
-fn cli_list_sources() -> Result<(), Box<dyn Error>> {
+fn cli_list_sources(json: bool) -> Result<u8, Box<dyn Error>> {
     let sources = list_sources();
+    if json {
+        let names: Vec<&str> = sources.iter().map(|source| source.name()).collect();
+        println!("{}", json!({ "sources": names }));
+        return Ok(EXIT_SUCCESS);
+    }
+
     println!("Currently available sources:");
     for source in &sources {
         println!("  * {}", source.name());
     }
     println!("({} sources)", sources.len());
 
-    Ok(())
+    Ok(EXIT_SUCCESS)
 }
 
-fn cli_list_devices(only_already_inited: bool) -> Result<(), Box<dyn Error>>  {
+fn cli_list_devices(only_already_inited: bool, json: bool) -> Result<u8, Box<dyn Error>>  {
     let devices = list_devices(only_already_inited);
+    let sources_of = |dev: &dyn starsync::device::Device| dev.config().map(|config| config.source_names().map(|name| name.to_string()).collect::<Vec<_>>());
+    if json {
+        let devices: Vec<_> = devices.iter().map(|dev| json!({ "name": dev.name(), "inited": dev.is_inited(), "rockbox": dev.rockbox_folder().is_some(), "file_system": dev.file_system(), "sources": sources_of(dev.as_ref()) })).collect();
+        println!("{}", json!({ "devices": devices }));
+        return Ok(EXIT_SUCCESS);
+    }
+
     println!("Currently available devices:");
     for dev in &devices {
-        println!("  * {}", dev.name());
-        // TODO: show if they are inited (and with which source)
+        match (dev.is_inited(), sources_of(dev.as_ref())) {
+            (false, _) => println!("  * {}", dev.name()),
+            (true, None) => println!("  * {} (inited)", dev.name()),
+            (true, Some(sources)) => println!("  * {} (inited, synced with {})", dev.name(), sources.join(", ")),
+        }
     }
     println!("({} devices)", devices.len());
 
-    Ok(())
+    Ok(EXIT_SUCCESS)
 }
 
-fn cli_init_device(args: &InitArgs) -> Result<(), Box<dyn Error>> {
-    let config_display_path = starsync::init_device(&args.device, &args.source)?;
+fn cli_init_device(args: &InitArgs, json: bool) -> Result<u8, Box<dyn Error>> {
+    let config_display_path = starsync::init_device(&args.device, &args.source, &args.playlists, &args.exclude)?;
+    if json {
+        println!("{}", json!({ "device": args.device, "source": args.source, "config_path": config_display_path }));
+        return Ok(EXIT_SUCCESS);
+    }
+
     println!("Successfully inited {}.", args.device);
     println!("You probably want to review the config at {} before starting a sync!", config_display_path);
-    Ok(())
+    Ok(EXIT_SUCCESS)
 }
 
-fn cli_deinit_device(args: &DeinitArgs) -> Result<(), Box<dyn Error>> {
-    match starsync::deinit_device(&args.device) {
-        Ok(()) => {
-            println!("Successfully deinited {}", args.device);
-            Ok(())
+fn cli_deinit_device(args: &DeinitArgs, json: bool) -> Result<u8, Box<dyn Error>> {
+    let was_inited = match starsync::deinit_device(&args.device) {
+        Ok(()) => true,
+        Err(starsync::DeinitError::NotInited) => false,
+        Err(err) => return Err(err.into()),
+    };
+
+    if json {
+        println!("{}", json!({ "device": args.device, "was_inited": was_inited }));
+    } else if was_inited {
+        println!("Successfully deinited {}", args.device);
+    } else {
+        println!("Device {} is not inited", args.device);
+    }
+    Ok(EXIT_SUCCESS)
+}
+
+fn cli_config(args: &ConfigArgs, json: bool) -> Result<u8, Box<dyn Error>> {
+    let device = starsync::device::get(&args.device).ok_or_else(|| format!("Device {} not found", args.device))?;
+    let mut config = device.config().ok_or("This device is not inited (or its config is unreadable)")?;
+    let available_sources = |config: &starsync::config::Config| -> Vec<Box<dyn starsync::source::Source>> {
+        config.source_names().filter_map(starsync::source::get).collect()
+    };
+
+    // The source whose playlists are listed or edited
+    let source_name = match &args.source {
+        Some(name) => name.clone(),
+        None => config.source_names().next().ok_or("This device is synced with no source")?.to_string(),
+    };
+    let source_playlists = || -> Result<Vec<String>, Box<dyn Error>> {
+        let source = starsync::source::get(&source_name).ok_or_else(|| format!("Source {} not found", source_name))?;
+        Ok(source.playlists()?.iter().map(|p| p.name()).collect())
+    };
+    let not_a_source = || format!("Device {} is not synced with source {}", args.device, source_name);
+
+    match &args.action {
+        ConfigAction::Playlists => {
+            let source_config = config.source(&source_name).ok_or_else(not_a_source)?;
+            let source_playlists = source_playlists()?;
+            let mut playlists: Vec<_> = source_playlists.iter()
+                .map(|name| json!({ "name": name, "selected": source_config.playlists().any(|selected| selected == name), "in_source": true, "smart": false }))
+                .collect();
+            // Selected playlists that are not in the source are listed as well
+            for playlist in source_config.playlists.iter().filter(|p| source_playlists.contains(&p.name) == false) {
+                playlists.push(json!({ "name": playlist.name, "selected": true, "in_source": false, "smart": playlist.rule.is_some() }));
+            }
+
+            if json {
+                println!("{}", json!({ "source": source_name, "playlists": playlists }));
+                return Ok(EXIT_SUCCESS);
+            }
+            println!("Playlists of {}:", source_name);
+            for playlist in &playlists {
+                let marker = if playlist["selected"] == true { "[x]" } else { "[ ]" };
+                let note = match (playlist["in_source"] == true, playlist["smart"] == true) {
+                    (_, true) => " (smart playlist)",
+                    (false, false) => " (not found in the source!)",
+                    (true, false) => "",
+                };
+                println!("{} {}{}", marker, playlist["name"].as_str().unwrap_or_default(), note);
+            }
+            return Ok(EXIT_SUCCESS);
+        },
+
+        ConfigAction::Validate => {
+            let issues = config.validate(&available_sources(&config));
+            if json {
+                println!("{}", json!({ "valid": issues.is_empty(), "issues": issues }));
+            } else if issues.is_empty() {
+                println!("The config is valid.");
+            } else {
+                for issue in &issues {
+                    println!("  * {}", issue);
+                }
+            }
+            return Ok(if issues.is_empty() { EXIT_SUCCESS } else { EXIT_WARNINGS });
+        },
+
+        ConfigAction::Add{ patterns } => {
+            let source_playlists = source_playlists()?;
+            let source_config = config.source_mut(&source_name).ok_or_else(not_a_source)?;
+            let mut added = Vec::new();
+            for pattern in patterns {
+                let matching: Vec<&String> = source_playlists.iter().filter(|name| starsync::utils::matches_pattern(pattern, name)).collect();
+                if matching.is_empty() {
+                    return Err(format!("No playlist of the source matches '{}'", pattern).into());
+                }
+                for name in matching {
+                    if source_config.add_playlist(name) {
+                        added.push(name.clone());
+                    }
+                }
+            }
+            if json {
+                println!("{}", json!({ "added": added }));
+            } else {
+                println!("Added {} playlists: {}", added.len(), added.join(", "));
+            }
         },
-        Err(starsync::DeinitError::NotInited) => {
-            println!("Device {} is not inited", args.device);
-            Ok(())
+
+        ConfigAction::Remove{ patterns } => {
+            let source_config = config.source_mut(&source_name).ok_or_else(not_a_source)?;
+            let mut removed = Vec::new();
+            for pattern in patterns {
+                removed.extend(source_config.remove_playlists(pattern));
+            }
+            if json {
+                println!("{}", json!({ "removed": removed }));
+            } else {
+                println!("Removed {} playlists: {}", removed.len(), removed.join(", "));
+            }
         },
-        Err(err) => Err(err.into()),
+
+        ConfigAction::AddSource{ source, playlists, exclude } => {
+            let new_source = starsync::source::get(source).ok_or_else(|| format!("Source {} not found", source))?;
+            let source_config = starsync::config::SourceConfig::new_template(new_source.name(), &new_source.playlists()?, playlists, exclude);
+            let selected: Vec<String> = source_config.playlists().map(|name| name.to_string()).collect();
+            if config.add_source(source_config) == false {
+                return Err(format!("Device {} is already synced with source {}", args.device, source).into());
+            }
+            if json {
+                println!("{}", json!({ "added_source": source, "playlists": selected }));
+            } else {
+                println!("Device {} will be synced with {} as well ({} playlists: {})", args.device, source, selected.len(), selected.join(", "));
+            }
+        },
+
+        ConfigAction::RemoveSource{ source } => {
+            if config.source(source).is_none() {
+                return Err(format!("Device {} is not synced with source {}", args.device, source).into());
+            }
+            if config.sources().len() == 1 {
+                return Err("A device must be synced with at least one source. Use deinit to stop syncing it altogether".into());
+            }
+            config.remove_source(source);
+            if json {
+                println!("{}", json!({ "removed_source": source }));
+            } else {
+                println!("Device {} will no longer be synced with {}", args.device, source);
+            }
+        },
+
+        ConfigAction::IncludeRatings{ enabled } => {
+            config.set_include_ratings(*enabled);
+            if json {
+                println!("{}", json!({ "include_ratings": enabled }));
+            } else {
+                println!("Ratings will {}be synced", if *enabled { "" } else { "no longer " });
+            }
+        },
+
+        ConfigAction::ComputedRatings{ enabled } => {
+            config.set_use_computed_ratings(*enabled);
+            if json {
+                println!("{}", json!({ "use_computed_ratings": enabled }));
+            } else {
+                println!("Computed ratings will {}be used", if *enabled { "" } else { "no longer " });
+            }
+        },
+
+        ConfigAction::RatingConflicts{ policy } => {
+            config.set_rating_conflict_policy(*policy);
+            if json {
+                println!("{}", json!({ "rating_conflicts": policy }));
+            } else {
+                println!("Rating conflicts will be resolved with policy {:?}", policy);
+            }
+        },
+
+        ConfigAction::Layout{ template } => {
+            if let Some(template) = template {
+                template.parse::<starsync::layout::Layout>()?;
+            }
+            config.set_layout(template.clone());
+            if json {
+                println!("{}", json!({ "layout": template }));
+            } else {
+                match template {
+                    None => println!("Files will be pushed under the paths they have in the source"),
+                    Some(template) => println!("Files will be pushed as {}", template),
+                }
+            }
+        },
+
+        ConfigAction::AutoSync{ enabled } => {
+            config.set_auto_sync(*enabled);
+            if json {
+                println!("{}", json!({ "auto_sync": enabled }));
+            } else {
+                println!("Device {} will {}be synced as soon as it is plugged in", args.device, if *enabled { "" } else { "no longer " });
+            }
+        },
+    }
+
+    device.push_config(&config)?;
+
+    // Editing the config may have made it inconsistent (e.g. removing the last playlist is allowed, but probably not intended)
+    let issues = config.validate(&available_sources(&config));
+    for issue in &issues {
+        log::warn!("{}", issue);
+    }
+    Ok(if issues.is_empty() { EXIT_SUCCESS } else { EXIT_WARNINGS })
+}
+
+fn cli_device_status(args: &StatusArgs, json: bool) -> Result<u8, Box<dyn Error>> {
+    let status = starsync::device_status(&args.device)?;
+    let exit_code = if status.warnings.is_empty() { EXIT_SUCCESS } else { EXIT_WARNINGS };
+    if json {
+        println!("{}", serde_json::to_string_pretty(&status)?);
+        return Ok(exit_code);
+    }
+
+    println!("Device {}", status.device);
+    if status.inited == false {
+        println!("  Not inited");
+        return Ok(exit_code);
+    }
+    if status.sources.is_empty() == false {
+        println!("  Synced with {}", status.sources.join(", "));
+    }
+    println!("  Playlists: {}", status.playlists.join(", "));
+    match &status.last_sync {
+        None => println!("  Never synced"),
+        Some((hostname, timestamp)) => println!("  Last sync at {} on {}", timestamp, hostname),
+    }
+    if status.interrupted_sync {
+        println!("  The last sync has been interrupted. It will be offered to resume it or to undo it during the next sync.");
+    }
+    if let Some(used_space) = status.used_space {
+        match status.free_space {
+            None => println!("  {} used", format_size(used_space, humansize::DECIMAL)),
+            Some(free_space) => println!("  {} used ({} free)", format_size(used_space, humansize::DECIMAL), format_size(free_space, humansize::DECIMAL)),
+        }
+    }
+
+    if let Some(pending) = &status.pending_changes {
+        println!("Changes to sync:");
+        println!("  {} files to push ({}), {} to update, {} to remove ({})",
+            pending.files_to_push, format_size(pending.bytes_to_push, humansize::DECIMAL), pending.files_to_update,
+            pending.files_to_remove, format_size(pending.bytes_to_remove, humansize::DECIMAL));
+        if pending.playlists_edited_on_device.is_empty() == false {
+            println!("  Playlists edited on the device: {}", pending.playlists_edited_on_device.join(", "));
+        }
+        if pending.ratings_edited_on_device > 0 {
+            println!("  {} ratings edited on the device", pending.ratings_edited_on_device);
+        }
     }
+
+    for warning in &status.warnings {
+        log::warn!("{}", warning);
+    }
+
+    Ok(exit_code)
 }
 
-fn cli_sync_device(args: &SyncArgs) -> Result<(), Box<dyn Error>> {
+fn cli_sync_device(device_name: &str, json: bool) -> Result<u8, Box<dyn Error>> {
     let (status_tx, status_rx) = starsync::sync::status::channel();
     let (validator_tx, validator_rx) = mpsc::channel();
     let (acknowledged_validator_tx, acknowledged_validator_rx) = mpsc::channel();
-    let device_name = args.device.to_string();
+    let device_name = device_name.to_string();
     log::info!("Syncing {}...", device_name);
 
     let sync_thread = std::thread::spawn(move || {
         let _prevent_computer_going_to_sleep = starsync::os::PleaseStayAwake::new();
 
-        let sync_manager = SyncManager::with_device(&device_name).unwrap(
-            //
-            //
-            //
-            //
-            // TODO: fix this unwrap (at the same time as fixing the error/warning/status/Result<()> thing)
-            //       note: this could be a NotInited
-            //
-        );
+        let sync_manager = SyncManager::with_device(&device_name)?;
         sync_manager.start_sync(
             status_tx,
             validator_tx,
@@ -146,8 +539,280 @@ fn cli_sync_device(args: &SyncArgs) -> Result<(), Box<dyn Error>> {
         )
     });
 
-    // Wait for the validator to be sent
-    let mut validator = validator_rx.recv().expect("sending half not to disconnect");
+    // Validators are sent in between the messages of the sync: first the sanity checks, then possibly the rating conflicts
+    // (the sync thread may have failed before, in which case its error is reported below)
+    loop {
+        if let Ok(validator) = validator_rx.try_recv() {
+            answer_validator(validator, json, &acknowledged_validator_tx)?;
+        }
+
+        match status_rx.recv_timeout(Duration::from_millis(100)) {
+            Err(mpsc::RecvTimeoutError::Timeout) => continue,
+            Err(mpsc::RecvTimeoutError::Disconnected) => break,
+            Ok(message) if json => println!("{}", serde_json::to_string(&message)?),
+            Ok(message) => log_sync_message(message),
+        }
+    }
+
+    let result = match sync_thread.join() {
+        Err(err) => std::panic::resume_unwind(err),
+        Ok(result) => result,
+    };
+
+    let exit_code = match &result {
+        Err(_) => EXIT_FATAL,
+        Ok(0) => EXIT_SUCCESS,
+        Ok(_) => EXIT_WARNINGS,
+    };
+
+    if json {
+        println!("{}", json!({ "event": "finished", "data": match result {
+            Err(err) => json!({ "status": "failed", "error": err.to_string() }),
+            Ok(n_warns) => json!({ "status": if n_warns == 0 { "success" } else { "completed_with_warnings" }, "warnings": n_warns }),
+        }}));
+    } else {
+        match result {
+            Err(err) => println!("Sync failed: {}", err),
+            Ok(0) => println!("Sync successfully completed."),
+            Ok(n_warns) => println!("Sync completed with {} warnings", n_warns),
+        }
+    }
+
+    Ok(exit_code)
+}
+
+fn cli_sync_all_devices(json: bool) -> Result<u8, Box<dyn Error>> {
+    let (status_tx, status_rx) = starsync::sync::status::channel();
+    let (validator_tx, validator_rx) = mpsc::channel();
+    let (acknowledged_validator_tx, acknowledged_validator_rx) = mpsc::channel();
+    log::info!("Syncing every inited device...");
+
+    let sync_thread = std::thread::spawn(move || {
+        let _prevent_computer_going_to_sleep = starsync::os::PleaseStayAwake::new();
+        starsync::sync::sync_all_devices(status_tx, validator_tx, acknowledged_validator_rx)
+    });
+
+    // Sanity checks are sent for every device, in between the messages of the syncs
+    loop {
+        if let Ok(validator) = validator_rx.try_recv() {
+            answer_validator(validator, json, &acknowledged_validator_tx)?;
+        }
+
+        match status_rx.recv_timeout(Duration::from_millis(100)) {
+            Err(mpsc::RecvTimeoutError::Timeout) => continue,
+            Err(mpsc::RecvTimeoutError::Disconnected) => break,
+            Ok(message) if json => println!("{}", serde_json::to_string(&message)?),
+            Ok(message) => log_sync_message(message),
+        }
+    }
+
+    let outcomes = match sync_thread.join() {
+        Err(err) => std::panic::resume_unwind(err),
+        Ok(outcomes) => outcomes,
+    };
+
+    let exit_code = if outcomes.iter().any(|outcome| outcome.result.is_err()) {
+        EXIT_FATAL
+    } else if outcomes.iter().any(|outcome| matches!(outcome.result, Ok(n_warns) if n_warns > 0)) {
+        EXIT_WARNINGS
+    } else {
+        EXIT_SUCCESS
+    };
+
+    if json {
+        let devices: Vec<_> = outcomes.iter().map(|outcome| match &outcome.result {
+            Err(err) => json!({ "device": outcome.device, "status": "failed", "error": err.to_string() }),
+            Ok(n_warns) => json!({ "device": outcome.device, "status": if *n_warns == 0 { "success" } else { "completed_with_warnings" }, "warnings": n_warns }),
+        }).collect();
+        println!("{}", json!({ "event": "finished", "data": { "devices": devices } }));
+        return Ok(exit_code);
+    }
+
+    if outcomes.is_empty() {
+        println!("No inited device is connected.");
+    }
+    for outcome in &outcomes {
+        match &outcome.result {
+            Err(err) => println!("  * {}: sync failed: {}", outcome.device, err),
+            Ok(0) => println!("  * {}: sync successfully completed", outcome.device),
+            Ok(n_warns) => println!("  * {}: sync completed with {} warnings", outcome.device, n_warns),
+        }
+    }
+
+    Ok(exit_code)
+}
+
+fn cli_watch(args: &WatchArgs, json: bool) -> Result<u8, Box<dyn Error>> {
+    let mut watcher = DeviceWatcher::new(WatchOptions{
+        poll_interval: Duration::from_secs(args.interval),
+        settle_delay: Duration::from_secs(args.settle),
+    });
+
+    let connected: Vec<&str> = watcher.connected_devices().collect();
+    if json {
+        println!("{}", json!({ "event": "watching", "data": { "connected_devices": connected } }));
+    } else {
+        log::info!("Watching for devices to be plugged in...");
+        for device_name in connected {
+            log::info!("{} is already connected. It will be synced the next time it is plugged in", device_name);
+        }
+    }
+
+    loop {
+        for device_name in watcher.wait_for_new_devices() {
+            let auto_sync = starsync::device::get(&device_name)
+                .and_then(|device| device.config())
+                .map(|config| config.auto_sync())
+                .unwrap_or(false);
+            if auto_sync == false {
+                if json {
+                    println!("{}", json!({ "event": "device_plugged", "data": { "device": device_name, "auto_sync": false } }));
+                } else {
+                    log::info!("{} has been plugged in, but auto-sync is not enabled for it", device_name);
+                }
+                continue;
+            }
+
+            let (status_tx, status_rx) = starsync::sync::status::channel();
+            let sync_thread = {
+                let device_name = device_name.clone();
+                std::thread::spawn(move || {
+                    let _prevent_computer_going_to_sleep = starsync::os::PleaseStayAwake::new();
+                    starsync::sync::auto_sync(&device_name, status_tx)
+                })
+            };
+
+            for message in status_rx {
+                if json {
+                    println!("{}", serde_json::to_string(&message)?);
+                } else {
+                    log_sync_message(message);
+                }
+            }
+
+            let result = match sync_thread.join() {
+                Err(err) => std::panic::resume_unwind(err),
+                Ok(result) => result,
+            };
+
+            if json {
+                println!("{}", json!({ "event": "finished", "data": match result {
+                    Err(err) => json!({ "device": device_name, "status": "failed", "error": err.to_string() }),
+                    Ok(n_warns) => json!({ "device": device_name, "status": if n_warns == 0 { "success" } else { "completed_with_warnings" }, "warnings": n_warns }),
+                }}));
+            } else {
+                match result {
+                    Err(err) => println!("{}: sync failed: {}", device_name, err),
+                    Ok(0) => println!("{}: sync successfully completed", device_name),
+                    Ok(n_warns) => println!("{}: sync completed with {} warnings", device_name, n_warns),
+                }
+            }
+        }
+    }
+}
+
+fn log_sync_message(message: status::Message) {
+    match message {
+        status::Message::SyncingDevice(device) => log::info!("Syncing {}...", device),
+        status::Message::Progress(starsync::sync::status::Progress::Done) => {
+            log::info!("Sync done.");
+        },
+        status::Message::PushingFile{path, file_size: _, size_so_far, total_size, n_files, i_file} => {
+            let ratio = 100.0 * size_so_far as f32 / total_size as f32;
+            let total = format_size(total_size, humansize::DECIMAL);
+            log::debug!("Pushing file {i_file}/{n_files} ({ratio:.1}% of {total}) {path}...");
+        },
+        status::Message::UpdatingFile{path, file_size: _, size_so_far, total_size, n_files, i_file} => {
+            let ratio = 100.0 * size_so_far as f32 / total_size as f32;
+            let total = format_size(total_size, humansize::DECIMAL);
+            log::debug!("Updating modified file {i_file}/{n_files} ({ratio:.1}% of {total}) {path}...");
+        },
+        status::Message::LeavingOutTrack{track_name, playlist_name, reason} => log::info!("Not pushing '{track_name}' from playlist '{playlist_name}': {reason}"),
+        status::Message::Progress(prog) => log::info!("===={:?}=====", prog),
+        status::Message::Info(info) => log::info!("{}", info),
+        status::Message::Warning(warn) => log::warn!("{}", warn),
+        msg => log::debug!("{:x?}", msg),
+    }
+}
+
+/// Have the user review a validator sent by a sync (either its sanity checks, or the playlist and rating conflicts it found), and send it back
+fn answer_validator(mut validator: SyncValidator, json: bool, acknowledged_validator_tx: &mpsc::Sender<SyncValidator>) -> Result<(), Box<dyn Error>> {
+    let has_conflicts = validator.rating_conflicts.is_empty() == false || validator.playlist_conflicts.is_empty() == false;
+    if json {
+        // There is no way to prompt the user. Any failed check (as well as an interrupted previous sync) aborts the sync, and the source wins conflicts
+        let event = if has_conflicts { "conflicts" } else { "sanity_checks" };
+        println!("{}", json!({ "event": event, "data": validator }));
+    } else if has_conflicts {
+        prompt_user_about_playlist_conflicts(&mut validator)?;
+        prompt_user_about_rating_conflicts(&mut validator)?;
+    } else {
+        prompt_user_about_checks(&mut validator)?;
+    }
+
+    // Send the acknowledged validator back
+    acknowledged_validator_tx.send(validator).expect("transmission to be possible");
+    Ok(())
+}
+
+fn prompt_user_about_playlist_conflicts(validator: &mut SyncValidator) -> Result<(), Box<dyn Error>> {
+    let print_tracks = |label: &str, tracks: &[starsync::sync::ConflictTrack]| {
+        println!("  {}:", label);
+        if tracks.is_empty() {
+            println!("      (no track)");
+        }
+        for track in tracks {
+            println!("      {}", track.name);
+        }
+    };
+    for conflict in &mut validator.playlist_conflicts {
+        println!("Playlist '{}' has been edited on both the source and the device, in ways that conflict", conflict.playlist_name);
+        for hunk in &mut conflict.hunks {
+            print_tracks("During the previous sync", &hunk.ancestor);
+            print_tracks("On the source", &hunk.source);
+            print_tracks("On the device", &hunk.device);
+            print!("Which tracks do you want to keep? [s]ource, [d]evice, [b]oth, or leave the playlist unchanged [s/d/b/n] ");
+            let mut user_input = String::new();
+            let stdin = std::io::stdin();
+            stdin.read_line(&mut user_input)?;
+            hunk.resolution = match user_input.trim() {
+                "s" => Some(HunkResolution::TakeSource),
+                "d" => Some(HunkResolution::TakeDevice),
+                "b" => Some(HunkResolution::Concatenate),
+                _ => break,
+            };
+        }
+    }
+    Ok(())
+}
+
+fn prompt_user_about_rating_conflicts(validator: &mut SyncValidator) -> Result<(), Box<dyn Error>> {
+    let stars = |rating: starsync::source::Rating| rating.map(|stars| format!("rated {} stars", stars)).unwrap_or_else(|| "unrated".to_string());
+    for conflict in &mut validator.rating_conflicts {
+        println!("Song '{}' was {} during the previous sync, but it is now {} on the source, and {} on the device",
+            conflict.track_name, stars(conflict.rating_at_previous_sync), stars(conflict.rating_on_source), stars(conflict.rating_on_device));
+        print!("Which rating do you want to keep? [s]ource, [d]evice [s/d] ");
+        let mut user_input = String::new();
+        let stdin = std::io::stdin();
+        stdin.read_line(&mut user_input)?;
+        conflict.keep_device_rating = user_input.trim() == "d";
+    }
+    Ok(())
+}
+
+fn prompt_user_about_checks(validator: &mut SyncValidator) -> Result<(), Box<dyn Error>> {
+    if let Some(interrupted_sync) = &mut validator.interrupted_sync {
+        println!("The previous sync (started on {} from computer \"{}\") has been interrupted after {} of its {} operations",
+            interrupted_sync.timestamp, interrupted_sync.hostname, interrupted_sync.done_operations, interrupted_sync.total_operations);
+        print!("Do you want to [r]esume it, [u]ndo it, or [a]bort? [r/u/a] ");
+        let mut user_input = String::new();
+        let stdin = std::io::stdin();
+        stdin.read_line(&mut user_input)?;
+        interrupted_sync.action = match user_input.trim() {
+            "r" => Some(InterruptedSyncAction::Resume),
+            "u" => Some(InterruptedSyncAction::RollBack),
+            _ => None,
+        };
+    }
 
     if let Some((previous_hostname, current_hostname)) = &validator.last_sync_computer_mismatch {
         println!("Last sync was done on computer \"{}\" instead of the current computer \"{}\"", previous_hostname, current_hostname);
@@ -160,34 +825,219 @@ fn cli_sync_device(args: &SyncArgs) -> Result<(), Box<dyn Error>> {
         }
     }
 
-    // Send the acknowledged validator back
-    acknowledged_validator_tx.send(validator).expect("transmission to be possible");
+    if let Some((required_space, available_space)) = &validator.not_enough_space {
+        println!("This sync requires {} on the device, but only {} are available",
+            format_size(*required_space, humansize::DECIMAL),
+            format_size(*available_space, humansize::DECIMAL),
+        );
+        print!("Do you still want to proceed? [y/n] ");
+        let mut user_input = String::new();
+        let stdin = std::io::stdin();
+        stdin.read_line(&mut user_input)?;
+        if user_input.trim() == "y" {
+            validator.not_enough_space = None;
+        }
+    }
 
-    loop {
-        match status_rx.recv() {
-            Err(_) => break,
-            Ok(status::Message::Progress(starsync::sync::status::Progress::Done)) => {
-                log::info!("Sync done.");
-                break;
-            },
-            Ok(status::Message::PushingFile{path, file_size: _, size_so_far, total_size, n_files, i_file}) => {
-                let ratio = 100.0 * size_so_far as f32 / total_size as f32;
-                let total = format_size(total_size, humansize::DECIMAL);
-                log::debug!("Pushing file {i_file}/{n_files} ({ratio:.1}% of {total}) {path}...");
+    for renamed_playlist in &mut validator.renamed_playlists {
+        println!("Playlist '{}' of {} seems to have been renamed into '{}' ({:.0}% of its songs match)",
+            renamed_playlist.old_name, renamed_playlist.source, renamed_playlist.new_name, 100.0 * renamed_playlist.similarity);
+        print!("Do you want to keep syncing it under its new name? [y/n] ");
+        let mut user_input = String::new();
+        let stdin = std::io::stdin();
+        stdin.read_line(&mut user_input)?;
+        renamed_playlist.follow = user_input.trim() == "y";
+    }
+
+    Ok(())
+}
+
+fn cli_plan_sync(device_name: &str, json: bool) -> Result<u8, Box<dyn Error>> {
+    let (status_tx, status_rx) = starsync::sync::status::channel();
+    let device_name = device_name.to_string();
+    log::info!("Computing what syncing {} would do...", device_name);
+
+    let plan_thread = std::thread::spawn(move || {
+        let sync_manager = SyncManager::with_device(&device_name)?;
+        sync_manager.plan(status_tx)
+    });
+
+    // Messages are logged (to stderr), so that the JSON output only contains the plan
+    let mut n_warns = 0;
+    for message in status_rx {
+        match message {
+            status::Message::LeavingOutTrack{track_name, playlist_name, reason} => log::info!("Not pushing '{track_name}' from playlist '{playlist_name}': {reason}"),
+            status::Message::Progress(prog) => log::debug!("===={:?}=====", prog),
+            status::Message::Info(info) => log::info!("{}", info),
+            status::Message::Warning(warn) => {
+                n_warns += 1;
+                log::warn!("{}", warn);
             },
-            Ok(status::Message::Progress(prog)) => log::info!("===={:?}=====", prog),
-            Ok(status::Message::Info(info)) => log::info!("{}", info),
-            Ok(status::Message::Warning(warn)) => log::warn!("{}", warn),
-            Ok(msg) => log::debug!("{:x?}", msg),
+            msg => log::debug!("{:x?}", msg),
         }
     }
 
-    match sync_thread.join() {
+    let plan = match plan_thread.join() {
         Err(err) => std::panic::resume_unwind(err),
-        Ok(Err(err)) => println!("Sync failed: {}", err),
-        Ok(Ok(0)) => println!("Sync successfully completed."),
-        Ok(Ok(n_warns)) => println!("Sync completed with {} warnings", n_warns),
+        Ok(Err(err)) => {
+            if json {
+                println!("{}", json!({ "error": err.to_string() }));
+            } else {
+                println!("Dry run failed: {}", err);
+            }
+            return Ok(EXIT_FATAL);
+        },
+        Ok(Ok(plan)) => plan,
     };
 
-    Ok(())
+    if json {
+        println!("{}", serde_json::to_string_pretty(&plan)?);
+    } else {
+        print_plan(&plan);
+    }
+
+    Ok(if n_warns == 0 { EXIT_SUCCESS } else { EXIT_WARNINGS })
+}
+
+fn print_plan(plan: &SyncPlan) {
+    if plan.is_empty() {
+        println!("Device is up to date, only playlists would be re-written.");
+    }
+
+    if plan.source_playlist_updates.is_empty() == false {
+        println!("Playlists to update in the source:");
+        for update in &plan.source_playlist_updates {
+            println!("  * {} ({} tracks -> {} tracks)", update.name, update.current_content.len(), update.new_content.len());
+        }
+    }
+
+    if plan.source_rating_updates.is_empty() == false {
+        println!("Ratings to update in the source:");
+        for update in &plan.source_rating_updates {
+            let stars = |r: starsync::source::Rating| r.map(|s| s.get()).unwrap_or(0);
+            println!("  * {}: {} stars -> {} stars", update.track_name, stars(update.current_rating_on_source), stars(update.new_rating));
+        }
+    }
+
+    if plan.source_play_updates.is_empty() == false {
+        println!("Plays to record into the source:");
+        for update in &plan.source_play_updates {
+            println!("  * {}: played {} times on the device", update.track_name, update.plays);
+        }
+    }
+
+    if plan.files_to_remove.is_empty() == false {
+        println!("Files to remove from the device ({}):", format_size(plan.size_to_remove(), humansize::DECIMAL));
+        for file in &plan.files_to_remove {
+            println!("  - {}", file.path.display());
+        }
+    }
+
+    if plan.files_to_push.is_empty() == false || plan.files_to_update.is_empty() == false {
+        println!("Files to push to the device ({}):", format_size(plan.size_to_push(), humansize::DECIMAL));
+        for file in &plan.files_to_push {
+            println!("  + {} ({})", file.path.display(), format_size(file.size.unwrap_or(0), humansize::DECIMAL));
+        }
+        for file in &plan.files_to_update {
+            println!("  ~ {} ({}, modified since the last sync)", file.path.display(), format_size(file.size.unwrap_or(0), humansize::DECIMAL));
+        }
+    }
+
+    if plan.playlists_to_remove.is_empty() == false {
+        println!("Playlists to remove from the device:");
+        for name in &plan.playlists_to_remove {
+            println!("  - {}", name);
+        }
+    }
+
+    println!("Playlists to write into the device:");
+    for name in &plan.playlists_to_push {
+        println!("  * {}", name);
+    }
+}
+
+fn cli_history(args: &HistoryArgs, json: bool) -> Result<u8, Box<dyn Error>> {
+    // The latest sync comes first
+    let mut sessions = starsync::sync_history(&args.device)?;
+    sessions.reverse();
+
+    match args.session {
+        None => {
+            if json {
+                println!("{}", json!({ "sessions": sessions }));
+                return Ok(EXIT_SUCCESS);
+            }
+
+            if sessions.is_empty() {
+                println!("Device {} has not been synced yet (or its history has been lost).", args.device);
+            }
+            for (index, session) in sessions.iter().enumerate() {
+                println!("#{} {} on {}: {} files pushed ({}), {} removed ({}), {} playlists, {} ratings and {} plays reverse synced, {} warnings, took {:.0}s",
+                    index + 1, session.timestamp, session.hostname,
+                    session.files_pushed.len() + session.files_updated.len(), format_size(session.bytes_pushed, humansize::DECIMAL),
+                    session.files_removed.len(), format_size(session.bytes_removed, humansize::DECIMAL),
+                    session.source_playlist_updates.len(), session.source_rating_updates.len(),
+                    session.source_play_updates.iter().map(|update| update.plays).sum::<u32>(),
+                    session.warnings, session.duration_secs,
+                );
+            }
+        },
+
+        Some(number) => {
+            let session = number.checked_sub(1)
+                .and_then(|index| sessions.get(index))
+                .ok_or_else(|| format!("There is no sync #{} in the history of this device ({} syncs are recorded)", number, sessions.len()))?;
+
+            if json {
+                println!("{}", serde_json::to_string_pretty(session)?);
+                return Ok(EXIT_SUCCESS);
+            }
+            print_session(session);
+        },
+    }
+
+    Ok(EXIT_SUCCESS)
+}
+
+fn print_session(session: &starsync::sync::SyncSession) {
+    println!("Sync on {} from computer \"{}\" (took {:.0}s, {} warnings)", session.timestamp, session.hostname, session.duration_secs, session.warnings);
+
+    if session.source_playlist_updates.is_empty() == false {
+        println!("Playlists updated in the source:");
+        for change in &session.source_playlist_updates {
+            println!("  * {} ({} tracks added, {} removed)", change.name, change.added_tracks, change.removed_tracks);
+        }
+    }
+
+    if session.source_rating_updates.is_empty() == false {
+        println!("Ratings updated in the source:");
+        for update in &session.source_rating_updates {
+            let stars = |r: starsync::source::Rating| r.map(|s| s.get()).unwrap_or(0);
+            println!("  * {}: {} stars -> {} stars", update.track_name, stars(update.current_rating_on_source), stars(update.new_rating));
+        }
+    }
+
+    if session.source_play_updates.is_empty() == false {
+        println!("Plays recorded into the source:");
+        for update in &session.source_play_updates {
+            println!("  * {}: {} plays -> {} plays", update.track_name, update.current_play_count, update.current_play_count + update.plays);
+        }
+    }
+
+    if session.files_removed.is_empty() == false {
+        println!("Files removed from the device ({}):", format_size(session.bytes_removed, humansize::DECIMAL));
+        for path in &session.files_removed {
+            println!("  - {}", path.display());
+        }
+    }
+
+    if session.files_pushed.is_empty() == false || session.files_updated.is_empty() == false {
+        println!("Files pushed to the device ({}):", format_size(session.bytes_pushed, humansize::DECIMAL));
+        for path in &session.files_pushed {
+            println!("  + {}", path.display());
+        }
+        for path in &session.files_updated {
+            println!("  ~ {} (modified since the previous sync)", path.display());
+        }
+    }
 }
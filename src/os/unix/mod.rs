@@ -3,6 +3,7 @@ use std::time::Duration;
 
 use dbus::blocking::Connection;
 use log::warn;
+use time::UtcOffset;
 
 mod login1;
 use login1::OrgFreedesktopLogin1Manager;
@@ -54,3 +55,14 @@ impl Drop for PleaseStayAwake {
         }
     }
 }
+
+/// The offset of the local time zone at a given time (a Unix timestamp), e.g. to convert timestamps that are in local time
+pub fn local_offset_at(timestamp: i64) -> Option<UtcOffset> {
+    let time = nix::libc::time_t::try_from(timestamp).ok()?;
+    // The `time` crate refuses to do this in multi-threaded programs, as `localtime_r` is not safe in case other threads modify the environment. We never do.
+    let mut tm = unsafe{ std::mem::zeroed::<nix::libc::tm>() };
+    if unsafe{ nix::libc::localtime_r(&time, &mut tm) }.is_null() {
+        return None;
+    }
+    UtcOffset::from_whole_seconds(i32::try_from(tm.tm_gmtoff).ok()?).ok()
+}
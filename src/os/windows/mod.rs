@@ -1,3 +1,7 @@
+use time::{Date, Month, PrimitiveDateTime, Time, UtcOffset};
+use windows::Win32::Foundation::{FILETIME, SYSTEMTIME};
+use windows::Win32::System::Time::{FileTimeToSystemTime, SystemTimeToTzSpecificLocalTime};
+
 /// A RAII wrapper to tell the OS not to go to sleep while a sync is in progress
 pub struct PleaseStayAwake {}
 
@@ -24,3 +28,30 @@ impl Drop for PleaseStayAwake {
         }
     }
 }
+
+/// The offset of the local time zone at a given time (a Unix timestamp), e.g. to convert timestamps that are in local time
+pub fn local_offset_at(timestamp: i64) -> Option<UtcOffset> {
+    // FILETIMEs count 100-nanosecond intervals since 1601-01-01
+    let intervals = u64::try_from(timestamp.checked_add(11_644_473_600)?.checked_mul(10_000_000)?).ok()?;
+    let file_time = FILETIME{ dwLowDateTime: intervals as u32, dwHighDateTime: (intervals >> 32) as u32 };
+
+    let mut utc = SYSTEMTIME::default();
+    let mut local = SYSTEMTIME::default();
+    unsafe{
+        if FileTimeToSystemTime(&file_time, &mut utc).as_bool() == false {
+            return None;
+        }
+        if SystemTimeToTzSpecificLocalTime(None, &utc, &mut local).as_bool() == false {
+            return None;
+        }
+    }
+
+    let offset = to_date_time(&local)? - to_date_time(&utc)?;
+    UtcOffset::from_whole_seconds(i32::try_from(offset.whole_seconds()).ok()?).ok()
+}
+
+fn to_date_time(system_time: &SYSTEMTIME) -> Option<PrimitiveDateTime> {
+    let date = Date::from_calendar_date(system_time.wYear as i32, Month::try_from(system_time.wMonth as u8).ok()?, system_time.wDay as u8).ok()?;
+    let time = Time::from_hms(system_time.wHour as u8, system_time.wMinute as u8, system_time.wSecond as u8).ok()?;
+    Some(PrimitiveDateTime::new(date, time))
+}
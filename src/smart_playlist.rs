@@ -0,0 +1,525 @@
+//! Smart playlists, i.e. playlists that are defined by a rule in the device config, rather than in the source
+//!
+//! Rules are made of conditions, that can be combined with `and`, `or`, `not` and parentheses:
+//! * `rating` and `year` are compared to a number with `=`, `!=`, `<`, `<=`, `>` or `>=` (unrated tracks are rated 0)
+//! * `title`, `artist`, `album` and `genre` are compared to a quoted string with `=`, `!=` or `contains` (this ignores the case)
+//! * `added within 30 days`, `added before "2024-01-31"` and `added after "2024-01-31"` check when tracks were added to the library
+//! * `in playlist "Some name"` checks whether tracks are in a (regular) playlist of the source
+//!
+//! e.g. `rating >= 4`, or `genre = "Jazz" and not in playlist "Christmas"`.
+//!
+//! Smart playlists are read-only: changes made to them on the device are not reverse synced.
+
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+
+use time::{Date, Month, OffsetDateTime};
+
+use crate::source::{Playlist, PlaylistId, Source, Track, TrackId};
+
+#[derive(thiserror::Error, Debug, PartialEq)]
+#[error("Invalid rule: {0}")]
+pub struct InvalidRule(pub String);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NumberField {
+    Rating,
+    Year,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TextField {
+    Title,
+    Artist,
+    Album,
+    Genre,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+    Contains,
+}
+
+impl Comparison {
+    fn compare<T: PartialOrd>(&self, left: T, right: T) -> bool {
+        match self {
+            Comparison::Equal => left == right,
+            Comparison::NotEqual => left != right,
+            Comparison::Less => left < right,
+            Comparison::LessOrEqual => left <= right,
+            Comparison::Greater => left > right,
+            Comparison::GreaterOrEqual => left >= right,
+            Comparison::Contains => false,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Rule {
+    And(Box<Rule>, Box<Rule>),
+    Or(Box<Rule>, Box<Rule>),
+    Not(Box<Rule>),
+    Number{ field: NumberField, comparison: Comparison, value: i64 },
+    Text{ field: TextField, comparison: Comparison, value: String },
+    AddedWithin{ days: u64 },
+    AddedBefore(Date),
+    AddedAfter(Date),
+    InPlaylist(String),
+}
+
+impl std::str::FromStr for Rule {
+    type Err = InvalidRule;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let tokens = tokenize(s)?;
+        let mut parser = Parser{ tokens, position: 0 };
+        let rule = parser.parse_or()?;
+        match parser.next() {
+            None => Ok(rule),
+            Some(token) => Err(InvalidRule(format!("unexpected {token}"))),
+        }
+    }
+}
+
+/// What a rule needs to know (besides the tracks themselves) to be evaluated
+pub struct Context<'a> {
+    source: &'a dyn Source,
+    use_computed_ratings: bool,
+    now: SystemTime,
+    /// The tracks of the playlists rules refer to, lazily loaded
+    playlists: RefCell<HashMap<String, HashSet<TrackId>>>,
+}
+
+impl<'a> Context<'a> {
+    pub fn new(source: &'a dyn Source, use_computed_ratings: bool) -> Self {
+        Self{ source, use_computed_ratings, now: SystemTime::now(), playlists: RefCell::new(HashMap::new()) }
+    }
+
+    fn is_in_playlist(&self, playlist_name: &str, track_id: TrackId) -> bool {
+        let mut playlists = self.playlists.borrow_mut();
+        let tracks = playlists.entry(playlist_name.to_string()).or_insert_with(|| {
+            let tracks = self.source
+                .playlist_by_name(playlist_name)
+                .ok_or_else(|| "no such playlist".into())
+                .and_then(|playlist| playlist.tracks());
+            match tracks {
+                Err(err) => {
+                    log::warn!("Unable to get the tracks of playlist '{playlist_name}', that is used in a rule: {err}");
+                    HashSet::new()
+                },
+                Ok(tracks) => tracks.iter().map(|track| track.id()).collect(),
+            }
+        });
+        tracks.contains(&track_id)
+    }
+}
+
+impl Rule {
+    pub fn matches(&self, track: &dyn Track, context: &Context) -> bool {
+        match self {
+            Rule::And(left, right) => left.matches(track, context) && right.matches(track, context),
+            Rule::Or(left, right) => left.matches(track, context) || right.matches(track, context),
+            Rule::Not(rule) => rule.matches(track, context) == false,
+            Rule::Number{ field, comparison, value } => {
+                let actual = match field {
+                    NumberField::Rating => Some(track.rating(context.use_computed_ratings).map(|r| r.get() as i64).unwrap_or(0)),
+                    NumberField::Year => track.year().map(|year| year as i64),
+                };
+                actual.map(|actual| comparison.compare(actual, *value)).unwrap_or(false)
+            },
+            Rule::Text{ field, comparison, value } => {
+                let actual = match field {
+                    TextField::Title => Some(track.name()),
+                    TextField::Artist => track.artist(),
+                    TextField::Album => track.album(),
+                    TextField::Genre => track.genre(),
+                };
+                let actual = actual.unwrap_or_default().to_lowercase();
+                let value = value.to_lowercase();
+                match comparison {
+                    Comparison::Contains => actual.contains(&value),
+                    _ => comparison.compare(actual, value),
+                }
+            },
+            Rule::AddedWithin{ days } => track
+                .date_added()
+                // Periods that are too long to be represented obviously include the date the track was added
+                .map(|added| days
+                    .checked_mul(24 * 3600)
+                    .and_then(|secs| added.checked_add(Duration::from_secs(secs)))
+                    .map(|limit| limit >= context.now)
+                    .unwrap_or(true))
+                .unwrap_or(false),
+            Rule::AddedBefore(date) => track
+                .date_added()
+                .map(|added| OffsetDateTime::from(added).date() < *date)
+                .unwrap_or(false),
+            Rule::AddedAfter(date) => track
+                .date_added()
+                .map(|added| OffsetDateTime::from(added).date() > *date)
+                .unwrap_or(false),
+            Rule::InPlaylist(name) => context.is_in_playlist(name, track.id()),
+        }
+    }
+}
+
+
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Word(String),
+    Number(i64),
+    Text(String),
+    Operator(&'static str),
+    OpeningParenthesis,
+    ClosingParenthesis,
+}
+
+impl std::fmt::Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Token::Word(word) => write!(f, "'{word}'"),
+            Token::Number(number) => write!(f, "{number}"),
+            Token::Text(text) => write!(f, "\"{text}\""),
+            Token::Operator(op) => write!(f, "'{op}'"),
+            Token::OpeningParenthesis => write!(f, "'('"),
+            Token::ClosingParenthesis => write!(f, "')'"),
+        }
+    }
+}
+
+fn tokenize(s: &str) -> Result<Vec<Token>, InvalidRule> {
+    let mut tokens = Vec::new();
+    let mut chars = s.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => (),
+            '(' => tokens.push(Token::OpeningParenthesis),
+            ')' => tokens.push(Token::ClosingParenthesis),
+            '"' => {
+                let mut text = String::new();
+                loop {
+                    match chars.next() {
+                        None => return Err(InvalidRule("unterminated string".to_string())),
+                        Some('"') => break,
+                        Some('\\') => text.extend(chars.next()),
+                        Some(c) => text.push(c),
+                    }
+                }
+                tokens.push(Token::Text(text));
+            },
+            '=' => {
+                // Both = and == are accepted
+                if chars.peek() == Some(&'=') {
+                    chars.next();
+                }
+                tokens.push(Token::Operator("="));
+            },
+            '!' | '<' | '>' => {
+                let followed_by_equal = chars.peek() == Some(&'=');
+                if followed_by_equal {
+                    chars.next();
+                }
+                tokens.push(Token::Operator(match (c, followed_by_equal) {
+                    ('!', true) => "!=",
+                    ('<', false) => "<",
+                    ('<', true) => "<=",
+                    ('>', false) => ">",
+                    ('>', true) => ">=",
+                    _ => return Err(InvalidRule("unexpected '!'".to_string())),
+                }));
+            },
+            c if c.is_ascii_digit() => {
+                let mut number = c.to_string();
+                while let Some(digit) = chars.next_if(|c| c.is_ascii_digit()) {
+                    number.push(digit);
+                }
+                tokens.push(Token::Number(number.parse().map_err(|_| InvalidRule(format!("invalid number {number}")))?));
+            },
+            c if c.is_alphabetic() => {
+                let mut word = c.to_string();
+                while let Some(c) = chars.next_if(|c| c.is_alphanumeric() || *c == '_') {
+                    word.push(c);
+                }
+                tokens.push(Token::Word(word.to_lowercase()));
+            },
+            c => return Err(InvalidRule(format!("unexpected '{c}'"))),
+        }
+    }
+
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+impl Parser {
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn peek_word(&self, word: &str) -> bool {
+        matches!(self.tokens.get(self.position), Some(Token::Word(w)) if w == word)
+    }
+
+    fn expect_word(&mut self, word: &str) -> Result<(), InvalidRule> {
+        match self.next() {
+            Some(Token::Word(w)) if w == word => Ok(()),
+            Some(token) => Err(InvalidRule(format!("expected '{word}', found {token}"))),
+            None => Err(InvalidRule(format!("expected '{word}'"))),
+        }
+    }
+
+    fn expect_text(&mut self) -> Result<String, InvalidRule> {
+        match self.next() {
+            Some(Token::Text(text)) => Ok(text),
+            Some(token) => Err(InvalidRule(format!("expected a quoted string, found {token}"))),
+            None => Err(InvalidRule("expected a quoted string".to_string())),
+        }
+    }
+
+    fn expect_date(&mut self) -> Result<Date, InvalidRule> {
+        let text = self.expect_text()?;
+        parse_date(&text).ok_or_else(|| InvalidRule(format!("invalid date \"{text}\" (expected YYYY-MM-DD)")))
+    }
+
+    fn parse_or(&mut self) -> Result<Rule, InvalidRule> {
+        let mut rule = self.parse_and()?;
+        while self.peek_word("or") {
+            self.position += 1;
+            rule = Rule::Or(Box::new(rule), Box::new(self.parse_and()?));
+        }
+        Ok(rule)
+    }
+
+    fn parse_and(&mut self) -> Result<Rule, InvalidRule> {
+        let mut rule = self.parse_unary()?;
+        while self.peek_word("and") {
+            self.position += 1;
+            rule = Rule::And(Box::new(rule), Box::new(self.parse_unary()?));
+        }
+        Ok(rule)
+    }
+
+    fn parse_unary(&mut self) -> Result<Rule, InvalidRule> {
+        match self.next() {
+            None => Err(InvalidRule("unexpected end of rule".to_string())),
+            Some(Token::OpeningParenthesis) => {
+                let rule = self.parse_or()?;
+                match self.next() {
+                    Some(Token::ClosingParenthesis) => Ok(rule),
+                    _ => Err(InvalidRule("missing ')'".to_string())),
+                }
+            },
+            Some(Token::Word(word)) => match word.as_str() {
+                "not" => Ok(Rule::Not(Box::new(self.parse_unary()?))),
+                "in" => {
+                    self.expect_word("playlist")?;
+                    Ok(Rule::InPlaylist(self.expect_text()?))
+                },
+                "added" => match self.next() {
+                    Some(Token::Word(w)) if w == "within" => {
+                        let days = match self.next() {
+                            Some(Token::Number(days)) => days as u64,
+                            _ => return Err(InvalidRule("expected a number of days after 'within'".to_string())),
+                        };
+                        self.expect_word("days")?;
+                        Ok(Rule::AddedWithin{ days })
+                    },
+                    Some(Token::Word(w)) if w == "before" => Ok(Rule::AddedBefore(self.expect_date()?)),
+                    Some(Token::Word(w)) if w == "after" => Ok(Rule::AddedAfter(self.expect_date()?)),
+                    _ => Err(InvalidRule("expected 'within', 'before' or 'after' after 'added'".to_string())),
+                },
+                "rating" | "year" => {
+                    let field = if word == "rating" { NumberField::Rating } else { NumberField::Year };
+                    let comparison = self.parse_comparison()?;
+                    match (comparison, self.next()) {
+                        (Comparison::Contains, _) => Err(InvalidRule(format!("'contains' cannot be used with '{word}'"))),
+                        (comparison, Some(Token::Number(value))) => Ok(Rule::Number{ field, comparison, value }),
+                        _ => Err(InvalidRule(format!("expected a number after '{word}'"))),
+                    }
+                },
+                "title" | "artist" | "album" | "genre" => {
+                    let field = match word.as_str() {
+                        "title" => TextField::Title,
+                        "artist" => TextField::Artist,
+                        "album" => TextField::Album,
+                        _ => TextField::Genre,
+                    };
+                    let comparison = self.parse_comparison()?;
+                    if matches!(comparison, Comparison::Equal | Comparison::NotEqual | Comparison::Contains) == false {
+                        return Err(InvalidRule(format!("'{word}' can only be compared with '=', '!=' or 'contains'")));
+                    }
+                    Ok(Rule::Text{ field, comparison, value: self.expect_text()? })
+                },
+                _ => Err(InvalidRule(format!("unknown condition '{word}'"))),
+            },
+            Some(token) => Err(InvalidRule(format!("unexpected {token}"))),
+        }
+    }
+
+    fn parse_comparison(&mut self) -> Result<Comparison, InvalidRule> {
+        match self.next() {
+            Some(Token::Operator("=")) => Ok(Comparison::Equal),
+            Some(Token::Operator("!=")) => Ok(Comparison::NotEqual),
+            Some(Token::Operator("<")) => Ok(Comparison::Less),
+            Some(Token::Operator("<=")) => Ok(Comparison::LessOrEqual),
+            Some(Token::Operator(">")) => Ok(Comparison::Greater),
+            Some(Token::Operator(">=")) => Ok(Comparison::GreaterOrEqual),
+            Some(Token::Word(w)) if w == "contains" => Ok(Comparison::Contains),
+            _ => Err(InvalidRule("expected a comparison".to_string())),
+        }
+    }
+}
+
+fn parse_date(text: &str) -> Option<Date> {
+    let mut parts = text.trim().splitn(3, '-');
+    let year = parts.next()?.parse().ok()?;
+    let month = Month::try_from(parts.next()?.parse::<u8>().ok()?).ok()?;
+    let day = parts.next()?.parse().ok()?;
+    Date::from_calendar_date(year, month, day).ok()
+}
+
+
+
+/// A playlist made of the tracks of a source that match a rule
+pub struct SmartPlaylist<'a> {
+    name: String,
+    rule: Rule,
+    source: &'a dyn Source,
+    use_computed_ratings: bool,
+}
+
+impl<'a> SmartPlaylist<'a> {
+    pub fn new(name: &str, rule: &str, source: &'a dyn Source, use_computed_ratings: bool) -> Result<Self, InvalidRule> {
+        Ok(Self{ name: name.to_string(), rule: rule.parse()?, source, use_computed_ratings })
+    }
+}
+
+impl<'a> Playlist for SmartPlaylist<'a> {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    /// The matching tracks, sorted by path (so that the same albums stay together)
+    fn tracks(&self) -> Result<Vec<Box<dyn Track>>, Box<dyn Error>> {
+        let context = Context::new(self.source, self.use_computed_ratings);
+        let mut tracks: Vec<(PathBuf, Box<dyn Track>)> = self.source
+            .tracks()?
+            .into_iter()
+            .filter(|track| self.rule.matches(track.as_ref(), &context))
+            .map(|track| (track.absolute_path().unwrap_or_default(), track))
+            .collect();
+        tracks.sort_by(|(a, _), (b, _)| a.cmp(b));
+        Ok(tracks.into_iter().map(|(_, track)| track).collect())
+    }
+
+    fn id(&self) -> PlaylistId {
+        PlaylistId::Smart(self.name.clone())
+    }
+
+    fn change_contents_to(&self, _new_content: &[TrackId]) -> Result<(), Box<dyn Error>> {
+        Err(format!("Smart playlist {} is read-only", self.name).into())
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::num::NonZeroU8;
+    use crate::source::Rating;
+
+    struct FakeTrack {
+        rating: Rating,
+        genre: Option<String>,
+        year: Option<i32>,
+        added: Option<SystemTime>,
+    }
+
+    impl Track for FakeTrack {
+        fn name(&self) -> String { "Some song".to_string() }
+        fn id(&self) -> TrackId { TrackId(1) }
+        fn absolute_path(&self) -> Result<PathBuf, Box<dyn Error>> { Ok(PathBuf::from("/music/song.mp3")) }
+        fn rating(&self, _use_computed_ratings: bool) -> Rating { self.rating }
+        fn set_rating(&self, _new_rating: Rating) -> Result<(), Box<dyn Error>> { Ok(()) }
+        fn file_size(&self) -> Result<usize, Box<dyn Error>> { Ok(0) }
+        fn genre(&self) -> Option<String> { self.genre.clone() }
+        fn year(&self) -> Option<i32> { self.year }
+        fn date_added(&self) -> Option<SystemTime> { self.added }
+    }
+
+    struct FakeSource;
+
+    impl Source for FakeSource {
+        fn name(&self) -> &str { "fake" }
+        fn playlists(&self) -> Result<Vec<Box<dyn Playlist>>, Box<dyn Error>> { Ok(Vec::new()) }
+        fn playlist_by_name(&self, _name: &str) -> Option<Box<dyn Playlist>> { None }
+        fn playlist_by_id(&self, _id: &PlaylistId) -> Option<Box<dyn Playlist>> { None }
+        fn track_by_id(&self, _id: TrackId) -> Option<Box<dyn Track>> { None }
+    }
+
+    #[test]
+    fn parse_rules() {
+        assert_eq!("rating >= 4".parse(), Ok(Rule::Number{ field: NumberField::Rating, comparison: Comparison::GreaterOrEqual, value: 4 }));
+        assert_eq!("added within 30 days".parse(), Ok(Rule::AddedWithin{ days: 30 }));
+        assert_eq!(
+            "Genre = \"Jazz\" AND NOT in playlist \"Christmas \\\"special\\\"\"".parse(),
+            Ok(Rule::And(
+                Box::new(Rule::Text{ field: TextField::Genre, comparison: Comparison::Equal, value: "Jazz".to_string() }),
+                Box::new(Rule::Not(Box::new(Rule::InPlaylist("Christmas \"special\"".to_string())))),
+            ))
+        );
+        assert_eq!(
+            "year < 1990 or (rating = 5 and added after \"2024-02-29\")".parse::<Rule>().map(|rule| matches!(rule, Rule::Or(_, _))),
+            Ok(true)
+        );
+
+        assert!("rating >= ".parse::<Rule>().is_err());
+        assert!("genre > \"Jazz\"".parse::<Rule>().is_err());
+        assert!("added before \"2024-02-30\"".parse::<Rule>().is_err());
+        assert!("(rating = 5".parse::<Rule>().is_err());
+        assert!("rating = 5 rating".parse::<Rule>().is_err());
+        assert!("bpm > 120".parse::<Rule>().is_err());
+    }
+
+    #[test]
+    fn evaluate_rules() {
+        let source = FakeSource;
+        let context = Context::new(&source, false);
+        let track = FakeTrack{
+            rating: NonZeroU8::new(4),
+            genre: Some("Cool Jazz".to_string()),
+            year: None,
+            added: Some(SystemTime::now() - Duration::from_secs(10 * 24 * 3600)),
+        };
+        let matches = |rule: &str| rule.parse::<Rule>().unwrap().matches(&track, &context);
+
+        assert!(matches("rating >= 4"));
+        assert!(matches("rating > 4") == false);
+        assert!(matches("genre contains \"jazz\""));
+        assert!(matches("genre = \"jazz\"") == false);
+        assert!(matches("added within 30 days and not added within 5 days"));
+        assert!(matches("added within 9223372036854775807 days"));
+        assert!(matches("added after \"2000-01-01\""));
+        // Unknown metadata never matches
+        assert!(matches("year < 3000") == false);
+        assert!(matches("not year < 3000"));
+        assert!(matches("in playlist \"Missing\"") == false);
+    }
+}
@@ -0,0 +1,281 @@
+//! Minimal reading and writing of the Vorbis comments of FLAC files
+//!
+//! Only the metadata blocks are parsed, the audio frames are copied as-is.
+
+use std::error::Error;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+const MAGIC: &[u8; 4] = b"fLaC";
+const BLOCK_TYPE_PADDING: u8 = 1;
+const BLOCK_TYPE_VORBIS_COMMENT: u8 = 4;
+/// The amount of padding we leave when we have to rewrite a whole file, so that next changes can be done in place
+const NEW_PADDING_SIZE: usize = 4096;
+const MAX_BLOCK_SIZE: usize = 0xFF_FF_FF;
+
+struct MetadataBlock {
+    block_type: u8,
+    data: Vec<u8>,
+}
+
+/// The content of a `VORBIS_COMMENT` block
+#[derive(Debug, Default, PartialEq)]
+pub struct VorbisComments {
+    vendor: String,
+    /// Fields, as (name, value) pairs. Names are case-insensitive.
+    fields: Vec<(String, String)>,
+}
+
+impl VorbisComments {
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.fields
+            .iter()
+            .find(|(field, _)| field.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Set (or remove, if `value` is `None`) a field
+    pub fn set(&mut self, name: &str, value: Option<String>) {
+        self.fields.retain(|(field, _)| field.eq_ignore_ascii_case(name) == false);
+        if let Some(value) = value {
+            self.fields.push((name.to_string(), value));
+        }
+    }
+
+    fn parse(data: &[u8]) -> Result<Self, Box<dyn Error>> {
+        let mut cursor = data;
+        Self::read_from(&mut cursor)
+    }
+
+    /// Parse comments, and advance the cursor past them (Ogg streams may have more data afterwards)
+    pub(super) fn read_from(cursor: &mut &[u8]) -> Result<Self, Box<dyn Error>> {
+        let vendor = read_string(cursor)?;
+        let count = read_u32_le(cursor)?;
+        let mut fields = Vec::new();
+        for _ in 0..count {
+            let field = read_string(cursor)?;
+            match field.split_once('=') {
+                Some((name, value)) => fields.push((name.to_string(), value.to_string())),
+                None => log::warn!("Ignoring invalid Vorbis comment '{field}'"),
+            }
+        }
+        Ok(Self{ vendor, fields })
+    }
+
+    pub(super) fn serialize(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        write_string(&mut data, &self.vendor);
+        data.extend_from_slice(&(self.fields.len() as u32).to_le_bytes());
+        for (name, value) in &self.fields {
+            write_string(&mut data, &format!("{name}={value}"));
+        }
+        data
+    }
+}
+
+fn read_u32_le(cursor: &mut &[u8]) -> Result<u32, Box<dyn Error>> {
+    let mut bytes = [0; 4];
+    cursor.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_string(cursor: &mut &[u8]) -> Result<String, Box<dyn Error>> {
+    let len = read_u32_le(cursor)? as usize;
+    if len > cursor.len() {
+        return Err("Truncated Vorbis comment".into());
+    }
+    let (string, rest) = cursor.split_at(len);
+    *cursor = rest;
+    Ok(String::from_utf8_lossy(string).to_string())
+}
+
+fn write_string(data: &mut Vec<u8>, s: &str) {
+    data.extend_from_slice(&(s.len() as u32).to_le_bytes());
+    data.extend_from_slice(s.as_bytes());
+}
+
+
+
+/// Read the metadata blocks of a FLAC stream. The reader is left at the start of the audio frames.
+fn read_metadata_blocks<R: Read>(reader: &mut R) -> Result<Vec<MetadataBlock>, Box<dyn Error>> {
+    let mut magic = [0; 4];
+    reader.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err("Not a FLAC file".into());
+    }
+
+    let mut blocks = Vec::new();
+    loop {
+        let mut header = [0; 4];
+        reader.read_exact(&mut header)?;
+        let is_last = header[0] & 0x80 != 0;
+        let block_type = header[0] & 0x7F;
+        let len = u32::from_be_bytes([0, header[1], header[2], header[3]]) as usize;
+        let mut data = vec![0; len];
+        reader.read_exact(&mut data)?;
+        blocks.push(MetadataBlock{ block_type, data });
+
+        if is_last {
+            return Ok(blocks);
+        }
+    }
+}
+
+fn serialize_metadata_blocks(blocks: &[MetadataBlock]) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut output = Vec::new();
+    for (i, block) in blocks.iter().enumerate() {
+        if block.data.len() > MAX_BLOCK_SIZE {
+            return Err("Metadata block is too large".into());
+        }
+        let is_last = i == blocks.len() - 1;
+        let len = (block.data.len() as u32).to_be_bytes();
+        output.push(block.block_type | if is_last { 0x80 } else { 0 });
+        output.extend_from_slice(&len[1..]);
+        output.extend_from_slice(&block.data);
+    }
+    Ok(output)
+}
+
+fn metadata_size(blocks: &[MetadataBlock]) -> usize {
+    blocks.iter().map(|block| 4 + block.data.len()).sum()
+}
+
+pub fn read_comments(path: &Path) -> Result<VorbisComments, Box<dyn Error>> {
+    let mut reader = std::io::BufReader::new(std::fs::File::open(path)?);
+    let blocks = read_metadata_blocks(&mut reader)?;
+    match blocks.iter().find(|block| block.block_type == BLOCK_TYPE_VORBIS_COMMENT) {
+        None => Ok(VorbisComments::default()),
+        Some(block) => VorbisComments::parse(&block.data),
+    }
+}
+
+/// Replace the Vorbis comments of a FLAC file.
+///
+/// In case the existing padding is large enough, only the metadata blocks are overwritten.
+/// Otherwise, the whole file is rewritten (into a temporary file that then replaces the original one).
+pub fn write_comments(path: &Path, comments: &VorbisComments) -> Result<(), Box<dyn Error>> {
+    let mut file = std::fs::OpenOptions::new().read(true).write(true).open(path)?;
+    let mut blocks = read_metadata_blocks(&mut std::io::BufReader::new(&file))?;
+    let original_size = metadata_size(&blocks);
+
+    let new_comment_block = MetadataBlock{ block_type: BLOCK_TYPE_VORBIS_COMMENT, data: comments.serialize() };
+    match blocks.iter().position(|block| block.block_type == BLOCK_TYPE_VORBIS_COMMENT) {
+        Some(index) => blocks[index] = new_comment_block,
+        // STREAMINFO must remain the first block
+        None => blocks.insert(1.min(blocks.len()), new_comment_block),
+    }
+    blocks.retain(|block| block.block_type != BLOCK_TYPE_PADDING);
+    let size_without_padding = metadata_size(&blocks);
+
+    if size_without_padding == original_size || size_without_padding + 4 <= original_size {
+        // Fits in place
+        if size_without_padding < original_size {
+            let padding = original_size - size_without_padding - 4;
+            blocks.push(MetadataBlock{ block_type: BLOCK_TYPE_PADDING, data: vec![0; padding] });
+        }
+        let metadata = serialize_metadata_blocks(&blocks)?;
+        file.seek(SeekFrom::Start(MAGIC.len() as u64))?;
+        file.write_all(&metadata)?;
+        file.sync_all()?;
+        return Ok(());
+    }
+
+    // We have to move the audio frames
+    blocks.push(MetadataBlock{ block_type: BLOCK_TYPE_PADDING, data: vec![0; NEW_PADDING_SIZE] });
+    let metadata = serialize_metadata_blocks(&blocks)?;
+    drop(file);
+    rewrite_file(path, |original, writer| {
+        original.seek(SeekFrom::Start((MAGIC.len() + original_size) as u64))?;
+        writer.write_all(MAGIC)?;
+        writer.write_all(&metadata)?;
+        std::io::copy(original, writer)?;
+        Ok(())
+    })
+}
+
+/// Rewrite a file from its original content (e.g. when its metadata do not fit in place anymore).
+///
+/// This is written into a temporary file, that then replaces the original one, so that the original file is left untouched in case of failure.
+pub(super) fn rewrite_file<F>(path: &Path, rewrite: F) -> Result<(), Box<dyn Error>>
+where F: FnOnce(&mut std::fs::File, &mut dyn Write) -> Result<(), Box<dyn Error>>
+{
+    let mut tmp_name = path.file_name().ok_or("Invalid file name")?.to_os_string();
+    tmp_name.push(".starsync-tmp");
+    let tmp_path = path.with_file_name(tmp_name);
+    let result = (|| -> Result<(), Box<dyn Error>> {
+        let mut original = std::fs::File::open(path)?;
+        let tmp_file = std::fs::File::create(&tmp_path)?;
+        let mut writer = std::io::BufWriter::new(&tmp_file);
+        rewrite(&mut original, &mut writer)?;
+        writer.flush()?;
+        drop(writer);
+        tmp_file.sync_all()?;
+        Ok(())
+    })();
+    if let Err(err) = result {
+        let _ = std::fs::remove_file(&tmp_path);
+        return Err(err);
+    }
+
+    std::fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn comments_roundtrip() {
+        let mut comments = VorbisComments{ vendor: "reference libFLAC 1.4.2".to_string(), fields: vec![
+            ("TITLE".to_string(), "Some = song".to_string()),
+            ("rating".to_string(), "60".to_string()),
+        ]};
+        assert_eq!(comments.get("Title"), Some("Some = song"));
+        assert_eq!(comments.get("RATING"), Some("60"));
+
+        comments.set("RATING", Some("80".to_string()));
+        comments.set("FMPS_RATING", None);
+        assert_eq!(comments.fields.len(), 2);
+
+        let parsed = VorbisComments::parse(&comments.serialize()).unwrap();
+        assert_eq!(parsed, comments);
+        assert!(VorbisComments::parse(&comments.serialize()[..20]).is_err());
+    }
+
+    #[test]
+    fn rewrite_file() {
+        let streaminfo = MetadataBlock{ block_type: 0, data: vec![0xAB; 34] };
+        let padding = MetadataBlock{ block_type: BLOCK_TYPE_PADDING, data: vec![0; 64] };
+        let audio = vec![0xCD; 100];
+
+        let mut content = MAGIC.to_vec();
+        content.extend(serialize_metadata_blocks(&[streaminfo, padding]).unwrap());
+        content.extend(&audio);
+
+        let dir = std::env::temp_dir().join(format!("starsync-flac-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("song.flac");
+        std::fs::write(&path, &content).unwrap();
+
+        // Small enough to fit into the padding
+        let mut comments = read_comments(&path).unwrap();
+        comments.set("RATING", Some("20".to_string()));
+        write_comments(&path, &comments).unwrap();
+        let written = std::fs::read(&path).unwrap();
+        assert_eq!(written.len(), content.len());
+        assert!(written.ends_with(&audio));
+        assert_eq!(read_comments(&path).unwrap().get("rating"), Some("20"));
+
+        // Too large for the padding
+        comments.set("COMMENT", Some("x".repeat(100)));
+        write_comments(&path, &comments).unwrap();
+        let written = std::fs::read(&path).unwrap();
+        assert!(written.ends_with(&audio));
+        assert_eq!(read_comments(&path).unwrap(), comments);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
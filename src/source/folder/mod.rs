@@ -0,0 +1,455 @@
+//! A plain folder of music files, with `.m3u`/`.m3u8` playlists stored anywhere in it
+//!
+//! This does not need any music player library.<br/>
+//! Track IDs are derived from the paths of the songs, relative to the root folder (i.e. moving the root folder keeps them,
+//! but moving a song within the root folder does not). Ratings are read from (and written into) the tags of the music files.
+
+use std::cell::{OnceCell, RefCell};
+use std::collections::HashMap;
+use std::error::Error;
+use std::io::Write;
+use std::path::{Component, Path, PathBuf};
+use std::rc::Rc;
+use std::time::SystemTime;
+
+use crate::device::m3u::M3u;
+use crate::source::{Source, Playlist, Rating, Track, TrackId, PlaylistId, create_m3u};
+
+mod tags;
+mod flac;
+mod ogg;
+
+/// The prefix of the names of these sources
+pub const NAME_PREFIX: &str = "folder://";
+/// An environment variable that can list additional root folders (separated like `PATH`)
+pub const FOLDERS_ENV_VAR: &str = "STARSYNC_FOLDERS";
+
+const PLAYLIST_EXTENSIONS: &[&str] = &["m3u", "m3u8"];
+const MUSIC_EXTENSIONS: &[&str] = &["mp3", "flac", "ogg", "oga", "opus", "m4a", "aac", "wav", "wma", "aiff", "ape", "wv", "mpc"];
+
+/// Songs, indexed by their ID, mapped to their paths relative to the root folder
+type Index = Rc<HashMap<TrackId, PathBuf>>;
+
+/// The folders that are automatically listed as sources
+pub fn default_folders() -> Vec<PathBuf> {
+    let mut folders = Vec::new();
+
+    let music_dir = std::env::var_os("XDG_MUSIC_DIR")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join("Music")))
+        .or_else(|| std::env::var_os("USERPROFILE").map(|home| PathBuf::from(home).join("Music")));
+    folders.extend(music_dir);
+
+    if let Some(env_folders) = std::env::var_os(FOLDERS_ENV_VAR) {
+        folders.extend(std::env::split_paths(&env_folders));
+    }
+
+    folders.retain(|folder| folder.is_dir());
+    folders.dedup();
+    folders
+}
+
+fn has_extension(path: &Path, extensions: &[&str]) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| extensions.iter().any(|candidate| ext.eq_ignore_ascii_case(candidate)))
+        .unwrap_or(false)
+}
+
+/// Recursively list the files with the given extensions, as paths relative to `root`.
+///
+/// Hidden files and folders are skipped.
+fn find_files(root: &Path, extensions: &[&str]) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    let mut found = Vec::new();
+    let mut folders_to_visit = vec![PathBuf::new()];
+
+    while let Some(relative_folder) = folders_to_visit.pop() {
+        for entry in std::fs::read_dir(root.join(&relative_folder))? {
+            let entry = entry?;
+            if entry.file_name().to_string_lossy().starts_with('.') {
+                continue;
+            }
+            let relative_path = relative_folder.join(entry.file_name());
+            let file_type = entry.file_type()?;
+            if file_type.is_dir() {
+                folders_to_visit.push(relative_path);
+            } else if has_extension(&relative_path, extensions) {
+                found.push(relative_path);
+            }
+        }
+    }
+
+    found.sort();
+    Ok(found)
+}
+
+/// The string IDs are derived from. This is the relative path with `/` separators, so that it is the same on every OS
+fn id_string(relative_path: &Path) -> String {
+    relative_path.to_string_lossy().replace('\\', "/")
+}
+
+/// The ID of the track of a file. Paths are lowercased first, like [`crate::sync::SyncInfo`] does, so that a file that is renamed by changing its case keeps its ID
+fn track_id(relative_path: &Path) -> TrackId {
+    TrackId::from_hashed_str(&id_string(relative_path).to_lowercase())
+}
+
+/// Lexically resolve `.` and `..` components (the paths in playlists may point to files that do not exist)
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => (),
+            Component::ParentDir => { normalized.pop(); },
+            c => normalized.push(c),
+        }
+    }
+    normalized
+}
+
+/// The path of `target` relative to `from_folder`, provided they are both relative to the same root
+fn relative_path_from(from_folder: &Path, target: &Path) -> PathBuf {
+    let from: Vec<Component> = from_folder.components().collect();
+    let to: Vec<Component> = target.components().collect();
+    let common = from.iter().zip(to.iter()).take_while(|(a, b)| a == b).count();
+
+    let mut relative = PathBuf::new();
+    for _ in common..from.len() {
+        relative.push("..");
+    }
+    for component in &to[common..] {
+        relative.push(component);
+    }
+    relative
+}
+
+
+
+/// The content of the root folder
+struct Library {
+    root: PathBuf,
+    /// This is lazily loaded
+    index: RefCell<Option<Index>>,
+}
+
+impl Library {
+    fn index(&self) -> Result<Index, Box<dyn Error>> {
+        if let Some(index) = self.index.borrow().as_ref() {
+            return Ok(Rc::clone(index));
+        }
+
+        let index: HashMap<TrackId, PathBuf> = find_files(&self.root, MUSIC_EXTENSIONS)?
+            .into_iter()
+            .map(|relative_path| (track_id(&relative_path), relative_path))
+            .collect();
+        let index = Rc::new(index);
+        *self.index.borrow_mut() = Some(Rc::clone(&index));
+        Ok(index)
+    }
+
+    /// Read a playlist file, and return the paths of its songs, relative to the root folder
+    fn read_playlist(&self, relative_playlist_path: &Path) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+        let absolute_playlist_path = self.root.join(relative_playlist_path);
+        let playlist_folder = absolute_playlist_path.parent().unwrap_or(&self.root);
+        let file = std::fs::File::open(&absolute_playlist_path)?;
+
+        let mut songs = Vec::new();
+        for path in M3u::parse(Box::new(file)).paths() {
+            // Playlists made on Windows use backslashes
+            let path = PathBuf::from(path.to_string_lossy().replace('\\', "/"));
+            let absolute_path = normalize(&playlist_folder.join(path));
+            match absolute_path.strip_prefix(&self.root) {
+                Ok(relative_path) => songs.push(relative_path.to_path_buf()),
+                Err(_) => log::warn!("Playlist {} contains {}, which is outside of {}. Ignoring it.", relative_playlist_path.display(), absolute_path.display(), self.root.display()),
+            }
+        }
+        Ok(songs)
+    }
+
+    fn write_playlist(&self, relative_playlist_path: &Path, songs: &[PathBuf]) -> Result<(), Box<dyn Error>> {
+        let playlist_folder = relative_playlist_path.parent().unwrap_or_else(|| Path::new(""));
+        let relative_paths = songs.iter().map(|song| relative_path_from(playlist_folder, song));
+        let mut content = create_m3u(relative_paths, Path::new(""))?;
+        content.push_str("\r\n");
+
+        // Write into a temporary file first, so that a failure does not leave a truncated playlist
+        let path = self.root.join(relative_playlist_path);
+        let mut tmp_name = path.file_name().ok_or("Invalid file name")?.to_os_string();
+        tmp_name.push(".starsync-tmp");
+        let tmp_path = path.with_file_name(tmp_name);
+        let mut tmp_file = std::fs::File::create(&tmp_path)?;
+        if let Err(err) = tmp_file.write_all(content.as_bytes()).and_then(|_| tmp_file.sync_all()) {
+            let _ = std::fs::remove_file(&tmp_path);
+            return Err(err.into());
+        }
+        std::fs::rename(&tmp_path, &path)?;
+        Ok(())
+    }
+}
+
+
+
+pub struct Folder {
+    name: String,
+    library: Rc<Library>,
+}
+
+impl Folder {
+    pub fn new(root: &Path) -> Option<Self> {
+        if root.is_dir() == false {
+            log::info!("{} is not a folder", root.display());
+            return None;
+        }
+
+        let name = format!("{}{}", NAME_PREFIX, root.to_string_lossy().replace('\\', "/"));
+        let library = Library{ root: root.to_path_buf(), index: RefCell::new(None) };
+        Some(Self{ name, library: Rc::new(library) })
+    }
+
+    /// Get the source from its name (e.g. `folder:///home/user/Music`)
+    pub fn from_name(name: &str) -> Option<Self> {
+        let root = name.strip_prefix(NAME_PREFIX)?;
+        Self::new(Path::new(root))
+    }
+
+    fn make_playlist(&self, relative_path: PathBuf) -> Box<dyn Playlist> {
+        Box::new(M3uPlaylist{ library: Rc::clone(&self.library), relative_path }) as Box<dyn Playlist>
+    }
+}
+
+impl Source for Folder {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn playlists(&self) -> Result<Vec<Box<dyn Playlist>>, Box<dyn Error>> {
+        Ok(find_files(&self.library.root, PLAYLIST_EXTENSIONS)?
+            .into_iter()
+            .map(|relative_path| self.make_playlist(relative_path))
+            .collect())
+    }
+
+    fn playlist_by_name(&self, name: &str) -> Option<Box<dyn Playlist>> {
+        let playlists = match self.playlists() {
+            Err(err) => {
+                log::warn!("Unable to list playlists in {}: {err}", self.library.root.display());
+                return None;
+            },
+            Ok(pl) => pl,
+        };
+
+        playlists.into_iter().find(|pl| pl.name() == name)
+    }
+
+    fn playlist_by_id(&self, id: &PlaylistId) -> Option<Box<dyn Playlist>> {
+        match id {
+            PlaylistId::Name(name) => self.playlist_by_name(name),
+            _ => {
+                log::warn!("Invalid type ({id:?}) for playlist ID.");
+                None
+            }
+        }
+    }
+
+    fn track_by_id(&self, id: TrackId) -> Option<Box<dyn Track>> {
+        let index = match self.library.index() {
+            Err(err) => {
+                log::warn!("Unable to list songs in {}: {err}", self.library.root.display());
+                return None;
+            },
+            Ok(i) => i,
+        };
+
+        index
+            .get(&id)
+            .map(|relative_path| Box::new(FileTrack::new(Rc::clone(&self.library), relative_path.clone())) as Box<dyn Track>)
+    }
+
+    fn tracks(&self) -> Result<Vec<Box<dyn Track>>, Box<dyn Error>> {
+        Ok(self.library
+            .index()?
+            .values()
+            .map(|relative_path| Box::new(FileTrack::new(Rc::clone(&self.library), relative_path.clone())) as Box<dyn Track>)
+            .collect())
+    }
+}
+
+
+
+pub struct M3uPlaylist {
+    library: Rc<Library>,
+    /// The path of the playlist file, relative to the root folder
+    relative_path: PathBuf,
+}
+
+impl Playlist for M3uPlaylist {
+    /// The path of the playlist file (relative to the root folder), without its extension
+    fn name(&self) -> String {
+        id_string(&self.relative_path.with_extension(""))
+    }
+
+    fn tracks(&self) -> Result<Vec<Box<dyn Track>>, Box<dyn Error>> {
+        let songs = self.library.read_playlist(&self.relative_path)?;
+        Ok(songs
+            .into_iter()
+            .filter_map(|relative_path| {
+                if self.library.root.join(&relative_path).is_file() == false {
+                    log::warn!("Playlist {} contains {}, which does not exist", self.name(), relative_path.display());
+                    return None;
+                }
+                Some(Box::new(FileTrack::new(Rc::clone(&self.library), relative_path)) as Box<dyn Track>)
+            })
+            .collect())
+    }
+
+    fn id(&self) -> PlaylistId {
+        PlaylistId::Name(self.name())
+    }
+
+    /// Rewrite the playlist file.
+    ///
+    /// Note that extended M3U information (e.g. `#EXTINF` lines) is not kept.
+    fn change_contents_to(&self, new_content: &[TrackId]) -> Result<(), Box<dyn Error>> {
+        let index = self.library.index()?;
+        let mut songs = Vec::new();
+        for id in new_content {
+            match index.get(id) {
+                None => log::warn!("Unable to get track for ID {id:?}"),
+                Some(relative_path) => songs.push(relative_path.clone()),
+            }
+        }
+
+        self.library.write_playlist(&self.relative_path, &songs)
+    }
+}
+
+
+
+pub struct FileTrack {
+    library: Rc<Library>,
+    /// The path of the song, relative to the root folder
+    relative_path: PathBuf,
+    /// This is lazily read from the tags of the file
+    metadata: OnceCell<tags::Metadata>,
+}
+
+impl FileTrack {
+    fn new(library: Rc<Library>, relative_path: PathBuf) -> Self {
+        Self{ library, relative_path, metadata: OnceCell::new() }
+    }
+
+    fn metadata(&self) -> &tags::Metadata {
+        self.metadata.get_or_init(|| {
+            let path = self.library.root.join(&self.relative_path);
+            tags::read_metadata(&path).unwrap_or_else(|err| {
+                log::warn!("Unable to read the tags of {}: {err}", path.display());
+                tags::Metadata::default()
+            })
+        })
+    }
+}
+
+impl Track for FileTrack {
+    fn name(&self) -> String {
+        self.relative_path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().to_string())
+            .unwrap_or_default()
+    }
+
+    fn id(&self) -> TrackId {
+        track_id(&self.relative_path)
+    }
+
+    fn absolute_path(&self) -> Result<PathBuf, Box<dyn Error>> {
+        Ok(self.library.root.join(&self.relative_path))
+    }
+
+    fn rating(&self, _use_computed_ratings: bool) -> Rating {
+        let path = self.library.root.join(&self.relative_path);
+        tags::read_rating(&path).unwrap_or_else(|err| {
+            log::warn!("Unable to read the rating of {}: {err}", path.display());
+            None
+        })
+    }
+
+    fn set_rating(&self, new_rating: Rating) -> Result<(), Box<dyn Error>> {
+        tags::write_rating(&self.library.root.join(&self.relative_path), new_rating)
+    }
+
+    fn file_size(&self) -> Result<usize, Box<dyn Error>> {
+        let md = std::fs::metadata(self.absolute_path()?)?;
+        Ok(usize::try_from(md.len())?)
+    }
+
+    fn artist(&self) -> Option<String> {
+        self.metadata().artist.clone()
+    }
+
+    fn album(&self) -> Option<String> {
+        self.metadata().album.clone()
+    }
+
+    fn album_artist(&self) -> Option<String> {
+        self.metadata().album_artist.clone()
+    }
+
+    fn track_number(&self) -> Option<u32> {
+        self.metadata().track_number
+    }
+
+    fn disc_number(&self) -> Option<u32> {
+        self.metadata().disc_number
+    }
+
+    fn genre(&self) -> Option<String> {
+        self.metadata().genre.clone()
+    }
+
+    fn year(&self) -> Option<i32> {
+        self.metadata().year
+    }
+
+    /// There is no library database, let's use the creation time of the file (on filesystems that support it)
+    fn date_added(&self) -> Option<SystemTime> {
+        std::fs::metadata(self.library.root.join(&self.relative_path)).ok()?.created().ok()
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn relative_paths() {
+        assert_eq!(normalize(Path::new("/music/playlists/../Artist/./song.mp3")), PathBuf::from("/music/Artist/song.mp3"));
+        assert_eq!(relative_path_from(Path::new("playlists/rock"), Path::new("Artist/song.mp3")), PathBuf::from("../../Artist/song.mp3"));
+        assert_eq!(relative_path_from(Path::new("Artist"), Path::new("Artist/Album/song.mp3")), PathBuf::from("Album/song.mp3"));
+        assert_eq!(relative_path_from(Path::new(""), Path::new("song.mp3")), PathBuf::from("song.mp3"));
+        assert_eq!(id_string(Path::new("Artist/song.mp3")), "Artist/song.mp3");
+        assert_eq!(track_id(Path::new("Artist/Song.mp3")), TrackId::from_hashed_str("artist/song.mp3"));
+    }
+
+    #[test]
+    fn playlist_roundtrip() {
+        let root = std::env::temp_dir().join(format!("starsync-folder-test-{}", std::process::id()));
+        std::fs::create_dir_all(root.join("Artist")).unwrap();
+        std::fs::create_dir_all(root.join("playlists")).unwrap();
+        std::fs::write(root.join("Artist").join("one.mp3"), b"").unwrap();
+        std::fs::write(root.join("Artist").join("two.ogg"), b"").unwrap();
+        std::fs::write(root.join("playlists").join("best.m3u"), "#EXTM3U\r\n../Artist/two.ogg\r\n..\\Artist\\one.mp3\r\n/elsewhere/three.mp3\r\n").unwrap();
+
+        let source = Folder::new(&root).unwrap();
+        let playlist = source.playlist_by_name("playlists/best").unwrap();
+        let names: Vec<String> = playlist.tracks().unwrap().iter().map(|t| t.name()).collect();
+        assert_eq!(names, vec!["two", "one"]);
+
+        let one = track_id(Path::new("Artist/one.mp3"));
+        assert_eq!(source.track_by_id(one).unwrap().name(), "one");
+        playlist.change_contents_to(&[one]).unwrap();
+        assert_eq!(std::fs::read_to_string(root.join("playlists").join("best.m3u")).unwrap(), "../Artist/one.mp3\r\n");
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
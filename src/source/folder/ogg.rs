@@ -0,0 +1,309 @@
+//! Minimal reading and writing of the Vorbis comments of Ogg Vorbis and Ogg Opus files
+//!
+//! Only the header packets are parsed. When comments are written, the header pages are rebuilt,
+//! and the other pages are copied as-is (apart from their sequence numbers, in case the headers now span a different number of pages).
+
+use std::error::Error;
+use std::io::{BufReader, Read, Write};
+use std::path::Path;
+
+use super::flac::{rewrite_file, VorbisComments};
+
+const CAPTURE_PATTERN: &[u8; 4] = b"OggS";
+const HEADER_TYPE_CONTINUED: u8 = 0x01;
+const HEADER_TYPE_BEGINNING_OF_STREAM: u8 = 0x02;
+/// The granule position of pages on which no packet ends
+const NO_GRANULE_POSITION: u64 = u64::MAX;
+const MAX_SEGMENTS: usize = 255;
+
+struct Page {
+    header_type: u8,
+    granule_position: u64,
+    serial: u32,
+    sequence: u32,
+    /// The lacing values
+    segments: Vec<u8>,
+    data: Vec<u8>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Codec {
+    Vorbis,
+    Opus,
+}
+
+/// The header packets of the first logical stream of a file
+struct Headers {
+    codec: Codec,
+    serial: u32,
+    packets: Vec<Vec<u8>>,
+    /// How many pages these packets span
+    page_count: u32,
+}
+
+impl Codec {
+    fn detect(identification_packet: &[u8]) -> Result<Self, Box<dyn Error>> {
+        if identification_packet.starts_with(b"\x01vorbis") {
+            Ok(Self::Vorbis)
+        } else if identification_packet.starts_with(b"OpusHead") {
+            Ok(Self::Opus)
+        } else {
+            Err("Unsupported Ogg codec (only Vorbis and Opus are supported)".into())
+        }
+    }
+
+    fn comment_prefix(&self) -> &'static [u8] {
+        match self {
+            Self::Vorbis => b"\x03vorbis",
+            Self::Opus => b"OpusTags",
+        }
+    }
+
+    /// How many header packets there are. Audio data starts on the page after the last one
+    fn header_count(&self) -> usize {
+        match self {
+            Self::Vorbis => 3,
+            Self::Opus => 2,
+        }
+    }
+}
+
+impl Page {
+    fn new(serial: u32, sequence: u32, header_type: u8) -> Self {
+        Self{ header_type, granule_position: NO_GRANULE_POSITION, serial, sequence, segments: Vec::new(), data: Vec::new() }
+    }
+
+    /// Read the next page, or return `None` at the end of the stream
+    fn read<R: Read>(reader: &mut R) -> Result<Option<Self>, Box<dyn Error>> {
+        let mut header = [0; 27];
+        if reader.read(&mut header[..1])? == 0 {
+            return Ok(None);
+        }
+        reader.read_exact(&mut header[1..])?;
+        if &header[..4] != CAPTURE_PATTERN {
+            return Err("Not an Ogg file, or it is corrupted".into());
+        }
+
+        let mut segments = vec![0; header[26] as usize];
+        reader.read_exact(&mut segments)?;
+        let mut data = vec![0; segments.iter().map(|lacing| *lacing as usize).sum()];
+        reader.read_exact(&mut data)?;
+
+        Ok(Some(Self{
+            header_type: header[5],
+            granule_position: u64::from_le_bytes(header[6..14].try_into()?),
+            serial: u32::from_le_bytes(header[14..18].try_into()?),
+            sequence: u32::from_le_bytes(header[18..22].try_into()?),
+            segments,
+            data,
+        }))
+    }
+
+    fn write(&self, writer: &mut dyn Write) -> Result<(), Box<dyn Error>> {
+        let mut bytes = Vec::with_capacity(27 + self.segments.len() + self.data.len());
+        bytes.extend_from_slice(CAPTURE_PATTERN);
+        bytes.push(0);  // version
+        bytes.push(self.header_type);
+        bytes.extend_from_slice(&self.granule_position.to_le_bytes());
+        bytes.extend_from_slice(&self.serial.to_le_bytes());
+        bytes.extend_from_slice(&self.sequence.to_le_bytes());
+        bytes.extend_from_slice(&[0; 4]);  // CRC, computed below
+        bytes.push(self.segments.len() as u8);
+        bytes.extend_from_slice(&self.segments);
+        bytes.extend_from_slice(&self.data);
+
+        let crc = crc32(&bytes);
+        bytes[22..26].copy_from_slice(&crc.to_le_bytes());
+        writer.write_all(&bytes)?;
+        Ok(())
+    }
+}
+
+/// The CRC of Ogg pages (polynomial 0x04C11DB7, with no reflection and no final XOR)
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0u32;
+    for byte in data {
+        crc ^= (*byte as u32) << 24;
+        for _ in 0..8 {
+            crc = if crc & 0x8000_0000 != 0 { (crc << 1) ^ 0x04C1_1DB7 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+fn read_headers<R: Read>(reader: &mut R) -> Result<Headers, Box<dyn Error>> {
+    let mut serial = None;
+    let mut codec = None;
+    let mut packets = Vec::new();
+    let mut current_packet = Vec::new();
+    let mut page_count = 0;
+    loop {
+        let page = Page::read(reader)?.ok_or("Truncated Ogg headers")?;
+        let serial = *serial.get_or_insert(page.serial);
+        if page.serial != serial {
+            // Another (multiplexed) stream
+            continue;
+        }
+        page_count += 1;
+
+        let mut offset = 0;
+        for lacing in &page.segments {
+            let lacing = *lacing as usize;
+            current_packet.extend_from_slice(&page.data[offset..offset + lacing]);
+            offset += lacing;
+            if lacing < 255 {
+                packets.push(std::mem::take(&mut current_packet));
+            }
+        }
+
+        if codec.is_none() && packets.is_empty() == false {
+            codec = Some(Codec::detect(&packets[0])?);
+        }
+        if let Some(codec) = codec {
+            if packets.len() >= codec.header_count() {
+                if packets.len() > codec.header_count() || current_packet.is_empty() == false {
+                    return Err("Invalid Ogg stream: audio data starts on a header page".into());
+                }
+                return Ok(Headers{ codec, serial, packets, page_count });
+            }
+        }
+    }
+}
+
+/// Lay packets out into pages. Every header packet ends on the page it is on, so that its granule position is 0
+fn paginate(packets: &[&[u8]], serial: u32, first_sequence: u32) -> Vec<Page> {
+    let mut pages = Vec::new();
+    let mut page = Page::new(serial, first_sequence, 0);
+    for packet in packets {
+        let segment_count = packet.len() / 255 + 1;
+        for i in 0..segment_count {
+            if page.segments.len() == MAX_SEGMENTS {
+                let continued = if i > 0 { HEADER_TYPE_CONTINUED } else { 0 };
+                let next_page = Page::new(serial, page.sequence + 1, continued);
+                pages.push(std::mem::replace(&mut page, next_page));
+            }
+            let start = i * 255;
+            let lacing = if i + 1 < segment_count { 255 } else { packet.len() % 255 };
+            page.segments.push(lacing as u8);
+            page.data.extend_from_slice(&packet[start..start + lacing]);
+            if i + 1 == segment_count {
+                page.granule_position = 0;
+            }
+        }
+    }
+    pages.push(page);
+    pages
+}
+
+/// The comments and whatever follows them in a comment packet (the framing bit of Vorbis, or the padding of Opus)
+fn parse_comment_packet(codec: Codec, packet: &[u8]) -> Result<(VorbisComments, Vec<u8>), Box<dyn Error>> {
+    let mut cursor = packet.strip_prefix(codec.comment_prefix()).ok_or("Invalid Ogg comment header")?;
+    let comments = VorbisComments::read_from(&mut cursor)?;
+    Ok((comments, cursor.to_vec()))
+}
+
+pub fn read_comments(path: &Path) -> Result<VorbisComments, Box<dyn Error>> {
+    let mut reader = BufReader::new(std::fs::File::open(path)?);
+    let headers = read_headers(&mut reader)?;
+    parse_comment_packet(headers.codec, &headers.packets[1]).map(|(comments, _)| comments)
+}
+
+/// Replace the Vorbis comments of an Ogg file. The whole file is rewritten (into a temporary file that then replaces the original one)
+pub fn write_comments(path: &Path, comments: &VorbisComments) -> Result<(), Box<dyn Error>> {
+    let headers = read_headers(&mut BufReader::new(std::fs::File::open(path)?))?;
+    let (_, mut trailing_data) = parse_comment_packet(headers.codec, &headers.packets[1])?;
+    if headers.codec == Codec::Vorbis && trailing_data.is_empty() {
+        trailing_data.push(1);  // the framing bit
+    }
+    let mut comment_packet = headers.codec.comment_prefix().to_vec();
+    comment_packet.extend(comments.serialize());
+    comment_packet.extend(trailing_data);
+
+    rewrite_file(path, |original, writer| {
+        let mut reader = BufReader::new(original);
+        let mut remaining_header_pages = headers.page_count;
+        let mut new_page_count = 0;
+        while let Some(mut page) = Page::read(&mut reader)? {
+            if page.serial == headers.serial && remaining_header_pages > 0 {
+                if remaining_header_pages == headers.page_count {
+                    // The identification header is alone on the first page
+                    let mut new_pages = paginate(&[&headers.packets[0]], headers.serial, page.sequence);
+                    new_pages[0].header_type |= HEADER_TYPE_BEGINNING_OF_STREAM;
+                    let other_packets: Vec<&[u8]> = std::iter::once(comment_packet.as_slice())
+                        .chain(headers.packets[2..].iter().map(|packet| packet.as_slice()))
+                        .collect();
+                    new_pages.extend(paginate(&other_packets, headers.serial, page.sequence + 1));
+                    for new_page in &new_pages {
+                        new_page.write(writer)?;
+                    }
+                    new_page_count = new_pages.len() as u32;
+                }
+                remaining_header_pages -= 1;
+                continue;
+            }
+            if page.serial == headers.serial {
+                page.sequence = page.sequence.wrapping_sub(headers.page_count).wrapping_add(new_page_count);
+            }
+            page.write(writer)?;
+        }
+        Ok(())
+    })
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc() {
+        assert_eq!(crc32(b"123456789"), 0x89A1_897F);
+    }
+
+    #[test]
+    fn rewrite_comments() {
+        let mut comments = VorbisComments::default();
+        comments.set("TITLE", Some("Some song".to_string()));
+        let mut comment_packet = b"\x03vorbis".to_vec();
+        comment_packet.extend(comments.serialize());
+        comment_packet.push(1);
+        let identification = [b"\x01vorbis".as_slice(), &[0xAB; 23]].concat();
+        let setup = [b"\x05vorbis".as_slice(), &[0xCD; 600]].concat();
+
+        let mut pages = paginate(&[&identification], 1234, 0);
+        pages.extend(paginate(&[&comment_packet, &setup], 1234, 1));
+        let mut audio_page = Page::new(1234, pages.len() as u32, 0);
+        audio_page.segments = vec![100];
+        audio_page.data = vec![0xEF; 100];
+        audio_page.granule_position = 4800;
+        pages.push(audio_page);
+        let mut content = Vec::new();
+        for page in &pages {
+            page.write(&mut content).unwrap();
+        }
+
+        let dir = std::env::temp_dir().join(format!("starsync-ogg-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("song.ogg");
+        std::fs::write(&path, &content).unwrap();
+
+        let mut comments = read_comments(&path).unwrap();
+        assert_eq!(comments.get("title"), Some("Some song"));
+
+        // Large enough for the headers to span more pages
+        comments.set("RATING", Some("80".to_string()));
+        comments.set("COMMENT", Some("x".repeat(70_000)));
+        write_comments(&path, &comments).unwrap();
+        assert_eq!(read_comments(&path).unwrap(), comments);
+
+        let mut reader = BufReader::new(std::fs::File::open(&path).unwrap());
+        let headers = read_headers(&mut reader).unwrap();
+        assert_eq!(headers.packets[2], setup);
+        let last_page = Page::read(&mut reader).unwrap().unwrap();
+        assert_eq!((last_page.sequence, last_page.granule_position, last_page.data), (headers.page_count, 4800, vec![0xEF; 100]));
+        assert!(Page::read(&mut reader).unwrap().is_none());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,291 @@
+//! Ratings (and other metadata) stored in the tags of music files
+//!
+//! Supported rating tags are
+//! * for MP3 files: ID3v2 `POPM` frames (the convention used by Windows Media Player, MusicBee, etc.) and `TXXX:FMPS_Rating` frames
+//! * for FLAC, Ogg Vorbis and Ogg Opus files: `RATING` (0-100, or 1-5) and `FMPS_RATING` Vorbis comments
+
+use std::error::Error;
+use std::num::NonZeroU8;
+use std::path::Path;
+
+use id3::{Tag, TagLike, Version};
+use id3::frame::{Content, ExtendedText, Frame, Popularimeter};
+
+use crate::source::Rating;
+use super::flac::{self, VorbisComments};
+use super::ogg;
+
+/// The user written into new POPM frames
+const POPM_USER: &str = "starsync";
+const FMPS_RATING: &str = "FMPS_RATING";
+const VORBIS_RATING: &str = "RATING";
+
+enum Format {
+    Mp3,
+    /// Files whose tags are Vorbis comments
+    Vorbis(VorbisContainer),
+}
+
+#[derive(Clone, Copy)]
+enum VorbisContainer {
+    Flac,
+    Ogg,
+}
+
+impl VorbisContainer {
+    fn read_comments(&self, path: &Path) -> Result<VorbisComments, Box<dyn Error>> {
+        match self {
+            Self::Flac => flac::read_comments(path),
+            Self::Ogg => ogg::read_comments(path),
+        }
+    }
+
+    fn write_comments(&self, path: &Path, comments: &VorbisComments) -> Result<(), Box<dyn Error>> {
+        match self {
+            Self::Flac => flac::write_comments(path, comments),
+            Self::Ogg => ogg::write_comments(path, comments),
+        }
+    }
+}
+
+fn format_of(path: &Path) -> Option<Format> {
+    let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+    match extension.as_str() {
+        "mp3" => Some(Format::Mp3),
+        "flac" => Some(Format::Vorbis(VorbisContainer::Flac)),
+        "ogg" | "oga" | "opus" => Some(Format::Vorbis(VorbisContainer::Ogg)),
+        _ => None,
+    }
+}
+
+pub fn read_rating(path: &Path) -> Result<Rating, Box<dyn Error>> {
+    match format_of(path) {
+        Some(Format::Mp3) => read_id3_rating(path),
+        Some(Format::Vorbis(container)) => read_vorbis_rating(path, container),
+        None => Ok(None),
+    }
+}
+
+/// The metadata smart playlists (see [`crate::smart_playlist`]) and layouts (see [`crate::layout`]) can use
+#[derive(Debug, Default, PartialEq)]
+pub struct Metadata {
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub album_artist: Option<String>,
+    pub track_number: Option<u32>,
+    pub disc_number: Option<u32>,
+    pub genre: Option<String>,
+    pub year: Option<i32>,
+}
+
+pub fn read_metadata(path: &Path) -> Result<Metadata, Box<dyn Error>> {
+    match format_of(path) {
+        Some(Format::Mp3) => read_id3_metadata(path),
+        Some(Format::Vorbis(container)) => read_vorbis_metadata(path, container),
+        None => Ok(Metadata::default()),
+    }
+}
+
+pub fn write_rating(path: &Path, rating: Rating) -> Result<(), Box<dyn Error>> {
+    match format_of(path) {
+        Some(Format::Mp3) => write_id3_rating(path, rating),
+        Some(Format::Vorbis(container)) => write_vorbis_rating(path, container, rating),
+        None => Err(format!("Unable to store ratings into {}: unsupported file format", path.display()).into()),
+    }
+}
+
+
+
+/// Convert a POPM rating (1-255) into stars
+fn popm_to_stars(popm: u8) -> Rating {
+    let stars = match popm {
+        0 => 0,
+        1..=31 => 1,
+        32..=95 => 2,
+        96..=159 => 3,
+        160..=223 => 4,
+        224..=255 => 5,
+    };
+    NonZeroU8::new(stars)
+}
+
+fn stars_to_popm(rating: Rating) -> u8 {
+    match rating.map(|r| r.get()) {
+        None | Some(0) => 0,
+        Some(1) => 1,
+        Some(2) => 64,
+        Some(3) => 128,
+        Some(4) => 196,
+        Some(_) => 255,
+    }
+}
+
+/// Convert a FMPS rating (0.0 to 1.0) into stars
+fn fmps_to_stars(fmps: &str) -> Rating {
+    let value = fmps.trim().parse::<f64>().ok().filter(|v| (0.0..=1.0).contains(v))?;
+    NonZeroU8::new((value * 5.0).round() as u8)
+}
+
+fn stars_to_fmps(rating: Rating) -> String {
+    let stars = rating.map(|r| r.get().min(5)).unwrap_or(0);
+    format!("{:.1}", stars as f64 / 5.0)
+}
+
+/// Convert a Vorbis `RATING`, that is either between 1 and 5, or (more commonly) between 0 and 100
+fn vorbis_to_stars(rating: &str) -> Rating {
+    let value = rating.trim().parse::<u8>().ok().filter(|v| *v <= 100)?;
+    if value <= 5 {
+        NonZeroU8::new(value)
+    } else {
+        NonZeroU8::new(((value as f64) / 20.0).round() as u8)
+    }
+}
+
+fn stars_to_vorbis(rating: Rating) -> Option<String> {
+    rating.map(|r| (r.get().min(5) * 20).to_string())
+}
+
+
+
+fn read_id3_tag(path: &Path) -> Result<Option<Tag>, Box<dyn Error>> {
+    Ok(id3::no_tag_ok(id3::partial_tag_ok(Tag::read_from_path(path)))?)
+}
+
+fn read_id3_rating(path: &Path) -> Result<Rating, Box<dyn Error>> {
+    let tag = match read_id3_tag(path)? {
+        None => return Ok(None),
+        Some(tag) => tag,
+    };
+
+    let popm = tag.frames()
+        .filter_map(|frame| frame.content().popularimeter())
+        .find(|popm| popm.rating != 0);
+    if let Some(popm) = popm {
+        return Ok(popm_to_stars(popm.rating));
+    }
+
+    let fmps = tag.extended_texts()
+        .find(|text| text.description.eq_ignore_ascii_case(FMPS_RATING))
+        .and_then(|text| fmps_to_stars(&text.value));
+    Ok(fmps)
+}
+
+fn read_id3_metadata(path: &Path) -> Result<Metadata, Box<dyn Error>> {
+    let tag = match read_id3_tag(path)? {
+        None => return Ok(Metadata::default()),
+        Some(tag) => tag,
+    };
+
+    Ok(Metadata{
+        artist: tag.artist().map(|s| s.to_string()),
+        album: tag.album().map(|s| s.to_string()),
+        album_artist: tag.album_artist().map(|s| s.to_string()),
+        track_number: tag.track().filter(|n| *n > 0),
+        disc_number: tag.disc().filter(|n| *n > 0),
+        // This resolves ID3v1 genre numbers, such as "(8)"
+        genre: tag.genre_parsed().map(|s| s.to_string()),
+        year: tag.year().or_else(|| tag.date_recorded().map(|date| date.year)),
+    })
+}
+
+fn write_id3_rating(path: &Path, rating: Rating) -> Result<(), Box<dyn Error>> {
+    let mut tag = read_id3_tag(path)?.unwrap_or_default();
+    let version = match tag.version() {
+        Version::Id3v22 => Version::Id3v23,
+        v => v,
+    };
+
+    // Update the existing POPM frames (there may be one per user), or add ours
+    let mut popms: Vec<Popularimeter> = tag.frames()
+        .filter_map(|frame| frame.content().popularimeter())
+        .cloned()
+        .collect();
+    if popms.is_empty() {
+        popms.push(Popularimeter{ user: POPM_USER.to_string(), rating: 0, counter: 0 });
+    }
+    tag.remove("POPM");
+    for mut popm in popms {
+        popm.rating = stars_to_popm(rating);
+        tag.add_frame(Frame::with_content("POPM", Content::Popularimeter(popm)));
+    }
+
+    let has_fmps = tag.extended_texts().any(|text| text.description.eq_ignore_ascii_case(FMPS_RATING));
+    if has_fmps {
+        let descriptions: Vec<String> = tag.extended_texts()
+            .filter(|text| text.description.eq_ignore_ascii_case(FMPS_RATING))
+            .map(|text| text.description.clone())
+            .collect();
+        for description in descriptions {
+            tag.remove_extended_text(Some(&description), None);
+            if rating.is_some() {
+                tag.add_frame(ExtendedText{ description, value: stars_to_fmps(rating) });
+            }
+        }
+    }
+
+    tag.write_to_path(path, version)?;
+    Ok(())
+}
+
+fn read_vorbis_rating(path: &Path, container: VorbisContainer) -> Result<Rating, Box<dyn Error>> {
+    let comments = container.read_comments(path)?;
+    if let Some(rating) = comments.get(VORBIS_RATING) {
+        return Ok(vorbis_to_stars(rating));
+    }
+    Ok(comments.get(FMPS_RATING).and_then(fmps_to_stars))
+}
+
+fn read_vorbis_metadata(path: &Path, container: VorbisContainer) -> Result<Metadata, Box<dyn Error>> {
+    let comments = container.read_comments(path)?;
+    Ok(Metadata{
+        artist: comments.get("ARTIST").map(|s| s.to_string()),
+        album: comments.get("ALBUM").map(|s| s.to_string()),
+        album_artist: comments.get("ALBUMARTIST").or_else(|| comments.get("ALBUM ARTIST")).map(|s| s.to_string()),
+        track_number: comments.get("TRACKNUMBER").and_then(parse_position),
+        disc_number: comments.get("DISCNUMBER").and_then(parse_position),
+        genre: comments.get("GENRE").map(|s| s.to_string()),
+        // This is usually a year, or a full date
+        year: comments.get("DATE").and_then(|date| date.get(..4)).and_then(|year| year.parse().ok()),
+    })
+}
+
+/// Parse a track or disc number, which may be followed by the total (e.g. `3/12`)
+fn parse_position(position: &str) -> Option<u32> {
+    position.split('/').next()?.trim().parse().ok().filter(|n| *n > 0)
+}
+
+fn write_vorbis_rating(path: &Path, container: VorbisContainer, rating: Rating) -> Result<(), Box<dyn Error>> {
+    let mut comments = container.read_comments(path)?;
+    if comments.get(FMPS_RATING).is_some() {
+        comments.set(FMPS_RATING, rating.map(|_| stars_to_fmps(rating)));
+    }
+    if comments.get(VORBIS_RATING).is_some() || comments.get(FMPS_RATING).is_none() {
+        comments.set(VORBIS_RATING, stars_to_vorbis(rating));
+    }
+    container.write_comments(path, &comments)
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rating_conversions() {
+        for stars in 1..=5 {
+            let rating = NonZeroU8::new(stars);
+            assert_eq!(popm_to_stars(stars_to_popm(rating)), rating);
+            assert_eq!(fmps_to_stars(&stars_to_fmps(rating)), rating);
+            assert_eq!(vorbis_to_stars(&stars_to_vorbis(rating).unwrap()), rating);
+        }
+        assert_eq!(popm_to_stars(0), None);
+        assert_eq!(popm_to_stars(stars_to_popm(None)), None);
+        assert_eq!(vorbis_to_stars("3"), NonZeroU8::new(3));
+        assert_eq!(vorbis_to_stars("50"), NonZeroU8::new(3));
+        assert_eq!(vorbis_to_stars("0"), None);
+        assert_eq!(vorbis_to_stars("great"), None);
+        assert_eq!(fmps_to_stars("0.7"), NonZeroU8::new(4));
+        assert_eq!(fmps_to_stars("1.5"), None);
+    }
+}
@@ -258,4 +258,32 @@ impl Track for ITTrack {
         let focdt = self.as_file_or_cd_track().ok_or_else(|| format!("Track {} is not a local file", self.name()))?;
         Ok(focdt.Size()?.try_into()?)
     }
+
+    fn artist(&self) -> Option<String> {
+        self.Artist().ok().filter(|s| s.is_empty() == false)
+    }
+
+    fn album(&self) -> Option<String> {
+        self.Album().ok().filter(|s| s.is_empty() == false)
+    }
+
+    fn album_artist(&self) -> Option<String> {
+        self.as_file_or_cd_track()?.AlbumArtist().ok().filter(|s| s.is_empty() == false)
+    }
+
+    fn track_number(&self) -> Option<u32> {
+        self.TrackNumber().ok().and_then(|number| u32::try_from(number).ok()).filter(|number| *number > 0)
+    }
+
+    fn disc_number(&self) -> Option<u32> {
+        self.DiscNumber().ok().and_then(|number| u32::try_from(number).ok()).filter(|number| *number > 0)
+    }
+
+    fn genre(&self) -> Option<String> {
+        self.Genre().ok().filter(|s| s.is_empty() == false)
+    }
+
+    fn year(&self) -> Option<i32> {
+        self.Year().ok().filter(|year| *year > 0)
+    }
 }
@@ -0,0 +1,532 @@
+//! iTunes (or Apple Music) libraries, read from an exported `Library.xml` (or `iTunes Music Library.xml`) file
+//!
+//! This works on any OS, e.g. to sync devices from a library file copied off a Mac or Windows machine.<br/>
+//! Track and playlist IDs are the iTunes persistent IDs, i.e. the same as the ones the iTunes source uses on Windows.
+//!
+//! This source is read-only: reverse syncing ratings or playlists into it fails with a [`ReadOnlySource`] error.
+//!
+//! Song locations are the ones of the machine the library was exported from. In case a song cannot be found there,
+//! it is also looked up relative to the folder that contains the library file (that's the layout of a whole iTunes folder
+//! copied to another machine, where the library file sits next to its `iTunes Media` folder).
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::error::Error;
+use std::num::NonZeroU8;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::SystemTime;
+
+use serde::Deserialize;
+
+use crate::source::{Source, Playlist, Rating, Track, TrackId, PlaylistId, ReadOnlySource};
+
+/// The prefix of the names of these sources
+pub const NAME_PREFIX: &str = "itunes-xml://";
+
+/// The usual names of exported library files
+const DEFAULT_FILE_NAMES: &[&str] = &["iTunes Music Library.xml", "iTunes Library.xml", "Library.xml"];
+
+/// The usual locations of exported libraries
+pub fn default_library_files() -> Vec<PathBuf> {
+    let home = match std::env::var_os("HOME").or_else(|| std::env::var_os("USERPROFILE")) {
+        None => return Vec::new(),
+        Some(home) => PathBuf::from(home),
+    };
+
+    let folders = [
+        home.join("Music").join("iTunes"),
+        home.join("Music").join("Music"),
+    ];
+    folders.iter()
+        .flat_map(|folder| DEFAULT_FILE_NAMES.iter().map(move |name| folder.join(name)))
+        .filter(|path| path.is_file())
+        .collect()
+}
+
+fn parse_persistent_id(hex_id: &str) -> Result<u64, Box<dyn Error>> {
+    u64::from_str_radix(hex_id, 16)
+        .map_err(|err| format!("Invalid persistent ID '{hex_id}': {err}").into())
+}
+
+/// Convert a `file://` URI (e.g. `file://localhost/Users/me/Music/...` or `file://localhost/C:/Users/me/Music/...`) into a path
+fn location_to_path(location: &str) -> Result<PathBuf, Box<dyn Error>> {
+    let without_scheme = location.strip_prefix("file://").ok_or_else(|| format!("'{location}' is not a local file"))?;
+    let without_host = without_scheme.strip_prefix("localhost").unwrap_or(without_scheme);
+    let decoded = urlencoding::decode(without_host)?;
+
+    // Windows paths are written as /C:/...
+    let bytes = decoded.as_bytes();
+    if bytes.len() >= 3 && bytes[0] == b'/' && bytes[1].is_ascii_alphabetic() && bytes[2] == b':' {
+        return Ok(PathBuf::from(&decoded[1..]));
+    }
+    Ok(PathBuf::from(decoded.as_ref()))
+}
+
+/// Convert an iTunes rating (0 to 100) into stars
+fn rating_to_stars(rating: u8) -> Rating {
+    NonZeroU8::new(((rating.min(100) as f64) / 20.0).round() as u8)
+}
+
+
+
+#[derive(Debug, Deserialize)]
+struct XmlLibrary {
+    #[serde(rename = "Music Folder")]
+    music_folder: Option<String>,
+    #[serde(rename = "Tracks", default)]
+    tracks: HashMap<String, XmlTrackEntry>,
+    #[serde(rename = "Playlists", default)]
+    playlists: Vec<XmlPlaylistEntry>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct XmlTrackEntry {
+    #[serde(rename = "Track ID")]
+    track_id: u64,
+    #[serde(rename = "Persistent ID")]
+    persistent_id: String,
+    #[serde(rename = "Name", default)]
+    name: String,
+    #[serde(rename = "Location")]
+    location: Option<String>,
+    #[serde(rename = "Size")]
+    size: Option<u64>,
+    #[serde(rename = "Rating")]
+    rating: Option<u8>,
+    #[serde(rename = "Rating Computed", default)]
+    rating_computed: bool,
+    #[serde(rename = "Artist")]
+    artist: Option<String>,
+    #[serde(rename = "Album")]
+    album: Option<String>,
+    #[serde(rename = "Album Artist")]
+    album_artist: Option<String>,
+    #[serde(rename = "Track Number")]
+    track_number: Option<u32>,
+    #[serde(rename = "Disc Number")]
+    disc_number: Option<u32>,
+    #[serde(rename = "Genre")]
+    genre: Option<String>,
+    #[serde(rename = "Year")]
+    year: Option<i32>,
+    #[serde(rename = "Date Added")]
+    date_added: Option<plist::Date>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct XmlPlaylistEntry {
+    #[serde(rename = "Name")]
+    name: String,
+    #[serde(rename = "Playlist Persistent ID")]
+    persistent_id: String,
+    #[serde(rename = "Master", default)]
+    master: bool,
+    #[serde(rename = "Distinguished Kind")]
+    distinguished_kind: Option<u64>,
+    #[serde(rename = "Folder", default)]
+    folder: bool,
+    #[serde(rename = "Visible", default = "default_visible")]
+    visible: bool,
+    #[serde(rename = "Playlist Items", default)]
+    items: Vec<XmlPlaylistItem>,
+}
+
+fn default_visible() -> bool {
+    true
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct XmlPlaylistItem {
+    #[serde(rename = "Track ID")]
+    track_id: u64,
+}
+
+impl XmlPlaylistEntry {
+    /// Whether this is a playlist made by the user
+    fn is_user_playlist(&self) -> bool {
+        self.master == false
+        && self.distinguished_kind.is_none()
+        && self.folder == false
+        && self.visible
+    }
+}
+
+/// The parsed content of a library file
+struct Content {
+    /// The tracks, indexed by their persistent IDs
+    tracks: HashMap<TrackId, XmlTrackEntry>,
+    /// Maps the (non-persistent) track IDs used in playlists to persistent IDs
+    persistent_ids: HashMap<u64, TrackId>,
+    playlists: Vec<XmlPlaylistEntry>,
+    music_folder: Option<PathBuf>,
+}
+
+impl Content {
+    fn parse(xml_library: XmlLibrary) -> Self {
+        let mut tracks = HashMap::new();
+        let mut persistent_ids = HashMap::new();
+        for (_, track) in xml_library.tracks {
+            match parse_persistent_id(&track.persistent_id) {
+                Err(err) => log::warn!("Ignoring track {}: {err}", track.name),
+                Ok(id) => {
+                    persistent_ids.insert(track.track_id, TrackId(id));
+                    tracks.insert(TrackId(id), track);
+                }
+            }
+        }
+
+        let music_folder = xml_library.music_folder
+            .and_then(|folder| location_to_path(&folder).ok());
+
+        Self{ tracks, persistent_ids, playlists: xml_library.playlists, music_folder }
+    }
+}
+
+struct Library {
+    path: PathBuf,
+    /// This is lazily loaded
+    content: RefCell<Option<Rc<Content>>>,
+}
+
+impl Library {
+    fn content(&self) -> Result<Rc<Content>, Box<dyn Error>> {
+        if let Some(content) = self.content.borrow().as_ref() {
+            return Ok(Rc::clone(content));
+        }
+
+        let xml_library: XmlLibrary = plist::from_file(&self.path)?;
+        let content = Rc::new(Content::parse(xml_library));
+        *self.content.borrow_mut() = Some(Rc::clone(&content));
+        Ok(content)
+    }
+
+    /// Find a song on this machine
+    fn local_path(&self, original_path: PathBuf, music_folder: Option<&Path>) -> PathBuf {
+        if original_path.exists() {
+            return original_path;
+        }
+
+        let relocated = music_folder.and_then(|music_folder| {
+            let relative_path = original_path.strip_prefix(music_folder).ok()?;
+            let library_folder = self.path.parent()?;
+            Some(library_folder.join(music_folder.file_name()?).join(relative_path))
+        });
+        match relocated {
+            Some(path) if path.exists() => path,
+            _ => original_path,
+        }
+    }
+}
+
+
+
+pub struct ITunesXml {
+    name: String,
+    library: Rc<Library>,
+}
+
+impl ITunesXml {
+    pub fn new(library_file: &Path) -> Option<Self> {
+        if library_file.is_file() == false {
+            log::info!("No iTunes library file at {}", library_file.display());
+            return None;
+        }
+
+        let name = format!("{}{}", NAME_PREFIX, library_file.to_string_lossy().replace('\\', "/"));
+        let library = Library{ path: library_file.to_path_buf(), content: RefCell::new(None) };
+        Some(Self{ name, library: Rc::new(library) })
+    }
+
+    /// Get the source from its name (e.g. `itunes-xml:///home/user/iTunes/iTunes Library.xml`)
+    pub fn from_name(name: &str) -> Option<Self> {
+        let path = name.strip_prefix(NAME_PREFIX)?;
+        Self::new(Path::new(path))
+    }
+
+    fn user_playlists(&self) -> Result<Vec<Box<dyn Playlist>>, Box<dyn Error>> {
+        let content = self.library.content()?;
+        Ok(content.playlists
+            .iter()
+            .filter(|pl| pl.is_user_playlist())
+            .filter_map(|pl| match parse_persistent_id(&pl.persistent_id) {
+                Err(err) => {
+                    log::warn!("Ignoring playlist {}: {err}", pl.name);
+                    None
+                },
+                Ok(id) => Some(Box::new(XmlPlaylist{
+                    source_name: self.name.clone(),
+                    library: Rc::clone(&self.library),
+                    id,
+                    entry: pl.clone(),
+                }) as Box<dyn Playlist>),
+            })
+            .collect())
+    }
+}
+
+impl Source for ITunesXml {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn playlists(&self) -> Result<Vec<Box<dyn Playlist>>, Box<dyn Error>> {
+        self.user_playlists()
+    }
+
+    fn playlist_by_name(&self, name: &str) -> Option<Box<dyn Playlist>> {
+        match self.user_playlists() {
+            Err(err) => {
+                log::warn!("Unable to read iTunes library {}: {err}", self.library.path.display());
+                None
+            },
+            Ok(playlists) => playlists.into_iter().find(|pl| pl.name() == name),
+        }
+    }
+
+    fn playlist_by_id(&self, id: &PlaylistId) -> Option<Box<dyn Playlist>> {
+        let id = match id {
+            PlaylistId::Number(i) => *i,
+            _ => {
+                log::warn!("Invalid type ({id:?}) for playlist ID.");
+                return None;
+            }
+        };
+
+        match self.user_playlists() {
+            Err(err) => {
+                log::warn!("Unable to read iTunes library {}: {err}", self.library.path.display());
+                None
+            },
+            Ok(playlists) => playlists.into_iter().find(|pl| pl.id() == PlaylistId::Number(id)),
+        }
+    }
+
+    fn track_by_id(&self, id: TrackId) -> Option<Box<dyn Track>> {
+        let content = match self.library.content() {
+            Err(err) => {
+                log::warn!("Unable to read iTunes library {}: {err}", self.library.path.display());
+                return None;
+            },
+            Ok(c) => c,
+        };
+
+        content.tracks
+            .get(&id)
+            .map(|entry| Box::new(XmlTrack{ source_name: self.name.clone(), library: Rc::clone(&self.library), id, entry: entry.clone() }) as Box<dyn Track>)
+    }
+
+    fn tracks(&self) -> Result<Vec<Box<dyn Track>>, Box<dyn Error>> {
+        Ok(self.library
+            .content()?
+            .tracks
+            .iter()
+            .map(|(id, entry)| Box::new(XmlTrack{ source_name: self.name.clone(), library: Rc::clone(&self.library), id: *id, entry: entry.clone() }) as Box<dyn Track>)
+            .collect())
+    }
+}
+
+
+
+pub struct XmlPlaylist {
+    source_name: String,
+    library: Rc<Library>,
+    id: u64,
+    entry: XmlPlaylistEntry,
+}
+
+impl Playlist for XmlPlaylist {
+    fn name(&self) -> String {
+        self.entry.name.clone()
+    }
+
+    fn tracks(&self) -> Result<Vec<Box<dyn Track>>, Box<dyn Error>> {
+        let content = self.library.content()?;
+        Ok(self.entry.items
+            .iter()
+            .filter_map(|item| {
+                let id = content.persistent_ids.get(&item.track_id)?;
+                let entry = content.tracks.get(id)?;
+                Some(Box::new(XmlTrack{ source_name: self.source_name.clone(), library: Rc::clone(&self.library), id: *id, entry: entry.clone() }) as Box<dyn Track>)
+            })
+            .collect())
+    }
+
+    fn id(&self) -> PlaylistId {
+        PlaylistId::Number(self.id)
+    }
+
+    fn change_contents_to(&self, _new_content: &[TrackId]) -> Result<(), Box<dyn Error>> {
+        Err(Box::new(ReadOnlySource(self.source_name.clone())))
+    }
+}
+
+
+
+pub struct XmlTrack {
+    source_name: String,
+    library: Rc<Library>,
+    id: TrackId,
+    entry: XmlTrackEntry,
+}
+
+impl Track for XmlTrack {
+    fn name(&self) -> String {
+        self.entry.name.clone()
+    }
+
+    fn id(&self) -> TrackId {
+        self.id
+    }
+
+    fn absolute_path(&self) -> Result<PathBuf, Box<dyn Error>> {
+        let location = self.entry.location.as_ref().ok_or_else(|| format!("Track {} is not a local file", self.entry.name))?;
+        let original_path = location_to_path(location)?;
+        let music_folder = self.library.content()?.music_folder.clone();
+        Ok(self.library.local_path(original_path, music_folder.as_deref()))
+    }
+
+    fn rating(&self, use_computed_ratings: bool) -> Rating {
+        if self.entry.rating_computed && use_computed_ratings == false {
+            log::debug!("Ignoring rating for track {}, because it is computed", self.entry.name);
+            return None;
+        }
+        self.entry.rating.and_then(rating_to_stars)
+    }
+
+    fn set_rating(&self, _new_rating: Rating) -> Result<(), Box<dyn Error>> {
+        Err(Box::new(ReadOnlySource(self.source_name.clone())))
+    }
+
+    fn file_size(&self) -> Result<usize, Box<dyn Error>> {
+        match self.entry.size {
+            Some(size) => Ok(usize::try_from(size)?),
+            None => {
+                let md = std::fs::metadata(self.absolute_path()?)?;
+                Ok(usize::try_from(md.len())?)
+            }
+        }
+    }
+
+    fn artist(&self) -> Option<String> {
+        self.entry.artist.clone()
+    }
+
+    fn album(&self) -> Option<String> {
+        self.entry.album.clone()
+    }
+
+    fn album_artist(&self) -> Option<String> {
+        self.entry.album_artist.clone()
+    }
+
+    fn track_number(&self) -> Option<u32> {
+        self.entry.track_number
+    }
+
+    fn disc_number(&self) -> Option<u32> {
+        self.entry.disc_number
+    }
+
+    fn genre(&self) -> Option<String> {
+        self.entry.genre.clone()
+    }
+
+    fn year(&self) -> Option<i32> {
+        self.entry.year
+    }
+
+    fn date_added(&self) -> Option<SystemTime> {
+        self.entry.date_added.map(SystemTime::from)
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIBRARY: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple Computer//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+	<key>Major Version</key><integer>1</integer>
+	<key>Music Folder</key><string>file://localhost/Users/me/Music/iTunes/iTunes%20Media/</string>
+	<key>Tracks</key>
+	<dict>
+		<key>101</key>
+		<dict>
+			<key>Track ID</key><integer>101</integer>
+			<key>Name</key><string>First song</string>
+			<key>Genre</key><string>Jazz</string>
+			<key>Year</key><integer>1959</integer>
+			<key>Date Added</key><date>2021-03-04T05:06:07Z</date>
+			<key>Size</key><integer>1234</integer>
+			<key>Rating</key><integer>80</integer>
+			<key>Persistent ID</key><string>0123456789ABCDEF</string>
+			<key>Location</key><string>file://localhost/Users/me/Music/iTunes/iTunes%20Media/Music/Someone/First%20song.mp3</string>
+		</dict>
+		<key>102</key>
+		<dict>
+			<key>Track ID</key><integer>102</integer>
+			<key>Name</key><string>Second song</string>
+			<key>Rating</key><integer>60</integer>
+			<key>Rating Computed</key><true/>
+			<key>Persistent ID</key><string>FEDCBA9876543210</string>
+			<key>Location</key><string>file://localhost/C:/Users/me/Music/Second.m4a</string>
+		</dict>
+	</dict>
+	<key>Playlists</key>
+	<array>
+		<dict>
+			<key>Name</key><string>Library</string>
+			<key>Master</key><true/>
+			<key>Playlist Persistent ID</key><string>1111111111111111</string>
+			<key>Visible</key><false/>
+		</dict>
+		<dict>
+			<key>Name</key><string>Music</string>
+			<key>Distinguished Kind</key><integer>4</integer>
+			<key>Playlist Persistent ID</key><string>2222222222222222</string>
+		</dict>
+		<dict>
+			<key>Name</key><string>Favourites</string>
+			<key>Playlist Persistent ID</key><string>00000000000000FF</string>
+			<key>Playlist Items</key>
+			<array>
+				<dict><key>Track ID</key><integer>102</integer></dict>
+				<dict><key>Track ID</key><integer>101</integer></dict>
+			</array>
+		</dict>
+	</array>
+</dict>
+</plist>
+"#;
+
+    #[test]
+    fn parse_library() {
+        let xml_library: XmlLibrary = plist::from_reader_xml(LIBRARY.as_bytes()).unwrap();
+        let content = Content::parse(xml_library);
+
+        assert_eq!(content.music_folder, Some(PathBuf::from("/Users/me/Music/iTunes/iTunes Media/")));
+        assert_eq!(content.tracks.len(), 2);
+        assert_eq!(content.persistent_ids.get(&102), Some(&TrackId(0xFEDCBA9876543210)));
+
+        let first = &content.tracks[&TrackId(0x0123456789ABCDEF)];
+        assert_eq!(first.rating.and_then(rating_to_stars), NonZeroU8::new(4));
+        assert_eq!(first.genre.as_deref(), Some("Jazz"));
+        assert_eq!(first.year, Some(1959));
+        assert_eq!(first.date_added.map(SystemTime::from), Some(SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1614834367)));
+        assert_eq!(location_to_path(first.location.as_ref().unwrap()).unwrap(), PathBuf::from("/Users/me/Music/iTunes/iTunes Media/Music/Someone/First song.mp3"));
+        let second = &content.tracks[&TrackId(0xFEDCBA9876543210)];
+        assert!(second.rating_computed);
+        assert_eq!(location_to_path(second.location.as_ref().unwrap()).unwrap(), PathBuf::from("C:/Users/me/Music/Second.m4a"));
+
+        let user_playlists: Vec<&XmlPlaylistEntry> = content.playlists.iter().filter(|pl| pl.is_user_playlist()).collect();
+        assert_eq!(user_playlists.len(), 1);
+        assert_eq!(user_playlists[0].name, "Favourites");
+        assert_eq!(parse_persistent_id(&user_playlists[0].persistent_id).unwrap(), 0xFF);
+        assert_eq!(user_playlists[0].items.iter().map(|i| i.track_id).collect::<Vec<_>>(), vec![102, 101]);
+    }
+}
@@ -3,6 +3,7 @@
 use std::error::Error;
 use std::path::{Path, PathBuf};
 use std::num::NonZeroU8;
+use std::time::SystemTime;
 
 #[cfg(windows)]
 pub mod itunes;
@@ -10,6 +11,9 @@ pub mod itunes;
 #[cfg(unix)]
 pub mod rhythmbox;
 
+pub mod folder;
+pub mod itunes_xml;
+
 mod serde_u64_hex_utils;
 
 /// A song ID
@@ -19,6 +23,19 @@ pub struct TrackId(
     pub u64
 );
 
+impl TrackId {
+    /// Derive an ID from a string (e.g. a file location), for sources that do not provide persistent IDs.
+    ///
+    /// This uses FNV-1a, which (unlike the std hashers) is guaranteed to always give the same result.
+    pub fn from_hashed_str(s: &str) -> Self {
+        const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+        const FNV_PRIME: u64 = 0x100000001b3;
+
+        let hash = s.bytes().fold(FNV_OFFSET_BASIS, |hash, byte| (hash ^ byte as u64).wrapping_mul(FNV_PRIME));
+        Self(hash)
+    }
+}
+
 /// A playlist ID
 #[derive(Clone, Debug, Eq, PartialEq, Hash, serde::Serialize, serde::Deserialize)]
 pub enum PlaylistId{
@@ -30,9 +47,17 @@ pub enum PlaylistId{
     /// (e.g. Rhythmbox).
     /// Note that we may "lose track" of the playlist if it gets renamed...
     Name(String),
+    /// A smart playlist, defined by a rule in the device config rather than in the source.
+    /// (see [`crate::smart_playlist`])
+    Smart(String),
 }
 
 
+/// The error returned when trying to modify a source that does not support it
+#[derive(thiserror::Error, Debug)]
+#[error("Source {0} is read-only")]
+pub struct ReadOnlySource(pub String);
+
 /// The user rating of a track (None, or between 1 and 5 stars)
 pub type Rating = Option<NonZeroU8>;
 
@@ -43,6 +68,27 @@ pub trait Source {
     fn playlist_by_name(&self, name: &str) -> Option<Box<dyn Playlist>>;
     fn playlist_by_id(&self, id: &PlaylistId) -> Option<Box<dyn Playlist>>;
     fn track_by_id(&self, id: TrackId) -> Option<Box<dyn Track>>;
+
+    /// Every track of the library.
+    ///
+    /// By default, these are the tracks of every playlist. Sources that know about tracks that are in no playlist should override this.
+    fn tracks(&self) -> Result<Vec<Box<dyn Track>>, Box<dyn Error>> {
+        playlist_tracks(self.playlists()?)
+    }
+}
+
+/// The tracks of some playlists, without duplicates
+pub fn playlist_tracks(playlists: Vec<Box<dyn Playlist>>) -> Result<Vec<Box<dyn Track>>, Box<dyn Error>> {
+    let mut seen = std::collections::HashSet::new();
+    let mut tracks = Vec::new();
+    for playlist in playlists {
+        for track in playlist.tracks()? {
+            if seen.insert(track.id()) {
+                tracks.push(track);
+            }
+        }
+    }
+    Ok(tracks)
 }
 
 pub trait Playlist {
@@ -81,6 +127,64 @@ pub trait Track {
     fn rating(&self, use_computed_ratings: bool) -> Rating;
     fn set_rating(&self, new_rating: Rating) -> Result<(), Box<dyn Error>>;
     fn file_size(&self) -> Result<usize, Box<dyn Error>>;
+
+    /// The last modification time of the file of this track
+    fn modification_date(&self) -> Result<SystemTime, Box<dyn Error>> {
+        Ok(std::fs::metadata(self.absolute_path()?)?.modified()?)
+    }
+
+    // Metadata. These return `None` when they are unknown, or when a source does not support them.
+
+    fn artist(&self) -> Option<String> {
+        None
+    }
+
+    fn album(&self) -> Option<String> {
+        None
+    }
+
+    fn album_artist(&self) -> Option<String> {
+        None
+    }
+
+    /// The position of this track on its disc
+    fn track_number(&self) -> Option<u32> {
+        None
+    }
+
+    fn disc_number(&self) -> Option<u32> {
+        None
+    }
+
+    fn genre(&self) -> Option<String> {
+        None
+    }
+
+    fn year(&self) -> Option<i32> {
+        None
+    }
+
+    /// When this track has been added to the library
+    fn date_added(&self) -> Option<SystemTime> {
+        None
+    }
+
+    // Play statistics. Sources that do not keep track of plays return `None`, and refuse to update them.
+
+    /// How many times this track has been played
+    fn play_count(&self) -> Option<u32> {
+        None
+    }
+
+    /// When this track has been played for the last time
+    fn last_played(&self) -> Option<SystemTime> {
+        None
+    }
+
+    /// Update the play count and the date this track has been played for the last time (e.g. to record the plays that happened on a device)
+    fn set_play_stats(&self, _play_count: u32, _last_played: SystemTime) -> Result<(), Box<dyn Error>> {
+        Err(format!("Unable to record plays of '{}': this source does not keep track of them", self.name()).into())
+    }
 }
 
 pub fn create_m3u<T: Iterator<Item = P>, P: AsRef<Path>>(songs_relative_paths: T, prefix_to_add: &Path) -> Result<String, Box<dyn Error>> {
@@ -107,12 +211,37 @@ pub fn list_sources() -> Vec<Box<dyn Source>> {
         sources.push(Box::new(rhythmbox) as Box<dyn Source>);
     }
 
+    #[cfg(unix)]
+    if let Some(rhythmbox_db) = rhythmbox::RhythmboxOffline::try_new() {
+        sources.push(Box::new(rhythmbox_db) as Box<dyn Source>);
+    }
+
+    for library_file in itunes_xml::default_library_files() {
+        if let Some(itunes_xml) = itunes_xml::ITunesXml::new(&library_file) {
+            sources.push(Box::new(itunes_xml) as Box<dyn Source>);
+        }
+    }
+
+    for folder in folder::default_folders() {
+        if let Some(folder_source) = folder::Folder::new(&folder) {
+            sources.push(Box::new(folder_source) as Box<dyn Source>);
+        }
+    }
+
     // TODO: could we do anything with shared iTunes libraries on the network?
 
     sources
 }
 
 pub fn get(name: &str) -> Option<Box<dyn Source>> {
+    // Any folder can be used, not only the listed ones
+    if name.starts_with(folder::NAME_PREFIX) {
+        return folder::Folder::from_name(name).map(|f| Box::new(f) as Box<dyn Source>);
+    }
+    if name.starts_with(itunes_xml::NAME_PREFIX) {
+        return itunes_xml::ITunesXml::from_name(name).map(|i| Box::new(i) as Box<dyn Source>);
+    }
+
     // Not very smart, as it enumerates all sources.
     // For now, we only have one source, so that's fine
     list_sources().into_iter().find(|source| source.name() == name)
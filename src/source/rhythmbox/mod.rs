@@ -8,7 +8,7 @@
 
 use std::collections::HashMap;
 use std::error::Error;
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
 use std::path::PathBuf;
 use std::num::NonZeroU8;
 
@@ -18,7 +18,7 @@ use log::{debug, info, warn};
 
 use self::rhythmdb::OrgGnomeRhythmbox3RhythmDB;
 
-use super::{Source, Playlist, Rating, Track, TrackId, PlaylistId};
+use super::{Source, Playlist, Rating, Track, TrackId, PlaylistId, playlist_tracks};
 
 
 mod entry;
@@ -29,9 +29,13 @@ mod playlists;
 use playlists::OrgGnomeUPnPMediaContainer2;
 mod rhythmdb;
 mod playlistmanager;
+mod offline;
+pub use offline::RhythmboxOffline;
 
 
 const TIMEOUT: Duration = Duration::from_secs(1);
+/// The object the whole library is exposed under (by the MediaServer2 plugin of Rhythmbox)
+const LIBRARY_DBUS_PATH: &str = "/org/gnome/UPnP/MediaServer2/Library";
 
 pub struct Rhythmbox {
     connection: Connection,
@@ -125,6 +129,31 @@ impl Source for Rhythmbox {
             }
         }
     }
+
+    fn tracks(&self) -> Result<Vec<Box<dyn Track>>, Box<dyn Error>> {
+        let items = self
+            .connection
+            .with_proxy("org.mpris.MediaPlayer2.rhythmbox", LIBRARY_DBUS_PATH, TIMEOUT)
+            .list_items(0, u32::MAX, vec!["Path"]);
+
+        match items {
+            Ok(items) => Ok(items
+                .into_iter()
+                .filter_map(|data| match RhythmboxEntry::try_from_path_propmap(data) {
+                    Ok(entry) => Some(Box::new(entry) as Box<dyn Track>),
+                    Err(err) => {
+                        warn!("Failed to parse library entry ({err}).");
+                        None
+                    }
+                })
+                .collect()),
+            Err(err) => {
+                // e.g. in case the MediaServer2 plugin is disabled
+                warn!("Unable to list the Rhythmbox library ({err}). Only the songs that are in some playlist are considered.");
+                playlist_tracks(self.playlists()?)
+            },
+        }
+    }
 }
 
 
@@ -252,6 +281,18 @@ pub struct RhythmboxEntry {
     file_path: PathBuf,
     encoded_file_path: String,
     rating: Rating,
+    artist: Option<String>,
+    album: Option<String>,
+    album_artist: Option<String>,
+    track_number: Option<u32>,
+    disc_number: Option<u32>,
+    genre: Option<String>,
+    year: Option<i32>,
+    /// When the song was added to the library (a Unix timestamp)
+    first_seen: Option<u64>,
+    play_count: Option<u32>,
+    /// When the song has been played for the last time (a Unix timestamp)
+    last_played: Option<u64>,
 }
 
 impl RhythmboxEntry {
@@ -308,15 +349,50 @@ impl RhythmboxEntry {
             .strip_prefix("file://")
             .unwrap_or(&decoded_file_path));
 
-        let rating = Connection::new_session()?
+        let properties = Connection::new_session()?
             .with_proxy("org.mpris.MediaPlayer2.rhythmbox", "/org/gnome/Rhythmbox3/RhythmDB", TIMEOUT)
-            .get_entry_properties(&encoded_file_path)?
+            .get_entry_properties(&encoded_file_path)?;
+
+        let rating = properties
             .get("rating")
             .and_then(|r| r.as_f64())
             .map(|f| f as u8)
             .and_then(|u| NonZeroU8::new(u));
 
-        Ok(Self { display_name, entry_id, file_path, encoded_file_path, rating })
+        let string_property = |name: &str| properties
+            .get(name)
+            .and_then(|v| v.as_str())
+            .filter(|s| s.is_empty() == false)
+            .map(|s| s.to_string());
+        let artist = string_property("artist");
+        let album = string_property("album");
+        let album_artist = string_property("album-artist");
+        let genre = string_property("genre");
+        let number_property = |name: &str| properties
+            .get(name)
+            .and_then(|n| n.as_u64())
+            .filter(|n| *n > 0)
+            .and_then(|n| u32::try_from(n).ok());
+        let track_number = number_property("track-number");
+        let disc_number = number_property("disc-number");
+        let year = properties
+            .get("year")
+            .and_then(|y| y.as_u64())
+            .filter(|y| *y > 0)
+            .and_then(|y| i32::try_from(y).ok());
+        let first_seen = properties
+            .get("first-seen")
+            .and_then(|t| t.as_u64());
+        let play_count = properties
+            .get("play-count")
+            .and_then(|c| c.as_u64())
+            .and_then(|c| u32::try_from(c).ok());
+        let last_played = properties
+            .get("last-played")
+            .and_then(|t| t.as_u64())
+            .filter(|t| *t > 0);
+
+        Ok(Self { display_name, entry_id, file_path, encoded_file_path, rating, artist, album, album_artist, track_number, disc_number, genre, year, first_seen, play_count, last_played })
     }
 }
 
@@ -353,4 +429,57 @@ impl Track for RhythmboxEntry {
         let md = std::fs::metadata(&self.file_path)?;
         Ok(usize::try_from(md.len())?)
     }
+
+    fn artist(&self) -> Option<String> {
+        self.artist.clone()
+    }
+
+    fn album(&self) -> Option<String> {
+        self.album.clone()
+    }
+
+    fn album_artist(&self) -> Option<String> {
+        self.album_artist.clone()
+    }
+
+    fn track_number(&self) -> Option<u32> {
+        self.track_number
+    }
+
+    fn disc_number(&self) -> Option<u32> {
+        self.disc_number
+    }
+
+    fn genre(&self) -> Option<String> {
+        self.genre.clone()
+    }
+
+    fn year(&self) -> Option<i32> {
+        self.year
+    }
+
+    fn date_added(&self) -> Option<SystemTime> {
+        self.first_seen.map(|first_seen| SystemTime::UNIX_EPOCH + Duration::from_secs(first_seen))
+    }
+
+    fn play_count(&self) -> Option<u32> {
+        self.play_count
+    }
+
+    fn last_played(&self) -> Option<SystemTime> {
+        self.last_played.map(|last_played| SystemTime::UNIX_EPOCH + Duration::from_secs(last_played))
+    }
+
+    fn set_play_stats(&self, play_count: u32, last_played: SystemTime) -> Result<(), Box<dyn Error>> {
+        let last_played = last_played.duration_since(SystemTime::UNIX_EPOCH)?.as_secs();
+        let mut items = HashMap::new();
+        items.insert("play-count".to_string(), Variant(Box::new(play_count as u64) as Box<dyn RefArg>));
+        items.insert("last-played".to_string(), Variant(Box::new(last_played) as Box<dyn RefArg>));
+
+        Connection::new_session()?
+            .with_proxy("org.mpris.MediaPlayer2.rhythmbox", "/org/gnome/Rhythmbox3/RhythmDB", TIMEOUT)
+            .set_entry_properties(&self.encoded_file_path, items)?;
+
+        Ok(())
+    }
 }
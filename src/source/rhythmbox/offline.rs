@@ -0,0 +1,821 @@
+//! Rhythmbox support, without Rhythmbox
+//!
+//! This directly reads the database files (`rhythmdb.xml` and `playlists.xml`) Rhythmbox stores in its data folder.<br/>
+//! This works on headless machines, and listing the whole library is much faster than the D-Bus API.
+//!
+//! Rhythmbox keeps its whole database in memory, and overwrites these files when it exits.
+//! That's why modifying them (i.e. updating ratings, play counts or playlists) is refused while Rhythmbox is running.
+//!
+//! Note that track IDs are derived from the song locations, and are not the same as the ones the [`super::Rhythmbox`] source uses.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::error::Error;
+use std::io::{BufRead, BufReader, Write};
+use std::num::NonZeroU8;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::{Duration, SystemTime};
+
+use quick_xml::events::{BytesEnd, BytesStart, BytesText, Event};
+use quick_xml::{Reader, Writer};
+use sysinfo::{ProcessRefreshKind, RefreshKind, System, SystemExt};
+
+use crate::source::{Source, Playlist, Rating, Track, TrackId, PlaylistId};
+
+const DB_FILE: &str = "rhythmdb.xml";
+const PLAYLISTS_FILE: &str = "playlists.xml";
+
+/// Songs, indexed by the ID derived from their location
+type Entries = Rc<HashMap<TrackId, XmlEntry>>;
+/// The name of a static playlist, and the locations of its songs
+type StaticPlaylist = (String, Vec<String>);
+
+/// The usual location of the Rhythmbox data folder
+fn default_data_folder() -> Option<PathBuf> {
+    let xdg_data_home = std::env::var_os("XDG_DATA_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".local").join("share")))?;
+    Some(xdg_data_home.join("rhythmbox"))
+}
+
+fn is_rhythmbox_running() -> bool {
+    System::new_with_specifics(RefreshKind::new().with_processes(ProcessRefreshKind::new()))
+        .processes_by_exact_name("rhythmbox")
+        .next()
+        .is_some()
+}
+
+fn refuse_if_rhythmbox_is_running() -> Result<(), Box<dyn Error>> {
+    if is_rhythmbox_running() {
+        Err("Rhythmbox is running, and would overwrite any change made to its database files. Either quit Rhythmbox, or sync with the 'Rhythmbox' source instead".into())
+    } else {
+        Ok(())
+    }
+}
+
+/// Convert a `file://` URI into a local path
+fn location_to_path(location: &str) -> Result<PathBuf, Box<dyn Error>> {
+    let decoded = urlencoding::decode(location)?;
+    Ok(PathBuf::from(decoded
+        .strip_prefix("file://")
+        .unwrap_or(&decoded)))
+}
+
+
+
+/// A song, as stored in `rhythmdb.xml`
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct XmlEntry {
+    title: String,
+    location: String,
+    file_size: Option<usize>,
+    mtime: Option<u64>,
+    rating: Rating,
+    artist: Option<String>,
+    album: Option<String>,
+    album_artist: Option<String>,
+    track_number: Option<u32>,
+    disc_number: Option<u32>,
+    genre: Option<String>,
+    /// The release date, as a GLib Julian day (i.e. the number of days since January 1st of year 1)
+    date: Option<i32>,
+    /// When the song was added to the library (a Unix timestamp)
+    first_seen: Option<u64>,
+    play_count: Option<u32>,
+    /// When the song has been played for the last time (a Unix timestamp)
+    last_played: Option<u64>,
+}
+
+/// The contents of the Rhythmbox data folder
+struct Library {
+    db_path: PathBuf,
+    playlists_path: PathBuf,
+    /// This is lazily loaded, and dropped whenever we modify the database.
+    entries: RefCell<Option<Entries>>,
+}
+
+impl Library {
+    fn entries(&self) -> Result<Entries, Box<dyn Error>> {
+        if let Some(entries) = self.entries.borrow().as_ref() {
+            return Ok(Rc::clone(entries));
+        }
+
+        let reader = BufReader::new(std::fs::File::open(&self.db_path)?);
+        let entries: HashMap<TrackId, XmlEntry> = parse_db(reader)?
+            .into_iter()
+            .map(|entry| (TrackId::from_hashed_str(&entry.location), entry))
+            .collect();
+        let entries = Rc::new(entries);
+        *self.entries.borrow_mut() = Some(Rc::clone(&entries));
+        Ok(entries)
+    }
+
+    fn static_playlists(&self) -> Result<Vec<StaticPlaylist>, Box<dyn Error>> {
+        let reader = BufReader::new(std::fs::File::open(&self.playlists_path)?);
+        parse_static_playlists(reader)
+    }
+
+    fn set_rating(&self, location: &str, rating: Rating) -> Result<(), Box<dyn Error>> {
+        refuse_if_rhythmbox_is_running()?;
+        rewrite_file(&self.db_path, |reader, writer| rewrite_rating(reader, writer, location, rating))?;
+        self.entries.borrow_mut().take();
+        Ok(())
+    }
+
+    fn set_play_stats(&self, location: &str, play_count: u32, last_played: u64) -> Result<(), Box<dyn Error>> {
+        refuse_if_rhythmbox_is_running()?;
+        let elements = [("play-count", Some(play_count.to_string())), ("last-played", Some(last_played.to_string()))];
+        rewrite_file(&self.db_path, |reader, writer| rewrite_entry(reader, writer, location, &elements))?;
+        self.entries.borrow_mut().take();
+        Ok(())
+    }
+
+    fn set_playlist(&self, name: &str, locations: &[String]) -> Result<(), Box<dyn Error>> {
+        refuse_if_rhythmbox_is_running()?;
+        rewrite_file(&self.playlists_path, |reader, writer| rewrite_playlist(reader, writer, name, locations))
+    }
+}
+
+/// Safely rewrite a file: the new content is written to a temporary file, that then replaces the original file.
+fn rewrite_file<F>(path: &Path, rewrite: F) -> Result<(), Box<dyn Error>>
+where F: FnOnce(&mut dyn BufRead, &mut dyn Write) -> Result<(), Box<dyn Error>>
+{
+    let mut tmp_name = path.file_name().ok_or("Invalid file name")?.to_os_string();
+    tmp_name.push(".starsync-tmp");
+    let tmp_path = path.with_file_name(tmp_name);
+
+    let mut reader = BufReader::new(std::fs::File::open(path)?);
+    let tmp_file = std::fs::File::create(&tmp_path)?;
+    let mut writer = std::io::BufWriter::new(&tmp_file);
+    if let Err(err) = rewrite(&mut reader, &mut writer).and_then(|_| Ok(writer.flush()?)) {
+        let _ = std::fs::remove_file(&tmp_path);
+        return Err(err);
+    }
+    drop(writer);
+    tmp_file.sync_all()?;
+
+    std::fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+
+
+pub struct RhythmboxOffline {
+    library: Rc<Library>,
+}
+
+impl RhythmboxOffline {
+    pub fn try_new() -> Option<Self> {
+        Self::with_data_folder(&default_data_folder()?)
+    }
+
+    pub fn with_data_folder(folder: &Path) -> Option<Self> {
+        let db_path = folder.join(DB_FILE);
+        let playlists_path = folder.join(PLAYLISTS_FILE);
+        if db_path.is_file() == false || playlists_path.is_file() == false {
+            log::info!("No Rhythmbox database found in {}", folder.display());
+            return None;
+        }
+
+        let library = Library{ db_path, playlists_path, entries: RefCell::new(None) };
+        Some(Self{ library: Rc::new(library) })
+    }
+
+    fn make_playlist(&self, name: String, locations: Vec<String>) -> Box<dyn Playlist> {
+        Box::new(XmlPlaylist{ library: Rc::clone(&self.library), name, locations }) as Box<dyn Playlist>
+    }
+}
+
+impl Source for RhythmboxOffline {
+    fn name(&self) -> &str {
+        "Rhythmbox (offline)"
+    }
+
+    fn playlists(&self) -> Result<Vec<Box<dyn Playlist>>, Box<dyn Error>> {
+        Ok(self.library
+            .static_playlists()?
+            .into_iter()
+            .map(|(name, locations)| self.make_playlist(name, locations))
+            .collect())
+    }
+
+    fn playlist_by_name(&self, name: &str) -> Option<Box<dyn Playlist>> {
+        let playlists = match self.library.static_playlists() {
+            Err(err) => {
+                log::warn!("Unable to read Rhythmbox playlists: {err}");
+                return None;
+            },
+            Ok(pl) => pl,
+        };
+
+        playlists
+            .into_iter()
+            .find(|(pl_name, _)| pl_name == name)
+            .map(|(name, locations)| self.make_playlist(name, locations))
+    }
+
+    fn playlist_by_id(&self, id: &PlaylistId) -> Option<Box<dyn Playlist>> {
+        match id {
+            PlaylistId::Name(name) => self.playlist_by_name(name),
+            _ => {
+                log::warn!("Invalid type ({id:?}) for playlist ID.");
+                None
+            }
+        }
+    }
+
+    fn track_by_id(&self, id: TrackId) -> Option<Box<dyn Track>> {
+        let entries = match self.library.entries() {
+            Err(err) => {
+                log::warn!("Unable to read the Rhythmbox database: {err}");
+                return None;
+            },
+            Ok(e) => e,
+        };
+
+        entries
+            .get(&id)
+            .map(|entry| Box::new(XmlTrack{ library: Rc::clone(&self.library), entry: entry.clone() }) as Box<dyn Track>)
+    }
+
+    fn tracks(&self) -> Result<Vec<Box<dyn Track>>, Box<dyn Error>> {
+        Ok(self.library
+            .entries()?
+            .values()
+            .map(|entry| Box::new(XmlTrack{ library: Rc::clone(&self.library), entry: entry.clone() }) as Box<dyn Track>)
+            .collect())
+    }
+}
+
+
+
+pub struct XmlPlaylist {
+    library: Rc<Library>,
+    name: String,
+    locations: Vec<String>,
+}
+
+impl Playlist for XmlPlaylist {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn tracks(&self) -> Result<Vec<Box<dyn Track>>, Box<dyn Error>> {
+        let entries = self.library.entries()?;
+
+        Ok(self.locations
+            .iter()
+            .filter_map(|location| match entries.get(&TrackId::from_hashed_str(location)) {
+                None => {
+                    log::warn!("Playlist {} contains {}, which is not in the Rhythmbox database", self.name, location);
+                    None
+                },
+                Some(entry) => Some(Box::new(XmlTrack{ library: Rc::clone(&self.library), entry: entry.clone() }) as Box<dyn Track>),
+            })
+            .collect())
+    }
+
+    fn id(&self) -> PlaylistId {
+        PlaylistId::Name(self.name.clone())
+    }
+
+    fn change_contents_to(&self, new_content: &[TrackId]) -> Result<(), Box<dyn Error>> {
+        let entries = self.library.entries()?;
+        let mut locations = Vec::new();
+        for id in new_content {
+            match entries.get(id) {
+                None => log::warn!("Unable to get track for ID {id:?}"),
+                Some(entry) => locations.push(entry.location.clone()),
+            }
+        }
+
+        self.library.set_playlist(&self.name, &locations)
+    }
+}
+
+
+
+pub struct XmlTrack {
+    library: Rc<Library>,
+    entry: XmlEntry,
+}
+
+impl Track for XmlTrack {
+    fn name(&self) -> String {
+        self.entry.title.clone()
+    }
+
+    fn id(&self) -> TrackId {
+        TrackId::from_hashed_str(&self.entry.location)
+    }
+
+    fn absolute_path(&self) -> Result<PathBuf, Box<dyn Error>> {
+        location_to_path(&self.entry.location)
+    }
+
+    fn rating(&self, _use_computed_ratings: bool) -> Rating {
+        self.entry.rating
+    }
+
+    fn set_rating(&self, new_rating: Rating) -> Result<(), Box<dyn Error>> {
+        self.library.set_rating(&self.entry.location, new_rating)
+    }
+
+    fn file_size(&self) -> Result<usize, Box<dyn Error>> {
+        match self.entry.file_size {
+            Some(size) => Ok(size),
+            None => {
+                let md = std::fs::metadata(self.absolute_path()?)?;
+                Ok(usize::try_from(md.len())?)
+            }
+        }
+    }
+
+    fn modification_date(&self) -> Result<SystemTime, Box<dyn Error>> {
+        match self.entry.mtime {
+            Some(mtime) => Ok(SystemTime::UNIX_EPOCH + Duration::from_secs(mtime)),
+            None => Ok(std::fs::metadata(self.absolute_path()?)?.modified()?),
+        }
+    }
+
+    fn artist(&self) -> Option<String> {
+        self.entry.artist.clone()
+    }
+
+    fn album(&self) -> Option<String> {
+        self.entry.album.clone()
+    }
+
+    fn album_artist(&self) -> Option<String> {
+        self.entry.album_artist.clone()
+    }
+
+    fn track_number(&self) -> Option<u32> {
+        self.entry.track_number
+    }
+
+    fn disc_number(&self) -> Option<u32> {
+        self.entry.disc_number
+    }
+
+    fn genre(&self) -> Option<String> {
+        self.entry.genre.clone()
+    }
+
+    fn year(&self) -> Option<i32> {
+        self.entry.date.and_then(glib_julian_day_to_year)
+    }
+
+    fn date_added(&self) -> Option<SystemTime> {
+        self.entry.first_seen.map(|first_seen| SystemTime::UNIX_EPOCH + Duration::from_secs(first_seen))
+    }
+
+    fn play_count(&self) -> Option<u32> {
+        self.entry.play_count
+    }
+
+    fn last_played(&self) -> Option<SystemTime> {
+        self.entry.last_played.map(|last_played| SystemTime::UNIX_EPOCH + Duration::from_secs(last_played))
+    }
+
+    fn set_play_stats(&self, play_count: u32, last_played: SystemTime) -> Result<(), Box<dyn Error>> {
+        let last_played = last_played.duration_since(SystemTime::UNIX_EPOCH)?.as_secs();
+        self.library.set_play_stats(&self.entry.location, play_count, last_played)
+    }
+}
+
+/// GLib counts days from January 1st of year 1, the astronomical Julian day count starts 1721425 days earlier
+fn glib_julian_day_to_year(day: i32) -> Option<i32> {
+    time::Date::from_julian_day(day.checked_add(1_721_425)?).ok().map(|date| date.year())
+}
+
+
+
+fn new_reader<R: BufRead>(reader: R) -> Reader<R> {
+    let mut reader = Reader::from_reader(reader);
+    reader.trim_text(false);
+    reader
+}
+
+fn parse_rating(text: &str) -> Rating {
+    text.trim()
+        .parse::<f64>()
+        .ok()
+        .filter(|r| *r >= 1.0 && *r <= 5.0)
+        .and_then(|r| NonZeroU8::new(r.round() as u8))
+}
+
+/// Parse the songs stored in a `rhythmdb.xml` file
+fn parse_db<R: BufRead>(reader: R) -> Result<Vec<XmlEntry>, Box<dyn Error>> {
+    let mut reader = new_reader(reader);
+    let mut buf = Vec::new();
+    let mut entries = Vec::new();
+
+    let mut current_entry: Option<XmlEntry> = None;
+    let mut current_field: Option<Vec<u8>> = None;
+
+    loop {
+        match reader.read_event_into(&mut buf)? {
+            Event::Eof => break,
+            Event::Start(e) if e.name().as_ref() == b"entry" => {
+                let is_song = e.try_get_attribute("type")?
+                    .map(|attr| attr.value.as_ref() == b"song")
+                    .unwrap_or(false);
+                if is_song {
+                    current_entry = Some(XmlEntry::default());
+                }
+            },
+            Event::End(e) if e.name().as_ref() == b"entry" => {
+                if let Some(entry) = current_entry.take() {
+                    entries.push(entry);
+                }
+            },
+            Event::Start(e) if current_entry.is_some() => {
+                current_field = Some(e.name().as_ref().to_vec());
+            },
+            Event::End(_) => {
+                current_field = None;
+            },
+            Event::Text(t) => {
+                if let (Some(entry), Some(field)) = (current_entry.as_mut(), current_field.as_deref()) {
+                    let text = t.unescape()?;
+                    match field {
+                        b"title" => entry.title = text.to_string(),
+                        b"location" => entry.location = text.to_string(),
+                        b"file-size" => entry.file_size = text.trim().parse().ok(),
+                        b"mtime" => entry.mtime = text.trim().parse().ok(),
+                        b"rating" => entry.rating = parse_rating(&text),
+                        b"artist" => entry.artist = Some(text.to_string()),
+                        b"album" => entry.album = Some(text.to_string()),
+                        b"album-artist" => entry.album_artist = Some(text.to_string()),
+                        b"track-number" => entry.track_number = text.trim().parse().ok().filter(|n| *n > 0),
+                        b"disc-number" => entry.disc_number = text.trim().parse().ok().filter(|n| *n > 0),
+                        b"genre" => entry.genre = Some(text.to_string()),
+                        b"date" => entry.date = text.trim().parse().ok().filter(|day| *day > 0),
+                        b"first-seen" => entry.first_seen = text.trim().parse().ok(),
+                        b"play-count" => entry.play_count = text.trim().parse().ok(),
+                        b"last-played" => entry.last_played = text.trim().parse().ok().filter(|t| *t > 0),
+                        _ => (),
+                    }
+                }
+            },
+            _ => (),
+        }
+        buf.clear();
+    }
+
+    Ok(entries)
+}
+
+/// Parse the static playlists stored in a `playlists.xml` file.
+///
+/// Automatic playlists and the play queue are ignored.
+fn parse_static_playlists<R: BufRead>(reader: R) -> Result<Vec<StaticPlaylist>, Box<dyn Error>> {
+    let mut reader = new_reader(reader);
+    let mut buf = Vec::new();
+    let mut playlists = Vec::new();
+
+    let mut current_playlist: Option<StaticPlaylist> = None;
+    let mut in_location = false;
+
+    loop {
+        match reader.read_event_into(&mut buf)? {
+            Event::Eof => break,
+            Event::Start(e) if e.name().as_ref() == b"playlist" => {
+                if let Some(name) = static_playlist_name(&e)? {
+                    current_playlist = Some((name, Vec::new()));
+                }
+            },
+            Event::Empty(e) if e.name().as_ref() == b"playlist" => {
+                if let Some(name) = static_playlist_name(&e)? {
+                    playlists.push((name, Vec::new()));
+                }
+            },
+            Event::End(e) if e.name().as_ref() == b"playlist" => {
+                if let Some(playlist) = current_playlist.take() {
+                    playlists.push(playlist);
+                }
+            },
+            Event::Start(e) if e.name().as_ref() == b"location" => in_location = true,
+            Event::End(e) if e.name().as_ref() == b"location" => in_location = false,
+            Event::Text(t) if in_location => {
+                if let Some((_, locations)) = current_playlist.as_mut() {
+                    locations.push(t.unescape()?.to_string());
+                }
+            },
+            _ => (),
+        }
+        buf.clear();
+    }
+
+    Ok(playlists)
+}
+
+fn static_playlist_name(e: &BytesStart) -> Result<Option<String>, Box<dyn Error>> {
+    let is_static = e.try_get_attribute("type")?
+        .map(|attr| attr.value.as_ref() == b"static")
+        .unwrap_or(false);
+    if is_static == false {
+        return Ok(None);
+    }
+
+    Ok(e.try_get_attribute("name")?
+        .map(|attr| attr.unescape_value().map(|v| v.to_string()))
+        .transpose()?)
+}
+
+/// Copy a `rhythmdb.xml`, changing the rating of a single song
+fn rewrite_rating(reader: &mut dyn BufRead, writer: &mut dyn Write, location: &str, rating: Rating) -> Result<(), Box<dyn Error>> {
+    rewrite_entry(reader, writer, location, &[("rating", rating.map(|stars| stars.to_string()))])
+}
+
+/// Copy a `rhythmdb.xml`, setting (or removing, for `None` values) elements of the song at `location`
+fn rewrite_entry(reader: &mut dyn BufRead, writer: &mut dyn Write, location: &str, elements: &[(&str, Option<String>)]) -> Result<(), Box<dyn Error>> {
+    let mut reader = new_reader(reader);
+    let mut writer = Writer::new(writer);
+    let mut buf = Vec::new();
+
+    // Events of the current song are buffered, until we know whether this is the song we are looking for
+    let mut current_entry: Option<Vec<Event<'static>>> = None;
+    let mut found = false;
+
+    loop {
+        let event = reader.read_event_into(&mut buf)?.into_owned();
+        match (&event, current_entry.as_mut()) {
+            (Event::Eof, _) => break,
+            (Event::Start(e), None) if e.name().as_ref() == b"entry" => {
+                current_entry = Some(vec![event]);
+            },
+            (Event::End(e), Some(events)) if e.name().as_ref() == b"entry" => {
+                events.push(event);
+                let mut events = current_entry.take().unwrap_or_default();
+                if entry_location(&events)?.as_deref() == Some(location) {
+                    for (name, value) in elements {
+                        set_entry_element(&mut events, name, value.as_deref());
+                    }
+                    found = true;
+                }
+                for e in events {
+                    writer.write_event(e)?;
+                }
+            },
+            (_, Some(events)) => events.push(event),
+            (_, None) => writer.write_event(event)?,
+        }
+        buf.clear();
+    }
+
+    if found {
+        Ok(())
+    } else {
+        Err(format!("No song at {location} in the Rhythmbox database").into())
+    }
+}
+
+fn entry_location(events: &[Event]) -> Result<Option<String>, Box<dyn Error>> {
+    for pair in events.windows(2) {
+        if let [Event::Start(s), Event::Text(t)] = pair {
+            if s.name().as_ref() == b"location" {
+                return Ok(Some(t.unescape()?.to_string()));
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Update the events of a `<entry>` element, so that it contains the given element (or does not contain it, for a `None` value)
+fn set_entry_element(events: &mut Vec<Event<'static>>, name: &str, value: Option<&str>) {
+    let element_start = events.iter().position(|e| matches!(e, Event::Start(s) if s.name().as_ref() == name.as_bytes()));
+
+    match (element_start, value) {
+        (Some(start), Some(value)) => {
+            // Replace the existing value
+            if let Some(Event::Text(_)) = events.get(start + 1) {
+                events[start + 1] = Event::Text(BytesText::new(value).into_owned());
+            }
+        },
+        (Some(start), None) => {
+            // Remove the element (and its indentation)
+            let mut first = start;
+            if let Some(Event::Text(t)) = start.checked_sub(1).and_then(|i| events.get(i)) {
+                if t.iter().all(|b| b.is_ascii_whitespace()) {
+                    first -= 1;
+                }
+            }
+            let end = events[start..]
+                .iter()
+                .position(|e| matches!(e, Event::End(e) if e.name().as_ref() == name.as_bytes()))
+                .map(|offset| start + offset)
+                .unwrap_or(start);
+            events.drain(first..=end);
+        },
+        (None, Some(value)) => {
+            // Insert a new element, with the same indentation as the other ones
+            let indentation = match events.get(1) {
+                Some(Event::Text(t)) if t.iter().all(|b| b.is_ascii_whitespace()) => t.clone().into_owned(),
+                _ => BytesText::new("\n    ").into_owned(),
+            };
+            let entry_end = events.len() - 1;
+            let insert_at = match events.get(entry_end - 1) {
+                Some(Event::Text(_)) => entry_end - 1,
+                _ => entry_end,
+            };
+            let new_events = [
+                Event::Text(indentation),
+                Event::Start(BytesStart::new(name.to_string())),
+                Event::Text(BytesText::new(value).into_owned()),
+                Event::End(BytesEnd::new(name.to_string())),
+            ];
+            events.splice(insert_at..insert_at, new_events);
+        },
+        (None, None) => (),
+    }
+}
+
+/// Copy a `playlists.xml`, replacing the content of a static playlist
+fn rewrite_playlist(reader: &mut dyn BufRead, writer: &mut dyn Write, name: &str, locations: &[String]) -> Result<(), Box<dyn Error>> {
+    let mut reader = new_reader(reader);
+    let mut writer = Writer::new(writer);
+    let mut buf = Vec::new();
+
+    let mut found = false;
+    // Whether we are skipping the previous content of the playlist we're replacing
+    let mut skipping = false;
+
+    loop {
+        let event = reader.read_event_into(&mut buf)?;
+        match &event {
+            Event::Eof => break,
+            Event::Start(e) | Event::Empty(e) if skipping == false && e.name().as_ref() == b"playlist" && static_playlist_name(e)?.as_deref() == Some(name) => {
+                let start = e.to_owned();
+                let end = BytesEnd::new("playlist");
+                skipping = matches!(event, Event::Start(_));
+                found = true;
+
+                writer.write_event(Event::Start(start))?;
+                for location in locations {
+                    writer.write_event(Event::Text(BytesText::new("\n    ")))?;
+                    writer.write_event(Event::Start(BytesStart::new("location")))?;
+                    writer.write_event(Event::Text(BytesText::new(location)))?;
+                    writer.write_event(Event::End(BytesEnd::new("location")))?;
+                }
+                writer.write_event(Event::Text(BytesText::new("\n  ")))?;
+                writer.write_event(Event::End(end))?;
+            },
+            Event::End(e) if skipping && e.name().as_ref() == b"playlist" => {
+                skipping = false;
+            },
+            _ if skipping => (),
+            _ => writer.write_event(event)?,
+        }
+        buf.clear();
+    }
+
+    if found {
+        Ok(())
+    } else {
+        Err(format!("No static playlist named '{name}' in the Rhythmbox database").into())
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DB: &str = r#"<?xml version="1.0" standalone="yes"?>
+<rhythmdb version="2.0">
+  <entry type="iradio">
+    <title>Some radio</title>
+    <location>http://radio.example.com/stream</location>
+  </entry>
+  <entry type="song">
+    <title>First &amp; best</title>
+    <artist>Someone</artist>
+    <genre>Jazz</genre>
+    <track-number>3</track-number>
+    <date>733194</date>
+    <first-seen>1650000000</first-seen>
+    <file-size>1234</file-size>
+    <location>file:///music/Someone/First%20song.mp3</location>
+    <mtime>1600000000</mtime>
+    <rating>4</rating>
+  </entry>
+  <entry type="song">
+    <title>Second</title>
+    <file-size>5678</file-size>
+    <location>file:///music/Someone/Second.flac</location>
+  </entry>
+</rhythmdb>
+"#;
+
+    const PLAYLISTS: &str = r#"<?xml version="1.0"?>
+<rhythmdb-playlists>
+  <playlist name="Play Queue" show-browser="false" browser-position="180" search-type="search-match" type="queue"/>
+  <playlist name="Rock &amp; roll" show-browser="true" browser-position="180" search-type="search-match" type="static">
+    <location>file:///music/Someone/Second.flac</location>
+    <location>file:///music/Someone/First%20song.mp3</location>
+  </playlist>
+  <playlist name="Empty" show-browser="true" browser-position="180" search-type="search-match" type="static"/>
+  <playlist name="Recent" show-browser="true" browser-position="180" search-type="search-match" type="automatic">
+    <conjunction/>
+  </playlist>
+</rhythmdb-playlists>
+"#;
+
+    fn rewritten<F>(original: &str, rewrite: F) -> String
+    where F: FnOnce(&mut dyn BufRead, &mut dyn Write) -> Result<(), Box<dyn Error>>
+    {
+        let mut output = Vec::new();
+        rewrite(&mut original.as_bytes(), &mut output).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn parse_songs() {
+        let entries = parse_db(DB.as_bytes()).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0], XmlEntry{
+            title: "First & best".to_string(),
+            location: "file:///music/Someone/First%20song.mp3".to_string(),
+            file_size: Some(1234),
+            mtime: Some(1600000000),
+            rating: NonZeroU8::new(4),
+            artist: Some("Someone".to_string()),
+            album: None,
+            album_artist: None,
+            track_number: Some(3),
+            disc_number: None,
+            genre: Some("Jazz".to_string()),
+            date: Some(733194),
+            first_seen: Some(1650000000),
+            play_count: None,
+            last_played: None,
+        });
+        assert_eq!(glib_julian_day_to_year(733194), Some(2008));
+        assert_eq!(entries[1].rating, None);
+        assert_eq!(location_to_path(&entries[0].location).unwrap(), PathBuf::from("/music/Someone/First song.mp3"));
+    }
+
+    #[test]
+    fn parse_playlists() {
+        let playlists = parse_static_playlists(PLAYLISTS.as_bytes()).unwrap();
+        assert_eq!(playlists, vec![
+            ("Rock & roll".to_string(), vec![
+                "file:///music/Someone/Second.flac".to_string(),
+                "file:///music/Someone/First%20song.mp3".to_string(),
+            ]),
+            ("Empty".to_string(), vec![]),
+        ]);
+    }
+
+    #[test]
+    fn update_ratings() {
+        let first = "file:///music/Someone/First%20song.mp3";
+        let second = "file:///music/Someone/Second.flac";
+
+        let changed = rewritten(DB, |r, w| rewrite_rating(r, w, first, NonZeroU8::new(2)));
+        assert_eq!(changed, DB.replace("<rating>4</rating>", "<rating>2</rating>"));
+
+        let removed = rewritten(DB, |r, w| rewrite_rating(r, w, first, None));
+        assert_eq!(removed, DB.replace("\n    <rating>4</rating>", ""));
+
+        let added = rewritten(DB, |r, w| rewrite_rating(r, w, second, NonZeroU8::new(5)));
+        let entries = parse_db(added.as_bytes()).unwrap();
+        assert_eq!(entries[0].rating, NonZeroU8::new(4));
+        assert_eq!(entries[1].rating, NonZeroU8::new(5));
+        assert!(added.contains("<location>file:///music/Someone/Second.flac</location>\n    <rating>5</rating>\n  </entry>"));
+
+        let mut output = Vec::new();
+        assert!(rewrite_rating(&mut DB.as_bytes(), &mut output, "file:///nowhere.mp3", None).is_err());
+
+        let played = rewritten(DB, |r, w| rewrite_entry(r, w, first, &[("play-count", Some("3".to_string())), ("last-played", Some("1700000000".to_string()))]));
+        let entries = parse_db(played.as_bytes()).unwrap();
+        assert_eq!((entries[0].play_count, entries[0].last_played), (Some(3), Some(1700000000)));
+        assert_eq!(entries[0].rating, NonZeroU8::new(4));
+        assert_eq!(entries[1].play_count, None);
+    }
+
+    #[test]
+    fn update_playlist() {
+        let new_content = vec!["file:///music/Someone/First%20song.mp3".to_string()];
+
+        let changed = rewritten(PLAYLISTS, |r, w| rewrite_playlist(r, w, "Rock & roll", &new_content));
+        let playlists = parse_static_playlists(changed.as_bytes()).unwrap();
+        assert_eq!(playlists[0].1, new_content);
+        assert_eq!(playlists[1].1, Vec::<String>::new());
+        assert!(changed.contains(r#"type="automatic">
+    <conjunction/>
+  </playlist>"#));
+
+        let filled = rewritten(PLAYLISTS, |r, w| rewrite_playlist(r, w, "Empty", &new_content));
+        let playlists = parse_static_playlists(filled.as_bytes()).unwrap();
+        assert_eq!(playlists[0].1.len(), 2);
+        assert_eq!(playlists[1].1, new_content);
+
+        let mut output = Vec::new();
+        assert!(rewrite_playlist(&mut PLAYLISTS.as_bytes(), &mut output, "Recent", &new_content).is_err());
+    }
+}
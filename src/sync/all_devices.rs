@@ -0,0 +1,103 @@
+//! Syncing every connected device in a row
+
+use std::collections::HashSet;
+use std::sync::mpsc::{Sender, Receiver};
+
+use crate::device::Device;
+use super::{status, SharedSources, SyncError, SyncManager, SyncPlan, SyncValidator, Warnings};
+use super::status::Message;
+
+/// How the sync of a device went, see [`sync_all_devices`]
+#[derive(Debug)]
+pub struct DeviceSyncOutcome {
+    pub device: String,
+    /// The number of warnings issued while syncing this device, or the error that made its sync fail
+    pub result: Result<Warnings, SyncError>,
+}
+
+/// Sync every inited device that is currently connected, one after the other.
+///
+/// Sources (and the scans of their playlists) are shared between devices, so that devices synced with the same sources do not open and scan them again.
+///
+/// Each sync works like [`SyncManager::start_sync`]: a [`SyncValidator`] is sent into `outbound` for every device (its `device` field tells which one),
+/// and the sync of this device starts once it has been sent back into `inbound`.<br/>
+/// A [`Message::SyncingDevice`] is sent into `status_tx` whenever the sync of a device starts.
+///
+/// Changes made on a device are reverse synced into the sources, and then pushed to the other devices that share these sources.
+/// In case a device has been synced before another device modified one of its sources (e.g. because the same playlist has been edited on both devices),
+/// it is synced once again at the end, so that every device gets the merged changes.
+pub fn sync_all_devices(
+    status_tx: status::Sender,
+    outbound: Sender<SyncValidator>,
+    inbound: Receiver<SyncValidator>,
+) -> Vec<DeviceSyncOutcome> {
+    let mut shared_sources = SharedSources::default();
+    let mut outcomes = Vec::new();
+    // The sources of every successfully synced device, and whether its sync has modified them
+    let mut synced_sources: Vec<Option<(HashSet<String>, bool)>> = Vec::new();
+
+    for device in crate::device::list_devices(true) {
+        let device_name = device.name();
+        status_tx.send(Message::SyncingDevice(device_name.clone()));
+
+        let warnings_before = status_tx.warnings_count();
+        let result = sync_device(&status_tx, device, &mut shared_sources, &outbound, &inbound);
+        match result {
+            Err(err) => {
+                synced_sources.push(None);
+                outcomes.push(DeviceSyncOutcome{ device: device_name, result: Err(err) });
+            },
+            Ok((plan, sources)) => {
+                let modified_sources = plan.source_playlist_updates.is_empty() == false || plan.source_rating_updates.is_empty() == false;
+                synced_sources.push(Some((sources, modified_sources)));
+                outcomes.push(DeviceSyncOutcome{ device: device_name, result: Ok(status_tx.warnings_count() - warnings_before) });
+            },
+        }
+    }
+
+    // Devices that have been synced before another device modified their sources do not have these changes yet
+    for i_device in 0..outcomes.len() {
+        let sources = match &synced_sources[i_device] {
+            None => continue,
+            Some((sources, _)) => sources,
+        };
+        let outdated = synced_sources[i_device + 1..]
+            .iter()
+            .flatten()
+            .any(|(other_sources, modified)| *modified && other_sources.is_disjoint(sources) == false);
+        if outdated == false {
+            continue;
+        }
+
+        let outcome = &mut outcomes[i_device];
+        let n_warns = match &outcome.result {
+            Err(_) => continue,
+            Ok(n_warns) => *n_warns,
+        };
+        status_tx.send(Message::SyncingDevice(outcome.device.clone()));
+        status_tx.send_info(format!("Syncing {} again, so that it gets the changes that have been made on other devices", outcome.device));
+
+        let warnings_before = status_tx.warnings_count();
+        let result = crate::device::get(&outcome.device)
+            .ok_or_else(|| SyncError::DeviceNotFound(outcome.device.clone()))
+            .and_then(|device| sync_device(&status_tx, device, &mut shared_sources, &outbound, &inbound));
+        outcome.result = result.map(|_| n_warns + status_tx.warnings_count() - warnings_before);
+    }
+
+    outcomes
+}
+
+/// Sync a device, and return the changes that have been performed, along with the names of the sources of this device
+fn sync_device(
+    status_tx: &status::Sender,
+    device: Box<dyn Device>,
+    shared_sources: &mut SharedSources,
+    outbound: &Sender<SyncValidator>,
+    inbound: &Receiver<SyncValidator>,
+) -> Result<(SyncPlan, HashSet<String>), SyncError> {
+    let sync_manager = SyncManager::from_device(device, shared_sources)?;
+    let sources = sync_manager.config().source_names().map(|name| name.to_string()).collect();
+    let result = sync_manager.run(status_tx, outbound, inbound);
+    sync_manager.release_sources(shared_sources);
+    result.map(|plan| (plan, sources))
+}
@@ -0,0 +1,146 @@
+//! A record of the previous sync sessions of a device
+
+use std::collections::HashSet;
+use std::error::Error;
+use std::ffi::OsStr;
+use std::io::Read;
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+
+use crate::device::{Device, SYNC_HISTORY_CORRUPTED_FILE, SYNC_HISTORY_FILE};
+use super::{PlaylistUpdate, PlayUpdate, RatingUpdate, SyncPlan, Warnings};
+
+/// How many sessions are kept in the history of a device. Older ones are forgotten.
+pub const MAX_HISTORY_LENGTH: usize = 50;
+
+/// What happened during a sync session
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SyncSession {
+    /// When this sync has started
+    pub timestamp: OffsetDateTime,
+    /// The hostname of the computer the sync has been performed on
+    pub hostname: String,
+    /// How long the sync took, in seconds
+    pub duration_secs: f64,
+    /// How many warnings have been issued
+    pub warnings: Warnings,
+    /// Total size (in bytes) of the files pushed (including the updated ones)
+    pub bytes_pushed: usize,
+    /// Total size (in bytes) of the files removed (only counting files whose size is known)
+    pub bytes_removed: usize,
+    /// Music files copied into the device, with their paths relative to the music folder of the device
+    pub files_pushed: Vec<PathBuf>,
+    /// Music files copied again into the device, because they have been modified in the source
+    pub files_updated: Vec<PathBuf>,
+    /// Music files removed from the device
+    pub files_removed: Vec<PathBuf>,
+    /// Playlists whose changes on the device have been reverse synced into the source
+    pub source_playlist_updates: Vec<PlaylistChange>,
+    /// Ratings that changed on the device and have been reverse synced into the source
+    pub source_rating_updates: Vec<RatingUpdate>,
+    /// Plays recorded on the device, that have been imported into the source
+    #[serde(default)]
+    pub source_play_updates: Vec<PlayUpdate>,
+}
+
+/// How a playlist of the source has been changed by a reverse sync
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PlaylistChange {
+    pub name: String,
+    /// How many tracks have been added to the playlist
+    pub added_tracks: usize,
+    /// How many tracks have been removed from the playlist
+    pub removed_tracks: usize,
+}
+
+impl From<&PlaylistUpdate> for PlaylistChange {
+    fn from(update: &PlaylistUpdate) -> Self {
+        let current: HashSet<_> = update.current_content.iter().collect();
+        let new: HashSet<_> = update.new_content.iter().collect();
+        Self{
+            name: update.name.clone(),
+            added_tracks: new.difference(&current).count(),
+            removed_tracks: current.difference(&new).count(),
+        }
+    }
+}
+
+impl SyncSession {
+    /// Summarize a sync session, from the changes it has performed
+    pub(super) fn new(timestamp: OffsetDateTime, plan: &SyncPlan, warnings: Warnings) -> Self {
+        let paths = |files: &[super::PlannedFile]| files.iter().map(|f| f.path.clone()).collect();
+        Self{
+            timestamp,
+            hostname: crate::utils::current_hostname(),
+            duration_secs: (OffsetDateTime::now_utc() - timestamp).as_seconds_f64(),
+            warnings,
+            bytes_pushed: plan.size_to_push(),
+            bytes_removed: plan.size_to_remove(),
+            files_pushed: paths(&plan.files_to_push),
+            files_updated: paths(&plan.files_to_update),
+            files_removed: paths(&plan.files_to_remove),
+            source_playlist_updates: plan.source_playlist_updates.iter().map(PlaylistChange::from).collect(),
+            source_rating_updates: plan.source_rating_updates.clone(),
+            source_play_updates: plan.source_play_updates.clone(),
+        }
+    }
+}
+
+/// The previous sync sessions of a device, the oldest first
+pub fn history(device: &dyn Device) -> Result<Vec<SyncSession>, Box<dyn Error>> {
+    match device.config_file(SYNC_HISTORY_FILE) {
+        None => Ok(Vec::new()),
+        Some(reader) => Ok(serde_json::from_reader(reader)
+            .map_err(|err| format!("Unable to parse {}: {}", SYNC_HISTORY_FILE, err))?),
+    }
+}
+
+/// Add a session to the history stored on the device.
+///
+/// In case the history is corrupted, it is set aside (so that it can be inspected), and a new history is started.
+pub(super) fn record_session<F: Fn(String)>(device: &dyn Device, session: SyncSession, warn: F) -> Result<(), Box<dyn Error>> {
+    let mut sessions = match device.config_file(SYNC_HISTORY_FILE) {
+        None => Vec::new(),
+        Some(mut reader) => {
+            let mut history_json = Vec::new();
+            reader.read_to_end(&mut history_json)?;
+            match serde_json::from_slice(&history_json) {
+                Ok(sessions) => sessions,
+                Err(err) => {
+                    device.push_config_file(OsStr::new(SYNC_HISTORY_CORRUPTED_FILE), &history_json)?;
+                    warn(format!("The sync history is corrupted ({}). It has been moved to {}, and a new history is started", err, SYNC_HISTORY_CORRUPTED_FILE));
+                    Vec::new()
+                },
+            }
+        },
+    };
+    sessions.push(session);
+    let n_sessions = sessions.len();
+    if n_sessions > MAX_HISTORY_LENGTH {
+        sessions.drain(..n_sessions - MAX_HISTORY_LENGTH);
+    }
+
+    let history_json = serde_json::to_vec(&sessions).map_err(|err| format!("Unable to serialize the sync history: {}", err))?;
+    device.push_config_file(OsStr::new(SYNC_HISTORY_FILE), &history_json)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::source::{PlaylistId, TrackId};
+
+    #[test]
+    fn playlist_change() {
+        let update = PlaylistUpdate{
+            name: "Road trip".to_string(),
+            id: PlaylistId::Smart("Road trip".to_string()),
+            current_content: vec![TrackId(1), TrackId(2), TrackId(3)],
+            new_content: vec![TrackId(3), TrackId(1), TrackId(4), TrackId(5)],
+        };
+        let change = PlaylistChange::from(&update);
+        assert_eq!(change.added_tracks, 2);
+        assert_eq!(change.removed_tracks, 1);
+    }
+}
@@ -9,6 +9,7 @@ use time::OffsetDateTime;
 use crate::source::{PlaylistId, TrackId};
 use crate::source::Rating;
 use super::PlaylistsSet;
+use super::utils::FileData;
 
 /// Some info about a sync
 ///
@@ -20,16 +21,77 @@ pub struct SyncInfo {
     hostname: String,
     /// The timestamp of this sync
     timestamp: time::OffsetDateTime,
-    common_ancestor: PathBuf,
-    song_data: HashMap<PathBuf, (TrackId, Rating)>,
+    /// The folder of each source (indexed by name) the paths on the device are relative to
+    #[serde(default)]
+    roots: HashMap<String, PathBuf>,
+    song_data: HashMap<PathBuf, SongData>,
     playlists: PlaylistsSet,
 }
 
+/// What we know about a song that has been synced
+///
+/// Older versions of StarSync stored this as an `(id, rating)` tuple. These still deserialize fine (with unknown file properties).
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct SongData {
+    pub id: TrackId,
+    pub rating: Rating,
+    /// Size (in bytes) of the source file
+    #[serde(default)]
+    pub file_size: Option<usize>,
+    /// Last modification time of the source file
+    #[serde(default)]
+    pub modified: Option<OffsetDateTime>,
+    /// The transcoding profile (e.g. `opus@128k`) the file on the device has been converted with, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub transcoding: Option<String>,
+    /// The name of the source this song comes from. This is `None` for songs synced by older versions of StarSync, that come from the main source of the device
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+    /// The path of the file, relative to the root of its source (or absolute, in case it is not in this root), in case it differs from its path on the device (apart from the extension of transcoded files).
+    ///
+    /// This happens when the device has a layout (see [`crate::layout`]), or when the path of the file is not valid on the filesystem of the device (see [`crate::device::filesystem`])
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source_path: Option<PathBuf>,
+    /// How many times the database of Rockbox had counted this song as played, when its plays were last imported (see [`crate::device::rockbox`]).
+    ///
+    /// This is `None` in case this is unknown, e.g. because the device does not run Rockbox
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rockbox_play_count: Option<u32>,
+}
+
+/// What we know about a playlist that has been synced
+///
+/// Older versions of StarSync stored this as an `(id, tracks)` tuple. These still deserialize fine (with an unknown source).
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct SyncedPlaylist {
+    pub id: PlaylistId,
+    pub tracks: Vec<TrackId>,
+    /// The name of the source this playlist comes from. This is `None` for playlists synced by older versions of StarSync, that come from the main source of the device
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+}
+
+impl SongData {
+    /// Whether the source file has been modified since it was synced.
+    ///
+    /// This is based on the file size and modification date, and will return `false` when they were not recorded.<br/>
+    /// A file that is now converted with different transcoding settings also counts as modified.
+    pub fn has_changed(&self, current: &FileData) -> bool {
+        let size_changed = self.file_size.map(|size| size != current.file_size).unwrap_or(false);
+        let date_changed = match (self.modified, current.modified) {
+            (Some(previous), Some(current)) => previous != current,
+            _ => false,
+        };
+        let transcoding_changed = self.transcoding != current.transcoding.map(|profile| profile.to_string());
+        size_changed || date_changed || transcoding_changed
+    }
+}
+
 impl SyncInfo {
-    pub fn new(common_ancestor: PathBuf, song_data: HashMap<PathBuf, (TrackId, Rating)>, playlists: PlaylistsSet) -> Self {
+    pub fn new(roots: HashMap<String, PathBuf>, song_data: HashMap<PathBuf, SongData>, playlists: PlaylistsSet) -> Self {
         let hostname = crate::utils::current_hostname();
         let timestamp = OffsetDateTime::now_utc();
-        Self{ hostname, timestamp, common_ancestor, song_data, playlists }
+        Self{ hostname, timestamp, roots, song_data, playlists }
     }
 
     pub fn hostname(&self) -> &str {
@@ -41,34 +103,102 @@ impl SyncInfo {
     }
 
     pub fn id_for_relative_path(&self, relative_path: &Path) -> Option<TrackId> {
+        self.song_data_for_relative_path(relative_path).map(|data| data.id)
+    }
+
+    pub fn song_data_for_relative_path(&self, relative_path: &Path) -> Option<&SongData> {
         let lowercase_path = PathBuf::from(relative_path.to_string_lossy().to_lowercase());
-        self.song_data.get(&lowercase_path).map(|data| data.0)
+        self.song_data.get(&lowercase_path)
     }
 
     pub fn id_for_full_path(&self, path: &Path) -> Option<TrackId> {
-        let relative_path = path.strip_prefix(&self.common_ancestor).unwrap_or(path);
+        let relative_path = self.roots.values()
+            .find_map(|root| path.strip_prefix(root).ok())
+            .unwrap_or(path);
         let lowercase_path =  PathBuf::from(relative_path.to_string_lossy().to_lowercase());
-        self.song_data.get(&lowercase_path).map(|data| data.0)
+        self.song_data.get(&lowercase_path).map(|data| data.id)
     }
 
     pub fn rating_for_id(&self, needle: TrackId) -> Rating {
         self.song_data
-            .iter()
-            .find(|(_, (id, _))| *id == needle)
-            .and_then(|(_, (_, rating))| *rating)
+            .values()
+            .find(|data| data.id == needle)
+            .and_then(|data| data.rating)
+    }
+
+    /// The name of the source a song comes from, or `None` in case it comes from the main source (or is unknown)
+    pub fn source_for_id(&self, needle: TrackId) -> Option<&str> {
+        self.song_data
+            .values()
+            .find(|data| data.id == needle)
+            .and_then(|data| data.source.as_deref())
     }
 
-    pub fn path_for_id(&self, id: TrackId) -> Option<PathBuf> {
+    /// The path of a song in its source (see [`SongData::source_path`]). This is its path on the device, unless it has been laid out or sanitized
+    pub fn source_path_for_id(&self, id: TrackId) -> Option<PathBuf> {
         self.song_data.iter()
-            .find(|(_, (stored_id, _))| *stored_id == id)
-            .map(|(path, _)| path.clone())
+            .find(|(_, data)| data.id == id)
+            .map(|(path, data)| data.source_path.clone().unwrap_or_else(|| path.clone()))
+    }
+
+    /// What we know about every song, indexed by their (lowercased) relative paths on the device
+    pub fn song_data(&self) -> impl Iterator<Item = (&Path, &SongData)> {
+        self.song_data.iter().map(|(path, data)| (path.as_path(), data))
     }
 
-    pub fn playlist(&self, name: &str) -> Option<&(PlaylistId, Vec<TrackId>)> {
+    pub fn playlist(&self, name: &str) -> Option<&SyncedPlaylist> {
         self.playlists.get(name)
     }
 
+    /// Every playlist that has been synced, along with the name of its file on the device
+    pub fn playlists(&self) -> impl Iterator<Item = (&str, &SyncedPlaylist)> {
+        self.playlists.iter().map(|(file_name, playlist)| (file_name.as_str(), playlist))
+    }
+
     pub fn has_playlist_file_name<S: AsRef<str>>(&self, needle: S) -> bool {
         self.playlists.iter().any(|(file_name, _)| file_name == needle.as_ref())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::num::NonZeroU8;
+    use crate::transcode::{Codec, Profile};
+
+    #[test]
+    fn legacy_song_data() {
+        let song_data: SongData = serde_json::from_str(r#"["0x4d2", 3]"#).unwrap();
+        assert_eq!(song_data, SongData{ id: TrackId(1234), rating: NonZeroU8::new(3), file_size: None, modified: None, transcoding: None, source: None, source_path: None, rockbox_play_count: None });
+    }
+
+    #[test]
+    fn legacy_synced_playlist() {
+        let playlist: SyncedPlaylist = serde_json::from_str(r#"[{"Name": "Road trip"}, ["0x1", "0x2"]]"#).unwrap();
+        assert_eq!(playlist, SyncedPlaylist{ id: PlaylistId::Name("Road trip".to_string()), tracks: vec![TrackId(1), TrackId(2)], source: None });
+    }
+
+    #[test]
+    fn changed_song_data() {
+        let modified = OffsetDateTime::from_unix_timestamp(1_600_000_000).unwrap();
+        let previous = SongData{ id: TrackId(1), rating: None, file_size: Some(1000), modified: Some(modified), transcoding: None, source: None, source_path: None, rockbox_play_count: None };
+        let file_data = |file_size, modified| FileData{ file_size, id: TrackId(1), rating: None, modified, source_path: PathBuf::from("/music/song.flac"), transcoding: None, source: "rhythmbox".to_string(), tags: None };
+
+        let same = file_data(1000, Some(modified));
+        assert!(previous.has_changed(&same) == false);
+
+        let retagged = file_data(1000, Some(modified + time::Duration::SECOND));
+        assert!(previous.has_changed(&retagged));
+
+        let reencoded = file_data(900, None);
+        assert!(previous.has_changed(&reencoded));
+
+        let legacy = SongData{ id: TrackId(1), rating: None, file_size: None, modified: None, transcoding: None, source: None, source_path: None, rockbox_play_count: None };
+        assert!(legacy.has_changed(&reencoded) == false);
+
+        let transcoded = FileData{ transcoding: Some(Profile{ codec: Codec::Opus, bitrate_kbps: 128 }), ..file_data(1000, Some(modified)) };
+        assert!(previous.has_changed(&transcoded));
+        let previously_transcoded = SongData{ transcoding: Some("opus@128k".to_string()), ..previous };
+        assert!(previously_transcoded.has_changed(&transcoded) == false);
+    }
+}
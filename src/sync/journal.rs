@@ -0,0 +1,361 @@
+//! Keeping track of what a sync is doing on the device, so that an interrupted sync can be resumed or rolled back
+//!
+//! Before touching the device, a sync writes the list of all the operations it plans to do into a journal, stored in
+//! the config folder of the device. A small progress file is updated every time an operation is completed.<br/>
+//! Once everything is done (including writing the new sync info), the journal is removed.
+//!
+//! So, whenever a journal is found on the device, this means the previous sync has not completed (e.g. the cable has
+//! been pulled, or the computer went to sleep), and the device may be in a half-updated state.
+
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::error::Error;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+
+use crate::device::{Device, SYNC_JOURNAL_CORRUPTED_FILE, SYNC_JOURNAL_FILE, SYNC_JOURNAL_PROGRESS_CORRUPTED_FILE, SYNC_JOURNAL_PROGRESS_FILE};
+use crate::device::rockbox::CHANGELOG_FILE;
+use crate::transcode::{Encoder, Profile};
+use super::{status, SyncError, SyncInfo, SyncPlan};
+use super::status::{Message, Progress};
+use super::utils::{ActualPlaylistKind, FileSet};
+
+/// The plan of a sync, as stored on the device while this sync is running
+#[derive(Serialize, Deserialize)]
+pub struct Journal {
+    /// The hostname of the computer the sync has been started on
+    hostname: String,
+    /// When the sync has been started
+    timestamp: OffsetDateTime,
+    operations: Vec<Operation>,
+    /// The playlist files that were on the device before the sync started (file name → content), so that they can be restored
+    previous_playlists: HashMap<String, String>,
+    /// The changelog of the Rockbox database before the sync started, in case the sync replaces it
+    #[serde(default)]
+    previous_rockbox_changelog: Option<String>,
+    /// The info to write on the device once every operation is done
+    sync_info: SyncInfo,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "operation", rename_all = "snake_case")]
+enum Operation {
+    RemoveFile{ path: PathBuf },
+    PushFile{ path: PathBuf, source_path: PathBuf, size: usize, transcoding: Option<Profile> },
+    UpdateFile{ path: PathBuf, source_path: PathBuf, size: usize, transcoding: Option<Profile> },
+    RemovePlaylist{ name: String },
+    PushPlaylist{ name: String, content: String },
+    /// Replace the changelog of the Rockbox database (see [`crate::device::rockbox`])
+    PushRockboxChangelog{ content: String },
+}
+
+impl Journal {
+    /// Build the journal of a sync, and write it into the device
+    pub fn create(device: &dyn Device, plan: &SyncPlan, file_set: &FileSet, playlists: Vec<(String, String)>, rockbox_changelog: Option<String>, sync_info: SyncInfo) -> Result<Self, Box<dyn Error>> {
+        let file_operation = |path: &PathBuf, is_update: bool| {
+            let file_data = file_set.files_data.get(path)?;
+            let (path, source_path, size, transcoding) = (path.clone(), file_data.source_path.clone(), file_data.file_size, file_data.transcoding);
+            Some(match is_update {
+                false => Operation::PushFile{ path, source_path, size, transcoding },
+                true => Operation::UpdateFile{ path, source_path, size, transcoding },
+            })
+        };
+
+        let mut operations: Vec<Operation> = plan.files_to_remove.iter().map(|f| Operation::RemoveFile{ path: f.path.clone() }).collect();
+        operations.extend(plan.files_to_push.iter().filter_map(|f| file_operation(&f.path, false)));
+        operations.extend(plan.files_to_update.iter().filter_map(|f| file_operation(&f.path, true)));
+        operations.extend(plan.playlists_to_remove.iter().map(|name| Operation::RemovePlaylist{ name: name.clone() }));
+        operations.extend(playlists.into_iter().map(|(name, content)| Operation::PushPlaylist{ name, content }));
+        let previous_rockbox_changelog = match rockbox_changelog {
+            None => None,
+            Some(content) => {
+                operations.push(Operation::PushRockboxChangelog{ content });
+                rockbox_changelog_file(device)?
+            },
+        };
+
+        let journal = Self{
+            hostname: crate::utils::current_hostname(),
+            timestamp: OffsetDateTime::now_utc(),
+            operations,
+            previous_playlists: playlist_files(device)?,
+            previous_rockbox_changelog,
+            sync_info,
+        };
+
+        let journal_json = serde_json::to_vec(&journal).map_err(|err| format!("Unable to serialize the sync journal: {}", err))?;
+        device.remove_config_file(SYNC_JOURNAL_PROGRESS_FILE)?;
+        device.push_config_file(OsStr::new(SYNC_JOURNAL_FILE), &journal_json)?;
+        Ok(journal)
+    }
+
+    /// Read the journal left on the device by an unfinished sync (if any), along with the number of operations that were completed
+    pub fn from_device(device: &dyn Device) -> Result<Option<(Self, usize)>, Box<dyn Error>> {
+        let reader = match device.config_file(SYNC_JOURNAL_FILE) {
+            None => return Ok(None),
+            Some(reader) => reader,
+        };
+        let journal: Self = serde_json::from_reader(reader)?;
+
+        let n_done = match device.config_file(SYNC_JOURNAL_PROGRESS_FILE) {
+            // The sync has been interrupted before the first operation was completed
+            None => 0,
+            Some(mut reader) => {
+                let mut progress = String::new();
+                reader.read_to_string(&mut progress)?;
+                progress.trim().parse()?
+            },
+        };
+
+        Ok(Some((journal, n_done)))
+    }
+
+    /// Whether there is a journal on the device
+    pub fn exists(device: &dyn Device) -> bool {
+        device.config_file(SYNC_JOURNAL_FILE).is_some()
+    }
+
+    /// Remove the journal from the device
+    pub fn remove(device: &dyn Device) -> Result<(), Box<dyn Error>> {
+        device.remove_config_file(SYNC_JOURNAL_FILE)?;
+        device.remove_config_file(SYNC_JOURNAL_PROGRESS_FILE)
+    }
+
+    /// Move an unreadable journal (and its progress file) out of the way, so that it can be inspected, and so that a new sync can write its own journal.
+    ///
+    /// This returns the names of the files they have been moved to.
+    pub fn set_aside(device: &dyn Device) -> Result<Vec<&'static str>, Box<dyn Error>> {
+        let mut moved_to = Vec::new();
+        for (file_name, corrupted_file_name) in [(SYNC_JOURNAL_FILE, SYNC_JOURNAL_CORRUPTED_FILE), (SYNC_JOURNAL_PROGRESS_FILE, SYNC_JOURNAL_PROGRESS_CORRUPTED_FILE)] {
+            if let Some(mut reader) = device.config_file(file_name) {
+                let mut content = Vec::new();
+                reader.read_to_end(&mut content)?;
+                device.push_config_file(OsStr::new(corrupted_file_name), &content)?;
+                moved_to.push(corrupted_file_name);
+            }
+        }
+        Self::remove(device)?;
+        Ok(moved_to)
+    }
+
+    pub fn hostname(&self) -> &str {
+        &self.hostname
+    }
+
+    pub fn timestamp(&self) -> &OffsetDateTime {
+        &self.timestamp
+    }
+
+    /// The number of operations this sync had to perform
+    pub fn n_operations(&self) -> usize {
+        self.operations.len()
+    }
+
+    /// Whether every operation, as well as writing the sync info, has been done.
+    ///
+    /// In this case, only removing the journal has been interrupted.
+    pub fn is_finished(&self, n_done: usize) -> bool {
+        n_done > self.operations.len()
+    }
+
+    /// Perform the operations of this journal, starting at the `n_done`th one, then write the sync info and remove the journal.
+    ///
+    /// The first operation (which may have been interrupted half-way) is done again.
+    pub fn run(&self, status_tx: &status::Sender, device: &dyn Device, encoder: &dyn Encoder, n_done: usize) -> Result<(), SyncError> {
+        let music_folder = device.music_folder().ok_or(SyncError::DeviceReadError)?;
+
+        let file_sizes = |ops: &[Operation]| ops.iter()
+            .filter_map(|op| match op {
+                Operation::PushFile{ size, .. } | Operation::UpdateFile{ size, .. } => Some(*size),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        let all_sizes = file_sizes(&self.operations);
+        let sizes_so_far = file_sizes(&self.operations[..n_done.min(self.operations.len())]);
+        let n_files = all_sizes.len();
+        let total_size = all_sizes.iter().sum();
+        let mut i_file = sizes_so_far.len();
+        let mut size_so_far = sizes_so_far.iter().sum();
+
+        let mut current_step = None;
+        let mut record_progress = true;
+        for (i_op, operation) in self.operations.iter().enumerate().skip(n_done) {
+            let step = match operation {
+                Operation::RemoveFile{ .. } | Operation::PushFile{ .. } | Operation::UpdateFile{ .. } => Progress::SyncingFiles,
+                Operation::PushPlaylist{ name, .. } if ActualPlaylistKind::classify(name).stars().is_some() => Progress::PushingRatings,
+                Operation::PushRockboxChangelog{ .. } => Progress::PushingRatings,
+                Operation::RemovePlaylist{ .. } | Operation::PushPlaylist{ .. } => Progress::PushingPlaylists,
+            };
+            if current_step != Some(step) {
+                status_tx.send_progress(step);
+                current_step = Some(step);
+            }
+
+            match operation {
+                Operation::RemoveFile{ path } => {
+                    status_tx.send(Message::RemovingFile(path.display().to_string()));
+                    if let Err(err) = music_folder
+                        .file_at(path)
+                        .and_then(|mut f| f.delete())
+                    {
+                        status_tx.send_warning(format!("Unable to remove file at {}: {}", path.display(), err))
+                    }
+                },
+                Operation::PushFile{ path, source_path, size, transcoding } | Operation::UpdateFile{ path, source_path, size, transcoding } => {
+                    i_file += 1;
+                    let (display_path, file_size) = (path.display().to_string(), *size);
+                    if let Operation::UpdateFile{ .. } = operation {
+                        status_tx.send(Message::UpdatingFile{ path: display_path, file_size, size_so_far, total_size, n_files, i_file });
+                    } else {
+                        status_tx.send(Message::PushingFile{ path: display_path, file_size, size_so_far, total_size, n_files, i_file });
+                    }
+                    size_so_far += file_size;
+
+                    if let Err(err) = push_music_file(device, encoder, source_path, transcoding.as_ref(), path) {
+                        status_tx.send_warning(format!("Unable to push file {}: {}. Trying again...", path.display(), err));
+                        if let Err(err) = push_music_file(device, encoder, source_path, transcoding.as_ref(), path) {
+                            status_tx.send_warning(format!("Unable to push file {}: {}. Giving up.", path.display(), err));
+                        }
+                    }
+                },
+                Operation::RemovePlaylist{ name } => {
+                    status_tx.send(Message::RemovingPlaylist(name.clone()));
+                    if let Err(err) = device.starsync_folder()
+                        .ok_or_else(|| "Missing StarSync folder".into())
+                        .and_then(|folder| folder.file_at(Path::new(name)))
+                        .and_then(|mut f| f.delete())
+                    {
+                        status_tx.send_warning(format!("Unable to delete {}: {}", name, err));
+                    }
+                },
+                Operation::PushPlaylist{ name, content } => {
+                    status_tx.send(Message::PushingPlaylist(name.clone()));
+                    if let Err(err) = device.push_playlist(content, OsStr::new(name)) {
+                        status_tx.send_warning(format!("Unable to push m3u file for playlist '{}': {}", name, err));
+                    }
+                },
+                Operation::PushRockboxChangelog{ content } => {
+                    status_tx.send_info("Writing the ratings into the changelog of the Rockbox database");
+                    if let Err(err) = device.push_rockbox_file(OsStr::new(CHANGELOG_FILE), content.as_bytes()) {
+                        status_tx.send_warning(format!("Unable to write {}: {}", CHANGELOG_FILE, err));
+                    }
+                },
+            }
+
+            if record_progress {
+                if let Err(err) = record_done_operations(device, i_op + 1) {
+                    // Resuming this sync will perform more operations than needed, that's not a big deal
+                    status_tx.send_warning(format!("Unable to record the progress of the sync into the device: {}", err));
+                    record_progress = false;
+                }
+            }
+        }
+
+        // TODO: remove empty folders
+
+        status_tx.send_progress(Progress::UpdatingSyncInfo);
+        device.push_sync_infos(&self.sync_info)
+            .map_err(|err| SyncError::UpdateSyncInfoFailed(err.to_string()))?;
+        // In case removing the journal fails, the next sync will know there is nothing left to do
+        let _ = record_done_operations(device, self.operations.len() + 1);
+
+        if let Err(err) = Self::remove(device) {
+            status_tx.send_warning(format!("Unable to remove the sync journal from the device: {}", err));
+        }
+        Ok(())
+    }
+
+    /// Undo what can be undone from this (interrupted) sync, then remove the journal.
+    ///
+    /// Files that this sync pushed are removed, and the playlists are restored as they were before.
+    /// The sync info has not been updated by the interrupted sync, so that files that have been updated or removed will be
+    /// pushed again by the next sync.<br/>
+    /// Changes that have been reverse-synced into the source are kept.
+    pub fn roll_back(&self, status_tx: &status::Sender, device: &dyn Device) -> Result<(), SyncError> {
+        let music_folder = device.music_folder().ok_or(SyncError::DeviceReadError)?;
+        let starsync_folder = device.starsync_folder().ok_or(SyncError::DeviceReadError)?;
+
+        // Files that were pushed are new to the device (updated ones are in `Operation::UpdateFile`), so that they can safely be removed
+        for operation in &self.operations {
+            if let Operation::PushFile{ path, .. } = operation {
+                if let Ok(mut file) = music_folder.file_at(path) {
+                    status_tx.send(Message::RemovingFile(path.display().to_string()));
+                    if let Err(err) = file.delete() {
+                        status_tx.send_warning(format!("Unable to remove file at {}: {}", path.display(), err));
+                    }
+                }
+            }
+        }
+
+        // Restore the previous playlists
+        for mut file in starsync_folder.files().map_err(|_| SyncError::DeviceReadError)? {
+            if file.path().extension() == Some(OsStr::new("m3u")) {
+                status_tx.send(Message::RemovingPlaylist(file.path().display().to_string()));
+                if let Err(err) = file.delete() {
+                    status_tx.send_warning(format!("Unable to delete {}: {}", file.path().display(), err));
+                }
+            }
+        }
+        for (name, content) in &self.previous_playlists {
+            status_tx.send(Message::PushingPlaylist(name.clone()));
+            if let Err(err) = device.push_playlist(content, OsStr::new(name)) {
+                status_tx.send_warning(format!("Unable to restore playlist '{}': {}", name, err));
+            }
+        }
+
+        // Restore the changelog of the Rockbox database
+        if self.operations.iter().any(|operation| matches!(operation, Operation::PushRockboxChangelog{ .. })) {
+            let content = self.previous_rockbox_changelog.as_deref().unwrap_or_default();
+            if let Err(err) = device.push_rockbox_file(OsStr::new(CHANGELOG_FILE), content.as_bytes()) {
+                status_tx.send_warning(format!("Unable to restore {}: {}", CHANGELOG_FILE, err));
+            }
+        }
+
+        Self::remove(device).map_err(|err| SyncError::RecoveringInterruptedSyncFailed(err.to_string()))
+    }
+}
+
+fn record_done_operations(device: &dyn Device, n_done: usize) -> Result<(), Box<dyn Error>> {
+    device.push_config_file(OsStr::new(SYNC_JOURNAL_PROGRESS_FILE), n_done.to_string().as_bytes())
+}
+
+/// The content of every playlist file currently on the device
+fn playlist_files(device: &dyn Device) -> Result<HashMap<String, String>, Box<dyn Error>> {
+    let starsync_folder = device.starsync_folder().ok_or("Missing StarSync folder")?;
+    let mut playlists = HashMap::new();
+    for file in starsync_folder.files()? {
+        if file.path().extension() == Some(OsStr::new("m3u")) {
+            if let Some(file_name) = file.path().file_name() {
+                let mut content = String::new();
+                file.get_reader()?.read_to_string(&mut content)?;
+                playlists.insert(file_name.to_string_lossy().to_string(), content);
+            }
+        }
+    }
+    Ok(playlists)
+}
+
+/// The content of the changelog of the Rockbox database, in case there is one
+fn rockbox_changelog_file(device: &dyn Device) -> Result<Option<String>, Box<dyn Error>> {
+    let file = match device.rockbox_folder().and_then(|folder| folder.file_at(Path::new(CHANGELOG_FILE)).ok()) {
+        None => return Ok(None),
+        Some(file) => file,
+    };
+    let mut content = Vec::new();
+    file.get_reader()?.read_to_end(&mut content)?;
+    Ok(Some(String::from_utf8_lossy(&content).to_string()))
+}
+
+/// Push a music file into the device, converting it first if needed
+fn push_music_file(device: &dyn Device, encoder: &dyn Encoder, source_path: &Path, transcoding: Option<&Profile>, device_relative_path: &Path) -> Result<(), Box<dyn Error>> {
+    match transcoding {
+        None => device.push_music_file(source_path, device_relative_path),
+        Some(profile) => {
+            let transcoded = crate::transcode::transcode_to_temp_file(encoder, source_path, profile)?;
+            device.push_music_file(transcoded.path(), device_relative_path)
+        }
+    }
+}
@@ -0,0 +1,220 @@
+//! Three-way merge of playlists, that keeps track of the conflicts so that they can be resolved by the user
+
+use serde::{Deserialize, Serialize};
+
+use crate::source::{PlaylistId, TrackId};
+
+/// A playlist that has been edited on both the source and the device, in ways that cannot be merged automatically
+#[derive(Clone, Debug, Serialize)]
+pub struct PlaylistConflict {
+    /// The name of the source this playlist belongs to
+    pub source: String,
+    pub playlist_name: String,
+    /// The parts of the playlist that conflict. The playlist is reverse synced only once each of them has a resolution
+    pub hunks: Vec<ConflictHunk>,
+    /// The whole merged playlist, whose conflicting chunks are the hunks
+    #[serde(skip)]
+    pub(super) chunks: Vec<MergeChunk>,
+    #[serde(skip)]
+    pub(super) playlist_id: PlaylistId,
+    #[serde(skip)]
+    pub(super) current_content: Vec<TrackId>,
+}
+
+/// A part of a playlist that has been changed differently on the source and on the device
+#[derive(Clone, Debug, Serialize)]
+pub struct ConflictHunk {
+    /// This part of the playlist, as it was during the previous sync
+    pub ancestor: Vec<ConflictTrack>,
+    /// This part of the playlist, as it is now in the source
+    pub source: Vec<ConflictTrack>,
+    /// This part of the playlist, as it is now on the device
+    pub device: Vec<ConflictTrack>,
+    /// How to resolve this conflict
+    pub resolution: Option<HunkResolution>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ConflictTrack {
+    pub id: TrackId,
+    pub name: String,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HunkResolution {
+    /// Keep the tracks of the source
+    TakeSource,
+    /// Keep the tracks of the device
+    TakeDevice,
+    /// Keep the tracks of the source, followed by the tracks of the device that are not in the source
+    Concatenate,
+}
+
+/// A chunk of a merged playlist
+#[derive(Clone, Debug, PartialEq)]
+pub(super) enum MergeChunk {
+    /// Tracks that have been changed on (at most) one side, or identically on both sides
+    Merged(Vec<TrackId>),
+    Conflict{ ancestor: Vec<TrackId>, local: Vec<TrackId>, device: Vec<TrackId> },
+}
+
+impl ConflictHunk {
+    fn resolve(&self) -> Option<Vec<TrackId>> {
+        let ids = |tracks: &[ConflictTrack]| tracks.iter().map(|track| track.id).collect::<Vec<_>>();
+        match self.resolution? {
+            HunkResolution::TakeSource => Some(ids(&self.source)),
+            HunkResolution::TakeDevice => Some(ids(&self.device)),
+            HunkResolution::Concatenate => {
+                let mut tracks = ids(&self.source);
+                for track in &self.device {
+                    if tracks.contains(&track.id) == false {
+                        tracks.push(track.id);
+                    }
+                }
+                Some(tracks)
+            },
+        }
+    }
+}
+
+impl PlaylistConflict {
+    /// The merged content of the playlist, or `None` in case some hunks have not been resolved
+    pub(super) fn resolved_content(&self) -> Option<Vec<TrackId>> {
+        let mut hunks = self.hunks.iter();
+        let mut content = Vec::new();
+        for chunk in &self.chunks {
+            match chunk {
+                MergeChunk::Merged(tracks) => content.extend_from_slice(tracks),
+                MergeChunk::Conflict{ .. } => content.extend(hunks.next()?.resolve()?),
+            }
+        }
+        Some(content)
+    }
+}
+
+/// Merge the changes made to a playlist on the source (`local`) and on the device, since the previous sync (`ancestor`).
+///
+/// This is the usual diff3 algorithm: parts that have not changed on either side delimit chunks, and a chunk that changed on both sides (differently) is a conflict.
+pub(super) fn merge(ancestor: &[TrackId], local: &[TrackId], device: &[TrackId]) -> Vec<MergeChunk> {
+    let to_local = matching(ancestor, local);
+    let to_device = matching(ancestor, device);
+
+    let mut chunks = Vec::new();
+
+    let (mut i_ancestor, mut i_local, mut i_device) = (0, 0, 0);
+    loop {
+        // The next track that is unchanged on both sides
+        let stable = (i_ancestor..ancestor.len()).find_map(|i| Some((i, to_local[i]?, to_device[i]?)));
+        let (next_ancestor, next_local, next_device) = stable.unwrap_or((ancestor.len(), local.len(), device.len()));
+
+        if (next_ancestor, next_local, next_device) != (i_ancestor, i_local, i_device) {
+            let ancestor_part = &ancestor[i_ancestor..next_ancestor];
+            let local_part = &local[i_local..next_local];
+            let device_part = &device[i_device..next_device];
+            if local_part == ancestor_part {
+                push_merged(&mut chunks, device_part);
+            } else if device_part == ancestor_part || local_part == device_part {
+                push_merged(&mut chunks, local_part);
+            } else {
+                chunks.push(MergeChunk::Conflict{ ancestor: ancestor_part.to_vec(), local: local_part.to_vec(), device: device_part.to_vec() });
+            }
+        }
+
+        if stable.is_none() {
+            break;
+        }
+        push_merged(&mut chunks, &ancestor[next_ancestor..next_ancestor + 1]);
+        (i_ancestor, i_local, i_device) = (next_ancestor + 1, next_local + 1, next_device + 1);
+    }
+    chunks
+}
+
+/// Append tracks that do not conflict, to the last chunk if possible
+fn push_merged(chunks: &mut Vec<MergeChunk>, tracks: &[TrackId]) {
+    match chunks.last_mut() {
+        Some(MergeChunk::Merged(merged)) => merged.extend_from_slice(tracks),
+        _ => if tracks.is_empty() == false {
+            chunks.push(MergeChunk::Merged(tracks.to_vec()))
+        },
+    }
+}
+
+/// For each item of `a`, its index in `b`, according to their longest common subsequence
+fn matching(a: &[TrackId], b: &[TrackId]) -> Vec<Option<usize>> {
+    // lengths[i][j] is the length of the LCS of a[i..] and b[j..]
+    let mut lengths = vec![vec![0usize; b.len() + 1]; a.len() + 1];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            lengths[i][j] = if a[i] == b[j] {
+                lengths[i + 1][j + 1] + 1
+            } else {
+                lengths[i + 1][j].max(lengths[i][j + 1])
+            };
+        }
+    }
+
+    let mut matches = vec![None; a.len()];
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() && j < b.len() {
+        if a[i] == b[j] {
+            matches[i] = Some(j);
+            i += 1;
+            j += 1;
+        } else if lengths[i + 1][j] >= lengths[i][j + 1] {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+    matches
+}
+
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn ids(ids: &[u64]) -> Vec<TrackId> {
+        ids.iter().map(|id| TrackId(*id)).collect()
+    }
+
+    #[test]
+    fn merge_without_conflict() {
+        // A track added on the source, another one removed on the device
+        let chunks = merge(&ids(&[1, 2, 3, 4]), &ids(&[1, 2, 3, 4, 5]), &ids(&[1, 3, 4]));
+        assert_eq!(chunks, vec![MergeChunk::Merged(ids(&[1, 3, 4, 5]))]);
+    }
+
+    #[test]
+    fn merge_with_conflict() {
+        // The same part of the playlist has been reordered differently on both sides
+        let chunks = merge(&ids(&[1, 2, 3, 4, 5]), &ids(&[1, 3, 2, 4, 5, 6]), &ids(&[1, 2, 7, 4, 5]));
+        assert_eq!(chunks, vec![
+            MergeChunk::Merged(ids(&[1])),
+            MergeChunk::Conflict{ ancestor: ids(&[2, 3]), local: ids(&[3, 2]), device: ids(&[2, 7]) },
+            MergeChunk::Merged(ids(&[4, 5, 6])),
+        ]);
+
+        let track = |id| ConflictTrack{ id: TrackId(id), name: String::new() };
+        let mut conflict = PlaylistConflict{
+            source: "rhythmbox".to_string(),
+            playlist_name: "Road trip".to_string(),
+            hunks: vec![ConflictHunk{
+                ancestor: vec![track(2), track(3)],
+                source: vec![track(3), track(2)],
+                device: vec![track(2), track(7)],
+                resolution: None,
+            }],
+            chunks,
+            playlist_id: PlaylistId::Name("Road trip".to_string()),
+            current_content: ids(&[1, 3, 2, 4, 5, 6]),
+        };
+        assert_eq!(conflict.resolved_content(), None);
+        conflict.hunks[0].resolution = Some(HunkResolution::TakeDevice);
+        assert_eq!(conflict.resolved_content(), Some(ids(&[1, 2, 7, 4, 5, 6])));
+        conflict.hunks[0].resolution = Some(HunkResolution::Concatenate);
+        assert_eq!(conflict.resolved_content(), Some(ids(&[1, 3, 2, 7, 4, 5, 6])));
+    }
+}
@@ -9,28 +9,82 @@ use std::error::Error;
 use std::collections::{HashSet, HashMap};
 use std::sync::mpsc::{Sender, Receiver};
 use std::num::NonZeroU8;
+use std::cell::RefCell;
 
-use crate::device::{Device, Folder};
+use crate::device::{Device, Folder, SYNC_JOURNAL_CORRUPTED_FILE};
+use crate::device::filesystem::FileSystem;
 use crate::device::m3u::M3u;
-use crate::source::{PlaylistId, Rating, Source, TrackId};
-use crate::config::Config;
+use crate::device::rockbox::{Rockbox, Changelog, ChangelogEntry};
+use crate::source::{Playlist, PlaylistId, Rating, Source, Track, TrackId};
+use crate::smart_playlist::SmartPlaylist;
+use crate::layout::{Layout, TrackTags};
+use crate::config::{Config, PlaylistConfig};
+use crate::transcode::Encoder;
 use crate::utils::current_hostname;
 
+use time::OffsetDateTime;
+use humansize::format_size;
+use serde::{Deserialize, Serialize};
+
 pub mod status;
 use status::Message;
 use status::Progress;
 
 mod info;
-pub use info::SyncInfo;
+pub use info::{SyncInfo, SongData, SyncedPlaylist};
+
+mod journal;
+use journal::Journal;
+
+mod history;
+pub use history::{history, SyncSession, PlaylistChange, MAX_HISTORY_LENGTH};
+
+mod all_devices;
+pub use all_devices::{sync_all_devices, DeviceSyncOutcome};
+
+mod unattended;
+pub use unattended::auto_sync;
+
+mod renames;
+pub use renames::RenamedPlaylist;
+
+mod plays;
+use plays::{import_plays, import_rockbox_plays};
+
+mod merge;
+pub use merge::{PlaylistConflict, ConflictHunk, ConflictTrack, HunkResolution};
+use merge::MergeChunk;
+
+mod plan;
+pub use plan::{SyncPlan, PlannedFile, PlaylistUpdate, RatingUpdate, PlayUpdate, PendingChanges};
 
 mod utils;
 use utils::{FileSet, FileData, RequestedPlaylistKind, ActualPlaylistKind};
-use utils::{favorites_playlist_name, case_insensitive_difference};
+use utils::{favorites_playlist_name, case_insensitive_difference, place_files, PlacedFile, resolve_rating, RatingResolution};
 
 /// How many warnings have been issued
 pub type Warnings = usize;
 
-type PlaylistsSet = HashMap<String, (PlaylistId, Vec<TrackId>)>;
+type PlaylistsSet = HashMap<String, SyncedPlaylist>;
+
+/// The scans of the playlists of the sources, indexed by the source name, the playlist config, whether computed ratings are used and whether the tags of the tracks are read
+type ScanCache = HashMap<(String, PlaylistConfig, bool, bool), PlaylistScan>;
+
+/// What is needed from a playlist of a source to sync it
+#[derive(Clone)]
+struct PlaylistScan {
+    id: PlaylistId,
+    file_name: String,
+    /// The tracks of the playlist (along with their names), in the order of the playlist
+    tracks: Vec<(String, FileData)>,
+}
+
+/// Sources that can be shared between the syncs of several devices, so that they are opened and scanned only once
+#[derive(Default)]
+pub struct SharedSources {
+    sources: HashMap<String, Box<dyn Source>>,
+    scans: ScanCache,
+}
 
 #[derive(thiserror::Error, Debug)]
 pub enum SyncError {
@@ -42,6 +96,8 @@ pub enum SyncError {
     DeviceReadError,
     #[error("This device is not inited")]
     NotInited,
+    #[error("The info about the previous sync is corrupted, and there is no backup of them: {0}")]
+    CorruptedSyncInfo(String),
     #[error("Some sanity checks have failed")]
     SanityChecks,
     #[error("Scanning the computer for songs has failed: {0}")]
@@ -52,6 +108,12 @@ pub enum SyncError {
     PushingPlaylistsFailed(String),
     #[error("Pushing info about the current sync session into the device has failed: {0}")]
     UpdateSyncInfoFailed(String),
+    #[error("Writing the sync journal into the device has failed: {0}")]
+    WritingJournalFailed(String),
+    #[error("Resuming or rolling back the interrupted sync has failed: {0}")]
+    RecoveringInterruptedSyncFailed(String),
+    #[error("Automatic syncs are not enabled for this device")]
+    AutoSyncDisabled,
     // TODO: this could be supported after all, we'll just have to add one level of folders, with arbitrary names, one for each set of common ancestors
     #[error("Files have no common ancestor, there is no way to know how they should be saved into the device")]
     NoCommonAncestor,
@@ -59,18 +121,35 @@ pub enum SyncError {
 
 pub struct SyncManager {
     device: Box<dyn Device>,
-    source: Box<dyn Source>,
+    /// The sources of the device, in the order of its config
+    sources: Vec<Box<dyn Source>>,
     config: Config,
     previous_sync_infos: Option<SyncInfo>,
+    /// The journal of a previous sync that has been interrupted, along with the number of operations it completed
+    interrupted_sync: Option<(Journal, usize)>,
+    /// Whether a previous sync has been interrupted, but its journal (or its progress) cannot be read, so that it can be neither resumed nor rolled back
+    unreadable_journal: bool,
+    /// Used to convert files, in case the config requires it
+    encoder: Box<dyn Encoder>,
+    /// The playlists of the sources that have been scanned already
+    scans: RefCell<ScanCache>,
 }
 
 impl SyncManager {
     /// Initiate a sync with a given device.
     ///
-    /// This will fetch the config stored on this device
+    /// This will fetch the config stored on this device.<br/>
+    /// In case a previous sync has been interrupted, it will be offered to resume it or to roll it back
+    /// (see [`SyncValidator::interrupted_sync`]).
     pub fn with_device(device_name: &str) -> Result<Self, SyncError> {
-        // Get the device
         let device = crate::device::get(device_name).ok_or_else(|| SyncError::DeviceNotFound(device_name.to_string()))?;
+        Self::from_device(device, &mut SharedSources::default())
+    }
+
+    /// Initiate a sync with a device, using the sources (and the scans of their playlists) of a previous sync when possible.
+    ///
+    /// See [`Self::release_sources`] to get them back once this sync is over.
+    pub fn from_device(device: Box<dyn Device>, shared_sources: &mut SharedSources) -> Result<Self, SyncError> {
         if device.starsync_folder().is_none() {
             return Err(SyncError::NotInited);
         }
@@ -79,18 +158,65 @@ impl SyncManager {
         let config = device.config().ok_or(SyncError::NotInited)?;
 
         // Get info from the latest sync
-        let latest_info = device.previous_sync_infos();
+        let (latest_info, _) = previous_sync_info(device.as_ref(), |warning| log::warn!("{}", warning))?;
+
+        // Has the latest sync been interrupted?
+        let mut unreadable_journal = false;
+        let interrupted_sync = match Journal::from_device(device.as_ref()) {
+            Err(err) => {
+                log::warn!("A previous sync has been interrupted, but its journal is unreadable ({}). It will be moved to {} by the next sync", err, SYNC_JOURNAL_CORRUPTED_FILE);
+                unreadable_journal = true;
+                None
+            },
+            Ok(Some((journal, n_done))) if journal.is_finished(n_done) => {
+                // Only the removal of the journal has been interrupted
+                if let Err(err) = Journal::remove(device.as_ref()) {
+                    log::warn!("Unable to remove the journal of the previous sync: {}", err);
+                }
+                None
+            },
+            Ok(interrupted_sync) => interrupted_sync,
+        };
 
-        Self::with_options(device, config, latest_info)
+        Self::with_options(device, config, latest_info, interrupted_sync, unreadable_journal, shared_sources)
     }
 
     /// Initiate a sync with a given device, using a specific config
-    fn with_options(device: Box<dyn Device>, config: Config, previous_sync_infos: Option<SyncInfo>) -> Result<Self, SyncError> {
-        // Get the source
-        let source_name = config.source();
-        let source = crate::source::get(source_name).ok_or_else(|| SyncError::SourceNotFound(source_name.to_string()))?;
+    fn with_options(device: Box<dyn Device>, config: Config, previous_sync_infos: Option<SyncInfo>, interrupted_sync: Option<(Journal, usize)>, unreadable_journal: bool, shared_sources: &mut SharedSources) -> Result<Self, SyncError> {
+        // Get the sources
+        let sources = config.source_names()
+            .map(|source_name| shared_sources.sources
+                .remove(source_name)
+                .or_else(|| crate::source::get(source_name))
+                .ok_or_else(|| SyncError::SourceNotFound(source_name.to_string()))
+            )
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let encoder = crate::transcode::encoder_from_config(&config.transcoding().map(|t| t.encoder.clone()).unwrap_or_default());
+        let scans = RefCell::new(std::mem::take(&mut shared_sources.scans));
+
+        Ok( Self{device, sources, config, previous_sync_infos, interrupted_sync, unreadable_journal, encoder, scans} )
+    }
+
+    /// Give back the sources used by this sync (and the scans of their playlists), so that the sync of another device can use them
+    pub fn release_sources(self, shared_sources: &mut SharedSources) {
+        for source in self.sources {
+            shared_sources.sources.insert(source.name().to_string(), source);
+        }
+        shared_sources.scans = self.scans.into_inner();
+    }
+
+    pub fn device_name(&self) -> String {
+        self.device.name()
+    }
 
-        Ok( Self{device, source, config, previous_sync_infos} )
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Use a custom encoder to convert files (instead of the one set in the config)
+    pub fn set_encoder(&mut self, encoder: Box<dyn Encoder>) {
+        self.encoder = encoder;
     }
 
     /// Perform some sanity check, have the user review them, and run the sync
@@ -118,80 +244,401 @@ impl SyncManager {
         outbound: Sender<SyncValidator>,
         inbound: Receiver<SyncValidator>
     ) -> Result<Warnings, SyncError> {
-        let validator = SyncValidator::build(self.previous_sync_infos.as_ref());
+        self.run(&status_tx, &outbound, &inbound)?;
+        Ok(status_tx.warnings_count())
+    }
+
+    /// Run the sync (see [`Self::start_sync`]), and return the changes it has performed
+    fn run(&self, status_tx: &status::Sender, outbound: &Sender<SyncValidator>, inbound: &Receiver<SyncValidator>) -> Result<SyncPlan, SyncError> {
+        let started = OffsetDateTime::now_utc();
+
+        // Renamed playlists are expected to be followed. In case some of them are not, files will be listed again
+        let renamed_playlists = renames::renamed_playlists(&self.sources, &self.config, self.previous_sync_infos.as_ref());
+        let n_renamed_playlists = renamed_playlists.len();
+        let expected_config = renames::follow_renames(&self.config, &renamed_playlists);
+
+        // Scan the source now, to check the device is large enough
+        let file_set = match required_files(status_tx, &self.sources, &expected_config, self.device.file_system(), &mut self.scans.borrow_mut()) {
+            Err(err) => {
+                // This will be tried again (and reported) during the sync
+                log::info!("Unable to list files to sync: {err}");
+                None
+            },
+            Ok(file_set) => Some(file_set),
+        };
+        let missing_space = file_set.as_ref().and_then(|fs| {
+            let free_space = self.device.free_space()?;
+            let required_space = required_space(fs, self.previous_sync_infos.as_ref());
+            if required_space > free_space as i64 {
+                Some((required_space as u64, free_space))
+            } else {
+                None
+            }
+        });
+
+        let validator = SyncValidator::build(self.device.name(), self.previous_sync_infos.as_ref(), missing_space, self.interrupted_sync.as_ref(), renamed_playlists);
         outbound.send(validator).expect("transmission to be possible");
 
         let acknowledged_validator = inbound.recv().expect("sender end not to disconnect");
-        if acknowledged_validator.is_valid() {
-            self.sync_inner(&status_tx)?;
-            Ok(status_tx.warnings_count())
+        if acknowledged_validator.is_valid() == false {
+            return Err(SyncError::SanityChecks);
+        }
+
+        // Follow the playlists that have been renamed in the source
+        let renamed_playlists: Vec<RenamedPlaylist> = acknowledged_validator.renamed_playlists.iter()
+            .filter(|renamed| renamed.follow)
+            .cloned()
+            .collect();
+        let (config, file_set) = if renamed_playlists.len() == n_renamed_playlists {
+            (expected_config, file_set)
         } else {
-            Err(SyncError::SanityChecks)
+            (renames::follow_renames(&self.config, &renamed_playlists), None)
+        };
+        if renamed_playlists.is_empty() == false {
+            for renamed in &renamed_playlists {
+                status_tx.send_info(format!("Playlist '{}' has been renamed into '{}', it will now be synced under this name", renamed.old_name, renamed.new_name));
+            }
+            if let Err(err) = self.device.push_config(&config) {
+                status_tx.send_warning(format!("Unable to update the config of the device with the renamed playlists: {}", err));
+            }
+        }
+
+        // Bring the device back to a consistent state before syncing it again
+        if let (Some((journal, n_done)), Some(InterruptedSync{ action: Some(action), .. })) = (&self.interrupted_sync, &acknowledged_validator.interrupted_sync) {
+            match action {
+                InterruptedSyncAction::Resume => {
+                    status_tx.send_progress(Progress::ResumingInterruptedSync);
+                    journal.run(status_tx, self.device.as_ref(), self.encoder.as_ref(), *n_done)?;
+                },
+                InterruptedSyncAction::RollBack => {
+                    status_tx.send_progress(Progress::RollingBackInterruptedSync);
+                    journal.roll_back(status_tx, self.device.as_ref())?;
+                },
+            }
         }
+
+        self.sync_inner(status_tx, &config, &renamed_playlists, Some((outbound, inbound)), file_set, started)
     }
 
-    /* not pub, see `start_sync` instead */ fn sync_inner (&self, status_tx: &status::Sender) -> Result<(), SyncError> {
+    /// Run the same steps as a sync, but without writing anything to the device nor to the source.
+    ///
+    /// This returns the list of changes a sync would perform.<br/>
+    /// Like [`Self::start_sync`], this should be called on the thread that created this `SyncManager`.
+    pub fn plan(&self, status_tx: status::Sender) -> Result<SyncPlan, SyncError> {
+        for renamed in renames::renamed_playlists(&self.sources, &self.config, self.previous_sync_infos.as_ref()) {
+            status_tx.send_info(format!("Playlist '{}' seems to have been renamed into '{}'. The next sync will offer to keep syncing it under this name", renamed.old_name, renamed.new_name));
+        }
+        self.sync_inner(&status_tx, &self.config, &[], None, None, OffsetDateTime::now_utc())
+    }
+
+    /// Quickly summarize what the next sync would do.
+    ///
+    /// Unlike [`Self::plan`], this does not compute how the changes made on the device would be merged into the source.
+    /// It only tells which playlists and ratings have been edited on the device since the last sync.
+    pub fn pending_changes(&self, status_tx: status::Sender) -> Result<PendingChanges, SyncError> {
+        let status_tx = &status_tx;
+        let (previous_sync_info, is_backup) = previous_sync_info(self.device.as_ref(), |warning| status_tx.send_warning(warning))?;
+        let files_on_device = files_on_device(status_tx, self.device.as_ref())?;
+
+        let file_set = required_files(status_tx, &self.sources, &self.config, self.device.file_system(), &mut self.scans.borrow_mut())
+            .map_err(|err| SyncError::SongScanningFailed(err.to_string()))?;
+        let mut plan = SyncPlan::default();
+        plan_files(&file_set, &files_on_device, &previous_sync_info, self.device.as_ref(), &mut plan)?;
+
+        let mut pending = PendingChanges{
+            files_to_push: plan.files_to_push.len(),
+            files_to_update: plan.files_to_update.len(),
+            files_to_remove: plan.files_to_remove.len(),
+            bytes_to_push: plan.size_to_push(),
+            bytes_to_remove: plan.size_to_remove(),
+            ..Default::default()
+        };
+
+        let psi = match previous_sync_info {
+            None => return Ok(pending),
+            Some(psi) => psi,
+        };
+        if Journal::exists(self.device.as_ref()) {
+            // The device may be half-updated, there is no way to tell what the user has changed
+            status_tx.send_warning("A previous sync has been interrupted. Changes made on the device cannot be listed");
+            return Ok(pending);
+        }
+        if is_backup {
+            // Changes made in the source since the sync of the backup would look like changes made on the device
+            status_tx.send_warning("The info about the previous sync is corrupted. Changes made on the device cannot be listed");
+            return Ok(pending);
+        }
+
+        let playlists = playlists_on_device(status_tx, RequestedPlaylistKind::Regular, self.device.as_ref(), &psi)?;
+        for (name, m3u) in playlists {
+            let device_song_ids = m3u_to_song_ids(status_tx, m3u, &psi);
+            if let Some(synced_playlist) = psi.playlist(&name) {
+                if device_song_ids != synced_playlist.tracks {
+                    pending.playlists_edited_on_device.push(name);
+                }
+            }
+        }
+        pending.playlists_edited_on_device.sort();
+
+        if self.config.include_ratings() {
+            match ratings_on_device(status_tx, &psi, &files_on_device, self.device.as_ref()) {
+                Err(err) => status_tx.send_warning(format!("Unable to read ratings from the device: {}", err)),
+                Ok(ratings) => {
+                    pending.ratings_edited_on_device = ratings.iter()
+                        .flat_map(|(rating, ids)| ids.iter().map(move |id| (*rating, *id)))
+                        .filter(|(rating, id)| psi.rating_for_id(*id) != *rating)
+                        .count();
+                },
+            }
+        }
+
+        Ok(pending)
+    }
+
+    /// Run the sync.
+    ///
+    /// In case the list of files to sync has already been built, it can be provided in `scanned_file_set`. It will be used unless reverse sync modifies the source.<br/>
+    /// `config` is the config of the device, once updated with the `renamed_playlists` that are followed.<br/>
+    /// `validator_channels` are used to ask the caller about conflicts. Without them, this is a dry run, that does not write anything.
+    /* not pub, see `start_sync` and `plan` instead */ fn sync_inner (&self, status_tx: &status::Sender, config: &Config, renamed_playlists: &[RenamedPlaylist], validator_channels: Option<ValidatorChannels>, scanned_file_set: Option<FileSet>, started: OffsetDateTime) -> Result<SyncPlan, SyncError> {
+        let dry_run = validator_channels.is_none();
         status_tx.send_progress(Progress::Started);
+        let mut plan = SyncPlan::default();
 
-        let previous_sync_info = self.device.previous_sync_infos();
+        let (previous_sync_info, is_backup) = previous_sync_info(self.device.as_ref(), |warning| status_tx.send_warning(warning))?;
         if let Some(si) = &previous_sync_info {
             status_tx.send_info(format!("Last sync at {} on {}", si.timestamp(), si.hostname()))
         }
 
         let files_on_device = files_on_device(status_tx, self.device.as_ref())?;
 
-        // Reverse sync
-        if let Err(err) = reverse_sync_playlists(status_tx, &previous_sync_info, self.source.as_ref(), self.device.as_ref()) {
-            status_tx.send_warning(format!("{:?}", err));
-        }
+        // The play counts of the Rockbox database, so that the next sync only imports the plays counted after this one
+        // (the ones of the backup are outdated, plays counted since then may have been imported already)
+        let rockbox = Rockbox::detect(self.device.as_ref());
+        let mut rockbox_play_counts: HashMap<TrackId, u32> = match (&rockbox, &previous_sync_info, is_backup) {
+            (Some(_), Some(previous_sync_info), false) => previous_sync_info.song_data()
+                .filter_map(|(_, data)| data.rockbox_play_count.map(|play_count| (data.id, play_count)))
+                .collect(),
+            _ => HashMap::new(),
+        };
 
-        // Reverse sync for ratings
-        if self.config.include_ratings() {
-            if let Err(err) = reverse_sync_ratings(status_tx, &previous_sync_info, &files_on_device, self.source.as_ref(), self.device.as_ref(), &self.config) {
-                status_tx.send_warning(format!("{:?}", err));
+        if Journal::exists(self.device.as_ref()) {
+            // Playlists on the device may be half-written, they must not be mistaken for changes made by the user
+            match self.unreadable_journal {
+                true => status_tx.send_warning("A previous sync has been interrupted, and its journal is unreadable, so that it can be neither resumed nor rolled back. Not performing reverse sync"),
+                false => status_tx.send_warning("A previous sync has been interrupted, and has been neither resumed nor rolled back. Not performing reverse sync"),
+            }
+        } else if is_backup {
+            // The backup is older than the last sync: changes made in the source in between would look like changes made on the device
+            status_tx.send_warning("The info about the previous sync is corrupted, there is no way to tell what has changed on the device. Not performing reverse sync");
+        } else {
+            // Reverse sync
+            let mut playlist_conflicts = Vec::new();
+            match reverse_sync_playlists(status_tx, &previous_sync_info, &self.sources, self.device.as_ref(), renamed_playlists, dry_run) {
+                Err(err) => status_tx.send_warning(format!("{:?}", err)),
+                Ok((updates, conflicts)) => {
+                    plan.source_playlist_updates = updates;
+                    playlist_conflicts = conflicts;
+                },
+            }
+
+            // Reverse sync for ratings
+            let mut rating_conflicts = Vec::new();
+            if config.include_ratings() {
+                match reverse_sync_ratings(status_tx, &previous_sync_info, &files_on_device, &self.sources, self.device.as_ref(), config, dry_run) {
+                    Err(err) => status_tx.send_warning(format!("{:?}", err)),
+                    Ok((updates, conflicts)) => {
+                        plan.source_rating_updates = updates;
+                        rating_conflicts = conflicts;
+                    },
+                }
+            }
+
+            if playlist_conflicts.is_empty() == false || rating_conflicts.is_empty() == false {
+                let (playlist_conflicts, rating_conflicts) = self.ask_about_conflicts(status_tx, validator_channels, playlist_conflicts, rating_conflicts);
+                plan.source_playlist_updates.extend(resolve_playlist_conflicts(status_tx, &self.sources, playlist_conflicts, dry_run));
+                plan.source_rating_updates.extend(resolve_rating_conflicts(status_tx, &previous_sync_info, &self.sources, rating_conflicts, config.use_computed_ratings(), dry_run));
+            }
+
+            // Plays recorded by the player
+            if let Some(previous_sync_info) = &previous_sync_info {
+                plan.source_play_updates = import_plays(status_tx, previous_sync_info, &self.sources, self.device.as_ref(), dry_run);
+                if let Some(rockbox) = &rockbox {
+                    let (play_updates, play_counts) = import_rockbox_plays(status_tx, previous_sync_info, &self.sources, self.device.as_ref(), rockbox, dry_run);
+                    plan.source_play_updates.extend(play_updates);
+                    rockbox_play_counts.extend(play_counts);
+                }
             }
         }
 
         // Build the list of files that should be on the device
-        let file_set = required_files(status_tx, self.source.as_ref(), &self.config)
-            .map_err(|err| SyncError::SongScanningFailed(err.to_string()))?;
+        let source_unchanged = plan.source_playlist_updates.is_empty() && plan.source_rating_updates.is_empty();
+        if source_unchanged == false {
+            // Previous scans are outdated
+            self.scans.borrow_mut().clear();
+        }
+        let file_set = match scanned_file_set {
+            Some(file_set) if source_unchanged => file_set,
+            _ => required_files(status_tx, &self.sources, config, self.device.file_system(), &mut self.scans.borrow_mut())
+                .map_err(|err| SyncError::SongScanningFailed(err.to_string()))?,
+        };
 
-        // Push and delete files
-        sync_files(status_tx, &file_set, &files_on_device, self.device.as_ref())
+        // Files to push and delete
+        plan_files(&file_set, &files_on_device, &previous_sync_info, self.device.as_ref(), &mut plan)
             .map_err(|err| SyncError::SyncingFilesFailed(err.to_string()))?;
+        if rockbox.is_some() {
+            // Rockbox counts the plays of new files from zero
+            for data in plan.files_to_push.iter().filter_map(|file| file_set.files_data.get(&file.path)) {
+                rockbox_play_counts.insert(data.id, 0);
+            }
+        }
 
-        // Push playlists
-        let playlists = update_playlists(status_tx, self.source.as_ref(), self.device.as_ref(), &self.config, &file_set.common_ancestor)
+        // Playlists
+        let (mut playlist_files, playlists) = plan_playlists(status_tx, &self.scans.borrow(), self.device.as_ref(), config, &file_set, &mut plan)
             .map_err(|err| SyncError::PushingPlaylistsFailed(err.to_string()))?;
 
-        // Push made-up star playlists
-        if self.config.include_ratings() {
-            push_star_playlists(status_tx, self.device.as_ref(), &file_set);
+        // Made-up star playlists
+        let mut rockbox_changelog = None;
+        if config.include_ratings() {
+            playlist_files.extend(star_playlists(status_tx, &file_set, &mut plan));
+            // Rockbox players do not read them, but they can import ratings into their database
+            if let Some(rockbox) = &rockbox {
+                rockbox_changelog = Some(rockbox_changelog_for(rockbox, &file_set).to_string());
+            }
         }
 
-        // Update the last sync info
-        update_sync_info(status_tx, self.device.as_ref(), file_set, playlists)
-            .map_err(|err| SyncError::UpdateSyncInfoFailed(err.to_string()))?;
+        // Playlists that are pushed again are not really removed
+        let SyncPlan{ playlists_to_push, playlists_to_remove, .. } = &mut plan;
+        playlists_to_remove.retain(|name| playlists_to_push.contains(name) == false);
+
+        if dry_run == false {
+            if self.unreadable_journal && Journal::exists(self.device.as_ref()) {
+                let moved_to = Journal::set_aside(self.device.as_ref())
+                    .map_err(|err| SyncError::WritingJournalFailed(format!("unable to set the unreadable journal aside: {}", err)))?;
+                status_tx.send_warning(format!("The unreadable journal of the interrupted sync has been moved to {}", moved_to.join(" and ")));
+            }
+
+            // Record everything we are about to do, so that this sync can be resumed in case it is interrupted, then actually do it
+            let sync_info = build_sync_info(&file_set, playlists, &rockbox_play_counts);
+            let journal = Journal::create(self.device.as_ref(), &plan, &file_set, playlist_files, rockbox_changelog, sync_info)
+                .map_err(|err| SyncError::WritingJournalFailed(err.to_string()))?;
+            journal.run(status_tx, self.device.as_ref(), self.encoder.as_ref(), 0)?;
+
+            // Keep track of this sync
+            let session = SyncSession::new(started, &plan, status_tx.warnings_count());
+            if let Err(err) = history::record_session(self.device.as_ref(), session, |warning| status_tx.send_warning(warning)) {
+                status_tx.send_warning(format!("Unable to update the sync history on the device: {}", err));
+            }
+        }
 
         status_tx.send_progress(Progress::Done);
 
-        Ok(())
+        Ok(plan)
+    }
+
+    /// Send playlist and rating conflicts to the caller, and get them back with the choices of the user.
+    ///
+    /// In case there is no way to ask (e.g. during a dry run), they are returned as-is, so that the playlists and the ratings of the source are kept.
+    fn ask_about_conflicts(
+        &self,
+        status_tx: &status::Sender,
+        validator_channels: Option<ValidatorChannels>,
+        playlist_conflicts: Vec<PlaylistConflict>,
+        rating_conflicts: Vec<RatingConflict>,
+    ) -> (Vec<PlaylistConflict>, Vec<RatingConflict>) {
+        let (outbound, inbound) = match validator_channels {
+            None => {
+                if playlist_conflicts.is_empty() == false {
+                    status_tx.send_info(format!("{} playlists have been edited on both the source and the device. The sync will ask how to merge them", playlist_conflicts.len()));
+                }
+                if rating_conflicts.is_empty() == false {
+                    status_tx.send_info(format!("{} songs have changed their ratings on both the source and the device. The sync will ask which ratings to keep", rating_conflicts.len()));
+                }
+                return (playlist_conflicts, rating_conflicts);
+            },
+            Some(channels) => channels,
+        };
+
+        let validator = SyncValidator{
+            playlist_conflicts,
+            rating_conflicts,
+            ..SyncValidator::build(self.device.name(), None, None, None, Vec::new())
+        };
+        outbound.send(validator).expect("transmission to be possible");
+        let validator = inbound.recv().expect("sender end not to disconnect");
+        (validator.playlist_conflicts, validator.rating_conflicts)
     }
 }
 
+/// The channels [`SyncManager::start_sync`] uses to send [`SyncValidator`]s to the caller, and get them back
+type ValidatorChannels<'a> = (&'a Sender<SyncValidator>, &'a Receiver<SyncValidator>);
+
 /// The results of a sanity check.
 ///
 /// In case some checks failed, it is OK to acknowledge them by setting them to `true`, but that's a good idea
 /// to do so only after having prompted the user for confirmation.
 ///
 /// The sync will only start when all these checks are set (or overridden) to `true`.
+#[derive(Debug, Serialize)]
 pub struct SyncValidator {
+    /// The name of the device to sync
+    pub device: String,
     /// In case we are not attempting to sync with the same computer as last time, this will contain the previous and the current hostnames
     pub last_sync_computer_mismatch: Option<(String, String)>,
+    /// In case the files to sync do not fit into the device, this will contain the required and the available space (in bytes)
+    pub not_enough_space: Option<(u64, u64)>,
+    /// In case the previous sync has been interrupted, this describes it.<br/>
+    /// Its `action` must be set to tell what to do about it before syncing again.
+    pub interrupted_sync: Option<InterruptedSync>,
+    /// Playlists that seem to have been renamed in the source since the previous sync.<br/>
+    /// These do not prevent the sync from starting. Set their `follow` to update the config, so that they keep being synced under their new names.
+    pub renamed_playlists: Vec<RenamedPlaylist>,
+    /// Songs whose ratings have changed on both the source and the device, in case the config asks to choose which rating to keep.<br/>
+    /// These are sent in another validator, once the sync has started. They do not prevent the sync from going on: set their `keep_device_rating` to choose.
+    pub rating_conflicts: Vec<RatingConflict>,
+    /// Playlists that have been edited on both the source and the device, in ways that conflict.<br/>
+    /// These are sent along with the rating conflicts. Set the `resolution` of every hunk of a playlist to reverse sync it. Otherwise, the playlist of the source is kept as-is.
+    pub playlist_conflicts: Vec<PlaylistConflict>,
+}
+
+/// A song whose rating has changed on both the source and the device since the previous sync
+#[derive(Clone, Debug, Serialize)]
+pub struct RatingConflict {
+    pub track_name: String,
+    pub track_id: TrackId,
+    pub rating_at_previous_sync: Rating,
+    pub rating_on_source: Rating,
+    pub rating_on_device: Rating,
+    /// Set this to update the source with the rating of the device. Otherwise, the rating of the source is kept (and pushed to the device)
+    pub keep_device_rating: bool,
+}
+
+/// A sync that has been interrupted (e.g. because the device has been unplugged) before it completed
+#[derive(Debug, Serialize)]
+pub struct InterruptedSync {
+    /// The hostname of the computer this sync has been started on
+    pub hostname: String,
+    /// When this sync has been started
+    pub timestamp: OffsetDateTime,
+    /// How many operations (e.g. pushing a file) this sync had completed
+    pub done_operations: usize,
+    /// How many operations this sync had to perform
+    pub total_operations: usize,
+    /// What to do about this sync. The new sync will not start as long as this is `None`
+    pub action: Option<InterruptedSyncAction>,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InterruptedSyncAction {
+    /// Perform the remaining operations of the interrupted sync
+    Resume,
+    /// Undo the interrupted sync, so that the device is back to how it was after the sync before it
+    RollBack,
 }
 
 impl SyncValidator {
-    fn build(previous_sync_infos: Option<&SyncInfo>) -> Self {
+    fn build(device: String, previous_sync_infos: Option<&SyncInfo>, not_enough_space: Option<(u64, u64)>, interrupted_sync: Option<&(Journal, usize)>, renamed_playlists: Vec<RenamedPlaylist>) -> Self {
         let last_sync_computer_mismatch = previous_sync_infos.and_then(|psi| {
             let chn = current_hostname();
             if psi.hostname() != chn {
@@ -201,13 +648,94 @@ impl SyncValidator {
             }
         });
 
+        let interrupted_sync = interrupted_sync.map(|(journal, n_done)| InterruptedSync{
+            hostname: journal.hostname().to_string(),
+            timestamp: *journal.timestamp(),
+            done_operations: *n_done,
+            total_operations: journal.n_operations(),
+            action: None,
+        });
+
         Self {
-            last_sync_computer_mismatch
+            device,
+            last_sync_computer_mismatch,
+            not_enough_space,
+            interrupted_sync,
+            renamed_playlists,
+            rating_conflicts: Vec::new(),
+            playlist_conflicts: Vec::new(),
         }
     }
 
     fn is_valid(&self) -> bool {
         self.last_sync_computer_mismatch.is_none()
+        && self.not_enough_space.is_none()
+        && self.interrupted_sync.as_ref().map(|i| i.action.is_some()).unwrap_or(true)
+    }
+}
+
+/// Get the info written on the device by the previous sync.
+///
+/// In case they are corrupted (e.g. because they have been only partly written), the backup from the sync before is used instead.
+/// The returned boolean tells whether this is the case: the backup is outdated, and must only be used to tell which files are on the device, not to detect the changes made on the device.
+fn previous_sync_info<F: Fn(String)>(device: &dyn Device, warn: F) -> Result<(Option<SyncInfo>, bool), SyncError> {
+    match device.previous_sync_infos() {
+        Ok(info) => Ok((info, false)),
+        Err(err) => match device.sync_infos_backup() {
+            None => Err(SyncError::CorruptedSyncInfo(err.to_string())),
+            Some(backup) => {
+                warn(format!("The info about the previous sync is corrupted ({}). Using the backup from the sync at {} instead", err, backup.timestamp()));
+                Ok((Some(backup), true))
+            },
+        },
+    }
+}
+
+/// How much the sync will increase the used space on the device, in bytes (this is negative in case it frees more than it uses).
+///
+/// Files that were on the device at the previous sync are assumed to still be there.
+fn required_space(file_set: &FileSet, previous_sync_info: Option<&SyncInfo>) -> i64 {
+    let previous_sync_info = match previous_sync_info {
+        None => return file_set.total_size as i64,
+        Some(psi) => psi,
+    };
+
+    // Added or updated files
+    let mut required_space = 0;
+    for (path, data) in &file_set.files_data {
+        match previous_sync_info.song_data_for_relative_path(path) {
+            None => required_space += data.file_size as i64,
+            Some(previous) => {
+                if let Some(previous_size) = previous.file_size {
+                    if previous.has_changed(data) {
+                        required_space += data.file_size as i64 - previous_size as i64;
+                    }
+                }
+            }
+        }
+    }
+
+    // Removed files
+    let expected_files: HashSet<PathBuf> = file_set.files_data.keys().map(|path| PathBuf::from(path.to_string_lossy().to_lowercase())).collect();
+    for (path, previous) in previous_sync_info.song_data() {
+        if expected_files.contains(path) == false {
+            required_space -= previous.file_size.unwrap_or(0) as i64;
+        }
+    }
+
+    required_space
+}
+
+/// Find the source a song or a playlist comes from, given the source name recorded in the sync info.
+///
+/// Items synced by older versions of StarSync have no recorded source, they come from the main source.
+fn source_of<'a>(sources: &'a [Box<dyn Source>], source_name: Option<&str>) -> Result<&'a dyn Source, String> {
+    match source_name {
+        None => sources.first().map(|source| source.as_ref()).ok_or_else(|| "This device has no source".to_string()),
+        Some(name) => sources.iter()
+            .find(|source| source.name() == name)
+            .map(|source| source.as_ref())
+            .ok_or_else(|| format!("Source '{}' is not synced with this device anymore", name)),
     }
 }
 
@@ -232,7 +760,14 @@ pub enum ReverseSyncPlaylistError {
 }
 
 
-fn reverse_sync_playlists(status_tx: &status::Sender, previous_sync_info: &Option<SyncInfo>, source: &dyn Source, device: &dyn Device) -> Result<(), ReverseSyncPlaylistError>  {
+fn reverse_sync_playlists(
+    status_tx: &status::Sender,
+    previous_sync_info: &Option<SyncInfo>,
+    sources: &[Box<dyn Source>],
+    device: &dyn Device,
+    renamed_playlists: &[RenamedPlaylist],
+    dry_run: bool,
+) -> Result<(Vec<PlaylistUpdate>, Vec<PlaylistConflict>), ReverseSyncPlaylistError>  {
     status_tx.send_progress(Progress::ReverseSyncPlaylists);
 
     let previous_sync_info = match previous_sync_info {
@@ -240,7 +775,7 @@ fn reverse_sync_playlists(status_tx: &status::Sender, previous_sync_info: &Optio
         None => {
             // In case there was no previous sync, there is nothing to reverse sync.
             status_tx.send_info("This seems to be the first time this device is synced. Not performing reverse sync for playlists");
-            return Ok(());
+            return Ok((Vec::new(), Vec::new()));
         }
     };
 
@@ -254,20 +789,92 @@ fn reverse_sync_playlists(status_tx: &status::Sender, previous_sync_info: &Optio
         )
         .collect();
 
+    let mut updates = Vec::new();
+    let mut conflicts = Vec::new();
     for (playlist_name_on_device, device_song_ids) in content_on_device {
         match previous_sync_info.playlist(&playlist_name_on_device) {
             None => {
                 status_tx.send_warning(format!("Unable to get info about the last sync of playlist '{}'.", playlist_name_on_device));
             },
-            Some((playlist_id, ancestor_song_ids)) => {
-                if let Err(err) = reverse_sync_playlist(status_tx, source, &playlist_name_on_device, &playlist_id, ancestor_song_ids, &device_song_ids) {
-                    status_tx.send_warning(format!("Unable to reverse sync playlist '{}': {}", playlist_name_on_device, err));
+            Some(SyncedPlaylist{ id: PlaylistId::Smart(_), tracks: ancestor_song_ids, .. }) => {
+                if &device_song_ids != ancestor_song_ids {
+                    status_tx.send_info(format!("Smart playlist '{}' has been modified on the device. Smart playlists are read-only, these changes will be overwritten.", playlist_name_on_device));
+                }
+            },
+            Some(SyncedPlaylist{ id: playlist_id, tracks: ancestor_song_ids, source: source_name }) => {
+                let source = match source_of(sources, source_name.as_deref()) {
+                    Err(err) => {
+                        status_tx.send_warning(format!("Unable to reverse sync playlist '{}': {}", playlist_name_on_device, err));
+                        continue;
+                    },
+                    Ok(source) => source,
+                };
+                // Changes made on the device go to the playlist under its new name
+                let renamed_id = match playlist_id {
+                    PlaylistId::Name(name) => renamed_playlists.iter()
+                        .find(|renamed| renamed.source == source.name() && renamed.old_name == *name)
+                        .map(|renamed| PlaylistId::Name(renamed.new_name.clone())),
+                    _ => None,
+                };
+                let playlist_id = renamed_id.as_ref().unwrap_or(playlist_id);
+                match reverse_sync_playlist(status_tx, source, &playlist_name_on_device, playlist_id, ancestor_song_ids, &device_song_ids, dry_run) {
+                    Err(err) => status_tx.send_warning(format!("Unable to reverse sync playlist '{}': {}", playlist_name_on_device, err)),
+                    Ok(PlaylistMerge::Updated(update)) => updates.push(update),
+                    Ok(PlaylistMerge::Conflict(conflict)) => conflicts.push(conflict),
+                    Ok(PlaylistMerge::Unchanged) => (),
                 }
             }
         }
     }
 
-    Ok(())
+    Ok((updates, conflicts))
+}
+
+/// Update the playlists of the source whose conflicts have been resolved
+fn resolve_playlist_conflicts(status_tx: &status::Sender, sources: &[Box<dyn Source>], conflicts: Vec<PlaylistConflict>, dry_run: bool) -> Vec<PlaylistUpdate> {
+    let mut updates = Vec::new();
+    for conflict in conflicts {
+        let new_content = match conflict.resolved_content() {
+            None => {
+                status_tx.send_warning(format!("Playlist '{}' has been edited on both the source and the device, in ways that conflict. It has not been reverse synced, and the changes made on the device will be overwritten", conflict.playlist_name));
+                continue;
+            },
+            Some(new_content) => new_content,
+        };
+        if new_content == conflict.current_content {
+            continue;
+        }
+
+        let playlist = sources.iter()
+            .find(|source| source.name() == conflict.source)
+            .and_then(|source| source.playlist_by_id(&conflict.playlist_id));
+        let playlist = match playlist {
+            None => {
+                status_tx.send_warning(format!("Unable to reverse sync playlist '{}': No such playlist", conflict.playlist_name));
+                continue;
+            },
+            Some(playlist) => playlist,
+        };
+
+        let update = PlaylistUpdate{
+            name: conflict.playlist_name,
+            id: conflict.playlist_id,
+            current_content: conflict.current_content,
+            new_content,
+        };
+        if dry_run == false {
+            update_playlist_into_source(status_tx, playlist.as_ref(), &update);
+        }
+        updates.push(update);
+    }
+    updates
+}
+
+fn update_playlist_into_source(status_tx: &status::Sender, playlist: &dyn Playlist, update: &PlaylistUpdate) {
+    status_tx.send(Message::UpdatingPlaylistIntoSource{new_content: update.new_content.clone()});
+    if let Err(err) = playlist.change_contents_to(&update.new_content) {
+        status_tx.send_warning(format!("Unable to update the contents of playlist {}: {}", update.name, err));
+    }
 }
 
 
@@ -282,14 +889,19 @@ pub enum ReverseSyncRatingsError {
     DuplicateRatingsForASong,
 }
 
+/// Update the ratings of the source with the ratings that have changed on the device.
+///
+/// Songs whose ratings have changed on both sides are handled according to the config.
+/// In case the config asks to choose, they are returned as conflicts, and left untouched.
 fn reverse_sync_ratings(
     status_tx: &status::Sender,
     previous_sync_info: &Option<SyncInfo>,
     files_on_device: &HashSet<PathBuf>,
-    source: &dyn Source,
+    sources: &[Box<dyn Source>],
     device: &dyn Device,
     config: &Config,
-) -> Result<(), ReverseSyncRatingsError> {
+    dry_run: bool,
+) -> Result<(Vec<RatingUpdate>, Vec<RatingConflict>), ReverseSyncRatingsError> {
     //
     //
     //
@@ -305,10 +917,117 @@ fn reverse_sync_ratings(
         None => {
             // In case there was no previous sync, there is nothing to reverse sync.
             status_tx.send_info("This seems to be the first time this device is synced. Not performing reverse sync for ratings");
-            return Ok(());
+            return Ok((Vec::new(), Vec::new()));
         }
     };
 
+    let ratings_on_device = ratings_on_device(status_tx, previous_sync_info, files_on_device, device)?;
+
+    // Check which track has changed its rating
+    let mut updates = Vec::new();
+    let mut conflicts = Vec::new();
+    for (rating_on_device, list) in ratings_on_device {
+        for track_id in list {
+            let rating_at_previous_sync = previous_sync_info.rating_for_id(track_id);
+            if rating_at_previous_sync != rating_on_device {
+                // This song has changed its rating on the device.
+                // Has it changed on the source as well?
+                let source = match source_of(sources, previous_sync_info.source_for_id(track_id)) {
+                    Err(err) => {
+                        status_tx.send_warning(format!("The rating of track {:x?} has changed on the device, but it cannot be reverse synced: {}", track_id, err));
+                        continue;
+                    },
+                    Ok(source) => source,
+                };
+                match source.track_by_id(track_id) {
+                    None => status_tx.send_warning(format!("The rating of track {:x?} has changed on the device, but it has been removed from the source", track_id)),
+                    Some(track) => {
+                        let rating_on_source = track.rating(config.use_computed_ratings());
+                        let track_name = previous_sync_info
+                            .source_path_for_id(track_id)
+                            .and_then(|p| p.file_name().map(|s| s.to_string_lossy().to_string()))
+                            .unwrap_or("<unknown>".to_string());
+
+                        let new_rating = match resolve_rating(config.rating_conflict_policy(), rating_at_previous_sync, rating_on_source, rating_on_device) {
+                            RatingResolution::InSync => continue,
+                            // We are cleared to update the rating on the source
+                            RatingResolution::FromDevice => rating_on_device,
+                            RatingResolution::Conflict(new_rating) if new_rating == rating_on_source => {
+                                status_tx.send_info(format!("Song {:?} has changed its rating on both the source and the device. That's a conflict, keeping the rating of the source.", track_name));
+                                continue;
+                            },
+                            RatingResolution::Conflict(new_rating) => {
+                                status_tx.send_info(format!("Song {:?} has changed its rating on both the source and the device. That's a conflict, keeping the rating of the device.", track_name));
+                                new_rating
+                            },
+                            RatingResolution::Ask => {
+                                conflicts.push(RatingConflict{ track_name, track_id, rating_at_previous_sync, rating_on_source, rating_on_device, keep_device_rating: false });
+                                continue;
+                            },
+                        };
+
+                        let update = RatingUpdate{ track_name, track_id, current_rating_on_source: rating_on_source, new_rating };
+                        if dry_run == false {
+                            update_rating_into_source(status_tx, track.as_ref(), &update);
+                        }
+                        updates.push(update);
+                    }
+                }
+            }
+        }
+    }
+
+    Ok((updates, conflicts))
+}
+
+/// Update the ratings of the source for the conflicts that have been resolved in favour of the device
+fn resolve_rating_conflicts(
+    status_tx: &status::Sender,
+    previous_sync_info: &Option<SyncInfo>,
+    sources: &[Box<dyn Source>],
+    conflicts: Vec<RatingConflict>,
+    use_computed_ratings: bool,
+    dry_run: bool,
+) -> Vec<RatingUpdate> {
+    let mut updates = Vec::new();
+    for conflict in conflicts.into_iter().filter(|conflict| conflict.keep_device_rating) {
+        let source_name = previous_sync_info.as_ref().and_then(|psi| psi.source_for_id(conflict.track_id));
+        let track = match source_of(sources, source_name).ok().and_then(|source| source.track_by_id(conflict.track_id)) {
+            None => {
+                status_tx.send_warning(format!("Unable to update the rating of '{}' in the source: track not found", conflict.track_name));
+                continue;
+            },
+            Some(track) => track,
+        };
+
+        let update = RatingUpdate{
+            track_name: conflict.track_name,
+            track_id: conflict.track_id,
+            current_rating_on_source: track.rating(use_computed_ratings),
+            new_rating: conflict.rating_on_device,
+        };
+        if dry_run == false {
+            update_rating_into_source(status_tx, track.as_ref(), &update);
+        }
+        updates.push(update);
+    }
+    updates
+}
+
+fn update_rating_into_source(status_tx: &status::Sender, track: &dyn Track, update: &RatingUpdate) {
+    status_tx.send(Message::UpdatingSongRatingIntoSource{ track_name: update.track_name.clone(), new_rating: update.new_rating, current_rating_on_source: update.current_rating_on_source });
+    if let Err(err) = track.set_rating(update.new_rating) {
+        status_tx.send_warning(format!("Unable to update rating for track '{}' (to {:?} stars): {}", &update.track_name, update.new_rating, err));
+    }
+}
+
+/// Read the ratings playlists of the device, and get the tracks that have each rating (including the ones that have no rating)
+fn ratings_on_device(
+    status_tx: &status::Sender,
+    previous_sync_info: &SyncInfo,
+    files_on_device: &HashSet<PathBuf>,
+    device: &dyn Device,
+) -> Result<HashMap<Rating, HashSet<TrackId>>, ReverseSyncRatingsError> {
     let rating_playlists_on_device = playlists_on_device(status_tx, RequestedPlaylistKind::Ratings, device, previous_sync_info)
        .map_err(|err| ReverseSyncRatingsError::ListingDevicePlaylistsFailed(err))?;
 
@@ -369,39 +1088,52 @@ fn reverse_sync_ratings(
     // Add the songs that have no rating
     ratings_on_device.insert(None, no_ratings);
 
-    // Check which track has changed its rating
-    for (rating_on_device, list) in ratings_on_device {
-        for track_id in list {
-            let rating_at_previous_sync = previous_sync_info.rating_for_id(track_id);
-            if rating_at_previous_sync != rating_on_device {
-                // This song has changed its rating on the device.
-                // Has it changed on the source as well?
-                match source.track_by_id(track_id) {
-                    None => status_tx.send_warning(format!("The rating of track {:x?} has changed on the device, but it has been removed from the source", track_id)),
-                    Some(track) => {
-                        let rating_on_source = track.rating(config.use_computed_ratings());
-                        let track_name = previous_sync_info
-                            .path_for_id(track_id)
-                            .and_then(|p| p.file_name().map(|s| s.to_string_lossy().to_string()))
-                            .unwrap_or("<unknown>".to_string());
+    if let Some(rockbox) = Rockbox::detect(device) {
+        apply_rockbox_ratings(status_tx, previous_sync_info, &rockbox, &mut ratings_on_device);
+    }
 
-                        if rating_on_source != rating_at_previous_sync {
-                            // That's a conflict
-                            status_tx.send_info(format!("Song {:?} has changed its rating on both the source and the device. That's a conflict, let the source win.", track_name));
-                        } else {
-                            // We are cleared to update the rating on the source
-                            status_tx.send(Message::UpdatingSongRatingIntoSource{ track_name: track_name.clone(), new_rating: rating_on_device, current_rating_on_source: rating_on_source });
-                            if let Err(err) = track.set_rating(rating_on_device) {
-                                status_tx.send_warning(format!("Unable to update rating for track '{}' (to {:?} stars): {}", &track_name, rating_on_device, err));
-                            }
-                        }
-                    }
-                }
-            }
+    Ok(ratings_on_device)
+}
+
+/// Update the ratings read from the rating playlists with the ones of the Rockbox database, as exported into its changelog.
+///
+/// A song whose rating in the changelog differs from the one it had during the previous sync has been rated on the player. This wins over the rating playlists.
+fn apply_rockbox_ratings(status_tx: &status::Sender, previous_sync_info: &SyncInfo, rockbox: &Rockbox, ratings_on_device: &mut HashMap<Rating, HashSet<TrackId>>) {
+    let changelog = match rockbox.changelog() {
+        Err(err) => {
+            status_tx.send_warning(format!("Unable to read the ratings of the Rockbox database: {}", err));
+            return;
+        },
+        Ok(None) => return,
+        Ok(Some(changelog)) => changelog,
+    };
+
+    for entry in changelog.entries().filter(|entry| entry.has_rating()) {
+        let track_id = match entry.filename()
+            .and_then(|filename| rockbox.relative_path(filename))
+            .and_then(|path| previous_sync_info.id_for_relative_path(&path))
+        {
+            None => continue,
+            Some(track_id) => track_id,
+        };
+
+        let rating_at_previous_sync = previous_sync_info.rating_for_id(track_id);
+        let rockbox_rating = entry.rating();
+        if rockbox_rating == rating_at_previous_sync {
+            continue;
         }
-    }
 
-    Ok(())
+        let playlist_rating = ratings_on_device.iter()
+            .find(|(_, ids)| ids.contains(&track_id))
+            .map(|(rating, _)| *rating);
+        if playlist_rating != Some(rating_at_previous_sync) && playlist_rating != Some(rockbox_rating) {
+            status_tx.send_info(format!("Song {:?} has been rated differently in the rating playlists and in the Rockbox database. Keeping the rating of the Rockbox database.", entry.filename().unwrap_or_default()));
+        }
+        for ids in ratings_on_device.values_mut() {
+            ids.remove(&track_id);
+        }
+        ratings_on_device.entry(rockbox_rating).or_default().insert(track_id);
+    }
 }
 
 fn are_all_ratings_playslists_on_device(rating_playlists_on_device: &HashMap<String, M3u>) -> bool {
@@ -429,10 +1161,18 @@ fn have_sets_overlap(ratings_on_device: &HashMap<Rating, HashSet<TrackId>>, i: u
     Some(set_a.is_disjoint(set_b) == false)
 }
 
-fn reverse_sync_playlist(status_tx: &status::Sender, source: &dyn Source, playlist_name: &str, playlist_id: &PlaylistId, ancestor_song_ids: &[TrackId], device_song_ids: &[TrackId]) -> Result<(), Box<dyn Error>> {
+/// What reverse syncing a playlist leads to
+enum PlaylistMerge {
+    Unchanged,
+    Updated(PlaylistUpdate),
+    /// The changes made on the source and on the device conflict. The playlist has not been modified
+    Conflict(PlaylistConflict),
+}
+
+fn reverse_sync_playlist(status_tx: &status::Sender, source: &dyn Source, playlist_name: &str, playlist_id: &PlaylistId, ancestor_song_ids: &[TrackId], device_song_ids: &[TrackId], dry_run: bool) -> Result<PlaylistMerge, Box<dyn Error>> {
     status_tx.send(Message::ReverseSyncPlaylist(playlist_name.to_string()));
 
-    let local_playlist = source.playlist_by_id(&playlist_id).ok_or("No such playlist")?;
+    let local_playlist = source.playlist_by_id(playlist_id).ok_or("No such playlist")?;
     let local_song_ids: Vec<TrackId> = local_playlist
         .tracks()?
         .iter()
@@ -442,146 +1182,376 @@ fn reverse_sync_playlist(status_tx: &status::Sender, source: &dyn Source, playli
     // In case all playlists are the same, let's not bother doing a 3-way merge
     if device_song_ids == local_song_ids {
         status_tx.send_info(format!("Playlist {} has not been modified, skipping it.", playlist_name));
-        return Ok(());
+        return Ok(PlaylistMerge::Unchanged);
     }
 
-    let new_song_order = diffy::merge_custom(ancestor_song_ids, &local_song_ids, device_song_ids)?;
+    let owned_ids: Vec<TrackId> = match diffy::merge_custom(ancestor_song_ids, &local_song_ids, device_song_ids) {
+        Ok(new_song_order) => new_song_order.iter().map(|id| **id).collect(),
+        Err(err) => {
+            log::debug!("Unable to merge playlist {}: {}", playlist_name, err);
+            // Let's find out which parts of the playlist conflict
+            let chunks = merge::merge(ancestor_song_ids, &local_song_ids, device_song_ids);
+            let track = |id: &TrackId| ConflictTrack{
+                id: *id,
+                name: source.track_by_id(*id).map(|track| track.name()).unwrap_or_else(|| format!("{:x?}", id)),
+            };
+            let hunks: Vec<ConflictHunk> = chunks.iter()
+                .filter_map(|chunk| match chunk {
+                    MergeChunk::Merged(_) => None,
+                    MergeChunk::Conflict{ ancestor, local, device } => Some(ConflictHunk{
+                        ancestor: ancestor.iter().map(track).collect(),
+                        source: local.iter().map(track).collect(),
+                        device: device.iter().map(track).collect(),
+                        resolution: None,
+                    }),
+                })
+                .collect();
+            if hunks.is_empty() == false {
+                status_tx.send_info(format!("Playlist {} has been edited on both the source and the device, in ways that conflict.", playlist_name));
+                return Ok(PlaylistMerge::Conflict(PlaylistConflict{
+                    source: source.name().to_string(),
+                    playlist_name: playlist_name.to_string(),
+                    hunks,
+                    chunks,
+                    playlist_id: playlist_id.clone(),
+                    current_content: local_song_ids,
+                }));
+            }
+            chunks.into_iter()
+                .flat_map(|chunk| match chunk {
+                    MergeChunk::Merged(tracks) => tracks,
+                    MergeChunk::Conflict{ .. } => Vec::new(),
+                })
+                .collect()
+        },
+    };
     log::debug!("local:  {local_song_ids:x?}");
     log::debug!("device: {device_song_ids:x?}");
-    log::debug!("merged: {new_song_order:x?}");
+    log::debug!("merged: {owned_ids:x?}");
 
-    let owned_ids: Vec<TrackId> = new_song_order.iter().map(|id| **id).collect();
     if local_song_ids == owned_ids {
         status_tx.send_info(format!("Playlist {} has not been modified on the device. Not reverse syncing it.", playlist_name));
-    } else {
-        status_tx.send(Message::UpdatingPlaylistIntoSource{new_content: owned_ids.to_vec()});
-        if let Err(err) = local_playlist.change_contents_to(&owned_ids) {
-            status_tx.send_warning(format!("Unable to update the contents of playlist {}: {}", playlist_name, err));
-        }
+        return Ok(PlaylistMerge::Unchanged);
     }
 
-    Ok(())
+    let update = PlaylistUpdate{
+        name: playlist_name.to_string(),
+        id: playlist_id.clone(),
+        current_content: local_song_ids,
+        new_content: owned_ids,
+    };
+    if dry_run == false {
+        update_playlist_into_source(status_tx, local_playlist.as_ref(), &update);
+    }
+    Ok(PlaylistMerge::Updated(update))
 }
 
-fn required_files(status_tx: &status::Sender, source: &dyn Source, config: &Config) -> Result<FileSet, Box<dyn Error>> {
+/// Get a playlist the config asks for. This is either a playlist of the source, or a smart playlist
+fn configured_playlist<'a>(source: &'a dyn Source, config: &Config, playlist_config: &PlaylistConfig) -> Result<Box<dyn Playlist + 'a>, Box<dyn Error>> {
+    match &playlist_config.rule {
+        None => source
+            .playlist_by_name(&playlist_config.name)
+            .map(|playlist| playlist as Box<dyn Playlist + 'a>)
+            .ok_or_else(|| format!("Unable to find playlist '{}'", playlist_config.name).into()),
+        Some(rule) => SmartPlaylist::new(&playlist_config.name, rule, source, config.use_computed_ratings())
+            .map(|playlist| Box::new(playlist) as Box<dyn Playlist + 'a>)
+            .map_err(|err| format!("Unable to use smart playlist '{}': {}", playlist_config.name, err).into()),
+    }
+}
+
+/// List the files to push, from every source of the device.
+///
+/// Files are laid out according to the layout of the config (see [`crate::layout`]), if any.
+/// Otherwise (or in case their tracks lack some tags), files of each source are pushed relatively to the common ancestor of the files of this source.
+/// Their paths are sanitized in case they are not valid on the filesystem of the device.
+/// Playlists that have already been scanned (e.g. for another device) are taken from `scans`, and new scans are added to it.
+fn required_files(status_tx: &status::Sender, sources: &[Box<dyn Source>], config: &Config, file_system: Option<FileSystem>, scans: &mut ScanCache) -> Result<FileSet, Box<dyn Error>> {
     status_tx.send_progress(Progress::ListingFilesInSource);
 
+    let layout = config.layout()
+        .map(|template| template.parse::<Layout>())
+        .transpose()?;
+
     let mut total_size = 0;
     let mut data_with_absolute_paths = HashMap::new();
+    let mut selected_tracks = HashMap::new();
 
-    for playlist_name in config.playlists() {
-        match source.playlist_by_name(playlist_name) {
-            None => status_tx.send_warning(format!("Unable to find playlist '{}'", playlist_name)),
-            Some(list) => {
-                match list.tracks() {
-                    Err(err) => status_tx.send_warning(format!("Unable to list tracks for playlist '{}': {}", list.name(), err)),
-                    Ok(tracks) => {
-                        for track in tracks {
-                            match track.absolute_path() {
-                                Err(err) => status_tx.send_warning(format!("Unable to get path for song '{}': {}", track.name(), err)),
-                                Ok(absolute_path) => {
-                                    let file_size = match track.file_size() {
-                                        Err(err) => {
-                                            status_tx.send_warning(format!("Unable to get file size for song '{}': {}", track.name(), err));
-                                            0
-                                        },
-                                        Ok(size) => size,
-                                    };
-
-                                    let rating = track.rating(config.use_computed_ratings());
-
-                                    if data_with_absolute_paths.insert(
-                                        absolute_path.clone(),
-                                        FileData{ file_size, id: track.id(), rating }
-                                    ).is_some() {
-                                        // We've already kept track of this file, as it is in duplicate playlists.
-                                        // We must not count its size twice.
-                                        // No need to add it to the list of IDs either
-                                    } else {
-                                        total_size += file_size;
-                                    }
-                                }
-                            }
-                        }
-                    }
-                }
+    for (source_config, playlist_config) in config.playlists_by_priority() {
+        let playlist_name = &playlist_config.name;
+        let source = match sources.iter().find(|source| source.name() == source_config.name) {
+            None => {
+                status_tx.send_warning(format!("Source '{}' is not available, not syncing playlist '{}'", source_config.name, playlist_name));
+                continue;
+            },
+            Some(source) => source.as_ref(),
+        };
+        let scan_key = scan_key(&source_config.name, playlist_config, config);
+        let candidates = match scans.get(&scan_key) {
+            Some(scan) => scan.tracks.clone(),
+            None => match scan_playlist(status_tx, source, config, playlist_config) {
+                Err(err) => {
+                    status_tx.send_warning(err);
+                    continue;
+                },
+                Ok(scan) => {
+                    let candidates = scan.tracks.clone();
+                    scans.insert(scan_key, scan);
+                    candidates
+                },
+            },
+        };
+
+        let selected = select_tracks(status_tx, playlist_config, config.size_budget(), candidates, &mut data_with_absolute_paths, &mut total_size);
+        selected_tracks.insert(playlist_name.to_string(), selected);
+    }
+
+    // Get the common ancestor for the files of each source
+    // (of the folders of these files, actually, so that it is not the file itself when a source has a single file to push)
+    let mut roots = HashMap::new();
+    for source_name in config.source_names() {
+        let source_paths = data_with_absolute_paths.iter()
+            .filter(|(_, data)| data.source == source_name)
+            .filter_map(|(path, _)| path.parent());
+        if let Some(common_ancestor) = crate::common_path::common_path_all(source_paths) {
+            roots.insert(source_name.to_string(), common_ancestor);
+        } else if layout.is_none() && data_with_absolute_paths.values().any(|data| data.source == source_name) {
+            // With a layout, only the files that lack tags need a common ancestor
+            return Err(SyncError::NoCommonAncestor.into());
+        }
+    }
+
+    // Lay out the files, or strip the prefix from their paths
+    let mut files = Vec::new();
+    let mut left_out_ids = Vec::new();
+    for (path, mut file_data) in data_with_absolute_paths {
+        let laid_out_path = layout.as_ref()
+            .zip(file_data.tags.as_ref())
+            .and_then(|(layout, tags)| layout.path_for(tags, &path));
+        let is_laid_out = laid_out_path.is_some();
+        let stripped_path = match (laid_out_path, roots.get(&file_data.source)) {
+            (Some(laid_out_path), _) => laid_out_path,
+            (None, None) => {
+                status_tx.send_warning(format!("Not pushing '{}', because its track lacks tags the layout needs, and the files of source '{}' have no common folder", path.display(), file_data.source));
+                total_size -= file_data.file_size;
+                left_out_ids.push(file_data.id);
+                continue;
+            },
+            (None, Some(common_ancestor)) => match path.strip_prefix(common_ancestor) {
+                Err(_err) => {
+                    status_tx.send_warning(format!("File '{:?}' is not a child of the root folder '{:?}'. Ignoring this file", path, common_ancestor));
+                    total_size -= file_data.file_size;
+                    left_out_ids.push(file_data.id);
+                    continue;
+                },
+                Ok(stripped_path) => stripped_path.to_owned(),
+            },
+        };
+
+        // Files that are converted take a new extension on the device
+        let device_path = match config.transcoding().and_then(|t| t.profile_for(&stripped_path)) {
+            None => stripped_path,
+            Some(profile) => {
+                file_data.transcoding = Some(profile);
+                stripped_path.with_extension(profile.codec.extension())
+            },
+        };
+        files.push(PlacedFile{ device_path, data: file_data, is_laid_out });
+    }
+
+    // Files of different sources may have the same relative paths, and tracks may have the same tags
+    let source_names: Vec<&str> = config.source_names().collect();
+    let (mut relative_files, left_out) = place_files(files, &source_names);
+    for (file_data, existing_path) in left_out {
+        let existing = &relative_files[&existing_path];
+        match file_data.transcoding {
+            Some(_) => status_tx.send_warning(format!("Not pushing '{}', because it would be converted into '{}', which already exists", file_data.source_path.display(), existing_path.display())),
+            None => status_tx.send_warning(format!("Not pushing '{}', because '{}' (from source '{}') would be pushed at the same place", file_data.source_path.display(), existing.source_path.display(), existing.source)),
+        }
+        total_size -= file_data.file_size;
+        left_out_ids.push(file_data.id);
+    }
+
+    // Playlists must not refer to the tracks that are not pushed
+    let pushed_ids: HashSet<TrackId> = relative_files.values().map(|data| data.id).collect();
+    for id in left_out_ids.into_iter().filter(|id| pushed_ids.contains(id) == false) {
+        for tracks in selected_tracks.values_mut() {
+            tracks.remove(&id);
+        }
+    }
+
+    if let Some(file_system) = file_system {
+        let device_paths = file_system.device_paths(relative_files.keys().map(|path| path.as_path()));
+        let n_sanitized = device_paths.iter().filter(|(path, device_path)| path != device_path).count();
+        if n_sanitized > 0 {
+            log::info!("{} files will be pushed under different names, because their paths are not valid on a {:?} filesystem", n_sanitized, file_system);
+        }
+        relative_files = relative_files
+            .into_iter()
+            .map(|(path, file_data)| (device_paths[&path].clone(), file_data))
+            .collect();
+    }
+
+    Ok(FileSet{ roots, files_data: relative_files, total_size, selected_tracks })
+}
+
+fn scan_key(source_name: &str, playlist_config: &PlaylistConfig, config: &Config) -> (String, PlaylistConfig, bool, bool) {
+    (source_name.to_string(), playlist_config.clone(), config.use_computed_ratings(), config.layout().is_some())
+}
+
+/// Get the tracks of a playlist, along with their names and the data of their files
+fn scan_playlist(status_tx: &status::Sender, source: &dyn Source, config: &Config, playlist_config: &PlaylistConfig) -> Result<PlaylistScan, String> {
+    let list = configured_playlist(source, config, playlist_config).map_err(|err| err.to_string())?;
+    let tracks = list.tracks().map_err(|err| format!("Unable to list tracks for playlist '{}': {}", list.name(), err))?;
+
+    let mut candidates = Vec::new();
+    for track in tracks {
+        match track.absolute_path() {
+            Err(err) => status_tx.send_warning(format!("Unable to get path for song '{}': {}", track.name(), err)),
+            Ok(absolute_path) => {
+                let file_size = match track.file_size() {
+                    Err(err) => {
+                        status_tx.send_warning(format!("Unable to get file size for song '{}': {}", track.name(), err));
+                        0
+                    },
+                    Ok(size) => size,
+                };
+
+                let rating = track.rating(config.use_computed_ratings());
+
+                let modified = match track.modification_date() {
+                    Err(err) => {
+                        log::debug!("Unable to get modification date for song '{}': {}", track.name(), err);
+                        None
+                    },
+                    Ok(date) => Some(OffsetDateTime::from(date)),
+                };
+
+                // Reading tags may be slow (e.g. for folder sources), they are only needed to lay files out
+                let tags = config.layout().map(|_| TrackTags::of(track.as_ref()));
+
+                candidates.push((
+                    track.name(),
+                    FileData{ file_size, id: track.id(), rating, modified, source_path: absolute_path, transcoding: None, source: source.name().to_string(), tags },
+                ));
             }
         }
     }
 
-    // Get the common ancestor for all these files
-    let common_ancestor = crate::common_path::common_path_all(data_with_absolute_paths.keys()).ok_or(SyncError::NoCommonAncestor)?;
+    Ok(PlaylistScan{ id: list.id(), file_name: list.suitable_filename(), tracks: candidates })
+}
 
-    // Strip the prefix from the set
-    let relative_files = data_with_absolute_paths
-        .into_iter()
-        .filter_map(|(path, file_data)| {
-            let stripped_path = path
-                .strip_prefix(&common_ancestor)
-                .map(|path| path.to_owned())
-                .map_err(|_err| status_tx.send_warning(format!("File '{:?}' is not a child of the root folder '{:?}'. Ignoring this file", path, common_ancestor)))
-                .ok();
+/// Choose which tracks of a playlist will be pushed, so that the limits set in the config are respected.
+///
+/// The selected files are added to `files` (indexed by their absolute paths), unless they already are because of a previous playlist.
+/// Lowest-rated tracks are the first ones to be left out.
+fn select_tracks(
+    status_tx: &status::Sender,
+    playlist_config: &PlaylistConfig,
+    size_budget: Option<u64>,
+    mut candidates: Vec<(String, FileData)>,
+    files: &mut HashMap<PathBuf, FileData>,
+    total_size: &mut usize,
+) -> HashSet<TrackId> {
+    let PlaylistConfig{ name: playlist_name, options, .. } = playlist_config;
+
+    // This sort is stable, so that tracks with the same rating are considered in the order of the playlist
+    candidates.sort_by_key(|(_, data)| std::cmp::Reverse(data.rating));
+
+    let mut selected = HashSet::new();
+    let mut playlist_size: u64 = 0;
+    let mut n_left_out = 0;
+    for (track_name, data) in candidates {
+        if selected.contains(&data.id) {
+            // This track is in the playlist several times
+            continue;
+        }
 
-            stripped_path.map(|stripped_path| (stripped_path, file_data))
-        })
-        .collect();
+        let file_size = data.file_size as u64;
+        let already_pushed = files.contains_key(&data.source_path);
+        let stars = data.rating.map(|r| r.get()).unwrap_or(0);
+
+        let left_out_reason = options.min_rating
+            .filter(|min| stars < *min)
+            .map(|min| format!("it is rated below {min} stars"))
+            .or_else(|| options.max_tracks
+                .filter(|max| selected.len() >= *max)
+                .map(|max| format!("the playlist is limited to {max} tracks")))
+            .or_else(|| options.max_size
+                .filter(|max| playlist_size + file_size > max.0)
+                .map(|max| format!("the playlist is limited to {}", format_size(max.0, humansize::DECIMAL))))
+            .or_else(|| size_budget
+                .filter(|budget| already_pushed == false && *total_size as u64 + file_size > *budget)
+                .map(|budget| format!("the size budget of {} is reached", format_size(budget, humansize::DECIMAL))));
+
+        match left_out_reason {
+            Some(reason) => {
+                n_left_out += 1;
+                status_tx.send(Message::LeavingOutTrack{ track_name, playlist_name: playlist_name.clone(), reason });
+            },
+            None => {
+                selected.insert(data.id);
+                playlist_size += file_size;
+                if already_pushed == false {
+                    *total_size += data.file_size;
+                    files.insert(data.source_path.clone(), data);
+                }
+            },
+        }
+    }
 
-    Ok(FileSet{ common_ancestor, files_data: relative_files, total_size })
+    if n_left_out > 0 {
+        status_tx.send_info(format!("{} tracks of playlist '{}' will not be pushed, because of the limits set in the config", n_left_out, playlist_name));
+    }
+
+    selected
 }
 
-fn sync_files(status_tx: &status::Sender, file_set: &FileSet, files_on_device: &HashSet<PathBuf>, device: &dyn Device) -> Result<(), SyncError> {
-    let FileSet{ files_data, common_ancestor, .. } = file_set;
+/// List the files to push, update and remove
+fn plan_files(
+    file_set: &FileSet,
+    files_on_device: &HashSet<PathBuf>,
+    previous_sync_info: &Option<SyncInfo>,
+    device: &dyn Device,
+    plan: &mut SyncPlan,
+) -> Result<(), SyncError> {
+    let FileSet{ files_data, .. } = file_set;
 
     // What files should there be on the device?
     let expected_files: HashSet<PathBuf> = files_data.keys().map(|r| r.to_path_buf()).collect();
 
     // What files are there on the device already?
-    let files_to_remove = case_insensitive_difference(&files_on_device, &expected_files);
-    let paths_to_push = case_insensitive_difference(&expected_files, &files_on_device);
-    let files_to_push: Vec<_> = paths_to_push
-        .filter_map(|path| files_data.get(path).map(|item| (path, item)))
-        .collect();
-
-    // Actually sync files
-    status_tx.send_progress(Progress::SyncingFiles);
     let device_root = device.music_folder().ok_or(SyncError::DeviceReadError)?;
-    for file_to_remove in files_to_remove {
-        status_tx.send(Message::RemovingFile(file_to_remove.display().to_string()));
-        if let Err(err) = device_root
-            .file_at(file_to_remove)
-            .and_then(|mut f| f.delete())
-        {
-            status_tx.send_warning(format!("Unable to remove file at {}: {}", file_to_remove.display(), err))
-        }
-    }
-
-    let mut i_file = 0;
-    let n_files = files_to_push.len();
-    let mut size_so_far = 0;
-    let total_size = files_to_push.iter().fold(0, |size, (_, data)| size + data.file_size);
-    for (path_to_push, file_data) in files_to_push {
-        i_file += 1;
-        status_tx.send(Message::PushingFile{
-            path: path_to_push.display().to_string(),
-            file_size: file_data.file_size,
-            size_so_far,
-            total_size,
-            n_files,
-            i_file,
-        });
-        size_so_far += file_data.file_size;
+    plan.files_to_remove = case_insensitive_difference(files_on_device, &expected_files)
+        .map(|path| PlannedFile{
+            path: path.clone(),
+            size: device_root.file_at(path).ok().and_then(|f| f.size()),
+        })
+        .collect();
+    plan.files_to_push = case_insensitive_difference(&expected_files, files_on_device)
+        .filter_map(|path| files_data.get(path).map(|item| PlannedFile{
+            path: path.clone(),
+            size: Some(item.file_size),
+        }))
+        .collect();
 
-        let local_absolute_path = common_ancestor.join(path_to_push);
-        if let Err(err) = device.push_music_file(&local_absolute_path, path_to_push) {
-            status_tx.send_warning(format!("Unable to push file {}: {}. Trying again...", path_to_push.display(), err));
-            if let Err(err) = device.push_music_file(&local_absolute_path, path_to_push) {
-                status_tx.send_warning(format!("Unable to push file {}: {}. Giving up.", path_to_push.display(), err));
-            }
-        }
+    // What files are on the device already, but have been modified in the source since the last sync?
+    if let Some(psi) = previous_sync_info {
+        let paths_to_push: HashSet<&PathBuf> = plan.files_to_push.iter().map(|f| &f.path).collect();
+        plan.files_to_update = files_data
+            .iter()
+            .filter(|(path, _)| paths_to_push.contains(path) == false)
+            .filter(|(path, data)| psi
+                .song_data_for_relative_path(path)
+                .map(|previous| previous.has_changed(data))
+                .unwrap_or(false)
+            )
+            .map(|(path, data)| PlannedFile{
+                path: path.clone(),
+                size: Some(data.file_size),
+            })
+            .collect();
     }
 
-    // TODO: remove empty folders
-
     Ok(())
 }
 
@@ -651,7 +1621,7 @@ fn files_on_device(status_tx: &status::Sender, device: &dyn Device) -> Result<Ha
 }
 
 fn populate_device_files(status_tx: &status::Sender, root_folder_path: &Path, files_on_device: &mut HashSet<PathBuf>, current_folder: &dyn Folder) {
-    print!("Scanning folder {:?}                \r", current_folder.path().display());
+    eprint!("Scanning folder {:?}                \r", current_folder.path().display());
     match current_folder.files() {
         Err(err) => status_tx.send_warning(format!("Unable to list files from folder '{:?}': {}", current_folder.path(), err)),
         Ok(files) => {
@@ -678,28 +1648,28 @@ fn populate_device_files(status_tx: &status::Sender, root_folder_path: &Path, fi
 }
 
 
-fn update_playlists(status_tx: &status::Sender, source: &dyn Source, device: &dyn Device, config: &Config, common_ancestor: &Path) -> Result<PlaylistsSet, SyncError> {
-    status_tx.send_progress(Progress::PushingPlaylists);
+/// List the playlist files to remove, and generate the ones to push.
+///
+/// This returns the content of the playlist files (indexed by their file names), and the playlists to store in the sync info.
+fn plan_playlists(status_tx: &status::Sender, scans: &ScanCache, device: &dyn Device, config: &Config, file_set: &FileSet, plan: &mut SyncPlan) -> Result<(Vec<(String, String)>, PlaylistsSet), SyncError> {
     let main_folder = device.starsync_folder().ok_or(SyncError::DeviceReadError)?;
 
-    // Remove previous playlists
-    if let Err(err) = remove_current_playlists(status_tx, main_folder.as_ref()) {
-        status_tx.send_warning(format!("Unable to remove playlists: {}", err));
+    // Previous playlists are removed
+    if let Err(err) = current_playlists(main_folder.as_ref(), plan) {
+        status_tx.send_warning(format!("Unable to list playlists: {}", err));
     }
 
-    // Push updated playlists
-    let playlists = push_playlists(status_tx, device, source, config, common_ancestor);
-    Ok(playlists)
+    // Then updated playlists are pushed
+    Ok(generate_playlists(status_tx, scans, config, file_set, plan))
 }
 
-fn remove_current_playlists(status_tx: &status::Sender, main_folder: &dyn Folder) -> Result<(), SyncError> {
+fn current_playlists(main_folder: &dyn Folder, plan: &mut SyncPlan) -> Result<(), SyncError> {
     let m3u_extension = OsStr::new("m3u");
 
-    for mut file in main_folder.files().map_err(|_| SyncError::DeviceReadError)? {
+    for file in main_folder.files().map_err(|_| SyncError::DeviceReadError)? {
         if file.path().extension() == Some(m3u_extension) {
-            status_tx.send(Message::RemovingPlaylist(file.path().display().to_string()));
-            if let Err(err) = file.delete() {
-                status_tx.send_warning(format!("Unable to delete {}: {}", file.path().display(), err));
+            if let Some(file_name) = file.path().file_name() {
+                plan.playlists_to_remove.push(file_name.to_string_lossy().to_string());
             }
         }
     }
@@ -707,52 +1677,58 @@ fn remove_current_playlists(status_tx: &status::Sender, main_folder: &dyn Folder
     Ok(())
 }
 
-fn push_playlists(status_tx: &status::Sender, device: &dyn Device, source: &dyn Source, config: &Config, common_ancestor: &Path) -> PlaylistsSet {
+/// Generate the playlists of the device from the scans of the playlists of the sources (see [`required_files`]).
+///
+/// Playlists that could not be scanned (e.g. because their source is not available) have been warned about already, and are skipped.
+fn generate_playlists(status_tx: &status::Sender, scans: &ScanCache, config: &Config, file_set: &FileSet, plan: &mut SyncPlan) -> (Vec<(String, String)>, PlaylistsSet) {
+    let mut playlist_files = Vec::new();
     let mut pushed_playlists = HashMap::new();
+    let device_paths = file_set.device_paths_by_id();
 
-    for playlist_name in config.playlists() {
-        match source.playlist_by_name(playlist_name) {
-            None => status_tx.send_warning(format!("Unable to get local playlist '{}'", playlist_name)),
-            Some(list) => {
-                // Push an M3U file into the device
-                match list.to_m3u(common_ancestor, Path::new(crate::device::MUSIC_FOLDER_NAME)) {
-                    Err(err) => status_tx.send_warning(format!("Unable to generate m3u file for playlist '{}': {}", playlist_name, err)),
-                    Ok(m3u_content) => {
-                        let device_relative_path = list.suitable_filename();
-                        status_tx.send(Message::PushingPlaylist(playlist_name.to_string()));
-                        if let Err(err) = device.push_playlist(&m3u_content, &OsStr::new(&device_relative_path)) {
-                            status_tx.send_warning(format!("Unable to push m3u file for playlist '{}': {}", playlist_name, err));
-                        }
-                    }
-                }
+    let configured_playlists = config.sources().iter()
+        .flat_map(|source_config| source_config.playlists.iter().map(move |playlist_config| (&source_config.name, playlist_config)));
 
-                // Populate the list of pushed playlists
-                let playlist_id = list.id();
-                match list.tracks() {
-                    Err(err) => status_tx.send_warning(format!("Unable to get tracks from playlist '{}': {}", playlist_name, err)),
-                    Ok(tracks) => {
-                        let song_ids = tracks
-                            .iter()
-                            .map(|track| track.id())
-                            .collect();
-
-                        if let Some(_old_entry) = pushed_playlists.insert(
-                            list.suitable_filename(),
-                            (playlist_id, song_ids)
-                        ) {
-                            status_tx.send_warning(format!("Duplicate playlists named '{}'", playlist_name));
-                        }
-                    }
-                }
+    for (source_name, playlist_config) in configured_playlists {
+        let playlist_name = &playlist_config.name;
+        let scan = match scans.get(&scan_key(source_name, playlist_config, config)) {
+            None => continue,
+            Some(scan) => scan,
+        };
+        // Tracks that have been left out because of the limits set in the config are not in the playlist on the device
+        // (nor in the sync info, so that they are not considered as removed from the device during the next reverse sync)
+        let song_ids: Vec<TrackId> = scan.tracks.iter()
+            .map(|(_, data)| data.id)
+            .filter(|id| file_set.is_selected(playlist_name, *id))
+            .collect();
+
+        // Generate an M3U file for the device
+        // (songs are referred to by their paths on the device, that may differ from the source, e.g. when they are transcoded)
+        let m3u = crate::source::create_m3u(
+            song_ids.iter().filter_map(|id| device_paths.get(id)),
+            Path::new(crate::device::MUSIC_FOLDER_NAME),
+        );
+        match m3u {
+            Err(err) => status_tx.send_warning(format!("Unable to generate m3u file for playlist '{}': {}", playlist_name, err)),
+            Ok(m3u_content) => {
+                plan.playlists_to_push.push(scan.file_name.clone());
+                playlist_files.push((scan.file_name.clone(), m3u_content));
             }
         }
+
+        // Populate the list of pushed playlists
+        if let Some(_old_entry) = pushed_playlists.insert(
+            scan.file_name.clone(),
+            SyncedPlaylist{ id: scan.id.clone(), tracks: song_ids, source: Some(source_name.to_string()) }
+        ) {
+            status_tx.send_warning(format!("Duplicate playlists named '{}'", playlist_name));
+        }
     }
 
-    pushed_playlists
+    (playlist_files, pushed_playlists)
 }
 
-fn push_star_playlists(status_tx: &status::Sender, device: &dyn Device, file_set: &FileSet) {
-    status_tx.send_progress(Progress::PushingRatings);
+fn star_playlists(status_tx: &status::Sender, file_set: &FileSet, plan: &mut SyncPlan) -> Vec<(String, String)> {
+    let mut playlist_files = Vec::new();
 
     for (rating, songs) in file_set.song_paths_by_rating().iter() {
         match crate::source::create_m3u(
@@ -762,29 +1738,62 @@ fn push_star_playlists(status_tx: &status::Sender, device: &dyn Device, file_set
             Err(err) => status_tx.send_warning(format!("Unable to generate m3u file for songs rated {} stars: {}", rating, err)),
             Ok(m3u_content) => {
                 let playlist_file_name = favorites_playlist_name(*rating);
-                status_tx.send(Message::PushingPlaylist(playlist_file_name.clone()));
-                if let Err(err) = device.push_playlist(&m3u_content, &OsStr::new(&playlist_file_name)) {
-                    status_tx.send_warning(format!("Unable to push m3u file for rating playlist '{}': {}", playlist_file_name, err));
-                }
+                plan.playlists_to_push.push(playlist_file_name.clone());
+                playlist_files.push((playlist_file_name, m3u_content));
             }
         }
     }
+
+    playlist_files
 }
 
-fn update_sync_info(status_tx: &status::Sender, device: &dyn Device, file_set: FileSet, playlists: PlaylistsSet) -> Result<(), Box<dyn Error>> {
-    status_tx.send_progress(Progress::UpdatingSyncInfo);
+/// The changelog that makes Rockbox import the ratings of the synced songs into its database.
+///
+/// Only ratings are written, since importing play counts that may be outdated would overwrite the ones of the player.
+fn rockbox_changelog_for(rockbox: &Rockbox, file_set: &FileSet) -> Changelog {
+    let mut files: Vec<_> = file_set.files_data.iter().collect();
+    files.sort_by_key(|(path, _)| *path);
+
+    let mut changelog = Changelog::default();
+    for (path, data) in files {
+        let mut entry = ChangelogEntry::new(rockbox.rockbox_path(path));
+        entry.set_rating(data.rating);
+        changelog.push(entry);
+    }
+    changelog
+}
 
-    let FileSet{ common_ancestor, files_data, .. } = file_set;
+fn build_sync_info(file_set: &FileSet, playlists: PlaylistsSet, rockbox_play_counts: &HashMap<TrackId, u32>) -> SyncInfo {
+    let FileSet{ roots, files_data, .. } = file_set;
     let song_data_to_serialize = files_data
         .iter()
-        .map(|(path, FileData{id, rating, ..})|
-            (PathBuf::from(path.to_string_lossy().to_lowercase()), (*id, *rating)))
+        .map(|(path, FileData{id, rating, file_size, modified, source_path, transcoding, source, ..})| {
+            let lowercase_path = PathBuf::from(path.to_string_lossy().to_lowercase());
+            // Remember where the file comes from, in case it has been laid out or its path has been sanitized
+            let relative_source_path = match roots.get(source).and_then(|root| source_path.strip_prefix(root).ok()) {
+                None => Some(source_path.clone()),
+                Some(relative) => {
+                    let mirrored_path = match path.extension() {
+                        Some(extension) if transcoding.is_some() => relative.with_extension(extension),
+                        _ => relative.to_path_buf(),
+                    };
+                    if mirrored_path.to_string_lossy().to_lowercase() == lowercase_path.to_string_lossy() {
+                        None
+                    } else {
+                        Some(relative.to_path_buf())
+                    }
+                },
+            };
+            (
+                lowercase_path,
+                SongData{ id: *id, rating: *rating, file_size: Some(*file_size), modified: *modified, transcoding: transcoding.map(|profile| profile.to_string()), source: Some(source.clone()), source_path: relative_source_path, rockbox_play_count: rockbox_play_counts.get(id).copied() },
+            )
+        })
         .collect();
-    let sync_info = SyncInfo::new(
-        common_ancestor,
+
+    SyncInfo::new(
+        roots.clone(),
         song_data_to_serialize,
         playlists,
-    );
-
-    device.push_sync_infos(&sync_info)
+    )
 }
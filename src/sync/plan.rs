@@ -0,0 +1,117 @@
+//! What a sync session does (or would do)
+
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+
+use crate::source::{PlaylistId, Rating, TrackId};
+
+/// The list of changes a sync session performs on the device and on the source.
+///
+/// This is what [`super::SyncManager::plan`] returns, so that a sync can be reviewed before it touches anything.
+///
+/// Note that a dry run does not update the source. Because of that, files that would be removed from the device only
+/// because a reverse-synced playlist no longer contains them are not listed in [`Self::files_to_remove`].
+#[derive(Debug, Default, Serialize)]
+pub struct SyncPlan {
+    /// Music files to copy into the device
+    pub files_to_push: Vec<PlannedFile>,
+    /// Music files already on the device, but that have been modified in the source since the last sync
+    pub files_to_update: Vec<PlannedFile>,
+    /// Music files to remove from the device
+    pub files_to_remove: Vec<PlannedFile>,
+    /// Playlist files (re-)written into the device
+    pub playlists_to_push: Vec<String>,
+    /// Playlist files removed from the device (and not re-written)
+    pub playlists_to_remove: Vec<String>,
+    /// Playlists whose changes on the device are reverse synced into the source
+    pub source_playlist_updates: Vec<PlaylistUpdate>,
+    /// Ratings that changed on the device and are reverse synced into the source
+    pub source_rating_updates: Vec<RatingUpdate>,
+    /// Plays recorded on the device (in scrobbler logs, or in the database of Rockbox), that are imported into the source
+    pub source_play_updates: Vec<PlayUpdate>,
+}
+
+/// A music file, with its path relative to the music folder of the device
+#[derive(Debug, Serialize)]
+pub struct PlannedFile {
+    pub path: PathBuf,
+    /// Size (in bytes) of the file, if known
+    pub size: Option<usize>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PlaylistUpdate {
+    pub name: String,
+    pub id: PlaylistId,
+    /// The current content of the playlist in the source
+    pub current_content: Vec<TrackId>,
+    /// The content of the playlist once the changes from the device are merged
+    pub new_content: Vec<TrackId>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RatingUpdate {
+    pub track_name: String,
+    pub track_id: TrackId,
+    pub current_rating_on_source: Rating,
+    pub new_rating: Rating,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PlayUpdate {
+    pub track_name: String,
+    pub track_id: TrackId,
+    /// How many times the track has been played on the device (since the previous import)
+    pub plays: u32,
+    /// The play count of the track in the source, before the plays of the device are added
+    pub current_play_count: u32,
+    /// When the track has been played for the last time (either on the device or on the source)
+    pub last_played: OffsetDateTime,
+}
+
+/// A quick summary of what the next sync would do, see [`super::SyncManager::pending_changes`]
+#[derive(Debug, Default, Serialize)]
+pub struct PendingChanges {
+    /// How many music files are to be copied into the device
+    pub files_to_push: usize,
+    /// How many music files are to be copied again, because they have been modified in the source
+    pub files_to_update: usize,
+    /// How many music files are to be removed from the device
+    pub files_to_remove: usize,
+    /// Total size of the files to push (including the updated ones), in bytes
+    pub bytes_to_push: usize,
+    /// Total size of the files to remove, in bytes (only counting files whose size is known)
+    pub bytes_to_remove: usize,
+    /// Playlists that have been modified on the device since the last sync
+    pub playlists_edited_on_device: Vec<String>,
+    /// How many tracks have had their ratings changed on the device since the last sync
+    pub ratings_edited_on_device: usize,
+}
+
+impl SyncPlan {
+    /// Total size of the files to push (including the updated ones), in bytes
+    pub fn size_to_push(&self) -> usize {
+        self.files_to_push.iter()
+            .chain(self.files_to_update.iter())
+            .filter_map(|f| f.size)
+            .sum()
+    }
+
+    /// Total size of the files to remove, in bytes (only counting files whose size is known)
+    pub fn size_to_remove(&self) -> usize {
+        self.files_to_remove.iter().filter_map(|f| f.size).sum()
+    }
+
+    /// Whether this sync has nothing to do (apart from re-writing playlists, which is always done)
+    pub fn is_empty(&self) -> bool {
+        self.files_to_push.is_empty()
+        && self.files_to_update.is_empty()
+        && self.files_to_remove.is_empty()
+        && self.playlists_to_remove.is_empty()
+        && self.source_playlist_updates.is_empty()
+        && self.source_rating_updates.is_empty()
+        && self.source_play_updates.is_empty()
+    }
+}
@@ -0,0 +1,469 @@
+//! Importing the plays recorded on a device (into scrobbler logs, or into the database of Rockbox) into the source
+//!
+//! The plays of scrobbler logs are also appended to a file that can be submitted to ListenBrainz later on.
+//! Entries of the scrobbler logs that have been imported are remembered on the device, so that they are not counted twice.
+//! The play counts of the Rockbox database are recorded into the sync info, for the same reason.
+
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsStr;
+use std::io::Read;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+
+use crate::device::{Device, Folder, IMPORTED_PLAYS_FILE, LISTENBRAINZ_EXPORT_CORRUPTED_FILE, LISTENBRAINZ_EXPORT_FILE};
+use crate::device::rockbox::Rockbox;
+use crate::device::scrobbler::{ScrobblerEntry, ScrobblerLog, SCROBBLER_LOG_FILE};
+use crate::source::{Source, Track, TrackId};
+use super::{source_of, status, PlayUpdate, SyncInfo};
+use super::status::Progress;
+
+/// An entry of a scrobbler log that has been imported.
+///
+/// These entries have no ID, but there is no way the same song is played twice at the same time.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+struct ImportedPlay {
+    timestamp: i64,
+    artist: String,
+    title: String,
+}
+
+impl From<&ScrobblerEntry> for ImportedPlay {
+    fn from(entry: &ScrobblerEntry) -> Self {
+        Self{ timestamp: entry.timestamp, artist: entry.artist.clone(), title: entry.title.clone() }
+    }
+}
+
+/// Import the plays of the scrobbler logs of the device that have not been imported yet.
+///
+/// Entries are mapped to the songs that have been synced (see [`SyncInfo`]) by their artists and titles.
+pub(super) fn import_plays(
+    status_tx: &status::Sender,
+    previous_sync_info: &SyncInfo,
+    sources: &[Box<dyn Source>],
+    device: &dyn Device,
+    dry_run: bool,
+) -> Vec<PlayUpdate> {
+    status_tx.send_progress(Progress::ImportingPlays);
+
+    let logs = scrobbler_logs(status_tx, device);
+    if logs.is_empty() {
+        return Vec::new();
+    }
+
+    let already_imported = imported_plays(status_tx, device);
+    let mut logged = HashSet::new();
+    // New entries, along with their actual (UTC) timestamps
+    let mut new_entries = Vec::new();
+    for log in &logs {
+        for entry in log.entries() {
+            let play = ImportedPlay::from(entry);
+            if already_imported.contains(&play) == false && logged.contains(&play) == false {
+                new_entries.push((entry, utc_timestamp(entry, log.is_utc())));
+            }
+            logged.insert(play);
+        }
+    }
+    if new_entries.is_empty() {
+        return Vec::new();
+    }
+    if logs.iter().any(|log| log.is_utc() == false) {
+        log::info!("Scrobbler logs of the device do not tell their time zones, their timestamps are assumed to be in the local time of this computer");
+    }
+
+    let tracks = synced_tracks(status_tx, previous_sync_info, sources);
+    let index = TrackIndex::new(&tracks);
+
+    // Plays of each track: how many, and the latest one
+    let mut plays: HashMap<TrackId, (u32, i64)> = HashMap::new();
+    let mut n_unknown = 0;
+    for (entry, timestamp) in new_entries.iter().filter(|(entry, _)| entry.listened) {
+        match index.find(entry) {
+            None => n_unknown += 1,
+            Some(id) => {
+                let (count, latest) = plays.entry(id).or_insert((0, *timestamp));
+                *count += 1;
+                *latest = (*latest).max(*timestamp);
+            },
+        }
+    }
+    status_tx.send_info(format!("{} new plays found in the scrobbler logs of the device", new_entries.iter().filter(|(entry, _)| entry.listened).count()));
+    if n_unknown > 0 {
+        status_tx.send_info(format!("{} of them do not match any synced song, they are only exported for ListenBrainz", n_unknown));
+    }
+
+    let (updates, failed) = update_play_stats(status_tx, &tracks, &plays, dry_run);
+
+    if dry_run == false {
+        let is_imported = |(entry, _): &(&ScrobblerEntry, i64)| index.find(entry).map(|id| failed.contains(&id) == false).unwrap_or(true);
+        let imported_entries: Vec<(&ScrobblerEntry, i64)> = new_entries.iter().copied().filter(is_imported).collect();
+
+        let listens = imported_entries.iter()
+            .filter(|(entry, _)| entry.listened)
+            .map(|(entry, timestamp)| Listen{ listened_at: *timestamp, ..Listen::from(*entry) });
+        if let Err(err) = export_listens(status_tx, device, listens) {
+            status_tx.send_warning(format!("Unable to export plays for ListenBrainz: {}", err));
+        }
+
+        // Entries that are not in the logs anymore (e.g. because they have been submitted to Last.fm and cleared) can be forgotten
+        let mut imported: HashSet<ImportedPlay> = already_imported.into_iter().filter(|play| logged.contains(play)).collect();
+        // These are identified by their timestamps as they are written in the logs
+        imported.extend(imported_entries.into_iter().map(|(entry, _)| ImportedPlay::from(entry)));
+        if let Err(err) = remember_imported_plays(device, imported) {
+            status_tx.send_warning(format!("Unable to record the imported plays into the device, they will be imported again next time: {}", err));
+        }
+    }
+
+    updates
+}
+
+/// Import the plays counted by the database of Rockbox, as the player exports them into its changelog (see [`crate::device::rockbox`]).
+///
+/// Rockbox only tells how many times each song has been played. The plays counted since the previous import (see [`super::info::SongData::rockbox_play_count`]) are added to the source,
+/// unless the device has scrobbler logs: the database counts the plays that are logged as well, and these are imported from the logs (see [`import_plays`]).
+///
+/// This returns the updates, along with the play counts to record into the sync info. Songs whose plays could not be recorded into the source are left out, so that their plays are imported next time.
+pub(super) fn import_rockbox_plays(
+    status_tx: &status::Sender,
+    previous_sync_info: &SyncInfo,
+    sources: &[Box<dyn Source>],
+    device: &dyn Device,
+    rockbox: &Rockbox,
+    dry_run: bool,
+) -> (Vec<PlayUpdate>, HashMap<TrackId, u32>) {
+    let changelog = match rockbox.changelog() {
+        Err(err) => {
+            status_tx.send_warning(format!("Unable to read the play counts of the Rockbox database: {}", err));
+            return (Vec::new(), HashMap::new());
+        },
+        Ok(None) => return (Vec::new(), HashMap::new()),
+        Ok(Some(changelog)) => changelog,
+    };
+
+    // Rockbox does not tell when songs have been played, only that this happened after the previous sync
+    let previous_sync = previous_sync_info.timestamp().unix_timestamp();
+    let mut play_counts = HashMap::new();
+    let mut plays: HashMap<TrackId, (u32, i64)> = HashMap::new();
+    for entry in changelog.entries() {
+        let play_count = match entry.play_count() {
+            None => continue,
+            Some(play_count) => play_count,
+        };
+        let song_data = match entry.filename()
+            .and_then(|filename| rockbox.relative_path(filename))
+            .and_then(|path| previous_sync_info.song_data_for_relative_path(&path))
+        {
+            None => continue,
+            Some(song_data) => song_data,
+        };
+
+        play_counts.insert(song_data.id, play_count);
+        let new_plays = new_rockbox_plays(song_data.rockbox_play_count, play_count);
+        if new_plays > 0 {
+            plays.insert(song_data.id, (new_plays, previous_sync));
+        }
+    }
+    if plays.is_empty() || has_scrobbler_logs(device) {
+        return (Vec::new(), play_counts);
+    }
+    status_tx.send_info(format!("{} new plays found in the database of Rockbox", plays.values().map(|(count, _)| count).sum::<u32>()));
+
+    let tracks = synced_tracks(status_tx, previous_sync_info, sources);
+    let (updates, _failed) = update_play_stats(status_tx, &tracks, &plays, dry_run);
+    let updated: HashSet<TrackId> = updates.iter().map(|update| update.track_id).collect();
+    play_counts.retain(|id, _| plays.contains_key(id) == false || updated.contains(id));
+
+    (updates, play_counts)
+}
+
+/// How many plays the database of Rockbox has counted since the previous import.
+///
+/// When the previous count is unknown (e.g. for songs synced by older versions of StarSync), there is no way to tell which plays have been imported already (e.g. from scrobbler logs), so none is considered new.
+/// A count that decreased means the database has been rebuilt since then.
+fn new_rockbox_plays(previous_play_count: Option<u32>, play_count: u32) -> u32 {
+    match previous_play_count {
+        None => 0,
+        Some(previous_play_count) => play_count.checked_sub(previous_play_count).unwrap_or(play_count),
+    }
+}
+
+/// Add plays (counts, and the Unix timestamps of the latest ones) to the play stats of the source.
+///
+/// This returns the updates, and the IDs of the tracks whose play stats could not be updated.
+fn update_play_stats(status_tx: &status::Sender, tracks: &[Box<dyn Track>], plays: &HashMap<TrackId, (u32, i64)>, dry_run: bool) -> (Vec<PlayUpdate>, HashSet<TrackId>) {
+    let mut updates = Vec::new();
+    let mut failed = HashSet::new();
+    for track in tracks {
+        let (count, latest) = match plays.get(&track.id()) {
+            None => continue,
+            Some(plays) => *plays,
+        };
+        let played_on_device = SystemTime::UNIX_EPOCH + Duration::from_secs(latest.max(0) as u64);
+        let last_played = track.last_played().map(|last_played| last_played.max(played_on_device)).unwrap_or(played_on_device);
+        let update = PlayUpdate{
+            track_name: track.name(),
+            track_id: track.id(),
+            plays: count,
+            current_play_count: track.play_count().unwrap_or(0),
+            last_played: OffsetDateTime::from(last_played),
+        };
+        if dry_run == false {
+            if let Err(err) = track.set_play_stats(update.current_play_count + update.plays, last_played) {
+                status_tx.send_warning(format!("Unable to record the plays of '{}' into the source: {}", update.track_name, err));
+                // These will be imported again next time
+                failed.insert(track.id());
+                continue;
+            }
+        }
+        updates.push(update);
+    }
+    (updates, failed)
+}
+
+/// The Unix timestamp of an entry.
+///
+/// Logs that are not in UTC are in the local time of the player, which is assumed to be in the same time zone as this computer.
+fn utc_timestamp(entry: &ScrobblerEntry, is_utc: bool) -> i64 {
+    if is_utc {
+        return entry.timestamp;
+    }
+    match crate::os::local_offset_at(entry.timestamp) {
+        None => entry.timestamp,
+        Some(offset) => entry.timestamp - offset.whole_seconds() as i64,
+    }
+}
+
+/// The scrobbler logs, at the root of the device or in its StarSync folder
+fn scrobbler_logs(status_tx: &status::Sender, device: &dyn Device) -> Vec<ScrobblerLog> {
+    let mut logs = Vec::new();
+    for folder in scrobbler_log_folders(device).iter().flatten() {
+        let file = match folder.file_at(Path::new(SCROBBLER_LOG_FILE)) {
+            Err(_) => continue,
+            Ok(file) => file,
+        };
+        match file.get_reader().and_then(ScrobblerLog::parse) {
+            Err(err) => status_tx.send_warning(format!("Unable to read {}: {}", file.path().display(), err)),
+            Ok(log) => logs.push(log),
+        }
+    }
+    logs
+}
+
+fn has_scrobbler_logs(device: &dyn Device) -> bool {
+    scrobbler_log_folders(device).iter()
+        .flatten()
+        .any(|folder| folder.file_at(Path::new(SCROBBLER_LOG_FILE)).is_ok())
+}
+
+fn scrobbler_log_folders(device: &dyn Device) -> [Option<Box<dyn Folder>>; 2] {
+    [device.root_folder(), device.starsync_folder()]
+}
+
+fn imported_plays(status_tx: &status::Sender, device: &dyn Device) -> HashSet<ImportedPlay> {
+    match device.config_file(IMPORTED_PLAYS_FILE).map(serde_json::from_reader) {
+        None => HashSet::new(),
+        Some(Err(err)) => {
+            status_tx.send_warning(format!("Unable to parse {}, plays may be counted twice: {}", IMPORTED_PLAYS_FILE, err));
+            HashSet::new()
+        },
+        Some(Ok(plays)) => plays,
+    }
+}
+
+fn remember_imported_plays(device: &dyn Device, plays: HashSet<ImportedPlay>) -> Result<(), Box<dyn std::error::Error>> {
+    let mut plays: Vec<ImportedPlay> = plays.into_iter().collect();
+    plays.sort_by_key(|play| play.timestamp);
+    let json = serde_json::to_vec(&plays).map_err(|err| format!("Unable to serialize the imported plays: {}", err))?;
+    device.push_config_file(OsStr::new(IMPORTED_PLAYS_FILE), &json)
+}
+
+/// The songs of the sources that have been synced into the device
+fn synced_tracks(status_tx: &status::Sender, previous_sync_info: &SyncInfo, sources: &[Box<dyn Source>]) -> Vec<Box<dyn Track>> {
+    let mut synced_ids: HashMap<&str, HashSet<TrackId>> = HashMap::new();
+    for (_, data) in previous_sync_info.song_data() {
+        if let Ok(source) = source_of(sources, data.source.as_deref()) {
+            synced_ids.entry(source.name()).or_default().insert(data.id);
+        }
+    }
+
+    let mut tracks = Vec::new();
+    for source in sources {
+        let ids = match synced_ids.get(source.name()) {
+            None => continue,
+            Some(ids) => ids,
+        };
+        match source.tracks() {
+            Err(err) => status_tx.send_warning(format!("Unable to list the songs of {}: {}", source.name(), err)),
+            Ok(source_tracks) => tracks.extend(source_tracks.into_iter().filter(|track| ids.contains(&track.id()))),
+        }
+    }
+    tracks
+}
+
+/// The (lowercased) artist and album of a song, and its ID
+type IndexedTrack = (Option<String>, Option<String>, TrackId);
+
+/// Finds songs by their artists and titles (case-insensitively)
+struct TrackIndex {
+    by_title: HashMap<String, Vec<IndexedTrack>>,
+}
+
+impl TrackIndex {
+    fn new(tracks: &[Box<dyn Track>]) -> Self {
+        let mut by_title: HashMap<String, Vec<_>> = HashMap::new();
+        for track in tracks {
+            by_title.entry(track.name().to_lowercase())
+                .or_default()
+                .push((track.artist().map(|artist| artist.to_lowercase()), track.album().map(|album| album.to_lowercase()), track.id()));
+        }
+        Self{ by_title }
+    }
+
+    /// The song a scrobbler entry refers to, unless there is no such song, or several of them match
+    fn find(&self, entry: &ScrobblerEntry) -> Option<TrackId> {
+        let candidates = self.by_title.get(&entry.title.to_lowercase())?;
+        let artist = entry.artist.to_lowercase();
+        let album = entry.album.as_ref().map(|album| album.to_lowercase());
+
+        // Songs without artist (e.g. whose tags are missing in the source) may still match by title
+        let mut matching: Vec<_> = candidates.iter()
+            .filter(|(track_artist, _, _)| track_artist.as_deref().map(|track_artist| track_artist == artist).unwrap_or(true))
+            .collect();
+        if matching.len() > 1 && album.is_some() {
+            matching.retain(|(_, track_album, _)| track_album == &album);
+        }
+        match matching.as_slice() {
+            [(_, _, id)] => Some(*id),
+            _ => None,
+        }
+    }
+}
+
+
+
+/// The format ListenBrainz expects to import listens (see <https://listenbrainz.readthedocs.io/en/latest/users/json.html>)
+#[derive(Serialize, Deserialize)]
+struct ListenBrainzSubmission {
+    listen_type: String,
+    payload: Vec<Listen>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Listen {
+    listened_at: i64,
+    track_metadata: TrackMetadata,
+}
+
+#[derive(Serialize, Deserialize)]
+struct TrackMetadata {
+    artist_name: String,
+    track_name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    release_name: Option<String>,
+    additional_info: AdditionalInfo,
+}
+
+#[derive(Serialize, Deserialize)]
+struct AdditionalInfo {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tracknumber: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    duration_ms: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    track_mbid: Option<String>,
+    submission_client: String,
+}
+
+impl From<&ScrobblerEntry> for Listen {
+    fn from(entry: &ScrobblerEntry) -> Self {
+        Self{
+            listened_at: entry.timestamp,
+            track_metadata: TrackMetadata{
+                artist_name: entry.artist.clone(),
+                track_name: entry.title.clone(),
+                release_name: entry.album.clone(),
+                additional_info: AdditionalInfo{
+                    tracknumber: entry.track_number,
+                    duration_ms: entry.length.map(|secs| secs as u64 * 1000),
+                    track_mbid: entry.musicbrainz_id.clone(),
+                    submission_client: "StarSync".to_string(),
+                },
+            },
+        }
+    }
+}
+
+/// Append listens to the ones that have been exported already, and have not been submitted yet.
+///
+/// In case the exported listens are corrupted, they are set aside (so that they can be recovered by hand), and a new file is started.
+fn export_listens(status_tx: &status::Sender, device: &dyn Device, listens: impl Iterator<Item = Listen>) -> Result<(), Box<dyn std::error::Error>> {
+    let new_submission = || ListenBrainzSubmission{ listen_type: "import".to_string(), payload: Vec::new() };
+    let mut submission = match device.config_file(LISTENBRAINZ_EXPORT_FILE) {
+        None => new_submission(),
+        Some(mut reader) => {
+            let mut json = Vec::new();
+            reader.read_to_end(&mut json)?;
+            match serde_json::from_slice(&json) {
+                Ok(submission) => submission,
+                Err(err) => {
+                    device.push_config_file(OsStr::new(LISTENBRAINZ_EXPORT_CORRUPTED_FILE), &json)?;
+                    status_tx.send_warning(format!("Unable to parse {} ({}). It has been moved to {}, and new plays are exported into a new file", LISTENBRAINZ_EXPORT_FILE, err, LISTENBRAINZ_EXPORT_CORRUPTED_FILE));
+                    new_submission()
+                },
+            }
+        },
+    };
+    let n_listens = submission.payload.len();
+    submission.payload.extend(listens);
+    if submission.payload.len() == n_listens {
+        return Ok(());
+    }
+
+    let json = serde_json::to_vec_pretty(&submission).map_err(|err| format!("Unable to serialize the listens: {}", err))?;
+    device.push_config_file(OsStr::new(LISTENBRAINZ_EXPORT_FILE), &json)
+}
+
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn rockbox_plays() {
+        assert_eq!(new_rockbox_plays(Some(3), 5), 2);
+        assert_eq!(new_rockbox_plays(Some(3), 3), 0);
+        // Plays of songs whose previous count is unknown may have been imported from scrobbler logs already
+        assert_eq!(new_rockbox_plays(None, 5), 0);
+        // The database has been rebuilt
+        assert_eq!(new_rockbox_plays(Some(7), 2), 2);
+    }
+
+    #[test]
+    fn listen_format() {
+        let entry = ScrobblerEntry{
+            artist: "Someone".to_string(),
+            album: None,
+            title: "First song".to_string(),
+            track_number: Some(1),
+            length: Some(215),
+            listened: true,
+            timestamp: 1700000000,
+            musicbrainz_id: None,
+        };
+        let submission = ListenBrainzSubmission{ listen_type: "import".to_string(), payload: vec![Listen::from(&entry)] };
+        assert_eq!(serde_json::to_value(&submission).unwrap(), serde_json::json!({
+            "listen_type": "import",
+            "payload": [{
+                "listened_at": 1700000000,
+                "track_metadata": {
+                    "artist_name": "Someone",
+                    "track_name": "First song",
+                    "additional_info": { "tracknumber": 1, "duration_ms": 215000, "submission_client": "StarSync" },
+                },
+            }],
+        }));
+    }
+}
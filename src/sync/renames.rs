@@ -0,0 +1,171 @@
+//! Noticing playlists that have been renamed in the source since the previous sync
+//!
+//! Some sources (e.g. Rhythmbox) identify playlists by their names. Once such a playlist is renamed, there is no way to tell it is the same playlist,
+//! except that its content is (mostly) the same as what has been synced under its former name.
+
+use std::collections::HashSet;
+
+use serde::Serialize;
+
+use crate::config::Config;
+use crate::source::{PlaylistId, Source, TrackId};
+use super::SyncInfo;
+
+/// How similar the contents of a playlist must be to what has been synced under another name, to be considered a renamed playlist (see [`similarity`])
+const MIN_SIMILARITY: f64 = 0.5;
+
+/// A playlist of the source that seems to have been renamed since the previous sync
+#[derive(Clone, Debug, Serialize)]
+pub struct RenamedPlaylist {
+    /// The name of the source this playlist belongs to
+    pub source: String,
+    /// The name the playlist had during the previous sync (which the config still refers to)
+    pub old_name: String,
+    pub new_name: String,
+    /// How much the current content of the playlist matches what has been synced under its former name (between 0 and 1)
+    pub similarity: f64,
+    /// Whether the config should be updated to follow the new name.<br/>
+    /// Otherwise, this playlist is no longer synced, and the changes made to it on the device are not reverse synced.
+    pub follow: bool,
+}
+
+/// Look for playlists of the config that no longer exist in their source, but that seem to have been renamed
+pub(super) fn renamed_playlists(sources: &[Box<dyn Source>], config: &Config, previous_sync_info: Option<&SyncInfo>) -> Vec<RenamedPlaylist> {
+    let previous_sync_info = match previous_sync_info {
+        None => return Vec::new(),
+        Some(psi) => psi,
+    };
+    let main_source = config.source_names().next();
+
+    let mut renamed = Vec::new();
+    for source_config in config.sources() {
+        let source = match sources.iter().find(|source| source.name() == source_config.name) {
+            None => continue,
+            Some(source) => source,
+        };
+
+        // What has been synced from this source
+        let synced: Vec<(&str, &[TrackId])> = previous_sync_info.playlists()
+            .filter(|(_, synced)| synced.source.as_deref().or(main_source) == Some(source_config.name.as_str()))
+            .filter_map(|(_, synced)| match &synced.id {
+                PlaylistId::Name(name) => Some((name.as_str(), synced.tracks.as_slice())),
+                _ => None,
+            })
+            .collect();
+
+        // Configured playlists that have vanished from the source
+        let missing: Vec<(&str, &[TrackId])> = source_config.playlists.iter()
+            .filter(|playlist_config| playlist_config.rule.is_none())
+            .filter(|playlist_config| source.playlist_by_name(&playlist_config.name).is_none())
+            .filter_map(|playlist_config| synced.iter().find(|(name, _)| *name == playlist_config.name).copied())
+            .collect();
+        if missing.is_empty() {
+            continue;
+        }
+
+        // Playlists of the source that are neither configured nor synced are the candidates for their new names
+        let playlists = match source.playlists() {
+            Err(err) => {
+                log::warn!("Unable to list playlists of {}: {}", source.name(), err);
+                continue;
+            },
+            Ok(playlists) => playlists,
+        };
+        let candidates: Vec<(String, Vec<TrackId>)> = playlists.iter()
+            .filter(|playlist| source_config.playlists().any(|name| name == playlist.name()) == false)
+            .filter(|playlist| synced.iter().any(|(name, _)| *name == playlist.name()) == false)
+            .filter_map(|playlist| {
+                let tracks = playlist.tracks().ok()?;
+                Some((playlist.name(), tracks.iter().map(|track| track.id()).collect()))
+            })
+            .collect();
+        let candidates: Vec<(&str, &[TrackId])> = candidates.iter().map(|(name, tracks)| (name.as_str(), tracks.as_slice())).collect();
+
+        for (old_name, new_name, similarity) in match_renames(&missing, &candidates) {
+            renamed.push(RenamedPlaylist{
+                source: source_config.name.clone(),
+                old_name: old_name.to_string(),
+                new_name: new_name.to_string(),
+                similarity,
+                follow: false,
+            });
+        }
+    }
+    renamed
+}
+
+/// A copy of the config, updated so that renamed playlists are synced under their new names
+pub(super) fn follow_renames(config: &Config, renamed_playlists: &[RenamedPlaylist]) -> Config {
+    let mut config = config.clone();
+    for renamed in renamed_playlists {
+        if let Some(source_config) = config.source_mut(&renamed.source) {
+            source_config.rename_playlist(&renamed.old_name, &renamed.new_name);
+        }
+    }
+    config
+}
+
+/// Pair former playlists with current playlists, the most similar ones first.
+///
+/// Every playlist is paired at most once. This returns `(former name, current name, similarity)` tuples.
+fn match_renames<'a>(former: &[(&'a str, &[TrackId])], current: &[(&'a str, &[TrackId])]) -> Vec<(&'a str, &'a str, f64)> {
+    let mut pairs = Vec::new();
+    for (former_name, former_tracks) in former {
+        for (current_name, current_tracks) in current {
+            let score = similarity(former_tracks, current_tracks);
+            if score >= MIN_SIMILARITY {
+                pairs.push((*former_name, *current_name, score));
+            }
+        }
+    }
+    pairs.sort_by(|a, b| b.2.total_cmp(&a.2));
+
+    let mut matched_former = HashSet::new();
+    let mut matched_current = HashSet::new();
+    pairs.retain(|(former_name, current_name, _)| {
+        if matched_former.contains(former_name) || matched_current.contains(current_name) {
+            return false;
+        }
+        matched_former.insert(*former_name);
+        matched_current.insert(*current_name);
+        true
+    });
+    pairs
+}
+
+/// The ratio of tracks two playlists have in common (the Jaccard index of their sets of tracks). Two empty playlists are not considered similar.
+fn similarity(a: &[TrackId], b: &[TrackId]) -> f64 {
+    let a: HashSet<_> = a.iter().collect();
+    let b: HashSet<_> = b.iter().collect();
+    let union = a.union(&b).count();
+    if union == 0 {
+        return 0.0;
+    }
+    a.intersection(&b).count() as f64 / union as f64
+}
+
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn matching() {
+        let ids = |ids: &[u64]| ids.iter().map(|id| TrackId(*id)).collect::<Vec<_>>();
+        let road_trip = ids(&[1, 2, 3, 4]);
+        let jazz = ids(&[10, 11, 12]);
+        let empty = ids(&[]);
+        let former = [("Road trip", road_trip.as_slice()), ("Jazz", jazz.as_slice()), ("Empty", empty.as_slice())];
+
+        let summer_road_trip = ids(&[1, 2, 3, 4, 5]);
+        let road_trip_copy = ids(&[1, 2, 6, 7]);
+        let rock = ids(&[20, 21, 10]);
+        let new_empty = ids(&[]);
+        let current = [("Road trip copy", road_trip_copy.as_slice()), ("Summer road trip", summer_road_trip.as_slice()), ("Rock", rock.as_slice()), ("New", new_empty.as_slice())];
+
+        assert_eq!(similarity(&road_trip, &summer_road_trip), 0.8);
+        assert_eq!(similarity(&empty, &new_empty), 0.0);
+        assert_eq!(match_renames(&former, &current), vec![("Road trip", "Summer road trip", 0.8)]);
+    }
+}
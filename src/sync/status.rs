@@ -1,5 +1,7 @@
 use std::sync::atomic::AtomicUsize;
 
+use serde::Serialize;
+
 use super::{TrackId, Rating};
 
 pub struct Sender {
@@ -45,8 +47,15 @@ impl Sender {
     }
 }
 
-#[derive(Debug)]
+/// An event of the sync.
+///
+/// This is serialized as `{"event": "<variant_name>", "data": <content>}`, e.g. `{"event": "removing_file", "data": "Artist/song.mp3"}`
+/// or `{"event": "progress", "data": "syncing_files"}`.
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", content = "data", rename_all = "snake_case")]
 pub enum Message {
+    /// The sync of a device is starting, when several devices are synced in a row (see [`super::sync_all_devices`])
+    SyncingDevice(String),
     /// Info about the current step we have reached
     Progress(Progress),
     /// Reading a playlist from the device
@@ -59,8 +68,12 @@ pub enum Message {
     UpdatingSongRatingIntoSource{ track_name: String, new_rating: Rating, current_rating_on_source: Rating },
     /// A music file is about to be copied
     PushingFile{ path: String, file_size: usize, size_so_far: usize, total_size: usize, n_files: usize, i_file: usize },
+    /// A music file that has changed in the source since the last sync is about to be copied again
+    UpdatingFile{ path: String, file_size: usize, size_so_far: usize, total_size: usize, n_files: usize, i_file: usize },
     /// A music file is about to be removed
     RemovingFile(String),
+    /// A track of a playlist will not be pushed to the device, because of the limits set in the config
+    LeavingOutTrack{ track_name: String, playlist_name: String, reason: String },
     /// A playlist file is about to be copied
     PushingPlaylist(String),
     /// A playlist file is about to be removed
@@ -71,8 +84,13 @@ pub enum Message {
     Warning(String),
 }
 
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
 pub enum Progress {
+    /// Performing the remaining operations of a previous sync that has been interrupted
+    ResumingInterruptedSync,
+    /// Undoing a previous sync that has been interrupted
+    RollingBackInterruptedSync,
     /// Sync has started
     Started,
     /// Scanning the files on the device
@@ -81,6 +99,8 @@ pub enum Progress {
     ReverseSyncPlaylists,
     /// Reverse-syncing song ratings
     ReverseSyncRatings,
+    /// Importing the plays recorded on the device
+    ImportingPlays,
     /// Generating the list of files to sync
     ListingFilesInSource,
     /// Currently syncing files
@@ -0,0 +1,123 @@
+//! Syncs that run without anyone to answer their sanity checks
+
+use std::sync::mpsc::channel;
+
+use humansize::format_size;
+
+use crate::config::UnattendedPolicy;
+use super::{status, InterruptedSync, SyncError, SyncManager, SyncValidator, Warnings};
+use super::status::Message;
+
+impl SyncValidator {
+    /// Acknowledge the failed checks that `policy` allows. The other ones are left untouched, so that the sync will not start.
+    pub fn apply_policy(&mut self, policy: &UnattendedPolicy) {
+        if policy.allow_other_computer {
+            self.last_sync_computer_mismatch = None;
+        }
+        if policy.allow_not_enough_space {
+            self.not_enough_space = None;
+        }
+        if let Some(interrupted_sync) = &mut self.interrupted_sync {
+            interrupted_sync.action = policy.interrupted_sync;
+        }
+        for renamed_playlist in &mut self.renamed_playlists {
+            renamed_playlist.follow = policy.follow_renamed_playlists;
+        }
+    }
+}
+
+/// Sync a device whose config allows automatic syncs, answering the sanity checks with the unattended policy of its config.
+///
+/// A [`Message::SyncingDevice`] is sent into `status_tx` before the sync starts.
+pub fn auto_sync(device_name: &str, status_tx: status::Sender) -> Result<Warnings, SyncError> {
+    let sync_manager = SyncManager::with_device(device_name)?;
+    if sync_manager.config().auto_sync() == false {
+        return Err(SyncError::AutoSyncDisabled);
+    }
+    let policy = sync_manager.config().unattended_policy();
+    status_tx.send(Message::SyncingDevice(device_name.to_string()));
+
+    let (outbound_tx, outbound_rx) = channel::<SyncValidator>();
+    let (inbound_tx, inbound_rx) = channel();
+    let status_tx = &status_tx;
+    std::thread::scope(|scope| {
+        // Rating and playlist conflicts may be sent in other validators later on. They are left as-is, so that the source is kept unchanged
+        scope.spawn(move || {
+            for mut validator in outbound_rx {
+                validator.apply_policy(&policy);
+                for check in unacknowledged_checks(&validator) {
+                    status_tx.send_warning(format!("Not syncing, as the unattended policy of this device does not allow it: {check}"));
+                }
+                inbound_tx.send(validator).ok();
+            }
+        });
+        let result = sync_manager.run(status_tx, &outbound_tx, &inbound_rx);
+        drop(outbound_tx);
+        result.map(|_| status_tx.warnings_count())
+    })
+}
+
+/// Describe the failed checks that have not been acknowledged
+fn unacknowledged_checks(validator: &SyncValidator) -> Vec<String> {
+    let mut checks = Vec::new();
+    if let Some((previous_hostname, current_hostname)) = &validator.last_sync_computer_mismatch {
+        checks.push(format!("the previous sync has been done on computer \"{previous_hostname}\" instead of \"{current_hostname}\""));
+    }
+    if let Some((required, available)) = validator.not_enough_space {
+        checks.push(format!("the files to sync require {}, but only {} are available",
+            format_size(required, humansize::DECIMAL), format_size(available, humansize::DECIMAL)));
+    }
+    if let Some(InterruptedSync{ action: None, .. }) = &validator.interrupted_sync {
+        checks.push("the previous sync has been interrupted".to_string());
+    }
+    checks
+}
+
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::sync::InterruptedSyncAction;
+
+    #[test]
+    fn policy() {
+        let validator = || SyncValidator{
+            device: "My phone".to_string(),
+            last_sync_computer_mismatch: Some(("laptop".to_string(), "desktop".to_string())),
+            not_enough_space: Some((2_000, 1_000)),
+            interrupted_sync: Some(InterruptedSync{
+                hostname: "laptop".to_string(),
+                timestamp: time::OffsetDateTime::UNIX_EPOCH,
+                done_operations: 3,
+                total_operations: 10,
+                action: None,
+            }),
+            renamed_playlists: Vec::new(),
+            rating_conflicts: Vec::new(),
+            playlist_conflicts: Vec::new(),
+        };
+
+        let mut strict = validator();
+        strict.apply_policy(&UnattendedPolicy::default());
+        assert!(strict.is_valid() == false);
+        assert!(strict.last_sync_computer_mismatch.is_some());
+        assert_eq!(unacknowledged_checks(&strict).len(), 3);
+
+        let mut lenient = validator();
+        lenient.apply_policy(&UnattendedPolicy{
+            allow_other_computer: true,
+            allow_not_enough_space: true,
+            interrupted_sync: Some(InterruptedSyncAction::Resume),
+            follow_renamed_playlists: true,
+        });
+        assert!(lenient.is_valid());
+        assert!(unacknowledged_checks(&lenient).is_empty());
+
+        let mut partial = validator();
+        partial.apply_policy(&UnattendedPolicy{ allow_other_computer: true, ..Default::default() });
+        assert!(partial.is_valid() == false);
+        assert!(partial.last_sync_computer_mismatch.is_none());
+        assert!(partial.not_enough_space.is_some());
+    }
+}
@@ -2,7 +2,12 @@ use std::collections::{HashMap, HashSet};
 use std::path::{PathBuf, Path};
 use std::num::NonZeroU8;
 
+use time::OffsetDateTime;
+
 use crate::source::{TrackId, Rating};
+use crate::config::RatingConflictPolicy;
+use crate::transcode::Profile;
+use crate::layout::TrackTags;
 
 const RATINGS_PLAYLIST_PREFIX: &str = "Favourites - ";
 const RATINGS_PLAYLIST_SUFFIX: &str = " stars.m3u";
@@ -57,21 +62,38 @@ pub fn favorites_playlist_name(rating: u8) -> String {
 }
 
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct FileData {
     /// Size (in bytes) of the file
     pub file_size: usize,
     pub id: TrackId,
     pub rating: Rating,
+    /// Last modification time of the file, if known
+    pub modified: Option<OffsetDateTime>,
+    /// Absolute path of the file on the source
+    pub source_path: PathBuf,
+    /// How this file is converted before being pushed, if it is
+    pub transcoding: Option<Profile>,
+    /// The name of the source this file comes from
+    pub source: String,
+    /// The tags of the track, in case the device has a layout (see [`crate::layout`])
+    pub tags: Option<TrackTags>,
 }
 
 #[derive(Debug)]
 pub struct FileSet {
-    pub common_ancestor: PathBuf,
-    /// A hashmap indexed by relative paths on the source
+    /// The folder of each source (indexed by name) the paths on the device are relative to
+    pub roots: HashMap<String, PathBuf>,
+    /// A hashmap indexed by relative paths on the device.
+    ///
+    /// These are the paths relative to the root of their sources (unless the device has a layout, see [`crate::layout`]), apart from the extension of transcoded files, from the numbers given to laid out files that would collide, and from paths that are not valid on the filesystem of the device (see [`crate::device::filesystem`]).
     pub files_data: HashMap<PathBuf, FileData>,
     /// Total size of this file set, in bytes
     pub total_size: usize,
+    /// The tracks of each playlist (indexed by name) that made it into this set.
+    ///
+    /// Some may have been left out, because of the limits set in the config.
+    pub selected_tracks: HashMap<String, HashSet<TrackId>>,
 }
 
 impl FileSet {
@@ -91,6 +113,117 @@ impl FileSet {
 
         rated_songs
     }
+
+    /// Whether a track of a playlist should be pushed along with this playlist
+    pub fn is_selected(&self, playlist_name: &str, track_id: TrackId) -> bool {
+        self.selected_tracks
+            .get(playlist_name)
+            .map(|tracks| tracks.contains(&track_id))
+            .unwrap_or(false)
+    }
+
+    /// Relative paths on the device, indexed by song ID
+    pub fn device_paths_by_id(&self) -> HashMap<TrackId, &Path> {
+        self.files_data
+            .iter()
+            .map(|(path, data)| (data.id, path.as_path()))
+            .collect()
+    }
+}
+
+
+/// A file to push, with the path it would have on the device (relative to its music folder)
+pub struct PlacedFile {
+    pub device_path: PathBuf,
+    pub data: FileData,
+    /// Whether this path comes from the layout of the device (see [`crate::layout`])
+    pub is_laid_out: bool,
+}
+
+/// Files indexed by their paths on the device, and the files that have been left out, along with the path of the file they collide with
+pub type Placement = (HashMap<PathBuf, FileData>, Vec<(FileData, PathBuf)>);
+
+/// Resolve the collisions between files that would be pushed at the same place (paths are compared case-insensitively, like the sync info do).
+///
+/// This does not depend on the order of the files: files that are not transcoded come first, then files of the sources that come first in `source_names`, then files in the order of their source paths.
+/// Laid out files that collide with a previous file are numbered, e.g. `Song (2).mp3`. Other ones are left out.
+pub fn place_files(mut files: Vec<PlacedFile>, source_names: &[&str]) -> Placement {
+    files.sort_by(|a, b| {
+        let key = |file: &PlacedFile| (
+            file.data.transcoding.is_some(),
+            source_names.iter().position(|name| *name == file.data.source),
+        );
+        key(a).cmp(&key(b)).then_with(|| a.data.source_path.cmp(&b.data.source_path))
+    });
+
+    let mut placed = HashMap::new();
+    // Lowercased paths of the placed files
+    let mut taken: HashMap<String, PathBuf> = HashMap::new();
+    let mut left_out = Vec::new();
+    for PlacedFile{ device_path, data, is_laid_out } in files {
+        let mut placed_path = device_path.clone();
+        let mut number = 1;
+        loop {
+            let lowercase_path = placed_path.to_string_lossy().to_lowercase();
+            match taken.get(&lowercase_path) {
+                None => {
+                    taken.insert(lowercase_path, placed_path.clone());
+                    placed.insert(placed_path, data);
+                    break;
+                },
+                Some(existing) if is_laid_out == false => {
+                    left_out.push((data, existing.clone()));
+                    break;
+                },
+                Some(_) => {
+                    number += 1;
+                    placed_path = numbered(&device_path, number);
+                },
+            }
+        }
+    }
+    (placed, left_out)
+}
+
+/// Append a number to the name of a file, before its extension
+fn numbered(path: &Path, number: usize) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let file_name = match path.extension() {
+        None => format!("{} ({})", stem, number),
+        Some(extension) => format!("{} ({}).{}", stem, number, extension.to_string_lossy()),
+    };
+    path.with_file_name(file_name)
+}
+
+
+/// What to do with a song whose rating has changed on the device since the previous sync
+#[derive(Debug, PartialEq)]
+pub enum RatingResolution {
+    /// The source and the device agree, there is nothing to do
+    InSync,
+    /// Only the device has changed, the source is updated with its rating
+    FromDevice,
+    /// Both sides have changed, and the config chose this rating
+    Conflict(Rating),
+    /// Both sides have changed, and the user has to choose
+    Ask,
+}
+
+/// Decide how to reverse sync the rating of a song whose rating has changed on the device
+pub fn resolve_rating(policy: RatingConflictPolicy, at_previous_sync: Rating, on_source: Rating, on_device: Rating) -> RatingResolution {
+    if on_source == on_device {
+        RatingResolution::InSync
+    } else if on_source == at_previous_sync {
+        RatingResolution::FromDevice
+    } else {
+        match policy {
+            RatingConflictPolicy::SourceWins => RatingResolution::Conflict(on_source),
+            RatingConflictPolicy::DeviceWins => RatingResolution::Conflict(on_device),
+            RatingConflictPolicy::Highest => RatingResolution::Conflict(on_source.max(on_device)),
+            RatingConflictPolicy::Lowest => RatingResolution::Conflict(on_source.min(on_device)),
+            RatingConflictPolicy::Ask => RatingResolution::Ask,
+        }
+    }
 }
 
 
@@ -172,4 +305,96 @@ mod test {
         assert_eq!(diff.len(), 1);
         assert_eq!(diff.get(0), Some(&PathBuf::from("left")));
     }
+
+    #[test]
+    fn test_resolve_rating() {
+        let stars = NonZeroU8::new;
+        // Only the device has changed
+        assert_eq!(resolve_rating(RatingConflictPolicy::SourceWins, stars(2), stars(2), stars(4)), RatingResolution::FromDevice);
+        assert_eq!(resolve_rating(RatingConflictPolicy::Ask, None, None, stars(1)), RatingResolution::FromDevice);
+        // Both sides have changed the same way
+        assert_eq!(resolve_rating(RatingConflictPolicy::Ask, stars(2), stars(5), stars(5)), RatingResolution::InSync);
+        assert_eq!(resolve_rating(RatingConflictPolicy::DeviceWins, stars(2), None, None), RatingResolution::InSync);
+        // Conflicts
+        assert_eq!(resolve_rating(RatingConflictPolicy::SourceWins, stars(2), stars(3), stars(4)), RatingResolution::Conflict(stars(3)));
+        assert_eq!(resolve_rating(RatingConflictPolicy::DeviceWins, stars(2), stars(3), stars(4)), RatingResolution::Conflict(stars(4)));
+        assert_eq!(resolve_rating(RatingConflictPolicy::Highest, stars(2), stars(3), None), RatingResolution::Conflict(stars(3)));
+        assert_eq!(resolve_rating(RatingConflictPolicy::Lowest, stars(2), stars(3), None), RatingResolution::Conflict(None));
+        assert_eq!(resolve_rating(RatingConflictPolicy::Ask, stars(2), stars(3), stars(4)), RatingResolution::Ask);
+    }
+
+    #[test]
+    fn test_place_files() {
+        let file = |source: &str, source_path: &str, device_path: &str, id| PlacedFile{
+            device_path: PathBuf::from(device_path),
+            data: FileData{
+                file_size: 1000, id: TrackId(id), rating: None, modified: None, source_path: PathBuf::from(source_path),
+                transcoding: None, source: source.to_string(), tags: None,
+            },
+            is_laid_out: false,
+        };
+
+        // Files of the first sources win, whatever the order they come in
+        for reverse in [false, true] {
+            let mut files = vec![
+                file("recordings", "/recordings/Someone/Song.mp3", "Someone/Song.mp3", 1),
+                file("music", "/music/Someone/song.mp3", "Someone/song.mp3", 2),
+                file("team", "/team/Someone/Song.mp3", "Someone/Song.mp3", 3),
+            ];
+            if reverse {
+                files.reverse();
+            }
+            let (placed, left_out) = place_files(files, &["music", "recordings", "team"]);
+            assert_eq!(placed.len(), 1);
+            assert_eq!(placed[Path::new("Someone/song.mp3")].id, TrackId(2));
+            let left_out: Vec<(u64, PathBuf)> = left_out.into_iter().map(|(data, existing)| (data.id.0, existing)).collect();
+            assert_eq!(left_out, vec![(1, PathBuf::from("Someone/song.mp3")), (3, PathBuf::from("Someone/song.mp3"))]);
+        }
+    }
+
+    #[test]
+    fn test_place_laid_out_files() {
+        let layout: crate::layout::Layout = "{artist}/{title}".parse().unwrap();
+        let tags = TrackTags{ title: Some("Song".to_string()), artist: Some("Someone".to_string()), ..Default::default() };
+        let file = |source: &str, source_path: &str, id| {
+            let data = FileData{
+                file_size: 1000, id: TrackId(id), rating: None, modified: None, source_path: PathBuf::from(source_path),
+                transcoding: None, source: source.to_string(), tags: Some(tags.clone()),
+            };
+            match layout.path_for(&tags, Path::new(source_path)) {
+                Some(device_path) => PlacedFile{ device_path, data, is_laid_out: true },
+                None => unreachable!(),
+            }
+        };
+        let unlaid = |source: &str, source_path: &str, device_path: &str, id| {
+            let mut placed = file(source, source_path, id);
+            placed.device_path = PathBuf::from(device_path);
+            placed.is_laid_out = false;
+            placed
+        };
+
+        // Tracks with identical tags are numbered, in the order of their source paths
+        for reverse in [false, true] {
+            let mut files = vec![
+                file("music", "/music/b/song.mp3", 2),
+                file("music", "/music/a/song.mp3", 1),
+                file("music", "/music/c/song.MP3", 3),
+                unlaid("recordings", "/recordings/Someone/Song.mp3", "Someone/Song.mp3", 4),
+                unlaid("music", "/music/z/song.mp3", "Someone/song.mp3", 5),
+            ];
+            if reverse {
+                files.reverse();
+            }
+            let (placed, left_out) = place_files(files, &["music", "recordings"]);
+            let id = |path: &str| placed.get(Path::new(path)).map(|data| data.id.0);
+            assert_eq!(id("Someone/Song.mp3"), Some(1));
+            assert_eq!(id("Someone/Song (2).mp3"), Some(2));
+            assert_eq!(id("Someone/Song (3).MP3"), Some(3));
+            // This path is not laid out, so that it is left out, rather than pushed under another name
+            assert_eq!(id("Someone/song.mp3"), None);
+            assert_eq!(placed.len(), 3);
+            let left_out: Vec<(u64, PathBuf)> = left_out.into_iter().map(|(data, existing)| (data.id.0, existing)).collect();
+            assert_eq!(left_out, vec![(5, PathBuf::from("Someone/Song.mp3")), (4, PathBuf::from("Someone/Song.mp3"))]);
+        }
+    }
 }
@@ -0,0 +1,175 @@
+//! Converting music files into another format before they are pushed to a device
+//!
+//! Converting files is done by an [`Encoder`]. The default one runs `ffmpeg`, but any other command can be configured,
+//! and library users can provide their own implementation.
+
+use std::error::Error;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::EncoderConfig;
+
+/// Used to make temporary file names unique
+static TEMP_FILE_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// The format files are converted into
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Codec {
+    Opus,
+    Mp3,
+    Aac,
+}
+
+impl Codec {
+    /// The extension of the converted files
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Codec::Opus => "opus",
+            Codec::Mp3 => "mp3",
+            Codec::Aac => "m4a",
+        }
+    }
+
+    /// The name of the matching ffmpeg encoder
+    fn ffmpeg_encoder(&self) -> &'static str {
+        match self {
+            Codec::Opus => "libopus",
+            Codec::Mp3 => "libmp3lame",
+            Codec::Aac => "aac",
+        }
+    }
+}
+
+impl fmt::Display for Codec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", match self {
+            Codec::Opus => "opus",
+            Codec::Mp3 => "mp3",
+            Codec::Aac => "aac",
+        })
+    }
+}
+
+/// How a file should be converted
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Profile {
+    pub codec: Codec,
+    pub bitrate_kbps: u32,
+}
+
+impl fmt::Display for Profile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}@{}k", self.codec, self.bitrate_kbps)
+    }
+}
+
+pub trait Encoder {
+    /// Convert the file at `input` and write the result at `output`
+    fn transcode(&self, input: &Path, output: &Path, profile: &Profile) -> Result<(), Box<dyn Error>>;
+}
+
+/// Build the encoder described by a config
+pub fn encoder_from_config(config: &EncoderConfig) -> Box<dyn Encoder> {
+    match config {
+        EncoderConfig::Ffmpeg{ path } => Box::new(Ffmpeg::new(path.clone())),
+        EncoderConfig::Command{ program, args } => Box::new(CustomCommand{ program: program.clone(), args: args.clone() }),
+    }
+}
+
+fn run(command: &mut Command) -> Result<(), Box<dyn Error>> {
+    let output = command.output()
+        .map_err(|err| format!("Unable to run {:?}: {}", command.get_program(), err))?;
+    if output.status.success() == false {
+        return Err(format!("{:?} failed ({}): {}", command.get_program(), output.status, String::from_utf8_lossy(&output.stderr).trim()).into());
+    }
+    Ok(())
+}
+
+
+
+/// Transcode using `ffmpeg`, keeping the tags of the original file
+pub struct Ffmpeg {
+    program: PathBuf,
+}
+
+impl Ffmpeg {
+    /// Use a specific `ffmpeg` executable, or the one in the `PATH`
+    pub fn new(program: Option<PathBuf>) -> Self {
+        Self{ program: program.unwrap_or_else(|| PathBuf::from("ffmpeg")) }
+    }
+}
+
+impl Default for Ffmpeg {
+    fn default() -> Self {
+        Self::new(None)
+    }
+}
+
+impl Encoder for Ffmpeg {
+    fn transcode(&self, input: &Path, output: &Path, profile: &Profile) -> Result<(), Box<dyn Error>> {
+        run(Command::new(&self.program)
+            .args(["-nostdin", "-hide_banner", "-loglevel", "error", "-y", "-i"])
+            .arg(input)
+            .args(["-map", "0:a", "-map_metadata", "0", "-c:a", profile.codec.ffmpeg_encoder()])
+            .arg("-b:a").arg(format!("{}k", profile.bitrate_kbps))
+            .arg(output))
+    }
+}
+
+/// Transcode using an arbitrary command.
+///
+/// These placeholders are replaced in its arguments: `{input}`, `{output}`, `{codec}` and `{bitrate}` (in kbps)
+pub struct CustomCommand {
+    program: String,
+    args: Vec<String>,
+}
+
+impl Encoder for CustomCommand {
+    fn transcode(&self, input: &Path, output: &Path, profile: &Profile) -> Result<(), Box<dyn Error>> {
+        let args = self.args.iter().map(|arg| arg
+            .replace("{input}", &input.to_string_lossy())
+            .replace("{output}", &output.to_string_lossy())
+            .replace("{codec}", &profile.codec.to_string())
+            .replace("{bitrate}", &profile.bitrate_kbps.to_string())
+        );
+        run(Command::new(&self.program).args(args))
+    }
+}
+
+
+
+/// A converted file, in a temporary location. It is deleted when this is dropped.
+pub struct TranscodedFile {
+    path: PathBuf,
+}
+
+impl TranscodedFile {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TranscodedFile {
+    fn drop(&mut self) {
+        // The encoder may have failed before creating it
+        if self.path.exists() == false {
+            return;
+        }
+        if let Err(err) = std::fs::remove_file(&self.path) {
+            log::warn!("Unable to remove temporary file {}: {}", self.path.display(), err);
+        }
+    }
+}
+
+/// Convert a file into a temporary file
+pub fn transcode_to_temp_file(encoder: &dyn Encoder, input: &Path, profile: &Profile) -> Result<TranscodedFile, Box<dyn Error>> {
+    let file_name = format!("starsync-{}-{}.{}", std::process::id(), TEMP_FILE_COUNTER.fetch_add(1, Ordering::Relaxed), profile.codec.extension());
+    let transcoded = TranscodedFile{ path: std::env::temp_dir().join(file_name) };
+    encoder.transcode(input, transcoded.path(), profile)?;
+    Ok(transcoded)
+}
@@ -5,3 +5,52 @@ pub fn current_hostname() -> String {
     system.host_name().unwrap_or_else(|| "<unknown>".to_string())
 }
 
+/// Whether a text matches a (case-insensitive) pattern, where `*` stands for any number of characters and `?` for exactly one
+pub fn matches_pattern(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.to_lowercase().chars().collect();
+    let text: Vec<char> = text.to_lowercase().chars().collect();
+
+    // Classic wildcard matching, backtracking to the latest `*` on mismatches
+    let (mut p, mut t) = (0, 0);
+    let mut latest_star = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                latest_star = Some((p, t));
+                p += 1;
+            },
+            Some(c) if *c == '?' || *c == text[t] => {
+                p += 1;
+                t += 1;
+            },
+            _ => match latest_star {
+                None => return false,
+                Some((star_p, star_t)) => {
+                    // Let this `*` swallow one more character
+                    latest_star = Some((star_p, star_t + 1));
+                    p = star_p + 1;
+                    t = star_t + 1;
+                },
+            },
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn patterns() {
+        assert!(matches_pattern("Road trip", "road TRIP"));
+        assert!(matches_pattern("*jazz*", "Smooth Jazz classics"));
+        assert!(matches_pattern("Mix 20??", "Mix 2023"));
+        assert!(matches_pattern("*", ""));
+        assert!(matches_pattern("a*b*c", "aXbYbZc"));
+        assert!(matches_pattern("Mix 20??", "Mix 202") == false);
+        assert!(matches_pattern("*jazz", "Jazz night") == false);
+        assert!(matches_pattern("Road", "Road trip") == false);
+    }
+}